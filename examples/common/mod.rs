@@ -0,0 +1,263 @@
+// Console commands shared by the examples. Every command only locks the player for short calls,
+// walks run in their own task and report through the walk queue.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use krunker_client::prelude::*;
+use tokio::sync::{watch, Mutex};
+
+// Usage and description of every command
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("games [region]", "list joinable games"),
+    ("join <id|link>", "connect a player to the game"),
+    ("pos", "position and state of the player"),
+    ("walkto <x> <y> <z>", "queue a walk to the position"),
+    ("walkto-named <name>", "queue a walk to an annotated point"),
+    ("shoot <duration>", "hold fire, e.g. shoot 2s"),
+    ("players", "players in the lobby"),
+    ("stats", "session statistics and latency"),
+    ("help", "list the commands"),
+    ("quit", "disconnect and exit"),
+];
+
+pub struct Connection {
+    pub player: Arc<Mutex<Player>>,
+    pub game: Game,
+    pub snapshots: watch::Receiver<PlayerSnapshot>,
+    pub walk_queue: WalkQueue,
+    walking: Arc<AtomicBool>,
+}
+
+pub struct Console {
+    pub client: Arc<Mutex<Client>>,
+    pub connection: Option<Connection>,
+}
+
+impl Console {
+    pub fn new(client: Arc<Mutex<Client>>) -> Self {
+        Self {
+            client,
+            connection: None,
+        }
+    }
+
+    // Returns false when the console should exit
+    pub async fn dispatch(&mut self, line: &str) -> Result<bool, Error> {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        let (command, args) = match args.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(true),
+        };
+
+        match command {
+            "games" => self.games(args.first().copied()).await?,
+            "join" => {
+                self.join(args.first().ok_or("Usage: join <id|link>")?)
+                    .await?
+            }
+            "pos" => self.pos()?,
+            "walkto" => {
+                let coords = args
+                    .iter()
+                    .map(|arg| arg.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let [x, y, z] = coords[..] {
+                    self.walk_to(Vec3 { x, y, z })?;
+                } else {
+                    return Err("Usage: walkto <x> <y> <z>".into());
+                }
+            }
+            "walkto-named" => {
+                self.walk_to_named(args.first().ok_or("Usage: walkto-named <name>")?)
+                    .await?
+            }
+            "shoot" => self.shoot(args.first().copied().unwrap_or("1s"))?,
+            "players" => self.players().await?,
+            "stats" => self.stats()?,
+            "help" => {
+                for (usage, description) in COMMANDS {
+                    println!("{:<22} {}", usage, description);
+                }
+            }
+            "quit" => {
+                if let Some(connection) = self.connection.take() {
+                    connection.player.lock().await.disconnect().await?;
+                }
+                return Ok(false);
+            }
+            _ => return Err(format!("Unknown command {}, try help", command).into()),
+        }
+
+        Ok(true)
+    }
+
+    fn connection(&self) -> Result<&Connection, Error> {
+        self.connection
+            .as_ref()
+            .ok_or_else(|| "Not connected, use join <id|link>".into())
+    }
+
+    async fn games(&self, region: Option<&str>) -> Result<(), Error> {
+        let client_lock = self.client.lock().await;
+        let maps = client_lock.available_maps();
+        for game in client_lock.games().await? {
+            if region.is_none_or(|region| game.region.as_str() == region)
+                && !game.custom
+                && maps.contains(&game.map)
+            {
+                println!(
+                    "{:<12} {:<8} {:>2}/{:<2} {}",
+                    game.id, game.region, game.players, game.max_players, game.map
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn join(&mut self, id_or_link: &str) -> Result<(), Error> {
+        if let Some(connection) = self.connection.take() {
+            connection.player.lock().await.disconnect().await?;
+        }
+
+        let game = if id_or_link.contains("game=") {
+            Game::from_link(&*self.client.lock().await, id_or_link).await?
+        } else {
+            self.client.lock().await.game_by_id(id_or_link).await?
+        };
+        let player = PlayerBuilder::new(self.client.clone())
+            .connect(&game)
+            .await?;
+
+        let (snapshots, walk_queue) = {
+            let player_lock = player.lock().await;
+            (
+                player_lock.watch_snapshot(),
+                player_lock.walk_queue_handle(),
+            )
+        };
+
+        // Walk progress is printed while the console keeps reading commands
+        let mut walks = walk_queue.subscribe();
+        tokio::spawn(async move {
+            while let Ok(walk) = walks.recv().await {
+                println!(
+                    "walk to {:.1} {:.1} {:.1}: {:?}",
+                    walk.destination.x, walk.destination.y, walk.destination.z, walk.outcome
+                );
+            }
+        });
+
+        println!("joined {} on {}", game.id, game.map);
+        self.connection = Some(Connection {
+            player,
+            game,
+            snapshots,
+            walk_queue,
+            walking: Arc::new(AtomicBool::new(false)),
+        });
+        Ok(())
+    }
+
+    fn pos(&self) -> Result<(), Error> {
+        let snapshot = self.connection()?.snapshots.borrow().clone();
+        println!("{}", serde_json::to_string_pretty(&snapshot)?);
+        Ok(())
+    }
+
+    fn walk_to(&self, position: Vec3) -> Result<(), Error> {
+        let connection = self.connection()?;
+        connection.walk_queue.push(position, WalkOptions::default());
+
+        // One task walks the queue, it keeps going while destinations are added
+        if !connection.walking.swap(true, Ordering::SeqCst) {
+            let player = connection.player.clone();
+            let walk_queue = connection.walk_queue.clone();
+            let walking = connection.walking.clone();
+            tokio::spawn(async move {
+                loop {
+                    if let Err(err) = player.lock().await.walk_queued().await {
+                        println!("walking stopped: {}", err);
+                    }
+                    walking.store(false, Ordering::SeqCst);
+                    // A destination might have been added after the queue ran empty
+                    if walk_queue.is_empty() || walking.swap(true, Ordering::SeqCst) {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn walk_to_named(&self, name: &str) -> Result<(), Error> {
+        let map_name = self
+            .connection()?
+            .snapshots
+            .borrow()
+            .map
+            .clone()
+            .ok_or("Map not loaded yet")?;
+        let position = self
+            .client
+            .lock()
+            .await
+            .map(&map_name)
+            .ok_or("Map not available")?
+            .annotations()
+            .point(name)
+            .ok_or_else(|| format!("Unknown location {}", name))?;
+        self.walk_to(position)
+    }
+
+    fn shoot(&self, duration: &str) -> Result<(), Error> {
+        let duration = parse_duration(duration)?;
+        let player = self.connection()?.player.clone();
+        tokio::spawn(async move {
+            if let Err(err) = player.lock().await.shoot(true).await {
+                println!("shoot failed: {}", err);
+                return;
+            }
+            tokio::time::sleep(duration).await;
+            let _ = player.lock().await.shoot(false).await;
+        });
+        Ok(())
+    }
+
+    async fn players(&self) -> Result<(), Error> {
+        let connection = self.connection()?;
+        let game = Game::from_id(&*self.client.lock().await, &connection.game.id).await?;
+        println!("{}/{} players", game.players, game.max_players);
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<(), Error> {
+        // The player is locked while walking, the stats can wait for the next call
+        let player = self.connection()?.player.clone();
+        let player_lock = player
+            .try_lock()
+            .map_err(|_| "Player is busy walking, try again later")?;
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&player_lock.session_summary())?
+        );
+        println!("latency: {:?}", player_lock.latency());
+        Ok(())
+    }
+}
+
+// 2s, 500ms or plain seconds
+pub fn parse_duration(value: &str) -> Result<Duration, Error> {
+    if let Some(ms) = value.strip_suffix("ms") {
+        Ok(Duration::from_millis(ms.parse()?))
+    } else {
+        Ok(Duration::from_secs_f32(
+            value.strip_suffix('s').unwrap_or(value).parse()?,
+        ))
+    }
+}
@@ -0,0 +1,187 @@
+use std::{sync::Arc, time::Duration};
+
+use krunker_client::prelude::*;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tracing::{error, info, Level};
+use tracing_subscriber::FmtSubscriber;
+
+const BOTS: usize = 3;
+const PROJECTION_SIZE: usize = 256;
+
+const INDEX: &str = r##"<!DOCTYPE html>
+<html>
+<body style="background:#222">
+<canvas id="c" width="768" height="768"></canvas>
+<script>
+const ctx = document.getElementById("c").getContext("2d");
+let map = null;
+fetch("/map").then(r => r.json()).then(m => map = m);
+setInterval(async () => {
+    if (!map) return;
+    const players = await (await fetch("/players")).json();
+    const sx = 768 / map.width, sz = 768 / map.height;
+    for (let x = 0; x < map.width; x++) {
+        for (let z = 0; z < map.height; z++) {
+            ctx.fillStyle = map.cells[x * map.height + z] ? "#000" : "#ddd";
+            ctx.fillRect(x * sx, z * sz, sx + 1, sz + 1);
+        }
+    }
+    ctx.fillStyle = "#e33";
+    for (const p of players) {
+        if (!p.in_game) continue;
+        const x = (p.position.x - map.bounds.min_x) / (map.bounds.max_x - map.bounds.min_x) * 768;
+        const z = (p.position.z - map.bounds.min_z) / (map.bounds.max_z - map.bounds.min_z) * 768;
+        ctx.beginPath();
+        ctx.arc(x, z, 5, 0, 2 * Math.PI);
+        ctx.fill();
+    }
+}, 200);
+</script>
+</body>
+</html>"##;
+
+#[derive(Serialize)]
+struct Minimap {
+    name: String,
+    bounds: AABB,
+    width: usize,
+    height: usize,
+    cells: Vec<u8>,
+}
+
+#[tokio::main]
+async fn main() {
+    // logging
+    tracing::subscriber::set_global_default(
+        FmtSubscriber::builder()
+            .with_max_level(Level::INFO)
+            .finish(),
+    )
+    .expect("Failed to set default subscriber");
+
+    let client = Client::new().await.unwrap();
+
+    let filter = GameFilter::new()
+        .mode(GameMode::Ffa)
+        .exclude_custom()
+        .min_free_slots(BOTS as u8);
+    let game = client
+        .lock()
+        .await
+        .find_games(&filter)
+        .await
+        .unwrap()
+        .into_iter()
+        .next()
+        .expect("No matching game found");
+
+    info!("Joining {} on {}", game.id, game.map);
+
+    let pool = Arc::new(Mutex::new(PlayerPool::new()));
+    let builder = PlayerBuilder::new(client.clone());
+    for _ in 0..BOTS {
+        pool.lock().await.connect(&builder, &game).await.unwrap();
+    }
+
+    // The projection only has to be computed once, the positions are polled by the page
+    let minimap = loop {
+        let player = pool.lock().await.players()[0].clone();
+        let player_lock = player.lock().await;
+        if let Some(map) = player_lock.map() {
+            let projection = map.topdown_projection(PROJECTION_SIZE);
+            let shape = projection.shape();
+            break Arc::new(
+                serde_json::to_string(&Minimap {
+                    name: map.name(),
+                    bounds: map.bounds(),
+                    width: shape[0],
+                    height: shape[1],
+                    cells: projection.iter().copied().collect(),
+                })
+                .unwrap(),
+            );
+        }
+        drop(player_lock);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+
+    tokio::spawn(serve(pool.clone(), minimap));
+
+    // Let every bot walk between the spawns
+    for player in pool.lock().await.players() {
+        tokio::spawn(async move {
+            loop {
+                let mut player_lock = player.lock().await;
+                if let Some(spawns) = player_lock.map().map(|map| map.spawns()) {
+                    for spawn in spawns {
+                        if let Err(err) = player_lock.walk_to(&spawn).await {
+                            error!("{:?}", err);
+                            break;
+                        }
+                    }
+                }
+                drop(player_lock);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+    }
+
+    tokio::signal::ctrl_c().await.unwrap();
+    pool.lock().await.disconnect_all().await.unwrap();
+}
+
+async fn serve(pool: Arc<Mutex<PlayerPool>>, minimap: Arc<String>) {
+    let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
+    info!("Serving minimap on http://127.0.0.1:8080");
+
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let pool = pool.clone();
+            let minimap = minimap.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_request(stream, pool, minimap).await {
+                    error!("Failed to handle request: {}", err);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    pool: Arc<Mutex<PlayerPool>>,
+    minimap: Arc<String>,
+) -> Result<(), Error> {
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (content_type, body) = match path {
+        "/map" => ("application/json", minimap.to_string()),
+        "/players" => (
+            "application/json",
+            serde_json::to_string(&pool.lock().await.snapshots())?,
+        ),
+        _ => ("text/html", INDEX.to_owned()),
+    };
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,32 @@
+mod common;
+
+use krunker_client::prelude::*;
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+use common::Console;
+
+// cargo run --example repl, then type help
+#[tokio::main]
+async fn main() {
+    // logging
+    tracing::subscriber::set_global_default(
+        FmtSubscriber::builder()
+            .with_max_level(Level::WARN)
+            .finish(),
+    )
+    .expect("Failed to set default subscriber");
+
+    let client = Client::new().await.unwrap();
+    let mut console = Console::new(client);
+
+    let mut lines = BufReader::new(io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match console.dispatch(&line).await {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(err) => println!("error: {}", err),
+        }
+    }
+}
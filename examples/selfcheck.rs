@@ -0,0 +1,37 @@
+use std::process;
+
+use krunker_client::prelude::*;
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+// cargo run --example selfcheck -- [region]
+// Prints the report as JSON and exits with 1 if a stage failed
+#[tokio::main]
+async fn main() {
+    // logging, stdout is kept for the report
+    tracing::subscriber::set_global_default(
+        FmtSubscriber::builder()
+            .with_max_level(Level::WARN)
+            .with_writer(std::io::stderr)
+            .finish(),
+    )
+    .expect("Failed to set default subscriber");
+
+    let region = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "de-fra".to_owned());
+
+    let client = Client::new().await.unwrap();
+    let report = Client::self_check(&client, &region).await;
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    if let Some(stage) = report.failed_stage() {
+        eprintln!(
+            "Self-check failed at {}: {}",
+            stage.stage,
+            stage.error.as_deref().unwrap_or("unknown error")
+        );
+        process::exit(1);
+    }
+}
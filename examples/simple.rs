@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-use krunker_client::{player::PlayerBuilder, Client};
+use krunker_client::prelude::*;
 use tracing::{error, info, Level};
 use tracing_subscriber::FmtSubscriber;
 
@@ -17,25 +17,14 @@ async fn main() {
     let client = Client::new().await.unwrap();
 
     loop {
-        let games = {
-            let client_lock = client.lock().await;
-            let maps = client_lock.available_maps();
-            client_lock
-                .games()
-                .await
-                .unwrap()
-                .into_iter()
-                .filter(|g| {
-                    g.players == 0
-                        && !g.custom
-                        && maps.contains(&g.map)
-                        && g.mode == 0
-                        && g.region == "de-fra"
-                })
-                .collect::<Vec<_>>()
-        };
-
-        let game = games.get(0).unwrap();
+        let filter = GameFilter::new()
+            .region(ServerRegion::Frankfurt)
+            .mode(GameMode::Ffa)
+            .exclude_custom()
+            .max_players_at_most(0);
+        let games = client.lock().await.find_games(&filter).await.unwrap();
+
+        let game = games.first().unwrap();
 
         info!("{}", game.id);
 
@@ -0,0 +1,51 @@
+use std::{process, time::Duration};
+
+use krunker_client::prelude::*;
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+const DEFAULT_REGIONS: &[&str] = &["de-fra", "us-nj", "sgp"];
+const REGION_TIMEOUT: Duration = Duration::from_secs(90);
+
+// cargo run --example smoke -- [region...]
+// Prints the report as JSON and exits with 1 if a region failed
+#[tokio::main]
+async fn main() {
+    // logging, stdout is kept for the report
+    tracing::subscriber::set_global_default(
+        FmtSubscriber::builder()
+            .with_max_level(Level::WARN)
+            .with_writer(std::io::stderr)
+            .finish(),
+    )
+    .expect("Failed to set default subscriber");
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let regions = if args.is_empty() {
+        DEFAULT_REGIONS.to_vec()
+    } else {
+        args.iter().map(String::as_str).collect()
+    };
+
+    let client = Client::new().await.unwrap();
+    let report = Client::smoke_test(&client, &regions, REGION_TIMEOUT).await;
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    let mut failed = false;
+    for region in report.failed() {
+        failed = true;
+        let stage = region.failed_stage();
+        eprintln!(
+            "{} failed at {}: {}",
+            region.region,
+            stage.map_or("unknown stage", |stage| &stage.stage),
+            stage
+                .and_then(|stage| stage.error.as_deref())
+                .unwrap_or("unknown error")
+        );
+    }
+    if failed {
+        process::exit(1);
+    }
+}
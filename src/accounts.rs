@@ -0,0 +1,399 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{player::Account, utils::Error};
+
+// Hex encoded 32 byte key used by EncryptedFileStore::from_env
+pub const ACCOUNTS_KEY_ENV: &str = "KRUNKER_ACCOUNTS_KEY";
+
+const FILE_MAGIC: &[u8; 4] = b"KCA1";
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredAccount {
+    pub account: Account,
+    // Session token of the last successful login. Players still log in with the password, the login
+    // response isn't parsed yet, so the token has to be captured with a message hook.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl From<Account> for StoredAccount {
+    fn from(account: Account) -> Self {
+        Self {
+            account,
+            token: None,
+        }
+    }
+}
+
+pub trait AccountStore: Send + Sync {
+    fn load_all(&self) -> Result<Vec<StoredAccount>, Error>;
+
+    fn get(&self, username: &str) -> Result<Option<StoredAccount>, Error> {
+        Ok(self
+            .load_all()?
+            .into_iter()
+            .find(|stored| stored.account.username == username))
+    }
+
+    // Replaces the stored account with the same username
+    fn save(&self, account: StoredAccount) -> Result<(), Error>;
+
+    // Import a plaintext JSON array of accounts, e.g. the accounts of an old config.
+    // Returns the number of imported accounts, the plaintext file is left alone.
+    fn import_plaintext(&self, path: &Path) -> Result<usize, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        let accounts: Vec<Account> = serde_json::from_str(&content)
+            .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))?;
+
+        let count = accounts.len();
+        for account in accounts {
+            self.save(account.into())?;
+        }
+        Ok(count)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    accounts: std::sync::Mutex<BTreeMap<String, StoredAccount>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AccountStore for MemoryStore {
+    fn load_all(&self) -> Result<Vec<StoredAccount>, Error> {
+        Ok(self.accounts.lock().unwrap().values().cloned().collect())
+    }
+
+    fn get(&self, username: &str) -> Result<Option<StoredAccount>, Error> {
+        Ok(self.accounts.lock().unwrap().get(username).cloned())
+    }
+
+    fn save(&self, account: StoredAccount) -> Result<(), Error> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .insert(account.account.username.clone(), account);
+        Ok(())
+    }
+}
+
+// All accounts in one ChaCha20-Poly1305 encrypted JSON file, a missing file is an empty store.
+// Every save writes the whole file with a new nonce.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    cipher: ChaCha20Poly1305,
+    // Serializes read-modify-write cycles of save within this process
+    lock: std::sync::Mutex<()>,
+}
+
+impl fmt::Debug for EncryptedFileStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedFileStore")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedFileStore {
+    pub fn new(path: impl AsRef<Path>, key: &[u8; 32]) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            lock: std::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn from_env(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let key = std::env::var(ACCOUNTS_KEY_ENV)
+            .map_err(|_| format!("{} is not set", ACCOUNTS_KEY_ENV))?;
+        Ok(Self::new(path, &Self::parse_key(&key)?))
+    }
+
+    // 64 hex characters
+    pub fn parse_key(key: &str) -> Result<[u8; 32], Error> {
+        let bytes = hex::decode(key.trim())
+            .map_err(|err| format!("{} is not valid hex: {}", ACCOUNTS_KEY_ENV, err))?;
+        bytes.try_into().map_err(|bytes: Vec<u8>| {
+            format!(
+                "{} has to be 32 bytes, got {}",
+                ACCOUNTS_KEY_ENV,
+                bytes.len()
+            )
+            .into()
+        })
+    }
+
+    pub fn generate_key() -> [u8; 32] {
+        rand::thread_rng().gen()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Vec<StoredAccount>, Error> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                return Err(format!("Failed to read {}: {}", self.path.display(), err).into())
+            }
+        };
+
+        if data.len() < FILE_MAGIC.len() + NONCE_LEN || &data[..FILE_MAGIC.len()] != FILE_MAGIC {
+            return Err(
+                format!("{} is not an encrypted account store", self.path.display()).into(),
+            );
+        }
+
+        let (nonce, ciphertext) = data[FILE_MAGIC.len()..].split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                format!(
+                    "Failed to decrypt {}, the key is wrong or the file is corrupted",
+                    self.path.display()
+                )
+            })?;
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    fn write(&self, accounts: &[StoredAccount]) -> Result<(), Error> {
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                serde_json::to_vec(accounts)?.as_slice(),
+            )
+            .map_err(|_| format!("Failed to encrypt {}", self.path.display()))?;
+
+        let mut data = Vec::with_capacity(FILE_MAGIC.len() + NONCE_LEN + ciphertext.len());
+        data.extend_from_slice(FILE_MAGIC);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+
+        // Write next to the file and rename, so a crash never leaves a half written store
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)
+            .map_err(|err| format!("Failed to write {}: {}", tmp_path.display(), err))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .map_err(|err| format!("Failed to write {}: {}", self.path.display(), err))?;
+        Ok(())
+    }
+}
+
+impl AccountStore for EncryptedFileStore {
+    fn load_all(&self) -> Result<Vec<StoredAccount>, Error> {
+        let _lock = self.lock.lock().unwrap();
+        self.read()
+    }
+
+    fn save(&self, account: StoredAccount) -> Result<(), Error> {
+        let _lock = self.lock.lock().unwrap();
+        let mut accounts = self.read()?;
+        match accounts
+            .iter_mut()
+            .find(|stored| stored.account.username == account.account.username)
+        {
+            Some(stored) => *stored = account,
+            None => accounts.push(account),
+        }
+        self.write(&accounts)
+    }
+}
+
+// Account of a player, either given directly or looked up in a store when the player connects
+#[derive(Clone)]
+pub enum AccountSource {
+    Account(Account),
+    Stored {
+        store: Arc<dyn AccountStore>,
+        username: String,
+    },
+}
+
+impl AccountSource {
+    pub fn stored(store: Arc<dyn AccountStore>, username: &str) -> Self {
+        Self::Stored {
+            store,
+            username: username.to_owned(),
+        }
+    }
+
+    pub fn resolve(&self) -> Result<Account, Error> {
+        match self {
+            Self::Account(account) => Ok(account.clone()),
+            Self::Stored { store, username } => Ok(store
+                .get(username)?
+                .ok_or_else(|| format!("Account {} not found in the store", username))?
+                .account),
+        }
+    }
+}
+
+impl fmt::Debug for AccountSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Account(account) => f.debug_tuple("Account").field(&account.username).finish(),
+            Self::Stored { username, .. } => f.debug_tuple("Stored").field(username).finish(),
+        }
+    }
+}
+
+impl From<Account> for AccountSource {
+    fn from(account: Account) -> Self {
+        Self::Account(account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Store in its own directory under the temp dir, removed again when dropped
+    struct TempStore {
+        dir: PathBuf,
+        store: EncryptedFileStore,
+    }
+
+    impl TempStore {
+        fn new(name: &str, key: &[u8; 32]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "krunker-accounts-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let store = EncryptedFileStore::new(dir.join("accounts.bin"), key);
+            Self { dir, store }
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn stored(username: &str, password: &str) -> StoredAccount {
+        Account {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn accounts_round_trip_through_the_file() {
+        let key = EncryptedFileStore::generate_key();
+        let temp = TempStore::new("round-trip", &key);
+        assert!(temp.store.load_all().unwrap().is_empty());
+
+        temp.store.save(stored("a", "one")).unwrap();
+        temp.store.save(stored("b", "two")).unwrap();
+        let mut updated = stored("a", "three");
+        updated.token = Some("token".to_owned());
+        temp.store.save(updated).unwrap();
+
+        // A new store with the same key reads what the first one wrote
+        let store = EncryptedFileStore::new(temp.store.path(), &key);
+        let accounts = store.load_all().unwrap();
+        assert_eq!(accounts.len(), 2);
+        let a = store.get("a").unwrap().unwrap();
+        assert_eq!(a.account.password, "three");
+        assert_eq!(a.token.as_deref(), Some("token"));
+        assert!(store.get("c").unwrap().is_none());
+
+        // Nothing is stored in plaintext
+        let data = std::fs::read(temp.store.path()).unwrap();
+        assert!(data.starts_with(FILE_MAGIC));
+        assert!(!data.windows(5).any(|window| window == b"three"));
+    }
+
+    #[test]
+    fn wrong_keys_fail_to_decrypt() {
+        let temp = TempStore::new("wrong-key", &[1; 32]);
+        temp.store.save(stored("a", "one")).unwrap();
+
+        let store = EncryptedFileStore::new(temp.store.path(), &[2; 32]);
+        let err = store.load_all().unwrap_err().to_string();
+        assert!(err.contains("the key is wrong"), "{}", err);
+        // Saving doesn't overwrite a store it can't read
+        assert!(store.save(stored("b", "two")).is_err());
+        assert_eq!(temp.store.load_all().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn truncated_and_tampered_files_are_rejected() {
+        let temp = TempStore::new("tampered", &[3; 32]);
+        temp.store.save(stored("a", "one")).unwrap();
+        let path = temp.store.path().to_owned();
+        let data = std::fs::read(&path).unwrap();
+
+        std::fs::write(&path, &data[..FILE_MAGIC.len() + NONCE_LEN - 1]).unwrap();
+        let err = temp.store.load_all().unwrap_err().to_string();
+        assert!(err.contains("not an encrypted account store"), "{}", err);
+
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(temp.store.load_all().is_err());
+
+        let mut tampered = data.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        std::fs::write(&path, &tampered).unwrap();
+        assert!(temp.store.load_all().is_err());
+
+        let mut magic = data.clone();
+        magic[0] = b'X';
+        std::fs::write(&path, &magic).unwrap();
+        assert!(temp.store.load_all().is_err());
+
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(temp.store.load_all().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn keys_are_64_hex_characters() {
+        let key = EncryptedFileStore::generate_key();
+        assert_eq!(
+            EncryptedFileStore::parse_key(&format!(" {} \n", hex::encode(key))).unwrap(),
+            key
+        );
+        assert!(EncryptedFileStore::parse_key("zz").is_err());
+        assert!(EncryptedFileStore::parse_key(&hex::encode([0; 31])).is_err());
+    }
+
+    #[test]
+    fn stored_sources_resolve_from_the_store() {
+        let store = Arc::new(MemoryStore::new());
+        store.save(stored("a", "one")).unwrap();
+
+        let source = AccountSource::stored(store.clone(), "a");
+        assert_eq!(source.resolve().unwrap().password, "one");
+        assert!(AccountSource::stored(store, "b").resolve().is_err());
+        // Passwords don't end up in logs
+        assert_eq!(format!("{:?}", source), "Stored(\"a\")");
+    }
+}
@@ -0,0 +1,192 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{Error, Vec3};
+
+#[derive(Debug, Clone)]
+pub enum AnnotationSource {
+    Json(String),
+    Toml(String),
+    // The format is picked by the file extension
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Annotations {
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    #[serde(default)]
+    pub points: HashMap<String, Vec3>,
+    #[serde(default)]
+    pub routes: HashMap<String, Vec<String>>,
+    // Automatic region names (region-07) mapped to readable names
+    #[serde(default)]
+    pub regions: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AnnotationReport {
+    pub stale: bool,
+    pub unwalkable_points: Vec<String>,
+    pub unknown_route_points: Vec<(String, String)>,
+}
+
+impl AnnotationReport {
+    pub fn is_ok(&self) -> bool {
+        !self.stale && self.unwalkable_points.is_empty() && self.unknown_route_points.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct AnnotationFile {
+    #[serde(default)]
+    pub(crate) maps: HashMap<String, Annotations>,
+}
+
+impl AnnotationSource {
+    pub(crate) fn parse(&self) -> Result<AnnotationFile, Error> {
+        match self {
+            Self::Json(content) => Ok(serde_json::from_str(content)?),
+            Self::Toml(content) => Ok(toml::from_str(content)?),
+            Self::File(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("json") => Self::Json(content).parse(),
+                    Some("toml") => Self::Toml(content).parse(),
+                    _ => Err(format!(
+                        "Unknown annotation file format for {}, expected .json or .toml",
+                        path.display()
+                    )
+                    .into()),
+                }
+            }
+        }
+    }
+}
+
+impl Annotations {
+    pub fn point(&self, name: &str) -> Option<Vec3> {
+        self.points.get(name).copied()
+    }
+
+    pub fn route(&self, name: &str) -> Option<Vec<Vec3>> {
+        self.routes
+            .get(name)?
+            .iter()
+            .map(|point| self.point(point))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{map::Map, soak};
+
+    const JSON: &str = r#"{
+        "maps": {
+            "a": {
+                "points": {
+                    "spawn": { "x": 0.0, "y": 0.0, "z": 0.0 },
+                    "corner": { "x": 80.0, "y": 0.0, "z": 80.0 },
+                    "sky": { "x": 0.0, "y": 500.0, "z": 900.0 }
+                },
+                "routes": {
+                    "patrol": ["spawn", "corner", "spawn"],
+                    "broken": ["spawn", "nowhere"]
+                },
+                "regions": { "region-00": "Courtyard" }
+            }
+        }
+    }"#;
+
+    const TOML: &str = r#"
+        [maps.a]
+        fingerprint = "0000000000000000"
+
+        [maps.a.points]
+        spawn = { x = 0.0, y = 0.0, z = 0.0 }
+        corner = { x = 80.0, y = 0.0, z = 80.0 }
+
+        [maps.a.routes]
+        patrol = ["spawn", "corner"]
+    "#;
+
+    #[test]
+    fn json_and_toml_give_the_same_annotations() {
+        let json = AnnotationSource::Json(JSON.to_owned()).parse().unwrap();
+        let toml = AnnotationSource::Toml(TOML.to_owned()).parse().unwrap();
+        let (json, toml) = (&json.maps["a"], &toml.maps["a"]);
+
+        assert_eq!(json.fingerprint, None);
+        assert_eq!(toml.fingerprint.as_deref(), Some("0000000000000000"));
+        assert_eq!(json.point("corner").unwrap().x, 80.0);
+        assert_eq!(toml.point("corner").unwrap().z, 80.0);
+        assert_eq!(json.regions["region-00"], "Courtyard");
+        assert!(toml.regions.is_empty());
+
+        assert_eq!(json.route("patrol").unwrap().len(), 3);
+        assert_eq!(toml.route("patrol").unwrap().len(), 2);
+        // Routes over unknown points don't resolve
+        assert!(json.route("broken").is_none());
+        assert!(json.route("missing").is_none());
+    }
+
+    #[test]
+    fn files_are_parsed_by_their_extension() {
+        let dir = std::env::temp_dir().join(format!("krunker-annotations-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, content: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            AnnotationSource::File(path).parse()
+        };
+
+        assert!(write("a.json", JSON).unwrap().maps.contains_key("a"));
+        assert!(write("a.toml", TOML).unwrap().maps.contains_key("a"));
+        // The content has to match the extension
+        assert!(write("b.json", TOML).is_err());
+        let unknown = write("a.yaml", JSON).unwrap_err();
+        assert!(unknown.to_string().contains("expected .json or .toml"));
+        assert!(AnnotationSource::File(dir.join("missing.json"))
+            .parse()
+            .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loading_reports_problems_with_the_annotations() {
+        let mut map = Map::new(&soak::raw_map("a")).unwrap();
+        let report = map
+            .load_annotations(&AnnotationSource::Json(JSON.to_owned()))
+            .unwrap();
+        assert!(!report.is_ok());
+        assert!(!report.stale);
+        assert_eq!(report.unwalkable_points, vec!["sky"]);
+        assert_eq!(
+            report.unknown_route_points,
+            vec![("broken".to_owned(), "nowhere".to_owned())]
+        );
+        assert!(map.annotations().point("corner").is_some());
+
+        // Made for another version of the map
+        let report = map
+            .load_annotations(&AnnotationSource::Toml(TOML.to_owned()))
+            .unwrap();
+        assert!(report.stale);
+        assert!(report.unwalkable_points.is_empty() && report.unknown_route_points.is_empty());
+
+        let fitting = TOML.replace("0000000000000000", &map.fingerprint());
+        let report = map
+            .load_annotations(&AnnotationSource::Toml(fitting))
+            .unwrap();
+        assert!(report.is_ok());
+
+        // Files without the map are an error
+        let other = AnnotationSource::Json(r#"{ "maps": {} }"#.to_owned());
+        assert!(map.load_annotations(&other).is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    sync::{broadcast, Mutex},
+    task::JoinHandle,
+    time,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    modes::GameMode, player::PlayerBuilder, pool::PlayerPool, server_region::ServerRegion,
+    utils::Error, Client, Game,
+};
+
+#[derive(Debug, Clone)]
+pub struct AutoscalePolicy {
+    pub target_bots: usize,
+    pub max_per_lobby: usize,
+    // Players in the lobby that are not bots of this autoscaler
+    pub min_humans: u8,
+    // Empty means any region
+    pub regions: Vec<ServerRegion>,
+    // Empty means any mode
+    pub modes: Vec<GameMode>,
+    pub interval: Duration,
+}
+
+impl Default for AutoscalePolicy {
+    fn default() -> Self {
+        Self {
+            target_bots: 1,
+            max_per_lobby: 2,
+            min_humans: 1,
+            regions: vec![],
+            modes: vec![],
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl AutoscalePolicy {
+    pub fn matches(&self, game: &Game) -> bool {
+        (self.regions.is_empty() || self.regions.contains(&game.region))
+            && (self.modes.is_empty() || self.modes.contains(&game.mode))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AutoscaleEvent {
+    Joined { game_id: String },
+    JoinFailed { game_id: String, error: String },
+    Left { game_id: String, reason: String },
+    // Not enough matching lobbies to reach the target
+    Unsatisfied { bots: usize, target: usize },
+}
+
+pub struct Autoscaler {
+    client: Arc<Mutex<Client>>,
+    builder: PlayerBuilder,
+    policy: AutoscalePolicy,
+    pool: PlayerPool,
+    events: broadcast::Sender<AutoscaleEvent>,
+    stopped: bool,
+}
+
+impl Autoscaler {
+    pub fn new(
+        client: Arc<Mutex<Client>>,
+        builder: PlayerBuilder,
+        policy: AutoscalePolicy,
+    ) -> Arc<Mutex<Self>> {
+        let (events, _) = broadcast::channel(64);
+
+        Arc::new(Mutex::new(Self {
+            client,
+            builder,
+            policy,
+            pool: PlayerPool::new(),
+            events,
+            stopped: false,
+        }))
+    }
+
+    // Reconcile in the interval of the policy until the autoscaler is stopped
+    pub fn run(this: Arc<Mutex<Self>>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = time::interval(this.lock().await.policy.interval);
+            loop {
+                interval.tick().await;
+
+                let mut this_lock = this.lock().await;
+                if this_lock.stopped {
+                    break;
+                }
+
+                if let Err(err) = this_lock.reconcile().await {
+                    error!("Failed to reconcile autoscaler: {}", err);
+                }
+            }
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AutoscaleEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn policy(&self) -> &AutoscalePolicy {
+        &self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: AutoscalePolicy) {
+        self.policy = policy;
+    }
+
+    pub fn pool(&self) -> &PlayerPool {
+        &self.pool
+    }
+
+    pub async fn stop(&mut self) -> Result<(), Error> {
+        self.stopped = true;
+        self.pool.disconnect_all().await
+    }
+
+    // Join and leave lobbies once so the pool satisfies the policy for the current game list.
+    // Joins happen one after another and the per lobby counts are updated after every join,
+    // so a lobby never gets more than max_per_lobby bots.
+    pub async fn reconcile(&mut self) -> Result<(), Error> {
+        for game_id in self.pool.remove_disconnected() {
+            self.emit(AutoscaleEvent::Left {
+                game_id,
+                reason: "connection closed".to_owned(),
+            });
+        }
+
+        let games = {
+            let client_lock = self.client.lock().await;
+            let maps = client_lock.available_maps();
+            client_lock
+                .games()
+                .await?
+                .into_iter()
+                .filter(|g| !g.custom && maps.contains(&g.map) && self.policy.matches(g))
+                .map(|g| (g.id.clone(), g))
+                .collect::<HashMap<_, _>>()
+        };
+
+        // Leave lobbies that died, ran out of humans or have too many bots
+        for (game_id, bots) in self.pool.game_counts() {
+            let (leave, reason) = match self.to_leave(games.get(&game_id), bots) {
+                Some(leave) => leave,
+                None => continue,
+            };
+
+            let left = self.pool.disconnect_from(&game_id, leave).await?;
+            for _ in 0..left {
+                self.emit(AutoscaleEvent::Left {
+                    game_id: game_id.clone(),
+                    reason: reason.to_owned(),
+                });
+            }
+        }
+
+        // Scale down starting with the lobbies that have the most bots
+        while self.pool.len() > self.policy.target_bots {
+            let game_id = Self::busiest(self.pool.game_counts()).ok_or("Pool is empty")?;
+
+            self.pool.disconnect_from(&game_id, 1).await?;
+            self.emit(AutoscaleEvent::Left {
+                game_id,
+                reason: "above target".to_owned(),
+            });
+        }
+
+        // Scale up, preferring lobbies with fewer bots and then more humans.
+        // The game list was fetched before this pass joined anything, so the humans are counted with the
+        // bots from that time and the new bots are added to the listed players.
+        let listed = self.pool.game_counts();
+        let mut failed = HashSet::<String>::new();
+        while self.pool.len() < self.policy.target_bots {
+            let counts = self.pool.game_counts();
+            let candidate = self.next_lobby(games.values(), &counts, &listed, &failed);
+
+            let game = if let Some(game) = candidate {
+                game
+            } else {
+                warn!(
+                    "Autoscaler found no lobby for {} more bots",
+                    self.policy.target_bots - self.pool.len()
+                );
+                self.emit(AutoscaleEvent::Unsatisfied {
+                    bots: self.pool.len(),
+                    target: self.policy.target_bots,
+                });
+                break;
+            };
+
+            info!("Autoscaler joining {}", game.id);
+            match self.pool.connect(&self.builder, game).await {
+                Ok(_) => self.emit(AutoscaleEvent::Joined {
+                    game_id: game.id.clone(),
+                }),
+                Err(err) => {
+                    // The lobby might have died since the game list was fetched, try the next one
+                    failed.insert(game.id.clone());
+                    self.emit(AutoscaleEvent::JoinFailed {
+                        game_id: game.id.clone(),
+                        error: err.to_string(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // How many bots leave the lobby and why, None if they all stay
+    fn to_leave(&self, game: Option<&Game>, bots: usize) -> Option<(usize, &'static str)> {
+        match game {
+            None => Some((bots, "lobby is no longer listed")),
+            Some(game) if Self::humans(game, bots) < self.policy.min_humans => {
+                Some((bots, "not enough human players"))
+            }
+            Some(_) if bots > self.policy.max_per_lobby => {
+                Some((bots - self.policy.max_per_lobby, "too many bots in lobby"))
+            }
+            Some(_) => None,
+        }
+    }
+
+    // Lobby with the most bots, ties go to the first id
+    fn busiest(counts: HashMap<String, usize>) -> Option<String> {
+        counts
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+            .map(|(game_id, _)| game_id)
+    }
+
+    // Lobby the next bot joins. Counts are the bots of the pool now, listed the ones the game list
+    // already counted.
+    fn next_lobby<'a>(
+        &self,
+        games: impl Iterator<Item = &'a Game>,
+        counts: &HashMap<String, usize>,
+        listed: &HashMap<String, usize>,
+        failed: &HashSet<String>,
+    ) -> Option<&'a Game> {
+        games
+            .filter(|game| {
+                let bots = counts.get(&game.id).copied().unwrap_or(0);
+                let listed_bots = listed.get(&game.id).copied().unwrap_or(0);
+                !failed.contains(&game.id)
+                    && bots < self.policy.max_per_lobby
+                    && (game.players as usize + bots - listed_bots) < game.max_players as usize
+                    && Self::humans(game, listed_bots) >= self.policy.min_humans
+            })
+            .min_by_key(|game| {
+                let bots = counts.get(&game.id).copied().unwrap_or(0);
+                let listed_bots = listed.get(&game.id).copied().unwrap_or(0);
+                (
+                    bots,
+                    u8::MAX - Self::humans(game, listed_bots),
+                    game.id.clone(),
+                )
+            })
+    }
+
+    // The player count of the game list includes the bots
+    fn humans(game: &Game, bots: usize) -> u8 {
+        game.players
+            .saturating_sub(bots.min(u8::MAX as usize) as u8)
+    }
+
+    fn emit(&self, event: AutoscaleEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soak;
+
+    fn autoscaler(policy: AutoscalePolicy) -> Arc<Mutex<Autoscaler>> {
+        let client = Arc::new(Mutex::new(soak::client(vec![], "http://127.0.0.1:9")));
+        Autoscaler::new(client.clone(), PlayerBuilder::new(client), policy)
+    }
+
+    // Lobby of 8 with the players of the game list, bots included
+    fn lobby(id: &str, players: u8) -> Game {
+        let mut game = soak::game(id, ServerRegion::Frankfurt, "a", GameMode::Ffa);
+        game.players = players;
+        game
+    }
+
+    fn counts(entries: &[(&str, usize)]) -> HashMap<String, usize> {
+        entries
+            .iter()
+            .map(|(id, bots)| (id.to_string(), *bots))
+            .collect()
+    }
+
+    #[test]
+    fn policies_match_regions_and_modes() {
+        let game = lobby("FRA:a", 4);
+        assert!(AutoscalePolicy::default().matches(&game));
+
+        let policy = AutoscalePolicy {
+            regions: vec![ServerRegion::Frankfurt],
+            modes: vec![GameMode::Ffa, GameMode::Tdm],
+            ..Default::default()
+        };
+        assert!(policy.matches(&game));
+        let policy = AutoscalePolicy {
+            regions: vec![ServerRegion::Sydney],
+            ..Default::default()
+        };
+        assert!(!policy.matches(&game));
+        let policy = AutoscalePolicy {
+            modes: vec![GameMode::Tdm],
+            ..Default::default()
+        };
+        assert!(!policy.matches(&game));
+    }
+
+    #[tokio::test]
+    async fn bots_join_the_emptiest_lobby_with_the_most_humans() {
+        let autoscaler = autoscaler(AutoscalePolicy {
+            max_per_lobby: 2,
+            min_humans: 2,
+            ..Default::default()
+        });
+        let autoscaler = autoscaler.lock().await;
+        // Two bots of the pool are already counted in busy, none in the others
+        let games = [
+            lobby("FRA:busy", 7),
+            lobby("FRA:quiet", 3),
+            lobby("FRA:lively", 6),
+            lobby("FRA:lonely", 1),
+            lobby("FRA:full", 8),
+        ];
+        let listed = counts(&[("FRA:busy", 2)]);
+        let next = |counts: &HashMap<String, usize>, failed: &HashSet<String>| {
+            autoscaler
+                .next_lobby(games.iter(), counts, &listed, failed)
+                .map(|game| game.id.clone())
+        };
+
+        let failed = HashSet::new();
+        assert_eq!(next(&listed, &failed).as_deref(), Some("FRA:lively"));
+        // Lobbies with a bot come after the ones without
+        let joined = counts(&[("FRA:busy", 2), ("FRA:lively", 1)]);
+        assert_eq!(next(&joined, &failed).as_deref(), Some("FRA:quiet"));
+        // Lobbies the join failed for are skipped
+        let failed = HashSet::from(["FRA:lively".to_owned()]);
+        assert_eq!(next(&listed, &failed).as_deref(), Some("FRA:quiet"));
+
+        // Every lobby with room is at the limit, lonely and full never qualify
+        let joined = counts(&[("FRA:busy", 2), ("FRA:lively", 2), ("FRA:quiet", 2)]);
+        assert_eq!(next(&joined, &HashSet::new()), None);
+    }
+
+    #[tokio::test]
+    async fn bots_leave_lobbies_that_no_longer_fit() {
+        let autoscaler = autoscaler(AutoscalePolicy {
+            max_per_lobby: 2,
+            min_humans: 1,
+            ..Default::default()
+        });
+        let autoscaler = autoscaler.lock().await;
+
+        assert_eq!(autoscaler.to_leave(Some(&lobby("FRA:a", 4)), 2), None);
+        assert_eq!(
+            autoscaler.to_leave(None, 2),
+            Some((2, "lobby is no longer listed"))
+        );
+        // Only bots are left
+        assert_eq!(
+            autoscaler.to_leave(Some(&lobby("FRA:a", 2)), 2),
+            Some((2, "not enough human players"))
+        );
+        assert_eq!(
+            autoscaler.to_leave(Some(&lobby("FRA:a", 6)), 3),
+            Some((1, "too many bots in lobby"))
+        );
+    }
+
+    #[test]
+    fn scaling_down_starts_with_the_busiest_lobby() {
+        let busiest = |entries: &[(&str, usize)]| Autoscaler::busiest(counts(entries));
+        assert_eq!(busiest(&[]), None);
+        assert_eq!(
+            busiest(&[("FRA:a", 1), ("FRA:b", 3), ("FRA:c", 2)]).as_deref(),
+            Some("FRA:b")
+        );
+        assert_eq!(
+            busiest(&[("FRA:c", 2), ("FRA:b", 2), ("FRA:a", 1)]).as_deref(),
+            Some("FRA:b")
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::watch,
+    time::{self, Instant},
+};
+
+// Source of time for the player and socket layers. Everything that reads the time or waits (tick loop,
+// latency measurement, respawn delays, handshake timeout) goes through the clock, so a manual clock
+// makes the whole pipeline deterministic and ticks only happen when it is advanced.
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    #[default]
+    Tokio,
+    Manual(ManualClock),
+}
+
+impl Clock {
+    pub fn manual() -> Self {
+        Self::Manual(ManualClock::new())
+    }
+
+    pub fn now(&self) -> Instant {
+        match self {
+            Self::Tokio => Instant::now(),
+            Self::Manual(clock) => clock.now(),
+        }
+    }
+
+    pub fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    pub async fn sleep(&self, duration: Duration) {
+        match self {
+            Self::Tokio => tokio::time::sleep(duration).await,
+            Self::Manual(clock) => clock.sleep(duration).await,
+        }
+    }
+
+    // Like tokio::time::interval, the first tick completes right away
+    pub fn interval(&self, period: Duration) -> Interval {
+        match self {
+            Self::Tokio => Interval::Tokio(time::interval(period)),
+            Self::Manual(clock) => Interval::Manual {
+                clock: clock.clone(),
+                period,
+                next: clock.now(),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Interval {
+    Tokio(time::Interval),
+    Manual {
+        clock: ManualClock,
+        period: Duration,
+        next: Instant,
+    },
+}
+
+impl Interval {
+    pub async fn tick(&mut self) -> Instant {
+        match self {
+            Self::Tokio(interval) => interval.tick().await,
+            Self::Manual {
+                clock,
+                period,
+                next,
+            } => {
+                clock
+                    .sleep(next.saturating_duration_since(clock.now()))
+                    .await;
+                // Missed ticks complete right away one after another, like the default of tokio
+                let tick = *next;
+                *next += *period;
+                tick
+            }
+        }
+    }
+
+    // The next tick completes one period from now
+    pub fn reset(&mut self) {
+        match self {
+            Self::Tokio(interval) => interval.reset(),
+            Self::Manual {
+                clock,
+                period,
+                next,
+            } => *next = clock.now() + *period,
+        }
+    }
+}
+
+// Only moves forward when advanced, clones share the same time
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    offset: Arc<watch::Sender<Duration>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        let (offset, _) = watch::channel(Duration::ZERO);
+        Self {
+            start: Instant::now(),
+            offset: Arc::new(offset),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.start + *self.offset.borrow()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.offset.send_modify(|offset| *offset += duration);
+    }
+
+    pub async fn sleep(&self, duration: Duration) {
+        let mut offset = self.offset.subscribe();
+        let deadline = *offset.borrow() + duration;
+        while *offset.borrow_and_update() < deadline {
+            if offset.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_when_advanced() {
+        let clock = Clock::manual();
+        let start = clock.now();
+        assert_eq!(clock.elapsed(start), Duration::ZERO);
+
+        if let Clock::Manual(manual) = &clock {
+            manual.advance(Duration::from_secs(3));
+        }
+        assert_eq!(clock.elapsed(start), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn manual_sleep_waits_for_the_clock() {
+        let clock = ManualClock::new();
+        let mut sleep = Box::pin(clock.sleep(Duration::from_millis(100)));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        clock.advance(Duration::from_millis(99));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(sleep.now_or_never().is_some());
+    }
+
+    #[test]
+    fn manual_interval_ticks_only_when_advanced() {
+        let manual = ManualClock::new();
+        let clock = Clock::Manual(manual.clone());
+        let period = Duration::from_millis(50);
+        let mut interval = clock.interval(period);
+
+        let first = interval.tick().now_or_never().unwrap();
+        assert_eq!(first, manual.now());
+        assert!(interval.tick().now_or_never().is_none());
+
+        manual.advance(period);
+        assert_eq!(interval.tick().now_or_never(), Some(first + period));
+        assert!(interval.tick().now_or_never().is_none());
+
+        // Missed ticks complete one after another
+        manual.advance(period * 3);
+        for i in 2..=4 {
+            assert_eq!(interval.tick().now_or_never(), Some(first + period * i));
+        }
+        assert!(interval.tick().now_or_never().is_none());
+    }
+
+    #[test]
+    fn manual_interval_reset_starts_a_new_period() {
+        let manual = ManualClock::new();
+        let period = Duration::from_millis(50);
+        let mut interval = Clock::Manual(manual.clone()).interval(period);
+        interval.tick().now_or_never().unwrap();
+
+        manual.advance(Duration::from_millis(30));
+        interval.reset();
+        manual.advance(Duration::from_millis(30));
+        assert!(interval.tick().now_or_never().is_none());
+
+        manual.advance(Duration::from_millis(20));
+        assert_eq!(interval.tick().now_or_never(), Some(manual.now()));
+    }
+}
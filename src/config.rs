@@ -0,0 +1,259 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    modes::GameMode,
+    player::{Account, PlayerBuilder},
+    pool::PlayerPool,
+    server_region::ServerRegion,
+    utils::Error,
+    Client, Game,
+};
+
+fn default_tick_interval_ms() -> u64 {
+    66
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FleetConfig {
+    pub bots: usize,
+    #[serde(default)]
+    pub regions: Vec<ServerRegion>,
+    #[serde(default)]
+    pub mode: Option<GameMode>,
+    #[serde(default)]
+    pub maps: Vec<String>,
+    #[serde(default = "default_tick_interval_ms")]
+    pub tick_interval_ms: u64,
+    #[serde(default)]
+    pub accounts: Vec<Account>,
+}
+
+impl FleetConfig {
+    pub fn from_toml(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Self::from_toml_str(&content)
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<Self, Error> {
+        let config: Self = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml_string(&self) -> Result<String, Error> {
+        Ok(toml::to_string(self)?)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.bots == 0 {
+            return Err("bots: a fleet needs at least one bot".into());
+        }
+
+        if self.tick_interval_ms == 0 {
+            return Err("tick_interval_ms: must be greater than 0".into());
+        }
+
+        if self.accounts.len() > self.bots {
+            return Err(format!(
+                "accounts[{}] ({}): {} accounts configured for only {} bots",
+                self.bots,
+                self.accounts[self.bots].username,
+                self.accounts.len(),
+                self.bots
+            )
+            .into());
+        }
+
+        for (i, account) in self.accounts.iter().enumerate() {
+            if account.username.is_empty() {
+                return Err(format!("accounts[{}].username: must not be empty", i).into());
+            }
+            if account.password.is_empty() {
+                return Err(format!(
+                    "accounts[{}].password ({}): must not be empty",
+                    i, account.username
+                )
+                .into());
+            }
+            if let Some(j) = self.accounts[..i]
+                .iter()
+                .position(|a| a.username == account.username)
+            {
+                return Err(format!(
+                    "accounts[{}].username ({}): already used by accounts[{}]",
+                    i, account.username, j
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn matches(&self, game: &Game) -> bool {
+        (self.regions.is_empty() || self.regions.contains(&game.region))
+            && self.mode.is_none_or(|mode| mode == game.mode)
+            && (self.maps.is_empty() || self.maps.contains(&game.map))
+    }
+
+    // One builder per bot, the first bots get the configured accounts
+    pub fn player_builders(&self, client: &Arc<Mutex<Client>>) -> Vec<PlayerBuilder> {
+        (0..self.bots)
+            .map(|i| {
+                let builder = PlayerBuilder::new(client.clone())
+                    .tick_interval(Duration::from_millis(self.tick_interval_ms));
+                if let Some(account) = self.accounts.get(i) {
+                    builder.account(account.clone())
+                } else {
+                    builder
+                }
+            })
+            .collect()
+    }
+
+    pub async fn build(&self, client: Arc<Mutex<Client>>) -> Result<PlayerPool, Error> {
+        self.validate()?;
+
+        let mut games = {
+            let client_lock = client.lock().await;
+            let maps = client_lock.available_maps();
+            client_lock
+                .games()
+                .await?
+                .into_iter()
+                .filter(|g| !g.custom && maps.contains(&g.map) && self.matches(g))
+                .collect::<Vec<_>>()
+        };
+
+        // Fill the emptiest games first
+        games.sort_by_key(|g| g.players);
+
+        let mut pool = PlayerPool::new();
+        let mut builders = self.player_builders(&client).into_iter();
+        'outer: for game in games.iter() {
+            for _ in game.players..game.max_players {
+                if let Some(builder) = builders.next() {
+                    info!("Connecting bot {} to {}", pool.len(), game.id);
+                    if let Err(err) = pool.connect(&builder, game).await {
+                        pool.disconnect_all().await?;
+                        return Err(err);
+                    }
+                } else {
+                    break 'outer;
+                }
+            }
+        }
+
+        if pool.len() < self.bots {
+            let connected = pool.len();
+            pool.disconnect_all().await?;
+            return Err(format!(
+                "bots: only found room for {} of {} bots in matching games",
+                connected, self.bots
+            )
+            .into());
+        }
+
+        Ok(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fleet() -> FleetConfig {
+        FleetConfig::from_toml_str("bots = 2").unwrap()
+    }
+
+    fn account(username: &str) -> Account {
+        Account {
+            username: username.to_owned(),
+            password: "password".to_owned(),
+        }
+    }
+
+    #[test]
+    fn example_fleet_parses_and_round_trips() {
+        let config = FleetConfig::from_toml("examples/fleet.toml").unwrap();
+        assert_eq!(config.bots, 4);
+        assert_eq!(
+            config.regions,
+            vec![ServerRegion::Frankfurt, ServerRegion::NewJersey]
+        );
+        assert_eq!(config.mode, Some(GameMode::from_id(0)));
+        assert_eq!(config.accounts.len(), 2);
+
+        let toml = config.to_toml_string().unwrap();
+        let parsed = FleetConfig::from_toml_str(&toml).unwrap();
+        assert_eq!(parsed.to_toml_string().unwrap(), toml);
+    }
+
+    #[test]
+    fn missing_fields_take_their_defaults() {
+        let config = fleet();
+        assert_eq!(config.tick_interval_ms, 66);
+        assert!(config.regions.is_empty() && config.maps.is_empty());
+        assert!(config.mode.is_none() && config.accounts.is_empty());
+    }
+
+    #[test]
+    fn validation_names_the_broken_field() {
+        let error = |config: &FleetConfig| config.validate().unwrap_err().to_string();
+
+        let mut config = fleet();
+        config.bots = 0;
+        assert!(error(&config).starts_with("bots:"));
+
+        let mut config = fleet();
+        config.tick_interval_ms = 0;
+        assert!(error(&config).starts_with("tick_interval_ms:"));
+
+        let mut config = fleet();
+        config.accounts = vec![account("a"), account("b"), account("c")];
+        assert!(error(&config).starts_with("accounts[2] (c):"));
+
+        config.accounts = vec![account("a"), account("")];
+        assert!(error(&config).starts_with("accounts[1].username:"));
+
+        config.accounts = vec![account("a"), account("a")];
+        assert_eq!(
+            error(&config),
+            "accounts[1].username (a): already used by accounts[0]"
+        );
+
+        config.accounts[1].password.clear();
+        assert!(error(&config).starts_with("accounts[1].password (a):"));
+
+        assert!(FleetConfig::from_toml_str("bots = 0").is_err());
+    }
+
+    #[test]
+    fn empty_filters_match_every_game() {
+        let ffa = GameMode::from_id(0);
+        let game = crate::soak::game("FRA:a", ServerRegion::Frankfurt, "Burg", ffa);
+        let mut config = fleet();
+        assert!(config.matches(&game));
+
+        config.regions = vec![ServerRegion::NewJersey];
+        assert!(!config.matches(&game));
+        config.regions.push(ServerRegion::Frankfurt);
+        assert!(config.matches(&game));
+
+        config.mode = Some(GameMode::from_id(1));
+        assert!(!config.matches(&game));
+        config.mode = Some(ffa);
+        assert!(config.matches(&game));
+
+        config.maps = vec!["Sandstorm".to_owned()];
+        assert!(!config.matches(&game));
+        config.maps.push("Burg".to_owned());
+        assert!(config.matches(&game));
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::utils::{Error, Vec3};
+
+// Marks chat messages that carry a coordination payload
+pub const CHAT_PREFIX: &str = "~kc~";
+pub const MAX_CHAT_LEN: usize = 100;
+const DEFAULT_CHAT_INTERVAL: Duration = Duration::from_secs(2);
+// Sequence numbers remembered per sender for de-duplication
+const DEDUP_WINDOW: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Coordination {
+    ClaimObjective(String),
+    // Milliseconds since the unix epoch
+    EnemySpotted { position: Vec3, at: u64 },
+    Custom(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub sender: String,
+    pub seq: u32,
+    pub message: Coordination,
+}
+
+pub trait Bus: Send + Sync {
+    // Name of this bot on the bus
+    fn sender(&self) -> &str;
+
+    fn publish(&self, message: Coordination) -> Result<(), Error>;
+
+    // Messages of the other bots, duplicates and own messages are filtered out
+    fn subscribe(&self) -> BusReceiver;
+}
+
+pub struct BusReceiver {
+    sender: String,
+    receiver: broadcast::Receiver<Envelope>,
+    seen: HashMap<String, VecDeque<u32>>,
+}
+
+impl BusReceiver {
+    fn new(sender: &str, receiver: broadcast::Receiver<Envelope>) -> Self {
+        Self {
+            sender: sender.to_owned(),
+            receiver,
+            seen: HashMap::new(),
+        }
+    }
+
+    // None once the bus is gone
+    pub async fn recv(&mut self) -> Option<Envelope> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(envelope) => {
+                    if envelope.sender != self.sender && self.first_time(&envelope) {
+                        return Some(envelope);
+                    }
+                }
+                // Slow receivers skip the oldest messages
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn first_time(&mut self, envelope: &Envelope) -> bool {
+        let seen = self.seen.entry(envelope.sender.clone()).or_default();
+        if seen.contains(&envelope.seq) {
+            return false;
+        }
+        if seen.len() >= DEDUP_WINDOW {
+            seen.pop_front();
+        }
+        seen.push_back(envelope.seq);
+        true
+    }
+}
+
+// In-process bus for bots of the same program, e.g. the bots of a PlayerPool
+#[derive(Debug)]
+pub struct LocalBus {
+    sender: String,
+    seq: AtomicU32,
+    channel: broadcast::Sender<Envelope>,
+}
+
+impl LocalBus {
+    pub fn new(sender: &str) -> Self {
+        let (channel, _) = broadcast::channel(256);
+        Self {
+            sender: sender.to_owned(),
+            seq: AtomicU32::new(0),
+            channel,
+        }
+    }
+
+    // Another bot on the same bus
+    pub fn join(&self, sender: &str) -> Self {
+        Self {
+            sender: sender.to_owned(),
+            seq: AtomicU32::new(0),
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl Bus for LocalBus {
+    fn sender(&self) -> &str {
+        &self.sender
+    }
+
+    fn publish(&self, message: Coordination) -> Result<(), Error> {
+        // Nobody listening is fine
+        let _ = self.channel.send(Envelope {
+            sender: self.sender.clone(),
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            message,
+        });
+        Ok(())
+    }
+
+    fn subscribe(&self) -> BusReceiver {
+        BusReceiver::new(&self.sender, self.channel.subscribe())
+    }
+}
+
+// Bus over team chat for bots in different processes. The crate doesn't send chat yet, so the
+// caller moves the texts: send what poll_outgoing returns as chat and pass the text of every
+// PlayerEvent::ChatMessage to receive_chat, which returns true for texts that should be hidden.
+#[derive(Debug)]
+pub struct ChatBus {
+    sender: String,
+    seq: AtomicU32,
+    min_interval: Duration,
+    outgoing: std::sync::Mutex<(VecDeque<String>, Option<Instant>)>,
+    incoming: broadcast::Sender<Envelope>,
+}
+
+impl ChatBus {
+    pub fn new(sender: &str) -> Self {
+        let (incoming, _) = broadcast::channel(256);
+        Self {
+            sender: sender.to_owned(),
+            seq: AtomicU32::new(0),
+            min_interval: DEFAULT_CHAT_INTERVAL,
+            outgoing: std::sync::Mutex::new((VecDeque::new(), None)),
+            incoming,
+        }
+    }
+
+    // The server mutes players that chat too fast
+    pub fn min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    pub fn encode(envelope: &Envelope) -> Result<String, Error> {
+        let text = format!(
+            "{}{}",
+            CHAT_PREFIX,
+            URL_SAFE_NO_PAD.encode(rmp_serde::to_vec(envelope)?)
+        );
+        if text.len() > MAX_CHAT_LEN {
+            return Err(format!(
+                "Coordination message is {} characters long, chat allows {}",
+                text.len(),
+                MAX_CHAT_LEN
+            )
+            .into());
+        }
+        Ok(text)
+    }
+
+    // None for normal chat messages
+    pub fn decode(text: &str) -> Option<Result<Envelope, Error>> {
+        let payload = text.trim().strip_prefix(CHAT_PREFIX)?;
+        Some(
+            URL_SAFE_NO_PAD
+                .decode(payload)
+                .map_err(Error::from)
+                .and_then(|bytes| Ok(rmp_serde::from_slice(&bytes)?)),
+        )
+    }
+
+    // Next chat text to send, rate limited
+    pub fn poll_outgoing(&self) -> Option<String> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        let (queue, last_sent) = &mut *outgoing;
+        if last_sent.is_some_and(|last_sent| last_sent.elapsed() < self.min_interval) {
+            return None;
+        }
+
+        let text = queue.pop_front()?;
+        *last_sent = Some(Instant::now());
+        Some(text)
+    }
+
+    pub fn receive_chat(&self, text: &str) -> bool {
+        match Self::decode(text) {
+            Some(Ok(envelope)) => {
+                let _ = self.incoming.send(envelope);
+                true
+            }
+            // Looks like ours but is broken, still nothing a human wants to read
+            Some(Err(_)) => true,
+            None => false,
+        }
+    }
+}
+
+impl Bus for ChatBus {
+    fn sender(&self) -> &str {
+        &self.sender
+    }
+
+    fn publish(&self, message: Coordination) -> Result<(), Error> {
+        let text = Self::encode(&Envelope {
+            sender: self.sender.clone(),
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            message,
+        })?;
+        self.outgoing.lock().unwrap().0.push_back(text);
+        Ok(())
+    }
+
+    fn subscribe(&self) -> BusReceiver {
+        BusReceiver::new(&self.sender, self.incoming.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(sender: &str, seq: u32) -> Envelope {
+        Envelope {
+            sender: sender.to_owned(),
+            seq,
+            message: Coordination::ClaimObjective("a".to_owned()),
+        }
+    }
+
+    fn claimed(envelope: &Envelope) -> Option<&str> {
+        match &envelope.message {
+            Coordination::ClaimObjective(objective) => Some(objective),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn chat_texts_round_trip() {
+        let text = ChatBus::encode(&claim("bot-1", 7)).unwrap();
+        assert!(text.starts_with(CHAT_PREFIX) && text.len() <= MAX_CHAT_LEN);
+
+        let envelope = ChatBus::decode(&format!(" {} ", text)).unwrap().unwrap();
+        assert_eq!((envelope.sender.as_str(), envelope.seq), ("bot-1", 7));
+        assert_eq!(claimed(&envelope), Some("a"));
+
+        assert!(ChatBus::decode("gg").is_none());
+        assert!(ChatBus::decode(&format!("{}!!", CHAT_PREFIX))
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn long_messages_dont_fit_into_chat() {
+        let envelope = Envelope {
+            message: Coordination::Custom(vec![0; MAX_CHAT_LEN]),
+            ..claim("bot-1", 0)
+        };
+        assert!(ChatBus::encode(&envelope).is_err());
+        assert!(ChatBus::new("bot-1").publish(envelope.message).is_err());
+    }
+
+    #[tokio::test]
+    async fn local_bus_delivers_to_the_other_bots() {
+        let first = LocalBus::new("bot-1");
+        let second = first.join("bot-2");
+        let mut first_messages = first.subscribe();
+        let mut second_messages = second.subscribe();
+
+        first
+            .publish(Coordination::ClaimObjective("a".to_owned()))
+            .unwrap();
+        second
+            .publish(Coordination::ClaimObjective("b".to_owned()))
+            .unwrap();
+
+        let envelope = second_messages.recv().await.unwrap();
+        assert_eq!(
+            (envelope.sender.as_str(), claimed(&envelope)),
+            ("bot-1", Some("a"))
+        );
+        let envelope = first_messages.recv().await.unwrap();
+        assert_eq!(
+            (envelope.sender.as_str(), claimed(&envelope)),
+            ("bot-2", Some("b"))
+        );
+
+        drop((first, second));
+        assert!(first_messages.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn chat_bus_filters_own_and_repeated_messages() {
+        let bus = ChatBus::new("bot-1");
+        let mut messages = bus.subscribe();
+
+        let own = ChatBus::encode(&claim("bot-1", 0)).unwrap();
+        let other = ChatBus::encode(&claim("bot-2", 0)).unwrap();
+        let next = ChatBus::encode(&claim("bot-2", 1)).unwrap();
+        for text in [&own, &other, &other, &next] {
+            assert!(bus.receive_chat(text));
+        }
+        assert!(!bus.receive_chat("gg"));
+        // Broken payloads are hidden but not delivered
+        assert!(bus.receive_chat(&format!("{}!!", CHAT_PREFIX)));
+
+        assert_eq!(messages.recv().await.unwrap().seq, 0);
+        assert_eq!(messages.recv().await.unwrap().seq, 1);
+        drop(bus);
+        assert!(messages.recv().await.is_none());
+    }
+
+    #[test]
+    fn chat_is_rate_limited() {
+        let bus = ChatBus::new("bot-1").min_interval(Duration::from_secs(60));
+        assert!(bus.poll_outgoing().is_none());
+        for objective in ["a", "b"] {
+            bus.publish(Coordination::ClaimObjective(objective.to_owned()))
+                .unwrap();
+        }
+
+        let text = bus.poll_outgoing().unwrap();
+        assert_eq!(
+            claimed(&ChatBus::decode(&text).unwrap().unwrap()),
+            Some("a")
+        );
+        assert!(bus.poll_outgoing().is_none());
+
+        let bus = ChatBus::new("bot-1").min_interval(Duration::ZERO);
+        for objective in ["a", "b"] {
+            bus.publish(Coordination::ClaimObjective(objective.to_owned()))
+                .unwrap();
+        }
+        assert!(bus.poll_outgoing().is_some() && bus.poll_outgoing().is_some());
+    }
+}
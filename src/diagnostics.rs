@@ -0,0 +1,228 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{
+    map::{Map, MIN_WALKABLE_SHARE},
+    utils::AABB,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MapDiagnostics {
+    pub name: String,
+    pub bounds: AABB,
+    pub grid_size: (usize, usize, usize),
+    pub walkable_cells: usize,
+    pub ladder_cells: usize,
+    pub hazard_cells: usize,
+    // Walkable cells of every connected component by id, see Map::component_of
+    pub component_sizes: Vec<usize>,
+    // Component of every spawn in the order of the spawns, None for spawns without a walkable cell
+    // close to them
+    pub spawn_components: Vec<Option<u16>>,
+}
+
+impl MapDiagnostics {
+    pub fn components(&self) -> usize {
+        self.component_sizes.len()
+    }
+
+    pub fn ungrounded_spawns(&self) -> Vec<usize> {
+        self.spawn_components
+            .iter()
+            .enumerate()
+            .filter(|(_, component)| component.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+// Things that keep a bot from playing on a map, spawns are referred to by their index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum MapWarning {
+    NoSpawns,
+    UngroundedSpawn {
+        spawn: usize,
+    },
+    // In another component than the first grounded spawn
+    UnreachableSpawn {
+        spawn: usize,
+        from: usize,
+    },
+    LowWalkableShare {
+        walkable_cells: usize,
+        area_cells: usize,
+    },
+}
+
+impl fmt::Display for MapWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapWarning::NoSpawns => write!(f, "Map has no spawns"),
+            MapWarning::UngroundedSpawn { spawn } => {
+                write!(f, "Spawn {} has no walkable cell close to it", spawn)
+            }
+            MapWarning::UnreachableSpawn { spawn, from } => {
+                write!(f, "Spawn {} is unreachable from spawn {}", spawn, from)
+            }
+            MapWarning::LowWalkableShare {
+                walkable_cells,
+                area_cells,
+            } => write!(
+                f,
+                "Only {} walkable cells for an area of {} cells",
+                walkable_cells, area_cells
+            ),
+        }
+    }
+}
+
+impl Map {
+    pub fn diagnostics(&self) -> MapDiagnostics {
+        MapDiagnostics {
+            name: self.name(),
+            bounds: self.bounds(),
+            grid_size: self.grid_size(),
+            walkable_cells: self.walkable_cell_count(),
+            ladder_cells: self.walkable_grid.count(2),
+            hazard_cells: self.coverage().hazard_cells,
+            component_sizes: self.walkable_grid.component_sizes(),
+            spawn_components: self
+                .spawns()
+                .iter()
+                .map(|spawn| {
+                    self.closest_walkable_cell(spawn)
+                        .and_then(|cell| self.component_of(&cell))
+                })
+                .collect(),
+        }
+    }
+
+    // Empty for maps a bot can play on
+    pub fn validate(&self) -> Vec<MapWarning> {
+        let diagnostics = self.diagnostics();
+        let mut warnings = vec![];
+
+        if diagnostics.spawn_components.is_empty() {
+            warnings.push(MapWarning::NoSpawns);
+        }
+        let grounded = diagnostics
+            .spawn_components
+            .iter()
+            .enumerate()
+            .filter_map(|(i, component)| component.map(|component| (i, component)))
+            .collect::<Vec<_>>();
+        warnings.extend(
+            diagnostics
+                .ungrounded_spawns()
+                .into_iter()
+                .map(|spawn| MapWarning::UngroundedSpawn { spawn }),
+        );
+        // The last component id is shared by the components past it, see Map::same_component
+        if let Some((from, first)) = grounded.first() {
+            warnings.extend(
+                grounded
+                    .iter()
+                    .filter(|(_, component)| {
+                        component != first && *component != u16::MAX && *first != u16::MAX
+                    })
+                    .map(|(spawn, _)| MapWarning::UnreachableSpawn {
+                        spawn: *spawn,
+                        from: *from,
+                    }),
+            );
+        }
+
+        let area_cells = diagnostics.grid_size.0 * diagnostics.grid_size.2;
+        if (diagnostics.walkable_cells as f32) < area_cells as f32 * MIN_WALKABLE_SHARE {
+            warnings.push(MapWarning::LowWalkableShare {
+                walkable_cells: diagnostics.walkable_cells,
+                area_cells,
+            });
+        }
+
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{map::RawMapObject, soak};
+
+    fn map_with_spawns(spawns: &[[f32; 3]]) -> Map {
+        let mut raw_map = soak::raw_map("diagnostics");
+        raw_map.spawns = spawns
+            .iter()
+            .map(|spawn| spawn.iter().copied().map(Some).collect())
+            .collect();
+        Map::new(&raw_map).unwrap()
+    }
+
+    #[test]
+    fn playable_maps_have_no_warnings() {
+        let map = map_with_spawns(&[[0.0, 0.0, 0.0], [40.0, 0.0, 40.0]]);
+        let diagnostics = map.diagnostics();
+        assert_eq!(diagnostics.name, "diagnostics");
+        assert_eq!(diagnostics.components(), 1);
+        assert_eq!(diagnostics.component_sizes[0], diagnostics.walkable_cells);
+        assert_eq!(diagnostics.spawn_components, vec![Some(0), Some(0)]);
+        assert!(diagnostics.ungrounded_spawns().is_empty());
+        assert_eq!(diagnostics.ladder_cells, 0);
+        assert!(map.validate().is_empty());
+    }
+
+    #[test]
+    fn spawns_without_ground_or_a_way_to_the_others_are_reported() {
+        // A second floor of 40 with a gap of 40 to the first one, and a spawn far off both
+        let mut raw_map = soak::raw_map("islands");
+        raw_map.sizes.extend([40.0, 6.0, 40.0]);
+        raw_map.objects.push(RawMapObject {
+            position: [160.0, -6.0, 0.0],
+            size_index: Some(2),
+            ..Default::default()
+        });
+        raw_map.spawns = vec![
+            vec![Some(0.0), Some(0.0), Some(0.0)],
+            vec![Some(160.0), Some(0.0), Some(0.0)],
+            vec![Some(0.0), Some(0.0), Some(400.0)],
+        ];
+        let map = Map::new(&raw_map).unwrap();
+
+        let diagnostics = map.diagnostics();
+        assert_eq!(diagnostics.components(), 2);
+        assert_eq!(diagnostics.ungrounded_spawns(), vec![2]);
+        let warnings = map.validate();
+        assert_eq!(
+            warnings,
+            vec![
+                MapWarning::UngroundedSpawn { spawn: 2 },
+                MapWarning::UnreachableSpawn { spawn: 1, from: 0 },
+            ]
+        );
+        assert_eq!(
+            warnings[1].to_string(),
+            "Spawn 1 is unreachable from spawn 0"
+        );
+    }
+
+    #[test]
+    fn maps_without_spawns_have_nothing_to_walk_on() {
+        let map = map_with_spawns(&[]);
+        let diagnostics = map.diagnostics();
+        assert_eq!(diagnostics.walkable_cells, 0);
+        assert_eq!(diagnostics.components(), 0);
+
+        let (x, _, z) = diagnostics.grid_size;
+        assert_eq!(
+            map.validate(),
+            vec![
+                MapWarning::NoSpawns,
+                MapWarning::LowWalkableShare {
+                    walkable_cells: 0,
+                    area_cells: x * z,
+                },
+            ]
+        );
+    }
+}
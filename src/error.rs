@@ -0,0 +1,208 @@
+use std::fmt;
+
+use tokio_tungstenite::tungstenite;
+
+use crate::{lifecycle::InvalidTransition, utils::Vec3};
+
+#[derive(Debug)]
+pub enum KrunkerError {
+    Http(reqwest::Error),
+    // Boxed, the error is large and would bloat every Result
+    WebSocket(Box<tungstenite::Error>),
+    // Every matchmaker endpoint failed, with the response of the last one
+    Matchmaker {
+        status: u16,
+        body: String,
+    },
+    // A request kept failing with a retryable error, see RetryPolicy
+    RetriesExhausted {
+        attempts: u32,
+        last: Box<KrunkerError>,
+    },
+    MapParse(String),
+    MapUnavailable,
+    PositionNotWalkable,
+    // No walkable cell close to the start or the destination of a path
+    StartNotWalkable,
+    DestinationNotWalkable,
+    PathNotFound,
+    // The search expanded more cells than PathOptions allowed
+    PathBudgetExceeded,
+    // The search was given up through its CancellationToken
+    PathCancelled,
+    // Walking made no progress towards the waypoint, not even after planning the path again
+    Stuck {
+        position: Vec3,
+        waypoint: Vec3,
+    },
+    // WalkHandle::cancel or a walk that was started after it
+    WalkCancelled,
+    // The matchmaker has no game with the id
+    GameNotFound(String),
+    // The social server has no profile with the username
+    ProfileNotFound(String),
+    NotInGame,
+    Disconnected,
+    SourceExtraction(String),
+    // Frames arrived after connecting but none of them could be decoded
+    ProtocolDesync {
+        frames_received: usize,
+        decode_errors: usize,
+    },
+    Decode(rmp_serde::decode::Error),
+    Encode(rmp_serde::encode::Error),
+    Json(serde_json::Error),
+    Io(std::io::Error),
+    InvalidTransition(InvalidTransition),
+    // Everything without its own variant yet
+    Other(String),
+}
+
+impl KrunkerError {
+    // Failures that can go away by retrying, everything else is a logic or data error
+    pub fn is_network(&self) -> bool {
+        match self {
+            KrunkerError::Http(_) | KrunkerError::WebSocket(_) => true,
+            KrunkerError::Matchmaker { status, .. } => *status >= 500,
+            KrunkerError::RetriesExhausted { last, .. } => last.is_network(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for KrunkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KrunkerError::Http(err) => write!(f, "HTTP request failed: {}", err),
+            KrunkerError::WebSocket(err) => write!(f, "WebSocket failed: {}", err),
+            KrunkerError::Matchmaker { status, body } => {
+                write!(f, "Matchmaker responded with {}: {}", status, body)
+            }
+            KrunkerError::RetriesExhausted { attempts, last } => {
+                write!(f, "{} (gave up after {} attempts)", last, attempts)
+            }
+            KrunkerError::MapParse(message) => write!(f, "Failed to parse map: {}", message),
+            KrunkerError::MapUnavailable => write!(f, "Map information not available"),
+            KrunkerError::PositionNotWalkable => write!(f, "Position not walkable"),
+            KrunkerError::StartNotWalkable => write!(f, "Start of the path not walkable"),
+            KrunkerError::DestinationNotWalkable => {
+                write!(f, "Destination of the path not walkable")
+            }
+            KrunkerError::PathNotFound => write!(f, "No path found"),
+            KrunkerError::PathBudgetExceeded => {
+                write!(f, "Path search explored too many cells")
+            }
+            KrunkerError::PathCancelled => write!(f, "Path search was cancelled"),
+            KrunkerError::Stuck { position, waypoint } => {
+                write!(f, "Stuck at {:?} while walking to {:?}", position, waypoint)
+            }
+            KrunkerError::WalkCancelled => write!(f, "Walk was cancelled"),
+            KrunkerError::GameNotFound(id) => write!(f, "Game {} not found", id),
+            KrunkerError::ProfileNotFound(username) => write!(f, "Profile {} not found", username),
+            KrunkerError::NotInGame => write!(f, "Player not in game"),
+            KrunkerError::Disconnected => write!(f, "Player disconnected"),
+            KrunkerError::SourceExtraction(message) => write!(f, "{}", message),
+            KrunkerError::ProtocolDesync {
+                frames_received,
+                decode_errors,
+            } => write!(
+                f,
+                "Protocol desync: received {} frames but none could be decoded ({} decode errors), the client is probably outdated. Try Client::refresh",
+                frames_received, decode_errors
+            ),
+            KrunkerError::Decode(err) => write!(f, "Failed to decode message: {}", err),
+            KrunkerError::Encode(err) => write!(f, "Failed to encode message: {}", err),
+            KrunkerError::Json(err) => write!(f, "Invalid JSON: {}", err),
+            KrunkerError::Io(err) => write!(f, "{}", err),
+            KrunkerError::InvalidTransition(err) => write!(f, "{}", err),
+            KrunkerError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for KrunkerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KrunkerError::Http(err) => Some(err),
+            KrunkerError::WebSocket(err) => Some(err.as_ref()),
+            KrunkerError::RetriesExhausted { last, .. } => Some(last.as_ref()),
+            KrunkerError::Decode(err) => Some(err),
+            KrunkerError::Encode(err) => Some(err),
+            KrunkerError::Json(err) => Some(err),
+            KrunkerError::Io(err) => Some(err),
+            KrunkerError::InvalidTransition(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<tungstenite::Error> for KrunkerError {
+    fn from(err: tungstenite::Error) -> Self {
+        KrunkerError::WebSocket(Box::new(err))
+    }
+}
+
+impl From<&str> for KrunkerError {
+    fn from(message: &str) -> Self {
+        KrunkerError::Other(message.to_owned())
+    }
+}
+
+impl From<String> for KrunkerError {
+    fn from(message: String) -> Self {
+        KrunkerError::Other(message)
+    }
+}
+
+macro_rules! impl_from {
+    ($($variant:ident($err:ty)),* $(,)?) => {
+        $(
+            impl From<$err> for KrunkerError {
+                fn from(err: $err) -> Self {
+                    KrunkerError::$variant(err)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    Http(reqwest::Error),
+    Decode(rmp_serde::decode::Error),
+    Encode(rmp_serde::encode::Error),
+    Json(serde_json::Error),
+    Io(std::io::Error),
+    InvalidTransition(InvalidTransition),
+);
+
+// Errors that only ever show up as messages
+macro_rules! impl_from_other {
+    ($($err:ty),* $(,)?) => {
+        $(
+            impl From<$err> for KrunkerError {
+                fn from(err: $err) -> Self {
+                    KrunkerError::Other(err.to_string())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_other!(
+    regex::Error,
+    tungstenite::http::Error,
+    tokio::sync::watch::error::RecvError,
+    std::str::Utf8Error,
+    std::num::ParseIntError,
+    std::num::ParseFloatError,
+    toml::de::Error,
+    toml::ser::Error,
+    base64::DecodeError,
+    bincode::Error,
+    hex::FromHexError,
+    chacha20poly1305::Error,
+    tokio_socks::Error,
+);
+
+#[cfg(feature = "debug-export")]
+impl_from_other!(png::EncodingError);
@@ -0,0 +1,269 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::utils::Error;
+
+// Repeats of the same error are logged after these intervals, the last one keeps repeating
+const LOG_INTERVALS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum ErrorCategory {
+    Network,
+    // Messages the player couldn't decode or handle
+    Protocol,
+    // Missing maps and failed path searches
+    Map,
+    // Calls that don't fit the lifecycle state
+    State,
+    Other,
+}
+
+impl From<&Error> for ErrorCategory {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::Http(_) | Error::WebSocket(_) | Error::Matchmaker { .. } => Self::Network,
+            Error::RetriesExhausted { last, .. } => Self::from(last.as_ref()),
+            Error::Decode(_) | Error::Encode(_) | Error::Json(_) => Self::Protocol,
+            Error::MapParse(_)
+            | Error::MapUnavailable
+            | Error::PositionNotWalkable
+            | Error::StartNotWalkable
+            | Error::DestinationNotWalkable
+            | Error::PathNotFound
+            | Error::PathBudgetExceeded
+            | Error::Stuck { .. } => Self::Map,
+            Error::NotInGame | Error::Disconnected | Error::InvalidTransition(_) => Self::State,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerAction {
+    // Only mark the player unhealthy
+    Report,
+    // Stop handling the message kind that keeps failing, other errors are reported
+    PauseMessage,
+    Disconnect,
+}
+
+// A category trips the breaker once it had max_errors errors within the window
+#[derive(Debug, Clone)]
+pub struct ErrorBudgetOptions {
+    pub max_errors: usize,
+    pub window: Duration,
+    // Categories without an entry are reported
+    pub actions: HashMap<ErrorCategory, BreakerAction>,
+}
+
+impl Default for ErrorBudgetOptions {
+    fn default() -> Self {
+        Self {
+            // A failing tick for ten seconds straight
+            max_errors: 150,
+            window: Duration::from_secs(10),
+            actions: HashMap::from([
+                (ErrorCategory::Network, BreakerAction::Disconnect),
+                (ErrorCategory::Protocol, BreakerAction::PauseMessage),
+            ]),
+        }
+    }
+}
+
+impl ErrorBudgetOptions {
+    pub fn action(&self, category: ErrorCategory) -> BreakerAction {
+        self.actions
+            .get(&category)
+            .copied()
+            .unwrap_or(BreakerAction::Report)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Trip {
+    pub(crate) category: ErrorCategory,
+    pub(crate) count: usize,
+    pub(crate) action: BreakerAction,
+}
+
+#[derive(Debug)]
+struct Repeated {
+    line: String,
+    // Since the line was last logged
+    repeats: usize,
+    logged_at: Instant,
+    interval: usize,
+}
+
+// Logs errors with identical consecutive ones collapsed and counts them per category
+#[derive(Debug)]
+pub(crate) struct ErrorBudget {
+    options: ErrorBudgetOptions,
+    last: Option<Repeated>,
+    recent: HashMap<ErrorCategory, VecDeque<Instant>>,
+    tripped: HashSet<ErrorCategory>,
+}
+
+impl ErrorBudget {
+    pub(crate) fn new(options: ErrorBudgetOptions) -> Self {
+        Self {
+            options,
+            last: None,
+            recent: HashMap::new(),
+            tripped: HashSet::new(),
+        }
+    }
+
+    // Returns the trip if the error used up the budget of its category. A tripped category trips
+    // again only after a window without errors.
+    pub(crate) fn record(
+        &mut self,
+        now: Instant,
+        category: ErrorCategory,
+        context: &str,
+        err: &Error,
+    ) -> Option<Trip> {
+        self.log(now, format!("{}: {}", context, err));
+
+        let recent = self.recent.entry(category).or_default();
+        while recent
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) > self.options.window)
+        {
+            recent.pop_front();
+        }
+        if recent.is_empty() {
+            self.tripped.remove(&category);
+        }
+        recent.push_back(now);
+
+        if recent.len() < self.options.max_errors || !self.tripped.insert(category) {
+            return None;
+        }
+        Some(Trip {
+            category,
+            count: recent.len(),
+            action: self.options.action(category),
+        })
+    }
+
+    fn log(&mut self, now: Instant, line: String) {
+        if let Some(last) = self.last.as_mut() {
+            if last.line == line {
+                last.repeats += 1;
+                if now.saturating_duration_since(last.logged_at) >= LOG_INTERVALS[last.interval] {
+                    error!("{} (repeated {} times)", line, last.repeats);
+                    last.repeats = 0;
+                    last.logged_at = now;
+                    last.interval = (last.interval + 1).min(LOG_INTERVALS.len() - 1);
+                }
+                return;
+            }
+
+            if last.repeats > 0 {
+                error!("{} (repeated {} times)", last.line, last.repeats);
+            }
+        }
+
+        error!("{}", line);
+        self.last = Some(Repeated {
+            line,
+            repeats: 0,
+            logged_at: now,
+            interval: 0,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(max_errors: usize) -> ErrorBudget {
+        ErrorBudget::new(ErrorBudgetOptions {
+            max_errors,
+            window: Duration::from_secs(10),
+            ..Default::default()
+        })
+    }
+
+    // Whether each error at the given seconds tripped the breaker
+    fn trips(budget: &mut ErrorBudget, category: ErrorCategory, seconds: &[u64]) -> Vec<bool> {
+        let start = Instant::now();
+        seconds
+            .iter()
+            .map(|second| {
+                let now = start + Duration::from_secs(*second);
+                budget
+                    .record(now, category, "test", &"failed".into())
+                    .is_some()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_breaker_trips_once_per_burst() {
+        let mut budget = budget(3);
+        let tripped = trips(&mut budget, ErrorCategory::Protocol, &[0, 1, 2, 3, 4]);
+        assert_eq!(tripped, [false, false, true, false, false]);
+
+        // Only a window without errors lets the category trip again
+        let mut budget = self::budget(3);
+        let tripped = trips(&mut budget, ErrorCategory::Protocol, &[0, 1, 2, 20, 21, 22]);
+        assert_eq!(tripped, [false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn errors_outside_the_window_dont_count() {
+        let mut budget = budget(3);
+        let tripped = trips(&mut budget, ErrorCategory::Map, &[0, 8, 16, 24, 25]);
+        assert_eq!(tripped, [false, false, false, false, true]);
+    }
+
+    #[test]
+    fn categories_have_their_own_budget() {
+        let mut budget = budget(2);
+        assert_eq!(trips(&mut budget, ErrorCategory::Map, &[0]), [false]);
+        assert_eq!(trips(&mut budget, ErrorCategory::State, &[0]), [false]);
+
+        let now = Instant::now();
+        let err = Error::from("failed");
+        assert!(budget
+            .record(now, ErrorCategory::Network, "test", &err)
+            .is_none());
+        let trip = budget
+            .record(now, ErrorCategory::Network, "test", &err)
+            .unwrap();
+        assert_eq!(trip.category, ErrorCategory::Network);
+        assert_eq!(trip.count, 2);
+        assert_eq!(trip.action, BreakerAction::Disconnect);
+    }
+
+    #[test]
+    fn categories_without_an_action_are_reported() {
+        let options = ErrorBudgetOptions::default();
+        assert_eq!(
+            options.action(ErrorCategory::Protocol),
+            BreakerAction::PauseMessage
+        );
+        assert_eq!(options.action(ErrorCategory::Map), BreakerAction::Report);
+        assert_eq!(
+            ErrorCategory::from(&Error::PathNotFound),
+            ErrorCategory::Map
+        );
+        assert_eq!(
+            ErrorCategory::from(&Error::from("failed")),
+            ErrorCategory::Other
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{modes::GameMode, server_region::ServerRegion, Game};
+
+// Conditions for games of the game list, see Client::find_games. Unset conditions match every
+// game, the filter can be kept and used for every poll.
+#[derive(Debug, Clone, Default)]
+pub struct GameFilter {
+    region: Option<ServerRegion>,
+    mode: Option<GameMode>,
+    exclude_custom: bool,
+    max_players: Option<u8>,
+    min_free_slots: Option<u8>,
+    maps: Option<Vec<String>>,
+    only_loaded_maps: bool,
+}
+
+impl GameFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn region(mut self, region: ServerRegion) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    pub fn mode(mut self, mode: GameMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub fn exclude_custom(mut self) -> Self {
+        self.exclude_custom = true;
+        self
+    }
+
+    // At most this many players already in the game
+    pub fn max_players_at_most(mut self, players: u8) -> Self {
+        self.max_players = Some(players);
+        self
+    }
+
+    pub fn min_free_slots(mut self, slots: u8) -> Self {
+        self.min_free_slots = Some(slots);
+        self
+    }
+
+    pub fn map_any_of(mut self, maps: &[&str]) -> Self {
+        self.maps = Some(maps.iter().map(|map| map.to_string()).collect());
+        self
+    }
+
+    // Only games on maps the client has already parsed, the others are parsed on join
+    pub fn only_loaded_maps(mut self) -> Self {
+        self.only_loaded_maps = true;
+        self
+    }
+
+    pub fn matches(&self, game: &Game, loaded_maps: &[String]) -> bool {
+        self.region
+            .as_ref()
+            .is_none_or(|region| game.region == *region)
+            && self.mode.is_none_or(|mode| game.mode == mode)
+            && !(self.exclude_custom && game.custom)
+            && self.max_players.is_none_or(|max| game.players <= max)
+            && self
+                .min_free_slots
+                .is_none_or(|slots| game.max_players.saturating_sub(game.players) >= slots)
+            && self
+                .maps
+                .as_ref()
+                .is_none_or(|maps| maps.contains(&game.map))
+            && (!self.only_loaded_maps || loaded_maps.contains(&game.map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soak;
+
+    // Frankfurt, free for all on "burg" with 5 of 8 players
+    fn game() -> Game {
+        let mut game = soak::game("FRA:a", ServerRegion::Frankfurt, "burg", GameMode::Ffa);
+        game.players = 5;
+        game
+    }
+
+    #[test]
+    fn empty_filters_match_every_game() {
+        let mut custom = game();
+        custom.custom = true;
+        assert!(GameFilter::new().matches(&game(), &[]));
+        assert!(GameFilter::new().matches(&custom, &[]));
+    }
+
+    #[test]
+    fn every_condition_has_to_match() {
+        let game = game();
+        let matches = |filter: GameFilter| filter.matches(&game, &[]);
+
+        assert!(matches(GameFilter::new().region(ServerRegion::Frankfurt)));
+        assert!(!matches(GameFilter::new().region(ServerRegion::Sydney)));
+        assert!(matches(GameFilter::new().mode(GameMode::Ffa)));
+        assert!(!matches(GameFilter::new().mode(GameMode::Tdm)));
+        assert!(matches(GameFilter::new().max_players_at_most(5)));
+        assert!(!matches(GameFilter::new().max_players_at_most(4)));
+        assert!(matches(GameFilter::new().min_free_slots(3)));
+        assert!(!matches(GameFilter::new().min_free_slots(4)));
+        assert!(matches(
+            GameFilter::new().map_any_of(&["sandstorm", "burg"])
+        ));
+        assert!(!matches(GameFilter::new().map_any_of(&["sandstorm"])));
+        assert!(!matches(GameFilter::new().map_any_of(&[])));
+
+        let filter = GameFilter::new()
+            .region(ServerRegion::Frankfurt)
+            .mode(GameMode::Ffa)
+            .min_free_slots(3);
+        assert!(filter.clone().matches(&game, &[]));
+        assert!(!filter.mode(GameMode::Tdm).matches(&game, &[]));
+    }
+
+    #[test]
+    fn custom_games_and_maps_that_are_not_loaded_can_be_excluded() {
+        let mut custom = game();
+        custom.custom = true;
+        let filter = GameFilter::new().exclude_custom();
+        assert!(filter.matches(&game(), &[]));
+        assert!(!filter.matches(&custom, &[]));
+
+        let filter = GameFilter::new().only_loaded_maps();
+        assert!(!filter.matches(&game(), &[]));
+        assert!(!filter.matches(&game(), &["sandstorm".to_owned()]));
+        assert!(filter.matches(&game(), &["burg".to_owned()]));
+    }
+
+    #[test]
+    fn full_games_have_no_free_slots() {
+        let mut full = game();
+        full.players = 8;
+        assert!(GameFilter::new().min_free_slots(0).matches(&full, &[]));
+        assert!(!GameFilter::new().min_free_slots(1).matches(&full, &[]));
+        // More players than the game allows don't wrap around
+        full.players = 9;
+        assert!(!GameFilter::new().min_free_slots(1).matches(&full, &[]));
+    }
+}
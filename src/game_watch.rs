@@ -0,0 +1,207 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{
+    game_filter::GameFilter, modes::GameMode, tasks::TaskRegistry, utils::Error, Client, Game,
+};
+
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug)]
+pub enum GameListEvent {
+    // Games that show up in the list or start matching the filter
+    Added(Game),
+    // Id of a game that left the list or no longer matches the filter
+    Removed(String),
+    Updated {
+        id: String,
+        players: u8,
+        map: String,
+        mode: GameMode,
+    },
+    // The poll failed, the watcher tries again in the next interval
+    Error(Error),
+}
+
+// Polls the game list in the background, the task stops when the watcher is dropped
+pub struct GameListWatcher {
+    events: mpsc::Receiver<GameListEvent>,
+    _tasks: TaskRegistry,
+}
+
+impl GameListWatcher {
+    pub(crate) fn spawn(
+        client: Arc<Mutex<Client>>,
+        filter: GameFilter,
+        interval: Duration,
+        tasks: TaskRegistry,
+    ) -> Self {
+        let (sender, events) = mpsc::channel(EVENT_BUFFER);
+        tasks.spawn(async move {
+            let mut known = HashMap::<String, Game>::new();
+            let mut interval = time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let games = client.lock().await.find_games(&filter).await;
+                let events = match games {
+                    Ok(games) => diff(&mut known, games),
+                    Err(err) => vec![GameListEvent::Error(err)],
+                };
+                for event in events {
+                    if sender.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self {
+            events,
+            _tasks: tasks,
+        }
+    }
+
+    // None once the watcher stopped
+    pub async fn recv(&mut self) -> Option<GameListEvent> {
+        self.events.recv().await
+    }
+
+    pub fn try_recv(&mut self) -> Option<GameListEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+fn diff(known: &mut HashMap<String, Game>, games: Vec<Game>) -> Vec<GameListEvent> {
+    // The list can have the same game more than once, the last entry wins
+    let current = games
+        .into_iter()
+        .map(|game| (game.id.clone(), game))
+        .collect::<HashMap<_, _>>();
+
+    let mut events = known
+        .keys()
+        .filter(|id| !current.contains_key(*id))
+        .map(|id| GameListEvent::Removed(id.clone()))
+        .collect::<Vec<_>>();
+
+    for (id, game) in &current {
+        match known.get(id) {
+            None => events.push(GameListEvent::Added(game.clone())),
+            Some(old)
+                if old.players != game.players || old.map != game.map || old.mode != game.mode =>
+            {
+                events.push(GameListEvent::Updated {
+                    id: id.clone(),
+                    players: game.players,
+                    map: game.map.clone(),
+                    mode: game.mode,
+                })
+            }
+            Some(_) => (),
+        }
+    }
+
+    *known = current;
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server_region::ServerRegion,
+        soak::{self, MockMatchmaker},
+    };
+
+    fn game(id: &str, players: u8, map: &str) -> Game {
+        let mut game = soak::game(id, ServerRegion::Frankfurt, map, GameMode::Ffa);
+        game.players = players;
+        game
+    }
+
+    // Events as text, sorted since games that are added or updated together come in any order
+    fn described(events: Vec<GameListEvent>) -> Vec<String> {
+        let mut events = events
+            .into_iter()
+            .map(|event| match event {
+                GameListEvent::Added(game) => format!("added {}", game.id),
+                GameListEvent::Removed(id) => format!("removed {}", id),
+                GameListEvent::Updated {
+                    id, players, map, ..
+                } => format!("updated {} {} {}", id, players, map),
+                GameListEvent::Error(err) => format!("error {}", err),
+            })
+            .collect::<Vec<_>>();
+        events.sort();
+        events
+    }
+
+    #[test]
+    fn the_game_list_is_diffed_against_the_last_poll() {
+        let mut known = HashMap::new();
+        let events = diff(
+            &mut known,
+            vec![game("FRA:a", 2, "a"), game("FRA:b", 4, "b")],
+        );
+        assert_eq!(described(events), vec!["added FRA:a", "added FRA:b"]);
+
+        // Nothing changed
+        let events = diff(
+            &mut known,
+            vec![game("FRA:b", 4, "b"), game("FRA:a", 2, "a")],
+        );
+        assert!(events.is_empty());
+
+        let events = diff(
+            &mut known,
+            vec![game("FRA:b", 5, "b"), game("FRA:c", 1, "c")],
+        );
+        assert_eq!(
+            described(events),
+            vec!["added FRA:c", "removed FRA:a", "updated FRA:b 5 b"]
+        );
+
+        // The last entry of a game wins
+        let events = diff(
+            &mut known,
+            vec![game("FRA:c", 1, "c"), game("FRA:c", 3, "d")],
+        );
+        assert_eq!(
+            described(events),
+            vec!["removed FRA:b", "updated FRA:c 3 d"]
+        );
+        assert_eq!(known.len(), 1);
+        assert_eq!(known["FRA:c"].players, 3);
+    }
+
+    async fn next(watcher: &mut GameListWatcher) -> String {
+        let event = time::timeout(Duration::from_secs(5), watcher.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        described(vec![event]).remove(0)
+    }
+
+    #[tokio::test]
+    async fn watchers_report_changes_of_the_polled_list() {
+        let matchmaker = MockMatchmaker::spawn("a").await;
+        let client = Arc::new(Mutex::new(soak::client(vec![], &matchmaker.url)));
+        let filter = GameFilter::new().map_any_of(&["a"]);
+        let mut watcher = Client::watch_games(&client, filter, Duration::from_millis(10)).await;
+        assert_eq!(next(&mut watcher).await, "added SOAK:game");
+
+        // Games that stop matching the filter are removed
+        matchmaker.set_map("b");
+        assert_eq!(next(&mut watcher).await, "removed SOAK:game");
+
+        // Failed polls are reported and the watcher keeps polling
+        matchmaker.delist();
+        assert!(next(&mut watcher).await.starts_with("error"));
+        assert!(next(&mut watcher).await.starts_with("error"));
+    }
+}
@@ -0,0 +1,304 @@
+use std::{collections::BTreeSet, fmt};
+
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Control {
+    Walk,
+    Fire,
+    Aim,
+    Jump,
+    Crouch,
+    Reload,
+    SwapWeapon,
+    Interact,
+    // Controls that are sent but not used by the client yet
+    Other(u8),
+}
+
+impl Control {
+    pub fn index(&self) -> u8 {
+        match self {
+            Self::Walk => 4,
+            Self::Fire => 5,
+            Self::Aim => 6,
+            Self::Jump => 7,
+            Self::Crouch => 8,
+            Self::Reload => 9,
+            Self::SwapWeapon => 10,
+            Self::Interact => 11,
+            Self::Other(index) => *index,
+        }
+    }
+
+    pub fn from_index(index: u8) -> Self {
+        match index {
+            4 => Self::Walk,
+            5 => Self::Fire,
+            6 => Self::Aim,
+            7 => Self::Jump,
+            8 => Self::Crouch,
+            9 => Self::Reload,
+            10 => Self::SwapWeapon,
+            11 => Self::Interact,
+            index => Self::Other(index),
+        }
+    }
+
+    // Every control the initial tick sends a value for
+    pub fn all() -> impl Iterator<Item = Self> {
+        (4..=14).map(Self::from_index)
+    }
+}
+
+// Key of an input in the tick message, "<slot>-<control index>" on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InputKey {
+    pub slot: u8,
+    pub control: Control,
+}
+
+impl InputKey {
+    pub fn new(slot: u8, control: Control) -> Self {
+        Self { slot, control }
+    }
+
+    pub fn parse(key: &str) -> Option<Self> {
+        let (slot, index) = key.split_once('-')?;
+        Some(Self {
+            slot: slot.parse().ok()?,
+            control: Control::from_index(index.parse().ok()?),
+        })
+    }
+}
+
+impl fmt::Display for InputKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.slot, self.control.index())
+    }
+}
+
+// Every input of a player, a tick message carries all of them at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InputState {
+    // Player slot used in the input keys
+    pub slot: u8,
+    pub walk: bool,
+    pub fire: bool,
+    pub aim: bool,
+    pub jump: bool,
+    pub crouch: bool,
+    pub reload: bool,
+    pub swap_weapon: bool,
+    pub interact: bool,
+}
+
+impl InputState {
+    pub fn new(slot: u8) -> Self {
+        Self {
+            slot,
+            ..Default::default()
+        }
+    }
+
+    pub fn value(&self, control: Control) -> i32 {
+        let pressed = match control {
+            // The movement axis is -1 while standing still
+            Control::Walk => return if self.walk { 1 } else { -1 },
+            Control::Fire => self.fire,
+            Control::Aim => self.aim,
+            Control::Jump => self.jump,
+            Control::Crouch => self.crouch,
+            Control::Reload => self.reload,
+            Control::SwapWeapon => self.swap_weapon,
+            Control::Interact => self.interact,
+            Control::Other(_) => false,
+        };
+        if pressed {
+            1
+        } else {
+            0
+        }
+    }
+
+    // Held buttons are sent again every tick instead of relying on the server to keep them
+    pub fn is_holding(&self) -> bool {
+        self.fire || self.aim
+    }
+
+    pub fn to_value(&self) -> Value {
+        let inputs = Control::all()
+            .map(|control| (control, self.value(control)))
+            .collect::<Vec<_>>();
+        input_state(self.slot, &inputs)
+    }
+}
+
+fn input_state(slot: u8, inputs: &[(Control, i32)]) -> Value {
+    Value::Object(
+        inputs
+            .iter()
+            .map(|(control, value)| {
+                (
+                    InputKey::new(slot, *control).to_string(),
+                    Value::from(*value),
+                )
+            })
+            .collect::<Map<_, _>>(),
+    )
+}
+
+// Debug helper for a sequence of tick messages (e.g. recorded from the official client) that lists the
+// keys whose values changed, useful to find out which indices a game update moved around
+pub fn changed_inputs(ticks: &[Value]) -> BTreeSet<(u8, u8)> {
+    let mut last = Map::new();
+    let mut changed = BTreeSet::new();
+
+    for tick in ticks {
+        let state = match tick.as_array().and_then(|tick| tick.get(6)?.as_object()) {
+            Some(state) => state,
+            None => continue,
+        };
+
+        for (key, value) in state {
+            if let Some(input_key) = InputKey::parse(key) {
+                if last.get(key).is_some_and(|last| last != value) {
+                    changed.insert((input_key.slot, input_key.control.index()));
+                }
+            }
+            last.insert(key.clone(), value.clone());
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn control_indices_round_trip() {
+        for index in 0..=20 {
+            assert_eq!(Control::from_index(index).index(), index);
+        }
+        assert_eq!(Control::Walk.index(), 4);
+        assert_eq!(Control::Fire.index(), 5);
+        assert_eq!(Control::Aim.index(), 6);
+        assert_eq!(Control::from_index(13), Control::Other(13));
+    }
+
+    #[test]
+    fn initial_tick_covers_indices_4_to_14() {
+        let indices = Control::all()
+            .map(|control| control.index())
+            .collect::<Vec<_>>();
+        assert_eq!(indices, (4..=14).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn input_keys_for_slot_0() {
+        let key = InputKey::new(0, Control::Walk);
+        assert_eq!(key.to_string(), "0-4");
+        assert_eq!(InputKey::parse("0-4"), Some(key));
+        assert_eq!(
+            InputKey::parse("0-12"),
+            Some(InputKey::new(0, Control::Other(12)))
+        );
+    }
+
+    #[test]
+    fn input_keys_for_slot_2() {
+        let key = InputKey::new(2, Control::Fire);
+        assert_eq!(key.to_string(), "2-5");
+        assert_eq!(InputKey::parse("2-5"), Some(key));
+        assert_eq!(
+            input_state(2, &[(Control::Walk, 1), (Control::Aim, 0)]),
+            json!({ "2-4": 1, "2-6": 0 })
+        );
+    }
+
+    #[test]
+    fn malformed_input_keys() {
+        assert_eq!(InputKey::parse("04"), None);
+        assert_eq!(InputKey::parse("a-4"), None);
+        assert_eq!(InputKey::parse("0-"), None);
+        assert_eq!(InputKey::parse("0-300"), None);
+    }
+
+    #[test]
+    fn changed_inputs_lists_keys_that_changed() {
+        let ticks = vec![
+            json!(["q", 0, 0, "3000", 2, [0, 0], { "0-4": -1, "0-5": 0, "0-9": 0 }]),
+            json!(["q", 0, 1, "3000", 2, [0, 0], ()]),
+            json!(["q", 0, 2, "3000", 2, [0, 0], { "0-4": 1 }]),
+            json!(["q", 0, 3, "3000", 2, [0, 0], { "0-9": 1, "0-5": 0, "x": 1 }]),
+        ];
+        assert_eq!(changed_inputs(&ticks), BTreeSet::from([(0, 4), (0, 9)]));
+    }
+
+    #[test]
+    fn released_input_state() {
+        assert_eq!(
+            InputState::new(0).to_value(),
+            json!({
+                "0-4": -1, "0-5": 0, "0-6": 0, "0-7": 0, "0-8": 0, "0-9": 0, "0-10": 0, "0-11": 0,
+                "0-12": 0, "0-13": 0, "0-14": 0
+            })
+        );
+    }
+
+    #[test]
+    fn walking_and_shooting_input_state() {
+        let state = InputState {
+            walk: true,
+            fire: true,
+            aim: true,
+            ..InputState::new(0)
+        };
+        assert_eq!(
+            state.to_value(),
+            json!({
+                "0-4": 1, "0-5": 1, "0-6": 1, "0-7": 0, "0-8": 0, "0-9": 0, "0-10": 0, "0-11": 0,
+                "0-12": 0, "0-13": 0, "0-14": 0
+            })
+        );
+        assert!(state.is_holding());
+    }
+
+    #[test]
+    fn every_button_input_state() {
+        let state = InputState {
+            slot: 2,
+            walk: false,
+            fire: false,
+            aim: false,
+            jump: true,
+            crouch: true,
+            reload: true,
+            swap_weapon: true,
+            interact: true,
+        };
+        assert_eq!(
+            state.to_value(),
+            json!({
+                "2-4": -1, "2-5": 0, "2-6": 0, "2-7": 1, "2-8": 1, "2-9": 1, "2-10": 1, "2-11": 1,
+                "2-12": 0, "2-13": 0, "2-14": 0
+            })
+        );
+        assert!(!state.is_holding());
+    }
+
+    #[test]
+    fn input_state_values() {
+        let state = InputState {
+            aim: true,
+            ..Default::default()
+        };
+        assert_eq!(state.value(Control::Walk), -1);
+        assert_eq!(state.value(Control::Aim), 1);
+        assert_eq!(state.value(Control::Other(13)), 0);
+    }
+}
@@ -1,22 +1,160 @@
+pub mod accounts;
+pub mod annotations;
+pub mod autoscaler;
+pub mod clock;
+pub mod config;
+pub mod coordination;
+pub mod diagnostics;
+pub mod error;
+pub mod error_budget;
+pub mod game_filter;
+pub mod game_watch;
+pub mod input;
+pub mod lifecycle;
 pub mod map;
+mod map_cache;
+#[cfg(feature = "debug-export")]
+mod map_export;
+pub mod matchmaker;
 pub mod messages;
+pub mod modes;
 pub mod player;
+pub mod pool;
+pub mod prelude;
+pub mod profile;
+pub mod proxy;
+pub mod quirks;
+pub mod raycast;
+pub mod regions;
+pub mod retry;
+pub mod selfcheck;
+pub mod server_clock;
+pub mod server_region;
+#[cfg(test)]
+mod soak;
 pub mod socket;
+mod tasks;
+pub mod tuning;
 pub mod utils;
+pub mod walk_queue;
+pub mod world;
 
-use std::{str::from_utf8, sync::Arc};
+pub use error::KrunkerError;
+pub use utils::Error;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::from_utf8,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use futures_util::future::try_join_all;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
-use tracing::info;
+use tokio::{
+    runtime::Handle,
+    sync::{oneshot, watch, Mutex, Semaphore},
+    task,
+};
+use tracing::{info, warn};
 
 use crate::{
-    map::{Map, RawMap},
-    utils::Error,
+    annotations::{AnnotationFile, AnnotationReport, AnnotationSource},
+    game_filter::GameFilter,
+    game_watch::GameListWatcher,
+    map::{Map, MapBuildOptions, RawMap},
+    matchmaker::{EndpointHealth, Matchmaker, DEFAULT_MATCHMAKER_URL},
+    modes::{GameMode, ModeInfo},
+    profile::{fetch_profile, Profile},
+    proxy::ProxyConfig,
+    regions::RegionOptions,
+    retry::RetryPolicy,
+    selfcheck::{self_check, smoke_test, SelfCheckReport, SmokeReport},
+    server_region::ServerRegion,
+    tasks::TaskRegistry,
+    tuning::MapTuning,
 };
 
+const PROFILE_CACHE_TTL: Duration = Duration::from_secs(60);
+// Serves the game source, the client key and the token hashing
+const DEFAULT_SOURCE_URL: &str = "https://api.sys32.dev/v3";
+const DEFAULT_HOSTNAME: &str = "krunker.io";
+
+// Urls and request settings shared by the client and its games
+#[derive(Debug)]
+pub(crate) struct Endpoints {
+    source_url: String,
+    hostname: String,
+    // Shared by every request of the client and its games, so connections and TLS sessions are
+    // reused
+    http: reqwest::Client,
+    request_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    proxy: Option<ProxyConfig>,
+}
+
+impl Endpoints {
+    fn new(
+        source_url: String,
+        hostname: String,
+        request_timeout: Option<Duration>,
+        retry: RetryPolicy,
+        proxy: Option<ProxyConfig>,
+    ) -> Result<Self, Error> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = request_timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = &proxy {
+            builder = builder.proxy(proxy.reqwest_proxy()?);
+        }
+        Ok(Self {
+            source_url,
+            hostname,
+            http: builder.build()?,
+            request_timeout,
+            retry,
+            proxy,
+        })
+    }
+
+    // Same settings with another proxy, the HTTP connections aren't shared with this one
+    fn with_proxy(&self, proxy: Option<ProxyConfig>) -> Result<Self, Error> {
+        Self::new(
+            self.source_url.clone(),
+            self.hostname.clone(),
+            self.request_timeout,
+            self.retry,
+            proxy,
+        )
+    }
+
+    pub(crate) fn proxy(&self) -> Option<&ProxyConfig> {
+        self.proxy.as_ref()
+    }
+
+    // Requests to the source server, with the retries of the client
+    async fn send(
+        &self,
+        what: &str,
+        build: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, Error> {
+        self.retry
+            .send(what, || async { Ok(build(&self.http).send().await?) })
+            .await
+    }
+
+    fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub(crate) fn origin(&self) -> String {
+        format!("https://{}", self.hostname)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct RawGameInfo {
     #[serde(rename = "c")]
@@ -47,57 +185,330 @@ struct RawGameList {
 pub struct Client {
     pub(crate) prime: u16,
     pub(crate) client_key: String,
-    maps: Vec<Map>,
+    matchmaker: Arc<Matchmaker>,
+    profiles: Arc<std::sync::Mutex<HashMap<String, (Instant, Profile)>>>,
+    // Same order as raw_maps, None for maps that haven't been parsed yet
+    maps: Vec<Option<Map>>,
+    // Every map of the source, kept to parse maps later or with different build options
+    raw_maps: Vec<RawMap>,
+    // Maps added with register_map, they have no raw map to be parsed again from
+    custom_maps: Vec<Map>,
+    map_options: MapBuildOptions,
+    map_tuning: HashMap<String, MapTuning>,
+    // Applied to maps that are loaded later
+    annotations: AnnotationFile,
+    region_options: Option<RegionOptions>,
+    retain_raw: bool,
+    // Parses a load can wait for, by index of raw_maps. Removed once a load picked the map up.
+    parsing: HashMap<usize, MapParse>,
+    // Maps parsed for a load right now, nice preparses wait for them
+    foreground: Arc<watch::Sender<usize>>,
+    preparse: Arc<watch::Sender<PreparseProgress>>,
+    tasks: TaskRegistry,
+    endpoints: Arc<Endpoints>,
+}
+
+// None until the parse task finished
+type MapParse = watch::Receiver<Option<Result<Map, String>>>;
+
+enum MapLoad {
+    Loaded(Box<Map>),
+    Parsing(usize, MapParse),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParseBudget {
+    // Maps parsed at the same time
+    pub concurrency: usize,
+    // Waits while a map is parsed for a join, so the join doesn't share the cores
+    pub nice: bool,
+}
+
+impl Default for ParseBudget {
+    fn default() -> Self {
+        Self {
+            concurrency: 1,
+            nice: true,
+        }
+    }
+}
+
+// Counts over every preparse of the client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PreparseProgress {
+    pub queued: usize,
+    pub parsed: usize,
+    pub failed: usize,
+}
+
+impl PreparseProgress {
+    pub fn is_done(&self) -> bool {
+        self.parsed + self.failed >= self.queued
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientBuilder {
+    map_options: MapBuildOptions,
+    map_tuning: HashMap<String, MapTuning>,
+    map_cache_dir: Option<PathBuf>,
+    maps: Option<Vec<String>>,
+    map_modes: Option<Vec<GameMode>>,
+    runtime: Option<Handle>,
+    retain_raw: bool,
+    source_url: Option<String>,
+    matchmaker_url: Option<String>,
+    hostname: Option<String>,
+    request_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    proxy: Option<ProxyConfig>,
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn map_options(mut self, map_options: MapBuildOptions) -> Self {
+        self.map_options = map_options;
+        self
+    }
+
+    // Tuning for the map with the name or fingerprint, see Client::set_map_tuning
+    pub fn map_tuning(mut self, key: &str, tuning: MapTuning) -> Self {
+        self.map_tuning.insert(key.to_owned(), tuning);
+        self
+    }
+
+    // Only parse these maps up front, the others are parsed when they are first needed,
+    // see Client::load_map
+    pub fn maps(mut self, names: &[&str]) -> Self {
+        self.maps = Some(names.iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    // Only maps that support one of the modes are available, all maps are by default
+    pub fn map_modes(mut self, modes: &[GameMode]) -> Self {
+        self.map_modes = Some(modes.to_vec());
+        self
+    }
+
+    // Keep the parsed maps in the directory and load them from there while the game version is
+    // the same. Maps with different build options are parsed again.
+    pub fn map_cache_dir(mut self, map_cache_dir: impl Into<PathBuf>) -> Self {
+        self.map_cache_dir = Some(map_cache_dir.into());
+        self
+    }
+
+    // Keep the JSON every Game and GameConnectInfo was parsed from, see Game::raw
+    pub fn retain_raw(mut self, retain_raw: bool) -> Self {
+        self.retain_raw = retain_raw;
+        self
+    }
+
+    // Base url of the source, key and token api, defaults to https://api.sys32.dev/v3
+    pub fn source_url(mut self, source_url: &str) -> Self {
+        self.source_url = Some(source_url.trim_end_matches('/').to_owned());
+        self
+    }
+
+    // Defaults to https://matchmaker.krunker.io
+    pub fn matchmaker_url(mut self, matchmaker_url: &str) -> Self {
+        self.matchmaker_url = Some(matchmaker_url.trim_end_matches('/').to_owned());
+        self
+    }
+
+    // Site the game list is requested for and requests claim to come from, e.g. browserfps.com
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.to_owned());
+        self
+    }
+
+    // Timeout of every HTTP request, there is none by default
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    // Every HTTP request and WebSocket of the client and its players goes through the proxy,
+    // PlayerBuilder::proxy overrides it for a player
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    // Retries of the matchmaker and source requests, RetryPolicy::none() turns them off
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    // Runtime the map parsing tasks are spawned on, defaults to the runtime build is called on
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    pub async fn build(&self) -> Result<Arc<Mutex<Client>>, Error> {
+        let tasks = TaskRegistry::new(self.runtime.clone().unwrap_or_else(Handle::current));
+        let endpoints = Arc::new(Endpoints::new(
+            self.source_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SOURCE_URL.to_owned()),
+            self.hostname
+                .clone()
+                .unwrap_or_else(|| DEFAULT_HOSTNAME.to_owned()),
+            self.request_timeout,
+            self.retry_policy,
+            self.proxy.clone(),
+        )?);
+        let matchmaker_url = self
+            .matchmaker_url
+            .as_deref()
+            .unwrap_or(DEFAULT_MATCHMAKER_URL);
+
+        let (source, client_key) = Client::download_source(&endpoints).await?;
+        let (raw_maps, maps) = Client::load_maps(
+            &source,
+            &self.map_options,
+            &self.map_tuning,
+            self.maps.as_deref(),
+            self.map_modes.as_deref(),
+            self.map_cache_dir.as_deref(),
+            &tasks,
+        )
+        .await?;
+
+        Ok(Arc::new(Mutex::new(Client {
+            prime: Client::extract_prime(&source)?,
+            client_key,
+            matchmaker: Arc::new(Matchmaker::new(&[matchmaker_url], self.retry_policy)),
+            profiles: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            maps,
+            raw_maps,
+            custom_maps: vec![],
+            map_options: self.map_options,
+            map_tuning: self.map_tuning.clone(),
+            annotations: AnnotationFile::default(),
+            region_options: None,
+            retain_raw: self.retain_raw,
+            parsing: HashMap::new(),
+            foreground: Arc::new(watch::channel(0).0),
+            preparse: Arc::new(watch::channel(PreparseProgress::default()).0),
+            tasks,
+            endpoints,
+        })))
+    }
 }
 
 impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
     pub async fn new() -> Result<Arc<Mutex<Self>>, Error> {
-        info!("Downloading krunker source...");
+        Self::builder().build().await
+    }
+
+    pub async fn with_map_options(options: MapBuildOptions) -> Result<Arc<Mutex<Self>>, Error> {
+        Self::builder().map_options(options).build().await
+    }
+
+    pub(crate) fn proxy(&self) -> Option<&ProxyConfig> {
+        self.endpoints.proxy()
+    }
+
+    // Background tasks of the client that are still running
+    pub fn task_count(&self) -> usize {
+        self.tasks.count()
+    }
 
-        let req_client = reqwest::Client::new();
+    // Download the source again and re-extract the values needed for the protocol,
+    // useful when the game has been updated since the client was created
+    pub async fn refresh(&mut self) -> Result<(), Error> {
+        let (source, client_key) = Self::download_source(&self.endpoints).await?;
+
+        self.prime = Self::extract_prime(&source)?;
+        self.client_key = client_key;
+
+        Ok(())
+    }
+
+    async fn download_source(endpoints: &Endpoints) -> Result<(String, String), Error> {
+        info!("Downloading krunker source...");
 
         let (source, client_key) = tokio::join!(
             async {
                 // Get the source to extract the prime number for rotating the padding bytes
-                req_client
-                    .get("https://api.sys32.dev/v3/source")
-                    .send()
-                    .await
-                    .unwrap()
-                    .text()
-                    .await
+                let url = format!("{}/source", endpoints.source_url);
+                Ok::<_, Error>(
+                    endpoints
+                        .send("Source download", |http| http.get(&url))
+                        .await?
+                        .text()
+                        .await?,
+                )
             },
             async {
                 // TODO: get key on the client
-                req_client
-                    .get("https://api.sys32.dev/v3/key")
-                    .send()
-                    .await
-                    .unwrap()
-                    .text()
-                    .await
+                let url = format!("{}/key", endpoints.source_url);
+                Ok::<_, Error>(
+                    endpoints
+                        .send("Client key download", |http| http.get(&url))
+                        .await?
+                        .text()
+                        .await?,
+                )
             }
         );
 
-        let source = source?;
+        Ok((source?, client_key?))
+    }
 
+    fn extract_prime(source: &str) -> Result<u16, Error> {
         // Get the version specific prime number used for message encoding from the source code
-        let prime = Regex::new(r"JSON\.parse\('(\d+)'\)")?
-            .captures(&source)
-            .ok_or("Could not extract prime number from source code")?
+        Ok(Regex::new(r"JSON\.parse\('(\d+)'\)")?
+            .captures(source)
+            .ok_or_else(|| {
+                Error::SourceExtraction(
+                    "Could not extract prime number from source code".to_owned(),
+                )
+            })?
             .get(1)
-            .ok_or("Could not extract prime number from source code")?
+            .ok_or_else(|| {
+                Error::SourceExtraction(
+                    "Could not extract prime number from source code".to_owned(),
+                )
+            })?
             .as_str()
-            .parse::<u16>()?;
+            .parse::<u16>()?)
+    }
 
-        Ok(Arc::new(Mutex::new(Self {
-            prime,
-            client_key: client_key?,
-            maps: Self::load_maps(&source).await?,
-        })))
+    // Version of the game the source belongs to, a hash of the source if it has none
+    fn extract_version(source: &str) -> String {
+        Regex::new(r#"\bversion\s*[:=]\s*["'](\d+(?:\.\d+)+)["']"#)
+            .ok()
+            .and_then(|regex| regex.captures(source))
+            .and_then(|captures| captures.get(1))
+            .map(|version| version.as_str().to_owned())
+            .unwrap_or_else(|| {
+                // FNV-1a, the same as for map fingerprints
+                let hash = source.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+                    (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+                });
+                format!("source-{:016x}", hash)
+            })
     }
 
-    async fn load_maps(source: &str) -> Result<Vec<Map>, Error> {
+    async fn load_maps(
+        source: &str,
+        options: &MapBuildOptions,
+        tuning: &HashMap<String, MapTuning>,
+        names: Option<&[String]>,
+        modes: Option<&[GameMode]>,
+        cache_dir: Option<&Path>,
+        tasks: &TaskRegistry,
+    ) -> Result<(Vec<RawMap>, Vec<Option<Map>>), Error> {
         // Get the json map data from the source code and deserialize them into RawMaps
         let maps = Regex::new(r#"\{"name":"[^"]+",[^']+"#)?
             .find_iter(source)
@@ -106,83 +517,627 @@ impl Client {
                 let raw_map = serde_json::from_str::<RawMap>(map.as_str());
                 match raw_map {
                     Ok(raw_map) => {
-                        if raw_map.config.modes.contains(&0) {
-                            Some(Ok(raw_map))
-                        } else {
-                            None
-                        }
+                        let supported = modes.is_none_or(|modes| {
+                            modes
+                                .iter()
+                                .any(|mode| raw_map.config.modes.contains(&(mode.id() as u32)))
+                        });
+                        supported.then_some(Ok(raw_map))
                     }
                     Err(err) => Some(Err(err)),
                 }
             })
             .collect::<Vec<_>>();
 
-        info!("Parsing {} maps...", maps.len());
+        let raw_maps = maps.into_iter().collect::<Result<Vec<_>, _>>()?;
 
-        // Spawn a task for parsing each map
-        let tasks = maps
-            .into_iter()
-            .map(|map| {
-                let raw_map = map?;
-                Ok(tokio::spawn(async move { Map::new(&raw_map) }))
+        if let Some(names) = names {
+            for name in names {
+                if !raw_maps.iter().any(|raw_map| raw_map.name == *name) {
+                    warn!("Map {} is not in the source", name);
+                }
+            }
+        }
+        let wanted = |raw_map: &RawMap| names.is_none_or(|names| names.contains(&raw_map.name));
+
+        info!(
+            "Parsing {} of {} maps...",
+            raw_maps.iter().filter(|raw_map| wanted(raw_map)).count(),
+            raw_maps.len()
+        );
+
+        let version = Self::extract_version(source);
+        let mut cached = match cache_dir {
+            Some(cache_dir) => map_cache::load(cache_dir, &version).await,
+            None => vec![],
+        };
+
+        // Cached maps are used if they were built from the same geometry with the same options,
+        // a task is spawned for parsing each of the other maps
+        let mut maps = Vec::with_capacity(raw_maps.len());
+        let mut parsing = vec![];
+        for (i, raw_map) in raw_maps.iter().enumerate() {
+            if !wanted(raw_map) {
+                maps.push(None);
+                continue;
+            }
+
+            let tuning = Self::tuning_for(tuning, raw_map)
+                .cloned()
+                .unwrap_or_default();
+            let options = tuning.build.unwrap_or(*options);
+
+            let fingerprint = raw_map.fingerprint();
+            match cached
+                .iter()
+                .position(|map| map.fingerprint == fingerprint && map.build_options() == options)
+            {
+                Some(position) => {
+                    let mut map = cached.swap_remove(position);
+                    map.set_tuning(tuning);
+                    maps.push(Some(map));
+                }
+                None => {
+                    maps.push(None);
+                    parsing.push((
+                        i,
+                        Self::spawn_parse(raw_map.clone(), options, tuning, tasks),
+                    ));
+                }
+            }
+        }
+
+        // Block until all maps are parsed, a closed channel means the task was aborted
+        let parsed = !parsing.is_empty();
+        let (indices, results): (Vec<_>, Vec<_>) = parsing.into_iter().unzip();
+        for (i, map) in indices.into_iter().zip(
+            try_join_all(results)
+                .await
+                .map_err(|_| "Map parsing task was aborted")?,
+        ) {
+            maps[i] = Some(map?);
+        }
+
+        if let (Some(cache_dir), true) = (cache_dir, parsed) {
+            let loaded = maps.iter().flatten().cloned().collect::<Vec<_>>();
+            // The maps are fine without the cache, it is only slower next time
+            if let Err(err) = map_cache::store(cache_dir, &version, &loaded).await {
+                warn!("Failed to store the map cache: {}", err);
+            }
+        }
+
+        Ok((raw_maps, maps))
+    }
+
+    fn spawn_parse(
+        raw_map: RawMap,
+        options: MapBuildOptions,
+        tuning: MapTuning,
+        tasks: &TaskRegistry,
+    ) -> oneshot::Receiver<Result<Map, Error>> {
+        let (result_tx, result_rx) = oneshot::channel();
+        tasks.spawn(async move {
+            let map = Map::with_options(&raw_map, &options).map(|mut map| {
+                map.set_tuning(tuning);
+                map
+            });
+            let _ = result_tx.send(map);
+        });
+        result_rx
+    }
+
+    // An entry for the fingerprint wins over one for the name
+    fn tuning_for<'a>(
+        tuning: &'a HashMap<String, MapTuning>,
+        raw_map: &RawMap,
+    ) -> Option<&'a MapTuning> {
+        tuning
+            .get(&raw_map.fingerprint())
+            .or_else(|| tuning.get(&raw_map.name))
+    }
+
+    fn tuning_for_map(tuning: &HashMap<String, MapTuning>, map: &Map) -> MapTuning {
+        tuning
+            .get(&map.fingerprint())
+            .or_else(|| tuning.get(&map.name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Parse a map that isn't loaded yet, returns a copy of the map. A map that is being parsed,
+    // e.g. by preparse_maps, isn't parsed a second time.
+    pub async fn load_map(&mut self, name: &str) -> Result<Map, Error> {
+        match self.start_load(name)? {
+            MapLoad::Loaded(map) => Ok(*map),
+            MapLoad::Parsing(i, parse) => {
+                let map = Self::wait_parse(parse.clone()).await;
+                self.finish_load(i, &parse, map)
+            }
+        }
+    }
+
+    // Same as load_map, without keeping the client locked while the map is parsed
+    pub(crate) async fn load_map_shared(this: &Arc<Mutex<Self>>, name: &str) -> Result<Map, Error> {
+        let load = this.lock().await.start_load(name)?;
+        match load {
+            MapLoad::Loaded(map) => Ok(*map),
+            MapLoad::Parsing(i, parse) => {
+                let map = Self::wait_parse(parse.clone()).await;
+                this.lock().await.finish_load(i, &parse, map)
+            }
+        }
+    }
+
+    async fn wait_parse(mut parse: MapParse) -> Result<Map, Error> {
+        let result = parse
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| "Map parsing task was aborted")?
+            .clone();
+        result
+            .ok_or("Map parsing task was aborted")?
+            .map_err(Error::MapParse)
+    }
+
+    // Parse the maps in the background so joining them later doesn't wait for the parse. Maps that
+    // are loaded or being parsed already are skipped.
+    pub fn preparse_maps(
+        &mut self,
+        names: &[&str],
+        budget: ParseBudget,
+    ) -> watch::Receiver<PreparseProgress> {
+        let permits = Arc::new(Semaphore::new(budget.concurrency.max(1)));
+        for name in names {
+            if self.custom_maps.iter().any(|map| map.name == *name) {
+                continue;
+            }
+            let i = match self
+                .raw_maps
+                .iter()
+                .position(|raw_map| raw_map.name == *name)
+            {
+                Some(i) => i,
+                None => {
+                    warn!("Map {} is not in the source", name);
+                    continue;
+                }
+            };
+            if self.maps[i].is_some() || self.parsing.contains_key(&i) {
+                continue;
+            }
+
+            self.preparse.send_modify(|progress| progress.queued += 1);
+            let parse = self.spawn_load_parse(i, Some((permits.clone(), budget.nice)));
+            self.parsing.insert(i, parse);
+        }
+
+        self.preparse.subscribe()
+    }
+
+    // Preparse the n maps most games in the game list are played on, see likely_maps
+    pub async fn preparse_likely_maps(
+        &mut self,
+        n: usize,
+        budget: ParseBudget,
+    ) -> Result<watch::Receiver<PreparseProgress>, Error> {
+        let likely = self.likely_maps(n).await?;
+        let names = likely
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        Ok(self.preparse_maps(&names, budget))
+    }
+
+    pub fn preparse_progress(&self) -> watch::Receiver<PreparseProgress> {
+        self.preparse.subscribe()
+    }
+
+    // A background parse takes a permit first and, if nice, waits for the parses of loads
+    fn spawn_load_parse(&self, i: usize, background: Option<(Arc<Semaphore>, bool)>) -> MapParse {
+        let raw_map = self.raw_maps[i].clone();
+        let tuning = Self::tuning_for(&self.map_tuning, &raw_map)
+            .cloned()
+            .unwrap_or_default();
+        let options = tuning.build.unwrap_or(self.map_options);
+        let foreground = self.foreground.clone();
+        let preparse = self.preparse.clone();
+        let (result_tx, result_rx) = watch::channel(None);
+
+        self.tasks.spawn(async move {
+            let _permit = match &background {
+                Some((permits, nice)) => {
+                    let permit = permits.clone().acquire_owned().await;
+                    if *nice {
+                        let _ = foreground.subscribe().wait_for(|loads| *loads == 0).await;
+                    }
+                    Some(permit)
+                }
+                None => {
+                    foreground.send_modify(|loads| *loads += 1);
+                    None
+                }
+            };
+
+            info!("Parsing {}...", raw_map.name);
+            let result = task::spawn_blocking(move || {
+                Map::with_options(&raw_map, &options).map(|mut map| {
+                    map.set_tuning(tuning);
+                    map
+                })
             })
-            .collect::<Result<Vec<_>, Error>>()?;
+            .await;
+            let result = match result {
+                Ok(map) => map.map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            };
 
-        // Block until all maps are parsed
-        try_join_all(tasks)
-            .await?
-            .into_iter()
-            .collect::<Result<Vec<_>, Error>>()
+            match background {
+                Some(_) => preparse.send_modify(|progress| match result {
+                    Ok(_) => progress.parsed += 1,
+                    Err(_) => progress.failed += 1,
+                }),
+                None => foreground.send_modify(|loads| *loads -= 1),
+            }
+            let _ = result_tx.send(Some(result));
+        });
+
+        result_rx
     }
 
-    pub async fn games(&self) -> Result<Vec<Game>, Error> {
-        let req_client = reqwest::Client::new();
-        let raw_games: RawGameList = req_client
-            .get("https://matchmaker.krunker.io/game-list")
-            .query(&[("hostname", "krunker.io")])
-            .send()
+    fn start_load(&mut self, name: &str) -> Result<MapLoad, Error> {
+        if let Some(map) = self.custom_maps.iter().find(|map| map.name == name) {
+            return Ok(MapLoad::Loaded(Box::new(map.clone())));
+        }
+
+        let i = self
+            .raw_maps
+            .iter()
+            .position(|raw_map| raw_map.name == name)
+            .ok_or(Error::MapUnavailable)?;
+        if let Some(map) = &self.maps[i] {
+            return Ok(MapLoad::Loaded(Box::new(map.clone())));
+        }
+
+        let parse = match self.parsing.get(&i) {
+            Some(parse) => parse.clone(),
+            None => {
+                let parse = self.spawn_load_parse(i, None);
+                self.parsing.insert(i, parse.clone());
+                parse
+            }
+        };
+        Ok(MapLoad::Parsing(i, parse))
+    }
+
+    fn finish_load(
+        &mut self,
+        i: usize,
+        parse: &MapParse,
+        map: Result<Map, Error>,
+    ) -> Result<Map, Error> {
+        // The map was registered or tuned again while it was parsed, the entry is a newer parse
+        if self
+            .parsing
+            .get(&i)
+            .is_some_and(|current| current.same_channel(parse))
+        {
+            self.parsing.remove(&i);
+        }
+        // Another caller might have loaded the map in the meantime
+        if let Some(loaded) = &self.maps[i] {
+            return Ok(loaded.clone());
+        }
+
+        let mut map = map?;
+
+        if let Err(err) = map.apply_annotations(&self.annotations) {
+            warn!("Failed to apply annotations to {}: {}", map.name, err);
+        }
+        if let Some(options) = self.region_options {
+            map.label_regions(&options);
+        }
+        Ok(self.maps[i].insert(map).clone())
+    }
+
+    // Maps built with different options are parsed again, maps that aren't loaded get the tuning when
+    // they are. Players copy the map when a game starts, they use the new tuning from their next game on.
+    pub async fn set_map_tuning(&mut self, key: &str, tuning: MapTuning) -> Result<(), Error> {
+        self.map_tuning.insert(key.to_owned(), tuning);
+
+        // Registered maps can't be parsed again, they keep their build options
+        for map in self.custom_maps.iter_mut() {
+            if map.name == key || map.fingerprint() == key {
+                let tuning = Self::tuning_for_map(&self.map_tuning, map);
+                if tuning
+                    .build
+                    .is_some_and(|options| options != map.build_options())
+                {
+                    warn!(
+                        "Build options of {} can't change, it was registered",
+                        map.name
+                    );
+                }
+                map.set_tuning(tuning);
+            }
+        }
+
+        let mut results = vec![];
+        for (i, raw_map) in self.raw_maps.iter().enumerate() {
+            if raw_map.name != key && raw_map.fingerprint() != key {
+                continue;
+            }
+            let map = match self.maps[i].as_mut() {
+                Some(map) => map,
+                None => {
+                    // A parse with the old options is picked up by no load
+                    self.parsing.remove(&i);
+                    continue;
+                }
+            };
+
+            let tuning = Self::tuning_for(&self.map_tuning, raw_map)
+                .cloned()
+                .unwrap_or_default();
+            let options = tuning.build.unwrap_or(self.map_options);
+            if map.build_options() == options {
+                map.set_tuning(tuning);
+            } else {
+                info!("Parsing {} again with {:?}", raw_map.name, options);
+                results.push((
+                    i,
+                    Self::spawn_parse(raw_map.clone(), options, tuning, &self.tasks),
+                ));
+            }
+        }
+
+        for (i, result) in results {
+            let mut map = result.await.map_err(|_| "Map parsing task was aborted")??;
+            if let Some(previous) = &self.maps[i] {
+                map.carry_over(previous);
+            }
+            self.maps[i] = Some(map);
+        }
+
+        Ok(())
+    }
+
+    // Add a map that isn't part of the game source, e.g. one from Map::from_raw_json. Players find
+    // it by name when they join a game on it. It takes the place of a registered or loaded map with
+    // the same name and gets the tuning, annotations and regions the loaded maps got.
+    pub fn register_map(&mut self, mut map: Map) {
+        map.set_tuning(Self::tuning_for_map(&self.map_tuning, &map));
+        if let Err(err) = map.apply_annotations(&self.annotations) {
+            warn!("Failed to apply annotations to {}: {}", map.name, err);
+        }
+        if let Some(options) = self.region_options {
+            map.label_regions(&options);
+        }
+
+        self.custom_maps.retain(|custom| custom.name != map.name);
+        for (i, raw_map) in self.raw_maps.iter().enumerate() {
+            if raw_map.name == map.name {
+                self.maps[i] = None;
+                self.parsing.remove(&i);
+            }
+        }
+        self.custom_maps.push(map);
+    }
+
+    // Applies every entry of a tuning file, see MapTuning::from_toml
+    pub async fn load_map_tuning(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), Error> {
+        for (key, tuning) in MapTuning::from_toml(path)? {
+            self.set_map_tuning(&key, tuning).await?;
+        }
+        Ok(())
+    }
+
+    pub fn map_tuning(&self, key: &str) -> Option<&MapTuning> {
+        self.map_tuning.get(key)
+    }
+
+    // Untouched game list of the matchmaker, for fields the crate doesn't parse yet.
+    // The format is not stable and can change with every game update.
+    pub async fn games_raw(&self) -> Result<serde_json::Value, Error> {
+        let req_client = self.endpoints.http();
+        Ok(self
+            .matchmaker
+            .send("/game-list", |url| {
+                req_client
+                    .get(url)
+                    .query(&[("hostname", &self.endpoints.hostname)])
+            })
             .await?
             .json()
-            .await?;
+            .await?)
+    }
+
+    pub async fn games(&self) -> Result<Vec<Game>, Error> {
+        let mut raw_games = self.games_raw().await?;
 
-        let games: Vec<Game> = raw_games
-            .games
+        // Entries are parsed one by one to keep the original value next to the game
+        let entries = match raw_games.get_mut("games").map(serde_json::Value::take) {
+            Some(serde_json::Value::Array(entries)) => entries,
+            _ => {
+                // Let serde produce the error
+                serde_json::from_value::<RawGameList>(raw_games)?;
+                return Err("Game list has no games".into());
+            }
+        };
+
+        entries
             .into_iter()
-            .map(|game| Game {
-                client_key: self.client_key.clone(),
-                id: game.0,
-                region: game.1,
-                players: game.2,
-                max_players: game.3,
-                custom: game.4.custom != 0,
-                version: game.4.version,
-                map: game.4.map,
-                mode: game.4.mode,
+            .map(|entry| {
+                let game = RawGame::deserialize(&entry)?;
+                Ok(Game::from_raw(self, game, entry))
             })
-            .collect();
+            .collect()
+    }
+
+    // The game list only takes the hostname, the filter is applied to the whole list
+    pub async fn find_games(&self, filter: &GameFilter) -> Result<Vec<Game>, Error> {
+        let loaded_maps = self.loaded_maps();
+        Ok(self
+            .games()
+            .await?
+            .into_iter()
+            .filter(|game| filter.matches(game, &loaded_maps))
+            .collect())
+    }
+
+    // The matching game with the fewest players
+    pub async fn find_game(&self, filter: &GameFilter) -> Result<Option<Game>, Error> {
+        Ok(self
+            .find_games(filter)
+            .await?
+            .into_iter()
+            .min_by(|a, b| a.players.cmp(&b.players).then_with(|| a.id.cmp(&b.id))))
+    }
+
+    pub async fn game_by_id(&self, id: &str) -> Result<Game, Error> {
+        Game::from_id(self, id).await
+    }
+
+    // Profiles are cached for a minute to not spam the social server when checking many accounts
+    pub async fn profile(&self, username: &str) -> Result<Profile, Error> {
+        let key = username.to_lowercase();
+        if let Some((fetched_at, profile)) = self.profiles.lock().unwrap().get(&key) {
+            if fetched_at.elapsed() < PROFILE_CACHE_TTL {
+                return Ok(profile.clone());
+            }
+        }
+
+        let profile = fetch_profile(username, self.endpoints.proxy()).await?;
+        self.profiles
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), profile.clone()));
+
+        Ok(profile)
+    }
+
+    // Requests to the matchmaker fail over to the mirrors in the order they were added
+    pub fn add_matchmaker_mirror(&self, url: &str) {
+        self.matchmaker.add_mirror(url);
+    }
 
-        Ok(games)
+    pub fn endpoint_health(&self) -> Vec<EndpointHealth> {
+        self.matchmaker.health()
     }
 
+    // Rank the maps by how many games in the current game list are played on them
+    pub async fn likely_maps(&self, n: usize) -> Result<Vec<(String, usize)>, Error> {
+        let mut counts = HashMap::<String, usize>::new();
+        for game in self.games().await? {
+            *counts.entry(game.map).or_default() += 1;
+        }
+
+        let mut ranked = counts.into_iter().collect::<Vec<_>>();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked.truncate(n);
+
+        Ok(ranked)
+    }
+
+    // Apply the annotations to every loaded map that has an entry in the source
+    pub fn load_annotations(
+        &mut self,
+        source: &AnnotationSource,
+    ) -> Result<Vec<(String, AnnotationReport)>, Error> {
+        let file = source.parse()?;
+
+        let mut reports = vec![];
+        for map in self.maps.iter_mut().flatten().chain(&mut self.custom_maps) {
+            if let Some(report) = map.apply_annotations(&file)? {
+                reports.push((map.name.clone(), report));
+            }
+        }
+
+        self.annotations.maps.extend(file.maps);
+        Ok(reports)
+    }
+
+    // Label the regions of every loaded map. Players copy the map when a game starts,
+    // so this has to happen before they connect.
+    pub fn label_regions(&mut self, options: &RegionOptions) {
+        for map in self.maps.iter_mut().flatten().chain(&mut self.custom_maps) {
+            map.label_regions(options);
+        }
+        self.region_options = Some(*options);
+    }
+
+    // Map data without locking a player, players use a copy of the same map. None for maps that
+    // aren't loaded, see load_map.
+    pub fn map(&self, name: &str) -> Option<&Map> {
+        self.custom_maps
+            .iter()
+            .chain(self.maps.iter().flatten())
+            .find(|map| map.name == name)
+    }
+
+    // Every map of the source, loaded or not, and the registered ones
     pub fn available_maps(&self) -> Vec<String> {
+        self.raw_maps
+            .iter()
+            .map(|raw_map| raw_map.name.clone())
+            .filter(|name| !self.custom_maps.iter().any(|map| map.name == *name))
+            .chain(self.custom_maps.iter().map(|map| map.name.clone()))
+            .collect::<Vec<_>>()
+    }
+
+    pub fn loaded_maps(&self) -> Vec<String> {
         self.maps
             .iter()
+            .flatten()
+            .chain(&self.custom_maps)
             .map(|map| map.name.clone())
             .collect::<Vec<_>>()
     }
+
+    // Join an empty lobby in the region with a throwaway player and report which handshake
+    // stages passed and how long they took. Takes the shared client because the player needs it.
+    pub async fn self_check(this: &Arc<Mutex<Self>>, region: &str) -> SelfCheckReport {
+        self_check(this, region, None).await
+    }
+
+    // Events for the games matching the filter, polled in the interval. The first poll reports every
+    // matching game as added.
+    pub async fn watch_games(
+        this: &Arc<Mutex<Self>>,
+        filter: GameFilter,
+        interval: Duration,
+    ) -> GameListWatcher {
+        let tasks = TaskRegistry::new(this.lock().await.tasks.runtime());
+        GameListWatcher::spawn(this.clone(), filter, interval, tasks)
+    }
+
+    // Self-check of every region, see SmokeReport. A region that takes longer than the timeout
+    // fails and its player is disconnected.
+    pub async fn smoke_test(
+        this: &Arc<Mutex<Self>>,
+        regions: &[&str],
+        per_region_timeout: Duration,
+    ) -> SmokeReport {
+        smoke_test(this, regions, per_region_timeout).await
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Game {
     pub client_key: String,
     pub id: String,
-    pub region: String,
+    pub region: ServerRegion,
     pub version: String,
     pub players: u8,
     pub max_players: u8,
     pub custom: bool,
     pub map: String,
-    pub mode: u8,
+    pub mode: GameMode,
+    matchmaker: Arc<Matchmaker>,
+    endpoints: Arc<Endpoints>,
+    raw: Option<Arc<serde_json::Value>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -192,48 +1147,98 @@ pub struct GameConnectInfo {
     pub client_id: String,
     #[serde(rename = "gameId")]
     pub game_id: String,
+    #[serde(skip)]
+    raw: Option<serde_json::Value>,
+}
+
+impl GameConnectInfo {
+    // Response of the matchmaker this was parsed from if the client retains raw values.
+    // The format is not stable and can change with every game update.
+    pub fn raw(&self) -> Option<&serde_json::Value> {
+        self.raw.as_ref()
+    }
 }
 
 impl Game {
+    // Also finds full and unlisted games, which the game list leaves out
     pub async fn from_id(client: &Client, id: &str) -> Result<Self, Error> {
-        let req_client = reqwest::Client::new();
-        let raw_game: RawGame = req_client
-            .get("https://matchmaker.krunker.io/game-info")
-            .query(&[("game", id)])
-            .send()
-            .await?
-            .json()
-            .await?;
+        let (game, raw) = fetch_game_info(&client.matchmaker, &client.endpoints, id).await?;
+        Ok(Self::from_raw(client, game, raw))
+    }
 
-        Ok(Self {
+    // Links like https://krunker.io/?game=FRA:abcde, the scheme can be left out
+    pub async fn from_link(client: &Client, link: &str) -> Result<Self, Error> {
+        Self::from_id(client, &game_id_from_link(link)?).await
+    }
+
+    fn from_raw(client: &Client, game: RawGame, raw: serde_json::Value) -> Self {
+        Self {
             client_key: client.client_key.clone(),
-            id: raw_game.0,
-            region: raw_game.1,
-            players: raw_game.2,
-            max_players: raw_game.3,
-            custom: raw_game.4.custom != 0,
-            version: raw_game.4.version,
-            map: raw_game.4.map,
-            mode: raw_game.4.mode,
+            matchmaker: client.matchmaker.clone(),
+            endpoints: client.endpoints.clone(),
+            id: game.0,
+            region: game.1.into(),
+            players: game.2,
+            max_players: game.3,
+            custom: game.4.custom != 0,
+            version: game.4.version,
+            map: game.4.map,
+            mode: GameMode::from_id(game.4.mode),
+            raw: client.retain_raw.then(|| Arc::new(raw)),
+        }
+    }
+
+    // Entry of the game list or game info this game was last parsed from, only kept if the client
+    // was built with retain_raw. The format is not stable and can change with every game update.
+    pub fn raw(&self) -> Option<&serde_json::Value> {
+        self.raw.as_deref()
+    }
+
+    pub fn mode_info(&self) -> ModeInfo {
+        self.mode.info()
+    }
+
+    // Values as the matchmaker sent them, unknown regions and modes included
+    pub fn region_code(&self) -> &str {
+        self.region.as_str()
+    }
+
+    pub fn mode_id(&self) -> u8 {
+        self.mode.id()
+    }
+
+    pub(crate) fn origin(&self) -> String {
+        self.endpoints.origin()
+    }
+
+    // The game with its matchmaker requests going through another proxy
+    pub(crate) fn with_proxy(&self, proxy: Option<&ProxyConfig>) -> Result<Self, Error> {
+        if self.endpoints.proxy() == proxy {
+            return Ok(self.clone());
+        }
+        Ok(Self {
+            endpoints: Arc::new(self.endpoints.with_proxy(proxy.cloned())?),
+            ..self.clone()
         })
     }
 
     pub async fn validation_token(&self) -> Result<String, Error> {
-        let req_client = reqwest::Client::new();
+        let req_client = self.endpoints.http();
 
-        let token: serde_json::Value = req_client
-            .get("https://matchmaker.krunker.io/generate-token")
-            .header("client-key", &self.client_key)
-            .send()
+        let token: serde_json::Value = self
+            .matchmaker
+            .send("/generate-token", |url| {
+                req_client.get(url).header("client-key", &self.client_key)
+            })
             .await?
             .json()
             .await?;
 
         // TODO: hash the token on the client
-        let token_hash: Vec<u8> = req_client
-            .post("https://api.sys32.dev/v3/token")
-            .json(&serde_json::json!(token))
-            .send()
+        let url = format!("{}/token", self.endpoints.source_url);
+        let token_hash: Vec<u8> = self
+            .endpoints
+            .send("Token hashing", |http| http.post(&url).json(&token))
             .await?
             .json()
             .await?;
@@ -242,40 +1247,230 @@ impl Game {
     }
 
     pub async fn connect_info(&self) -> Result<GameConnectInfo, Error> {
-        let req_client = reqwest::Client::new();
-        let game_info: GameConnectInfo = req_client
-            .get("https://matchmaker.krunker.io/seek-game")
-            .header("Origin", "https://krunker.io")
-            .query(&[
-                ("hostname", "krunker.io"),
-                ("region", &self.region),
-                ("autoChangeGame", "false"),
-                ("validationToken", &self.validation_token().await?),
-                ("game", &self.id),
-                ("dataQuery", &format!("{{\"v\":\"{}\"}}", self.version)),
-            ])
-            .send()
+        let req_client = self.endpoints.http();
+        let origin = self.endpoints.origin();
+        let validation_token = self.validation_token().await?;
+        let data_query = format!("{{\"v\":\"{}\"}}", self.version);
+        let raw: serde_json::Value = self
+            .matchmaker
+            .send("/seek-game", |url| {
+                req_client.get(url).header("Origin", &origin).query(&[
+                    ("hostname", self.endpoints.hostname.as_str()),
+                    ("region", self.region.as_str()),
+                    ("autoChangeGame", "false"),
+                    ("validationToken", &validation_token),
+                    ("game", &self.id),
+                    ("dataQuery", &data_query),
+                ])
+            })
             .await?
             .json()
             .await?;
 
+        let mut game_info = GameConnectInfo::deserialize(&raw)?;
+        if self.raw.is_some() {
+            game_info.raw = Some(raw);
+        }
+
         Ok(game_info)
     }
 
     pub async fn update_info(&mut self) -> Result<(), Error> {
-        let req_client = reqwest::Client::new();
-        let raw_game: RawGame = req_client
-            .get("https://matchmaker.krunker.io/game-info")
-            .query(&[("game", &self.id)])
-            .send()
-            .await?
-            .json()
-            .await?;
+        let (raw_game, raw) = fetch_game_info(&self.matchmaker, &self.endpoints, &self.id).await?;
 
         self.players = raw_game.2;
-        self.mode = raw_game.4.mode;
+        self.mode = GameMode::from_id(raw_game.4.mode);
         self.map = raw_game.4.map;
+        if self.raw.is_some() {
+            self.raw = Some(Arc::new(raw));
+        }
 
         Ok(())
     }
+
+    pub async fn is_listed(&self) -> Result<bool, Error> {
+        match fetch_game_info(&self.matchmaker, &self.endpoints, &self.id).await {
+            Ok(_) => Ok(true),
+            Err(Error::GameNotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// The matchmaker answers game-info of unknown or delisted games with 404 or an error payload
+async fn fetch_game_info(
+    matchmaker: &Matchmaker,
+    endpoints: &Endpoints,
+    id: &str,
+) -> Result<(RawGame, serde_json::Value), Error> {
+    let req_client = endpoints.http();
+    let res = matchmaker
+        .send("/game-info", |url| {
+            req_client.get(url).query(&[("game", id)])
+        })
+        .await?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(Error::GameNotFound(id.to_owned()));
+    }
+
+    let raw: serde_json::Value = res.error_for_status()?.json().await?;
+    let game = RawGame::deserialize(&raw).map_err(|_| Error::GameNotFound(id.to_owned()))?;
+    Ok((game, raw))
+}
+
+fn game_id_from_link(link: &str) -> Result<String, Error> {
+    let link = link.trim();
+    let url = if link.contains("://") {
+        reqwest::Url::parse(link)
+    } else {
+        reqwest::Url::parse(&format!("https://{}", link))
+    }
+    .map_err(|err| format!("Invalid game link {}: {}", link, err))?;
+
+    url.query_pairs()
+        .find(|(key, _)| key == "game")
+        .map(|(_, id)| id.into_owned())
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| format!("No game id in link {}", link).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soak::{client, raw_map, MockMatchmaker};
+
+    #[tokio::test]
+    async fn preparse_skips_known_and_unknown_maps() {
+        let mut client = client(vec![raw_map("a"), raw_map("b")], DEFAULT_MATCHMAKER_URL);
+        client.preparse_maps(&["a", "missing"], ParseBudget::default());
+        client.preparse_maps(&["a"], ParseBudget::default());
+        assert_eq!(client.preparse_progress().borrow().queued, 1);
+
+        let mut progress = client.preparse_maps(&["b"], ParseBudget::default());
+        progress.wait_for(PreparseProgress::is_done).await.unwrap();
+        assert_eq!(
+            *progress.borrow(),
+            PreparseProgress {
+                queued: 2,
+                parsed: 2,
+                failed: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn load_waits_for_the_preparse() {
+        let mut client = client(vec![raw_map("a")], DEFAULT_MATCHMAKER_URL);
+        client.preparse_maps(&["a"], ParseBudget::default());
+        let preparse = client.parsing[&0].clone();
+
+        match client.start_load("a").unwrap() {
+            MapLoad::Parsing(0, parse) => assert!(parse.same_channel(&preparse)),
+            _ => panic!("load didn't wait for the preparse"),
+        }
+
+        let map = client.load_map("a").await.unwrap();
+        assert_eq!(map.name, "a");
+        assert!(client.parsing.is_empty());
+        assert_eq!(client.loaded_maps(), ["a"]);
+        assert_eq!(*client.foreground.borrow(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_parses_can_be_loaded_again() {
+        let mut broken = raw_map("broken");
+        broken.objects.clear();
+        let mut client = client(vec![broken], DEFAULT_MATCHMAKER_URL);
+
+        let mut progress = client.preparse_maps(&["broken"], ParseBudget::default());
+        progress.wait_for(PreparseProgress::is_done).await.unwrap();
+        assert_eq!(progress.borrow().failed, 1);
+
+        assert!(client.load_map("broken").await.is_err());
+        assert!(client.parsing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn raw_values_are_only_kept_when_retained() {
+        let matchmaker = MockMatchmaker::spawn("Burg").await;
+        let mut client = client(vec![], &matchmaker.url);
+        let games = client.games().await.unwrap();
+        assert!(games[0].raw().is_none());
+        assert!(client
+            .game_by_id("SOAK:game")
+            .await
+            .unwrap()
+            .raw()
+            .is_none());
+
+        client.retain_raw = true;
+        let list = client.games_raw().await.unwrap();
+        let games = client.games().await.unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].raw(), Some(&list["games"][0]));
+        assert_eq!(games[0].raw().unwrap()[4]["new"]["nested"][1], 2);
+        assert_eq!((games[0].map.as_str(), games[0].players), ("Burg", 2));
+
+        let mut game = client.game_by_id("SOAK:game").await.unwrap();
+        assert_eq!(game.raw(), Some(&list["games"][0]));
+        game.raw = None;
+        game.update_info().await.unwrap();
+        assert!(game.raw().is_none());
+    }
+
+    #[test]
+    fn connect_info_ignores_unknown_fields() {
+        let info = GameConnectInfo::deserialize(&serde_json::json!({
+            "host": "a.krunker.io",
+            "clientId": "client",
+            "gameId": "FRA:abcde",
+            "new": true
+        }))
+        .unwrap();
+        assert_eq!(info.game_id, "FRA:abcde");
+        assert!(info.raw().is_none());
+    }
+
+    #[tokio::test]
+    async fn map_tuning_wins_over_the_client_options() {
+        let radius = |player_radius| MapBuildOptions {
+            player_radius,
+            ..Default::default()
+        };
+        let tuned = |player_radius| MapTuning {
+            build: Some(radius(player_radius)),
+            ..Default::default()
+        };
+        let a = raw_map("a");
+        let fingerprint = a.fingerprint();
+        let mut client = client(vec![a, raw_map("b")], DEFAULT_MATCHMAKER_URL);
+        client.map_options = radius(1.0);
+        client.set_map_tuning("a", tuned(2.0)).await.unwrap();
+
+        let a = client.load_map("a").await.unwrap();
+        assert_eq!(a.build_options(), radius(2.0));
+        assert_eq!(
+            client.load_map("b").await.unwrap().build_options(),
+            radius(1.0)
+        );
+
+        // An entry for the fingerprint wins over the name and parses the loaded map again
+        client
+            .set_map_tuning(&fingerprint, tuned(3.0))
+            .await
+            .unwrap();
+        let a = client.load_map("a").await.unwrap();
+        assert_eq!(a.build_options(), radius(3.0));
+        assert_eq!(a.tuning().build, Some(radius(3.0)));
+
+        // Tuning without build options keeps the client options
+        client
+            .set_map_tuning(&fingerprint, MapTuning::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.load_map("a").await.unwrap().build_options(),
+            radius(1.0)
+        );
+    }
 }
@@ -0,0 +1,189 @@
+use std::fmt;
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+// Where a player is in the lifetime of its connection. Ended and Disconnected are final, every other
+// state can end or be disconnected at any time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum LifecycleState {
+    Connecting,
+    // Connected, waiting for the server to send ready
+    Handshaking,
+    LoggingIn,
+    // Ready but not spawned, before the first enter and between rounds
+    Lobby,
+    // Sent enter, waiting for the spawn
+    Entering,
+    InGame,
+    // Killed and waiting to respawn or for the next round
+    Dead {
+        #[serde(skip_serializing)]
+        since: Instant,
+    },
+    // Stopped for a reason in the end state, the socket is closed next
+    Ended,
+    Reconnecting,
+    Disconnected,
+}
+
+impl LifecycleState {
+    pub fn can_transition(&self, to: &LifecycleState) -> bool {
+        use LifecycleState::*;
+
+        match (self, to) {
+            (Ended, Disconnected) => true,
+            (Ended | Disconnected, _) => false,
+            (_, Ended | Disconnected) => true,
+            (Connecting, Handshaking) => true,
+            (Handshaking, LoggingIn | Lobby) => true,
+            (LoggingIn, Lobby) => true,
+            // Some servers spawn the player before it is ready
+            (Handshaking | LoggingIn, InGame) => true,
+            (Lobby, Entering | InGame) => true,
+            (Entering, InGame | Lobby) => true,
+            (InGame, Dead { .. } | Lobby) => true,
+            (Dead { .. }, Entering | InGame | Lobby) => true,
+            (Handshaking | LoggingIn | Lobby | Entering | InGame | Dead { .. }, Reconnecting) => {
+                true
+            }
+            (Reconnecting, Connecting) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !matches!(self, LifecycleState::Ended | LifecycleState::Disconnected)
+    }
+
+    pub fn is_in_game(&self) -> bool {
+        matches!(self, LifecycleState::InGame)
+    }
+
+    pub fn is_dead(&self) -> bool {
+        matches!(self, LifecycleState::Dead { .. })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidTransition {
+    pub from: LifecycleState,
+    pub to: LifecycleState,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid transition from {:?} to {:?}",
+            self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states() -> [LifecycleState; 10] {
+        use LifecycleState::*;
+
+        [
+            Connecting,
+            Handshaking,
+            LoggingIn,
+            Lobby,
+            Entering,
+            InGame,
+            Dead {
+                since: Instant::now(),
+            },
+            Ended,
+            Reconnecting,
+            Disconnected,
+        ]
+    }
+
+    #[test]
+    fn final_states_only_lead_to_disconnected() {
+        use LifecycleState::*;
+
+        for to in states() {
+            assert_eq!(Ended.can_transition(&to), to == Disconnected, "{:?}", to);
+            assert!(!Disconnected.can_transition(&to), "{:?}", to);
+        }
+        for from in states().into_iter().filter(LifecycleState::is_connected) {
+            assert!(from.can_transition(&Ended) && from.can_transition(&Disconnected));
+        }
+    }
+
+    #[test]
+    fn a_round_and_a_reconnect() {
+        use LifecycleState::*;
+
+        let dead = Dead {
+            since: Instant::now(),
+        };
+        let path = [
+            Connecting,
+            Handshaking,
+            LoggingIn,
+            Lobby,
+            Entering,
+            InGame,
+            dead,
+            InGame,
+            Lobby,
+            Entering,
+            Reconnecting,
+            Connecting,
+            Handshaking,
+            InGame,
+            Ended,
+            Disconnected,
+        ];
+        for pair in path.windows(2) {
+            assert!(pair[0].can_transition(&pair[1]), "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn skipped_states_are_rejected() {
+        use LifecycleState::*;
+
+        let dead = Dead {
+            since: Instant::now(),
+        };
+        for (from, to) in [
+            (Connecting, Lobby),
+            (Connecting, InGame),
+            (Lobby, dead),
+            (Entering, dead),
+            (Lobby, LoggingIn),
+            (Connecting, Reconnecting),
+            (Reconnecting, InGame),
+            (InGame, Entering),
+        ] {
+            assert!(!from.can_transition(&to), "{:?} to {:?}", from, to);
+        }
+
+        let err = InvalidTransition {
+            from: Lobby,
+            to: dead,
+        };
+        assert!(err
+            .to_string()
+            .starts_with("Invalid transition from Lobby to Dead"));
+    }
+
+    #[test]
+    fn state_checks() {
+        let connected = states().iter().filter(|state| state.is_connected()).count();
+        assert_eq!(connected, 8);
+        assert!(LifecycleState::InGame.is_in_game());
+        assert!(!LifecycleState::Entering.is_in_game());
+        assert!(states()[6].is_dead());
+    }
+}
@@ -1,14 +1,37 @@
-use std::collections::VecDeque;
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    ops::{Index, Range},
+    sync::Arc,
+};
 
-use ndarray::{Array2, Array3};
-use pathfinding::prelude::astar;
+use ndarray::{s, Array2, Array3, Axis};
+use pathfinding::prelude::{astar, dijkstra_partial};
+use rand::{seq::SliceRandom, Rng};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
 
-use crate::utils::{position_to_cell, Error, Vec3, AABB};
+use crate::{
+    annotations::{AnnotationFile, AnnotationReport, AnnotationSource, Annotations},
+    modes::GameMode,
+    raycast::{HitKind, ObjectChunks, RayHit},
+    regions::{label_regions, Region, RegionMap, RegionOptions},
+    tuning::MapTuning,
+    utils::{cell_to_position, position_to_cell, position_to_cell_clamped, Error, Vec3, AABB},
+};
 
+// Teleporters are walked into, they aren't solid
+const TELEPORTER_ID: u32 = 24;
+// Death zones, water and lava. The player walks into them like teleporters, so they aren't solid,
+// but paths should stay out of them.
+const HAZARD_OBJECT_IDS: [u32; 3] = [12, 39, 40];
 const EXCLUDE_OBJECT_IDS: [u32; 12] = [4, 13, 14, 15, 18, 23, 26, 29, 32, 38, 45, 77];
-const MAX_MAP_BOUNDS: AABB = AABB {
+pub(crate) const MAX_MAP_BOUNDS: AABB = AABB {
     min_x: -800.0,
     min_y: -200.0,
     min_z: -800.0,
@@ -17,11 +40,47 @@ const MAX_MAP_BOUNDS: AABB = AABB {
     max_z: 800.0,
 };
 
-pub(crate) const CELL_SIZE: f32 = 2.4;
-const CHUNK_SIZE: f32 = 130.0 * CELL_SIZE;
+// Edge length of the cubic cells of the walkable grid in world units
+pub const CELL_SIZE: f32 = 2.4;
+// Chunks are made of whole cells
+const CHUNK_CELLS: usize = 130;
+const CHUNK_SIZE: f32 = CHUNK_CELLS as f32 * CELL_SIZE;
 const PLAYER_HEIGHT: usize = (15.0 / CELL_SIZE) as usize;
+// Height of a crouching player, low tunnels between this and the standing height are walkable
+// while crouching
+const CROUCH_HEIGHT: usize = (8.0 / CELL_SIZE) as usize;
+// Space kept above the highest non-border object so the player can still stand on it
+const GRID_Y_MARGIN: f32 = (PLAYER_HEIGHT + 1) as f32 * CELL_SIZE;
+// Path costs are scaled so the floored heuristic keeps some resolution when it is weighted
+const PATH_COST_SCALE: i32 = 10;
+// Extra cost for cells of routes that were already returned by likely_routes
+const ROUTE_OVERLAP_PENALTY: i32 = 4 * PATH_COST_SCALE;
+// Narrow passages are checked again with cells split into 2x2 columns of half the size
+const FINE_CELL_SIZE: f32 = CELL_SIZE / 2.0;
+// Columns around a position that closest_walkable_cell searches by default
+const CLOSEST_WALKABLE_RADIUS: usize = 4;
+// Longest run of cells that aren't walkable between two walkable cells that is checked again. The
+// cells next to a wall aren't walkable either, so a thin wall already leaves a gap of four.
+const MAX_REFINED_GAP: usize = 4;
+// Walkable grid value of cells that only have room for the player at the finer resolution
+const REFINED_CELL: u8 = 3;
+// Walkable grid value of cells that only have room for a crouching player
+const CROUCH_CELL: u8 = 4;
+// Walkable grid value of walkable cells inside a hazard object
+const HAZARD_CELL: u8 = 5;
+// Maps with fewer walkable cells than this share of the cells of their area are probably broken
+pub(crate) const MIN_WALKABLE_SHARE: f32 = 0.01;
+// Cells a jump can land lower than it started
+const MAX_JUMP_DROP: usize = 3;
+// Edge length in cells of the clusters of the portal graph, ten of them make up a chunk. Larger
+// clusters have fewer portals but make searching the cells between them slower.
+const CLUSTER_CELLS: usize = CHUNK_CELLS / 10;
+// Grid value of cells a rotated object only covers partly. They block the player like solid cells
+// but aren't a floor to stand on.
+const PARTIAL_CELL: u8 = 7;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+// Unknown keys of custom maps are ignored, missing ones are None
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub struct RawMapObject {
     #[serde(rename = "p")]
     pub position: [f32; 3],
@@ -35,19 +94,27 @@ pub struct RawMapObject {
     pub border: Option<u8>,
     #[serde(rename = "d")]
     pub direction: Option<u8>,
+    // Index of the object a teleporter puts the player on
+    #[serde(rename = "tl")]
+    pub teleport_link: Option<usize>,
+    // Euler angles in radians, applied in x, y, z order around the position
+    #[serde(rename = "r")]
+    pub rotation: Option<[f32; 3]>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RawMapConfig {
+    #[serde(default)]
     pub modes: Vec<u32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RawMap {
     pub name: String,
     #[serde(rename = "xyz")]
     pub sizes: Vec<f32>,
     pub objects: Vec<RawMapObject>,
+    #[serde(default)]
     pub config: RawMapConfig,
     pub spawns: Vec<Vec<Option<f32>>>,
 }
@@ -78,247 +145,1667 @@ impl RawMap {
 
         res
     }
+
+    // FNV-1a hash over the geometry, used to detect data that was made for a different version of the map
+    pub(crate) fn fingerprint(&self) -> String {
+        let mut hash = 0xcbf29ce484222325_u64;
+        let mut write = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+
+        write(self.name.as_bytes());
+        for size in self.sizes.iter() {
+            write(&size.to_le_bytes());
+        }
+        for object in self.objects.iter() {
+            for p in object.position.iter() {
+                write(&p.to_le_bytes());
+            }
+            write(&(object.size_index.unwrap_or(usize::MAX) as u64).to_le_bytes());
+            write(&object.id.unwrap_or(u32::MAX).to_le_bytes());
+        }
+
+        format!("{:016x}", hash)
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Ramp {
-    bounds: AABB,
-    direction: u8,
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Spawn {
+    pub position: Vec3,
+    // None spawns players of any team
+    pub team: Option<u8>,
+    // Yaw the player faces after spawning
+    pub rotation: Option<f32>,
 }
 
-type FilteredObjects = (AABB, Vec<AABB>, Vec<Ramp>, Vec<AABB>);
+impl Spawn {
+    // Rows are x, y, z, team, weight and rotation, everything after the position is optional
+    fn parse(row: &[Option<f32>]) -> Option<Self> {
+        let position = Vec3 {
+            x: (*row.first()?)?,
+            y: (*row.get(1)?)?,
+            z: (*row.get(2)?)?,
+        };
+        let team = row
+            .get(3)
+            .copied()
+            .flatten()
+            .filter(|team| (0.0..=u8::MAX as f32).contains(team) && team.fract() == 0.0)
+            .map(|team| team as u8);
 
-#[derive(Debug, Clone)]
-struct Chunk<'a> {
-    bounds: AABB,
-    objects: Vec<&'a AABB>,
-    ramps: Vec<&'a Ramp>,
-    ladders: Vec<&'a AABB>,
+        Some(Self {
+            position,
+            team,
+            rotation: row.get(5).copied().flatten(),
+        })
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Map {
-    pub(crate) name: String,
-    pub(crate) spawns: Vec<Vec3>,
-    pub(crate) bounds: AABB,
-    pub(crate) walkable_grid: Array3<u8>,
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Teleporter {
+    entry: AABB,
+    exit: Vec3,
 }
 
-impl Map {
-    pub fn new(raw_map: &RawMap) -> Result<Self, Error> {
-        debug!("Loading {}", raw_map.name);
+impl Teleporter {
+    pub fn entry(&self) -> AABB {
+        self.entry
+    }
 
-        let (map_bounds, objects, ramps, ladders) = Self::filter_objects(raw_map)?;
+    // Top of the linked object
+    pub fn exit(&self) -> Vec3 {
+        self.exit
+    }
+}
 
-        let spawns = raw_map
-            .spawns
-            .iter()
-            .map(|s| {
-                if s.len() < 3 {
-                    Err("Raw map spawn contains less than 3 coordinates".into())
-                } else {
-                    Ok(Vec3 {
-                        x: s[0].ok_or("Spawn coordinate is null")?,
-                        y: s[1].ok_or("Spawn coordinate is null")?,
-                        z: s[2].ok_or("Spawn coordinate is null")?,
-                    })
-                }
-            })
-            .collect::<Result<Vec<_>, Error>>()?;
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Ramp {
+    bounds: AABB,
+    direction: u8,
+}
 
-        let walkable_grid = Self::generate_walkable_grid(
-            &Self::generate_grid(
-                &map_bounds,
-                &Self::generate_object_chunks(&map_bounds, &objects, &ramps, &ladders),
-            ),
-            &map_bounds,
-            &spawns,
-        )?;
+impl Ramp {
+    pub fn bounds(&self) -> AABB {
+        self.bounds
+    }
 
-        debug!("Finished loading {}", raw_map.name);
+    // Direction of the slope as in the map data
+    pub fn direction(&self) -> u8 {
+        self.direction
+    }
+}
 
-        Ok(Self {
-            name: raw_map.name.clone(),
-            spawns,
-            bounds: map_bounds,
-            walkable_grid,
-        })
+// Grid bounds, top of the map geometry, objects, objects that aren't axis aligned, ramps, ladders
+// and teleporters
+type FilteredObjects = (
+    AABB,
+    f32,
+    Vec<AABB>,
+    Vec<RotatedBox>,
+    Vec<Ramp>,
+    Vec<AABB>,
+    Vec<Teleporter>,
+    // Bounds of the hazard objects
+    Vec<AABB>,
+    DegenerateObjects,
+);
+
+// Object box with an arbitrary rotation
+#[derive(Debug, Clone, Copy)]
+struct RotatedBox {
+    center: [f32; 3],
+    half: [f32; 3],
+    // Local axes in world space
+    axes: [[f32; 3]; 3],
+    // Contains the whole rotated box
+    bounds: AABB,
+}
+
+impl RotatedBox {
+    // Rotates the box around the pivot. Rotations by multiples of 90 degrees only swap axes, for
+    // them the bounds are exact and the second value is true.
+    fn new(bounds: &AABB, pivot: &Vec3, rotation: [f32; 3]) -> (Self, bool) {
+        let [(sin_x, cos_x), (sin_y, cos_y), (sin_z, cos_z)] = rotation.map(f32::sin_cos);
+        // Rotation matrix of the angles in x, y, z order, its columns are the rotated axes
+        let matrix = [
+            [cos_y * cos_z, -cos_y * sin_z, sin_y],
+            [
+                cos_x * sin_z + sin_x * cos_z * sin_y,
+                cos_x * cos_z - sin_x * sin_z * sin_y,
+                -sin_x * cos_y,
+            ],
+            [
+                sin_x * sin_z - cos_x * cos_z * sin_y,
+                sin_x * cos_z + cos_x * sin_z * sin_y,
+                cos_x * cos_y,
+            ],
+        ];
+        let axes = [0, 1, 2].map(|j| [matrix[0][j], matrix[1][j], matrix[2][j]]);
+
+        let half = [
+            (bounds.max_x - bounds.min_x) / 2.0,
+            (bounds.max_y - bounds.min_y) / 2.0,
+            (bounds.max_z - bounds.min_z) / 2.0,
+        ];
+        let offset = [
+            (bounds.min_x + bounds.max_x) / 2.0 - pivot.x,
+            (bounds.min_y + bounds.max_y) / 2.0 - pivot.y,
+            (bounds.min_z + bounds.max_z) / 2.0 - pivot.z,
+        ];
+        let pivot = [pivot.x, pivot.y, pivot.z];
+        let center = [0, 1, 2].map(|i| pivot[i] + dot(&matrix[i], &offset));
+        let extent = [0, 1, 2].map(|i| (0..3).map(|j| matrix[i][j].abs() * half[j]).sum::<f32>());
+
+        let aligned = rotation.iter().all(|angle| {
+            let quarters = angle / std::f32::consts::FRAC_PI_2;
+            (quarters - quarters.round()).abs() < 1e-3
+        });
+        let rotated = Self {
+            center,
+            half,
+            axes,
+            bounds: AABB {
+                min_x: center[0] - extent[0],
+                min_y: center[1] - extent[1],
+                min_z: center[2] - extent[2],
+                max_x: center[0] + extent[0],
+                max_y: center[1] + extent[1],
+                max_z: center[2] + extent[2],
+            },
+        };
+        (rotated, aligned)
     }
 
-    fn filter_objects(raw: &RawMap) -> Result<FilteredObjects, Error> {
-        let mut map_bounds = AABB::zero();
+    // Separating axis test against the box, any overlap counts
+    fn overlaps(&self, other: &AABB) -> bool {
+        let other_center = [
+            (other.min_x + other.max_x) / 2.0,
+            (other.min_y + other.max_y) / 2.0,
+            (other.min_z + other.max_z) / 2.0,
+        ];
+        let other_half = [
+            (other.max_x - other.min_x) / 2.0,
+            (other.max_y - other.min_y) / 2.0,
+            (other.max_z - other.min_z) / 2.0,
+        ];
+        let distance = [0, 1, 2].map(|i| self.center[i] - other_center[i]);
+        let world = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
 
-        // estimate the number of objects to avoid frequent allocation
-        let mut objects = Vec::<AABB>::with_capacity(raw.objects.len() / 3);
-        let mut ramps = Vec::<Ramp>::new();
-        let mut ladders = Vec::<AABB>::new();
+        let mut candidates = world.to_vec();
+        candidates.extend(self.axes);
+        for a in world.iter() {
+            for b in self.axes.iter() {
+                candidates.push(cross(a, b));
+            }
+        }
 
-        let sizes = raw.get_size_groups();
-        for object in raw.objects.iter() {
-            // filter out everything that is not collidable
-            if object.not_collidable.is_some() {
-                continue;
+        !candidates.iter().any(|axis| {
+            // Parallel axes give no cross product
+            if dot(axis, axis) < 1e-6 {
+                return false;
             }
+            let other_radius = (0..3).map(|i| axis[i].abs() * other_half[i]).sum::<f32>();
+            let radius = (0..3)
+                .map(|j| dot(axis, &self.axes[j]).abs() * self.half[j])
+                .sum::<f32>();
+            dot(axis, &distance).abs() > other_radius + radius
+        })
+    }
 
-            if let Some(id) = object.id {
-                if EXCLUDE_OBJECT_IDS.contains(&id) {
-                    continue;
+    // Whether the vertical segment at x, z between the heights goes through the box
+    fn crosses_column(&self, x: f32, z: f32, min_y: f32, max_y: f32) -> bool {
+        let origin = [
+            x - self.center[0],
+            min_y - self.center[1],
+            z - self.center[2],
+        ];
+        let (mut enter, mut exit) = (0.0_f32, max_y - min_y);
+        for j in 0..3 {
+            let position = dot(&origin, &self.axes[j]);
+            // Length along the local axis per unit of height
+            let slope = self.axes[j][1];
+            if slope.abs() < 1e-6 {
+                if position.abs() > self.half[j] {
+                    return false;
                 }
+                continue;
             }
+            let a = (-self.half[j] - position) / slope;
+            let b = (self.half[j] - position) / slope;
+            enter = enter.max(a.min(b));
+            exit = exit.min(a.max(b));
+        }
+        enter <= exit
+    }
+}
 
-            if let Some(size_index) = object.size_index {
-                let size = sizes
-                    .get(size_index)
-                    .ok_or("Raw map object size index out of bounds")?;
+fn dot(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
 
-                let mut bounds = AABB {
-                    min_x: object.position[0] - size.x / 2.0,
-                    min_y: object.position[1],
-                    min_z: object.position[2] - size.z / 2.0,
-                    max_x: object.position[0] + size.x / 2.0,
-                    max_y: object.position[1] + size.y,
-                    max_z: object.position[2] + size.z / 2.0,
-                };
+fn cross(a: &[f32; 3], b: &[f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
 
-                map_bounds.extend_by(&bounds);
+// Objects of community maps with broken geometry
+#[derive(Debug, Clone, Copy, Default)]
+struct DegenerateObjects {
+    // Zero or non-finite size or position, or a size index past the last complete size group
+    skipped: usize,
+    // Negative sizes, kept with the min and max swapped
+    normalized: usize,
+}
 
-                // extend the height of the object if it is a border object
-                if object.border.is_some() {
-                    bounds.max_y = MAX_MAP_BOUNDS.max_y;
-                }
+// Unsimplified path, cost and explored cells of a search
+type SearchResult = (Vec<(usize, usize, usize)>, i32, usize);
+// Index of the reached goal and the simplified path to it
+type GoalPath = (usize, Vec<(usize, usize, usize)>);
+// Cell a teleporter entered from the key cell puts the player on
+type TeleportEdges = HashMap<(usize, usize, usize), (usize, usize, usize)>;
+// Cells a jump from the key cell can land on
+type JumpEdges = HashMap<(usize, usize, usize), Vec<(usize, usize, usize)>>;
+// Step from a cell into a cell of another cluster with its cost
+type ClusterCrossing = ((usize, usize, usize), (usize, usize, usize), i32);
 
-                if let Some(id) = object.id {
-                    if id == 9 {
-                        // is ramp
-                        ramps.push(Ramp {
-                            bounds,
-                            direction: object.direction.unwrap_or(0),
-                        });
-                        continue;
-                    } else if id == 3 {
-                        // is ladder
-                        ladders.push(bounds);
-                        continue;
-                    }
-                }
+// Cell of a narrow passage that the player fits through at half the cell size
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct RefinedCell {
+    // Axes the cell can be crossed along
+    along_x: bool,
+    along_z: bool,
+    // Center of the free part, used instead of the cell center when walking
+    position: Vec3,
+}
+
+// Cells of the grid with any geometry in them, one bit per cell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SolidGrid {
+    shape: (usize, usize, usize),
+    bits: Vec<u64>,
+}
 
-                objects.push(bounds);
+impl SolidGrid {
+    fn new(grid: &Array3<u8>) -> Self {
+        let mut bits = vec![0_u64; grid.len().div_ceil(64)];
+        // Row-major like the index in get
+        for (i, value) in grid.iter().enumerate() {
+            if *value != 0 {
+                bits[i / 64] |= 1 << (i % 64);
             }
         }
+        Self {
+            shape: grid.dim(),
+            bits,
+        }
+    }
 
-        map_bounds.limit_by(&MAX_MAP_BOUNDS);
+    fn get(&self, cell: &(usize, usize, usize)) -> bool {
+        let i = (cell.0 * self.shape.1 + cell.1) * self.shape.2 + cell.2;
+        self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+}
+
+// Walkable grid values stored as runs along y for every (x, z) column. Most of a map is air or solid,
+// so a column only has a few runs where a dense grid has a byte for every cell.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct WalkableGrid {
+    shape: (usize, usize, usize),
+    // Runs of every column x * size_z + z, in order
+    run_counts: Vec<u8>,
+    // Index of the first run of every block of WALKABLE_BLOCK columns
+    block_starts: Vec<u32>,
+    runs: Vec<WalkableRun>,
+    // Connected component of every run, see label_components
+    components: Vec<u16>,
+}
+
+// Cells start..end of a column with the same non-zero value. Grids are at most 167 cells high
+// because of MAX_MAP_BOUNDS, so y fits in a byte.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct WalkableRun {
+    start: u8,
+    end: u8,
+    value: u8,
+}
+
+// Cluster level graph used by find_path_hierarchical. Portals are walkable cells on either side of
+// a step into another cluster, they are connected to the portals of their own cluster they can
+// reach without leaving it and to the portal across the border.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PortalGraph {
+    cluster_shape: (usize, usize),
+    // Sorted by cluster, the portals of cluster i are cluster_starts[i]..cluster_starts[i + 1]
+    portals: Vec<(usize, usize, usize)>,
+    cluster_starts: Vec<u32>,
+    // Portal index and cost of the edges of portal i, edges[edge_starts[i]..edge_starts[i + 1]]
+    edge_starts: Vec<u32>,
+    edges: Vec<(u32, i32)>,
+}
 
-        Ok((map_bounds, objects, ramps, ladders))
+impl PortalGraph {
+    fn cluster_of(&self, cell: &(usize, usize, usize)) -> usize {
+        (cell.0 / CLUSTER_CELLS) * self.cluster_shape.1 + cell.2 / CLUSTER_CELLS
     }
 
-    fn generate_object_chunks<'a>(
-        map_bounds: &AABB,
-        objects: &'a [AABB],
-        ramps: &'a [Ramp],
-        ladders: &'a [AABB],
-    ) -> Array2<Chunk<'a>> {
-        let chunk_shape = (
-            ((map_bounds.max_x - map_bounds.min_x) / CHUNK_SIZE).ceil() as usize,
-            ((map_bounds.max_z - map_bounds.min_z) / CHUNK_SIZE).ceil() as usize,
-        );
+    fn cluster_portals(&self, cluster: usize) -> Range<usize> {
+        self.cluster_starts[cluster] as usize..self.cluster_starts[cluster + 1] as usize
+    }
 
-        Array2::<Chunk<'a>>::from_shape_fn(chunk_shape, |(x, z)| {
-            let chunk_bounds = AABB {
-                min_x: map_bounds.min_x + x as f32 * CHUNK_SIZE,
-                min_y: MAX_MAP_BOUNDS.min_y,
-                min_z: map_bounds.min_z + z as f32 * CHUNK_SIZE,
-                max_x: map_bounds.min_x + x as f32 * CHUNK_SIZE + CHUNK_SIZE,
-                max_y: MAX_MAP_BOUNDS.max_y,
-                max_z: map_bounds.min_z + z as f32 * CHUNK_SIZE + CHUNK_SIZE,
-            };
+    fn portal_edges(&self, portal: usize) -> &[(u32, i32)] {
+        &self.edges[self.edge_starts[portal] as usize..self.edge_starts[portal + 1] as usize]
+    }
+}
 
-            let mut chunk_objects =
-                Vec::<&'a AABB>::with_capacity(objects.len() / (chunk_shape.0 * chunk_shape.1));
-            let mut chunk_ramps = Vec::<&'a Ramp>::new();
-            let mut chunk_ladders = Vec::<&'a AABB>::new();
+// Walkable cells of a cluster with the steps between them by index and the steps out of it, only
+// kept while building the portal graph
+struct ClusterSteps {
+    index: HashMap<(usize, usize, usize), usize>,
+    steps: Vec<Vec<(usize, i32)>>,
+    crossings: Vec<ClusterCrossing>,
+}
 
-            for object in objects.iter() {
-                if chunk_bounds.intersects(object) {
-                    chunk_objects.push(object);
-                }
-            }
+impl ClusterSteps {
+    // Cost from the cell at the index to every cell of the cluster, MAX for unreachable ones
+    fn costs_from(&self, start: usize) -> Vec<i32> {
+        let mut costs = vec![i32::MAX; self.steps.len()];
+        let mut queue = BinaryHeap::new();
+        costs[start] = 0;
+        queue.push(Reverse((0, start)));
 
-            for ramp in ramps.iter() {
-                if chunk_bounds.intersects(&ramp.bounds) {
-                    chunk_ramps.push(ramp);
+        while let Some(Reverse((cost, i))) = queue.pop() {
+            if cost > costs[i] {
+                continue;
+            }
+            for (next, step_cost) in self.steps[i].iter() {
+                let next_cost = cost + step_cost;
+                if next_cost < costs[*next] {
+                    costs[*next] = next_cost;
+                    queue.push(Reverse((next_cost, *next)));
                 }
             }
+        }
 
-            for ladder in ladders.iter() {
-                if chunk_bounds.intersects(ladder) {
-                    chunk_ladders.push(ladder);
+        costs
+    }
+}
+
+// Columns between two stored run indices, the others are summed up from the counts
+const WALKABLE_BLOCK: usize = 8;
+// Indexing returns references, the values are looked up in here
+const WALKABLE_VALUES: [u8; 6] = [0, 1, 2, REFINED_CELL, CROUCH_CELL, HAZARD_CELL];
+
+impl WalkableGrid {
+    pub(crate) fn new(grid: &Array3<u8>) -> Self {
+        let (size_x, size_y, size_z) = grid.dim();
+        let mut run_counts = Vec::with_capacity(size_x * size_z);
+        let mut block_starts = Vec::with_capacity((size_x * size_z).div_ceil(WALKABLE_BLOCK));
+        let mut runs = Vec::<WalkableRun>::new();
+
+        for x in 0..size_x {
+            for z in 0..size_z {
+                if run_counts.len() % WALKABLE_BLOCK == 0 {
+                    block_starts.push(runs.len() as u32);
+                }
+                let column_start = runs.len();
+                for (y, value) in grid.slice(s![x, .., z]).iter().enumerate() {
+                    if *value == 0 {
+                        continue;
+                    }
+                    match runs[column_start..].last_mut() {
+                        Some(run) if run.end as usize == y && run.value == *value => run.end += 1,
+                        _ => runs.push(WalkableRun {
+                            start: y as u8,
+                            end: y as u8 + 1,
+                            value: *value,
+                        }),
+                    }
                 }
+                run_counts.push((runs.len() - column_start) as u8);
             }
+        }
+        runs.shrink_to_fit();
 
-            Chunk {
-                bounds: chunk_bounds,
-                objects: chunk_objects,
-                ramps: chunk_ramps,
-                ladders: chunk_ladders,
-            }
-        })
+        Self {
+            shape: (size_x, size_y, size_z),
+            run_counts,
+            block_starts,
+            components: vec![0; runs.len()],
+            runs,
+        }
     }
 
-    fn generate_grid<'a>(map_bounds: &AABB, chunks: &Array2<Chunk<'a>>) -> Array3<u8> {
-        let grid_shape = (
-            ((map_bounds.max_x - map_bounds.min_x) / CELL_SIZE).ceil() as usize,
-            ((map_bounds.max_y - map_bounds.min_y) / CELL_SIZE).ceil() as usize,
-            ((map_bounds.max_z - map_bounds.min_z) / CELL_SIZE).ceil() as usize,
-        );
-
-        Array3::<u8>::from_shape_fn(grid_shape, |(x, y, z)| {
-            let cell_bounds = AABB {
-                min_x: map_bounds.min_x + x as f32 * CELL_SIZE,
-                min_y: map_bounds.min_y + y as f32 * CELL_SIZE,
-                min_z: map_bounds.min_z + z as f32 * CELL_SIZE,
-                max_x: map_bounds.min_x + x as f32 * CELL_SIZE + CELL_SIZE,
-                max_y: map_bounds.min_y + y as f32 * CELL_SIZE + CELL_SIZE,
-                max_z: map_bounds.min_z + z as f32 * CELL_SIZE + CELL_SIZE,
-            };
+    // Joins runs that touch, in the same column or in one next to it, and the runs of the linked
+    // cells into components. Every step of a path is between touching cells or along a link, so
+    // cells of different components can't reach each other. Steps are only checked for touching
+    // cells and links can be one way, so cells of the same component usually but not always can.
+    fn label_components(
+        &mut self,
+        links: impl Iterator<Item = ((usize, usize, usize), (usize, usize, usize))>,
+    ) {
+        fn root(parents: &mut [u32], mut i: u32) -> u32 {
+            while parents[i as usize] != i {
+                parents[i as usize] = parents[parents[i as usize] as usize];
+                i = parents[i as usize];
+            }
+            i
+        }
+        fn join(parents: &mut [u32], a: usize, b: usize) {
+            let (a, b) = (root(parents, a as u32), root(parents, b as u32));
+            parents[a.max(b) as usize] = a.min(b);
+        }
 
-            for chunk in chunks.iter() {
-                if chunk.bounds.intersects(&cell_bounds) {
-                    let mut cell = 0_u8;
+        let (size_x, _, size_z) = self.shape;
+        let mut column_starts = Vec::with_capacity(self.run_counts.len() + 1);
+        column_starts.push(0);
+        for count in self.run_counts.iter() {
+            column_starts.push(column_starts.last().unwrap() + *count as usize);
+        }
+        let column =
+            |x: usize, z: usize| column_starts[x * size_z + z]..column_starts[x * size_z + z + 1];
 
-                    for ladder in &chunk.ladders {
-                        if cell_bounds.intersects(ladder) {
-                            cell = 6;
-                            break;
-                        }
+        let mut parents = (0..self.runs.len() as u32).collect::<Vec<_>>();
+        for x in 0..size_x {
+            for z in 0..size_z {
+                let runs = column(x, z);
+                for i in runs.start + 1..runs.end {
+                    if self.runs[i - 1].end == self.runs[i].start {
+                        join(&mut parents, i - 1, i);
                     }
+                }
 
-                    if cell == 0 {
-                        for object in &chunk.objects {
-                            if cell_bounds.intersects(object) {
-                                cell = 1;
-                                break;
-                            }
-                        }
+                // Cells next to each other touch when they are at most one cell apart in height
+                for (nx, nz) in [(x + 1, z), (x, z + 1)] {
+                    if nx >= size_x || nz >= size_z {
+                        continue;
                     }
-
-                    if cell == 0 {
-                        for ramp in &chunk.ramps {
-                            if cell_bounds.intersects(&ramp.bounds) {
-                                cell = 2 + ramp.direction;
-                                break;
+                    for i in runs.clone() {
+                        for j in column(nx, nz) {
+                            if self.runs[i].start <= self.runs[j].end
+                                && self.runs[j].start <= self.runs[i].end
+                            {
+                                join(&mut parents, i, j);
                             }
                         }
                     }
-
-                    return cell;
                 }
             }
+        }
 
-            panic!("Cell not in a chunk");
-        })
-    }
+        for (a, b) in links {
+            if let (Some(a), Some(b)) = (self.run_at(a), self.run_at(b)) {
+                join(&mut parents, a, b);
+            }
+        }
+
+        // Numbered in the order of the runs so the same grid always gets the same ids, ids past
+        // the last one are shared and treated as connected to everything
+        let mut ids = HashMap::new();
+        self.components = (0..self.runs.len() as u32)
+            .map(|i| {
+                let next = ids.len().min(u16::MAX as usize) as u16;
+                *ids.entry(root(&mut parents, i)).or_insert(next)
+            })
+            .collect();
+    }
+
+    pub(crate) fn dim(&self) -> (usize, usize, usize) {
+        self.shape
+    }
+
+    // None for cells outside of the grid
+    pub(crate) fn get(&self, cell: (usize, usize, usize)) -> Option<u8> {
+        let (x, y, z) = cell;
+        if x >= self.shape.0 || y >= self.shape.1 || z >= self.shape.2 {
+            return None;
+        }
+
+        Some(self.run_at(cell).map_or(0, |i| self.runs[i].value))
+    }
+
+    // None for cells outside of the grid or that aren't walkable
+    fn component(&self, cell: (usize, usize, usize)) -> Option<u16> {
+        self.run_at(cell).map(|i| self.components[i])
+    }
+
+    // Walkable cells of every component by id, the last id also counts the components past it
+    pub(crate) fn component_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![
+            0;
+            self.components
+                .iter()
+                .max()
+                .map_or(0, |id| *id as usize + 1)
+        ];
+        for (run, id) in self.runs.iter().zip(&self.components) {
+            sizes[*id as usize] += (run.end - run.start) as usize;
+        }
+        sizes
+    }
+
+    pub(crate) fn count(&self, value: u8) -> usize {
+        self.runs
+            .iter()
+            .filter(|run| run.value == value)
+            .map(|run| (run.end - run.start) as usize)
+            .sum()
+    }
+
+    // Index of the run the cell is in
+    fn run_at(&self, cell: (usize, usize, usize)) -> Option<usize> {
+        let (x, y, z) = cell;
+        if x >= self.shape.0 || y >= self.shape.1 || z >= self.shape.2 {
+            return None;
+        }
+
+        let start = self.column_start(x, z);
+        let runs = &self.runs[start..start + self.run_counts[x * self.shape.2 + z] as usize];
+        let i = runs.partition_point(|run| (run.end as usize) <= y);
+        runs.get(i)
+            .filter(|run| run.start as usize <= y)
+            .map(|_| start + i)
+    }
+
+    // Whether any of the heights of the column is walkable
+    fn any_walkable(&self, x: usize, z: usize, ys: Range<usize>) -> bool {
+        self.column_runs(x, z)
+            .iter()
+            .any(|run| (run.start as usize) < ys.end && ys.start < run.end as usize)
+    }
+
+    // Heights of the walkable cells of the column
+    fn column(&self, x: usize, z: usize) -> impl Iterator<Item = usize> + '_ {
+        self.column_runs(x, z)
+            .iter()
+            .flat_map(|run| run.start as usize..run.end as usize)
+    }
+
+    fn column_runs(&self, x: usize, z: usize) -> &[WalkableRun] {
+        let start = self.column_start(x, z);
+        &self.runs[start..start + self.run_counts[x * self.shape.2 + z] as usize]
+    }
+
+    // Index of the first run of the column
+    fn column_start(&self, x: usize, z: usize) -> usize {
+        let column = x * self.shape.2 + z;
+        let block = column / WALKABLE_BLOCK;
+        self.block_starts[block] as usize
+            + self.run_counts[block * WALKABLE_BLOCK..column]
+                .iter()
+                .map(|count| *count as usize)
+                .sum::<usize>()
+    }
+
+    // Approximate heap size in bytes
+    fn memory_size(&self) -> usize {
+        self.run_counts.capacity()
+            + self.block_starts.capacity() * std::mem::size_of::<u32>()
+            + self.runs.capacity() * std::mem::size_of::<WalkableRun>()
+            + self.components.capacity() * std::mem::size_of::<u16>()
+    }
+}
+
+impl Index<(usize, usize, usize)> for WalkableGrid {
+    type Output = u8;
+
+    fn index(&self, cell: (usize, usize, usize)) -> &u8 {
+        match self.get(cell) {
+            Some(value) => &WALKABLE_VALUES[value as usize],
+            None => panic!("Walkable cell {:?} is outside of {:?}", cell, self.shape),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Chunk<'a> {
+    bounds: AABB,
+    objects: Vec<&'a AABB>,
+    rotated: Vec<&'a RotatedBox>,
+    ramps: Vec<&'a Ramp>,
+    ladders: Vec<&'a AABB>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MapBuildOptions {
+    // Horizontal radius of the player hitbox. Cells closer than this to a wall at body height are
+    // not walkable. A radius of half a cell or less keeps the single-cell player.
+    pub player_radius: f32,
+    // Check short gaps between walkable cells, e.g. doorways narrower than the grid resolution,
+    // again at half the cell size and make the ones the player fits through
+    // walkable. The finer cells are only kept for these gaps.
+    pub refine_narrow_passages: bool,
+    // Widest gap in cells between walkable cells at the same or a lower height that is jumped
+    // across, 0 doesn't look for jumps
+    pub max_jump_gap: usize,
+    // Build the portal graph find_path_hierarchical routes over, without it find_path is used
+    pub hierarchical_paths: bool,
+}
+
+impl Default for MapBuildOptions {
+    fn default() -> Self {
+        Self {
+            player_radius: 0.0,
+            refine_narrow_passages: false,
+            max_jump_gap: 2,
+            hierarchical_paths: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MapCoverage {
+    pub walkable_cells_before_clearance: usize,
+    pub walkable_cells: usize,
+    // Cells of narrow passages that are walkable because of the refinement
+    pub refined_cells: usize,
+    pub jump_edges: usize,
+    // Walkable cells inside hazard objects
+    pub hazard_cells: usize,
+    pub skipped_objects: usize,
+    pub normalized_objects: usize,
+}
+
+// Costs are relative to a step between two flat cells, which costs 1
+#[derive(Debug, Clone, Copy)]
+pub struct PathOptions {
+    heuristic_weight: f32,
+    // Added to cells on the edge of the walkable cells and to narrow passages
+    edge_penalty: f32,
+    // Added to steps up or down
+    vertical_penalty: f32,
+    // Added to steps into cells that need crouching
+    crouch_penalty: f32,
+    // Added to jumps on top of the distance
+    jump_penalty: f32,
+    // Of the base ladder cost of 3
+    ladder_cost_multiplier: f32,
+    forbid_ladders: bool,
+    // Of the cost of steps into hazardous cells
+    hazard_cost_multiplier: f32,
+    forbid_hazards: bool,
+    max_explored: Option<usize>,
+}
+
+impl Default for PathOptions {
+    fn default() -> Self {
+        Self {
+            heuristic_weight: 1.0,
+            edge_penalty: 2.0,
+            vertical_penalty: 1.0,
+            crouch_penalty: 2.0,
+            jump_penalty: 4.0,
+            ladder_cost_multiplier: 1.0,
+            forbid_ladders: false,
+            hazard_cost_multiplier: 20.0,
+            forbid_hazards: false,
+            max_explored: None,
+        }
+    }
+}
+
+impl PathOptions {
+    // Higher penalties keep the path away from ledges
+    pub fn edge_penalty(mut self, edge_penalty: f32) -> Self {
+        self.edge_penalty = edge_penalty.max(0.0);
+        self
+    }
+
+    pub fn vertical_penalty(mut self, vertical_penalty: f32) -> Self {
+        self.vertical_penalty = vertical_penalty.max(0.0);
+        self
+    }
+
+    // Crouching is slower than walking
+    pub fn crouch_penalty(mut self, crouch_penalty: f32) -> Self {
+        self.crouch_penalty = crouch_penalty.max(0.0);
+        self
+    }
+
+    // Jumps fail more often than walking and take longer
+    pub fn jump_penalty(mut self, jump_penalty: f32) -> Self {
+        self.jump_penalty = jump_penalty.max(0.0);
+        self
+    }
+
+    pub fn ladder_cost_multiplier(mut self, multiplier: f32) -> Self {
+        self.ladder_cost_multiplier = multiplier.max(0.0);
+        self
+    }
+
+    pub fn forbid_ladders(mut self, forbid_ladders: bool) -> Self {
+        self.forbid_ladders = forbid_ladders;
+        self
+    }
+
+    // Paths only cross hazards when the way around them costs more than this many times as much
+    pub fn hazard_cost_multiplier(mut self, multiplier: f32) -> Self {
+        self.hazard_cost_multiplier = multiplier.max(1.0);
+        self
+    }
+
+    // Fail instead of walking through hazards
+    pub fn forbid_hazards(mut self, forbid_hazards: bool) -> Self {
+        self.forbid_hazards = forbid_hazards;
+        self
+    }
+
+    // The search gives up with PathBudgetExceeded after expanding this many cells
+    pub fn max_explored(mut self, max_explored: usize) -> Self {
+        self.max_explored = Some(max_explored);
+        self
+    }
+
+    // Integer costs for the search, flat, vertical, edge, crouch, ladder steps and the jump penalty
+    fn step_costs(&self) -> (i32, i32, i32, i32, i32, i32) {
+        let scaled = |cost: f32| (cost * PATH_COST_SCALE as f32).round() as i32;
+        (
+            PATH_COST_SCALE,
+            scaled(1.0 + self.vertical_penalty),
+            scaled(1.0 + self.edge_penalty),
+            scaled(1.0 + self.crouch_penalty),
+            scaled(3.0 * self.ladder_cost_multiplier),
+            scaled(self.jump_penalty),
+        )
+    }
+
+    // Weights above 1.0 plan faster but the path can be up to weight times longer than the optimal one
+    pub fn heuristic_weight(mut self, heuristic_weight: f32) -> Self {
+        self.heuristic_weight = heuristic_weight.max(1.0);
+        self
+    }
+
+    // Accept paths that cost at most bound times the optimal path
+    pub fn suboptimality_bound(self, bound: f32) -> Self {
+        self.heuristic_weight(bound)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PathSearch {
+    pub cells: Vec<(usize, usize, usize)>,
+    pub cost: f32,
+    // Number of cells that were expanded during the search
+    pub explored: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum PathResult {
+    Found(Path),
+    // Every reachable cell was explored
+    NotFound,
+    // The search gave up after the allowed number of expanded cells
+    BudgetExceeded,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Path {
+    // World positions of the simplified path
+    pub waypoints: Vec<Vec3>,
+    // Every cell of the path before simplification
+    pub cells: Vec<(usize, usize, usize)>,
+    pub cost: f32,
+    // Length of the waypoints in world units
+    pub length: f32,
+    // Whether the segment from each waypoint to the next one needs crouching
+    pub crouch: Vec<bool>,
+    // Whether the segment from each waypoint to the next one is a jump across a gap
+    pub jumps: Vec<bool>,
+    // Whether the waypoint is a teleporter entry that puts the player on the next one
+    pub teleports: Vec<bool>,
+    // Whether the segment from each waypoint to the next one has hazardous cells
+    pub hazards: Vec<bool>,
+}
+
+impl Path {
+    // Average distance of the positions to the closest cell of the path, lower is a better fit
+    pub fn distance_to(&self, bounds: &AABB, positions: &[Vec3]) -> f32 {
+        if positions.is_empty() || self.cells.is_empty() {
+            return f32::INFINITY;
+        }
+
+        positions
+            .iter()
+            .map(|position| {
+                self.cells
+                    .iter()
+                    .map(|cell| {
+                        let p = cell_to_position(bounds, cell);
+                        ((p.x - position.x).powi(2)
+                            + (p.y - position.y).powi(2)
+                            + (p.z - position.z).powi(2))
+                        .sqrt()
+                    })
+                    .fold(f32::INFINITY, f32::min)
+            })
+            .sum::<f32>()
+            / positions.len() as f32
+    }
+}
+
+// Annotations, regions and tuning are set at runtime and not serialized
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Map {
+    pub(crate) name: String,
+    pub(crate) fingerprint: String,
+    pub(crate) spawns: Vec<Spawn>,
+    pub(crate) bounds: AABB,
+    ceiling: f32,
+    modes: Vec<GameMode>,
+    pub(crate) walkable_grid: WalkableGrid,
+    // Row-major indices of the walkable cells, for sampling
+    walkable_cells: Vec<u32>,
+    solid: SolidGrid,
+    // Filtered geometry the grid was built from
+    objects: Vec<AABB>,
+    ramps: Vec<Ramp>,
+    ladders: Vec<AABB>,
+    object_chunks: ObjectChunks,
+    refined: HashMap<(usize, usize, usize), RefinedCell>,
+    jumps: JumpEdges,
+    teleporters: Vec<Teleporter>,
+    teleports: TeleportEdges,
+    coverage: MapCoverage,
+    portals: PortalGraph,
+    #[serde(skip)]
+    annotations: Annotations,
+    #[serde(skip)]
+    regions: Option<RegionMap>,
+    #[serde(skip)]
+    region_options: Option<RegionOptions>,
+    options: MapBuildOptions,
+    #[serde(skip)]
+    tuning: MapTuning,
+}
+
+impl Map {
+    pub fn new(raw_map: &RawMap) -> Result<Self, Error> {
+        Self::with_options(raw_map, &MapBuildOptions::default())
+    }
+
+    // Map in the format of the game source, for custom maps or ones made by hand
+    pub fn from_raw_json(json: &str) -> Result<Self, Error> {
+        Self::new(&serde_json::from_str::<RawMap>(json)?)
+    }
+
+    pub fn with_options(raw_map: &RawMap, options: &MapBuildOptions) -> Result<Self, Error> {
+        debug!("Loading {}", raw_map.name);
+
+        let (
+            map_bounds,
+            ceiling,
+            mut objects,
+            rotated,
+            ramps,
+            ladders,
+            teleporters,
+            hazards,
+            degenerate,
+        ) = Self::filter_objects(raw_map)?;
+        if degenerate.skipped > 0 || degenerate.normalized > 0 {
+            warn!(
+                "{} has {} degenerate objects that were skipped and {} with negative sizes",
+                raw_map.name, degenerate.skipped, degenerate.normalized
+            );
+        }
+
+        // Rows without a full position are skipped, one broken spawn shouldn't lose the map
+        let spawns = raw_map
+            .spawns
+            .iter()
+            .filter_map(|row| Spawn::parse(row))
+            .collect::<Vec<_>>();
+        if spawns.len() < raw_map.spawns.len() {
+            warn!(
+                "{} has {} spawns without a full position that were skipped",
+                raw_map.name,
+                raw_map.spawns.len() - spawns.len()
+            );
+        }
+
+        let chunks =
+            Self::generate_object_chunks(&map_bounds, &objects, &rotated, &ramps, &ladders);
+        let grid = Self::generate_grid(&map_bounds, &chunks);
+        // Teleporters can lead to parts of the map without spawns
+        let start_positions = spawns
+            .iter()
+            .map(|spawn| spawn.position)
+            .chain(teleporters.iter().map(|teleporter| teleporter.exit))
+            .collect::<Vec<_>>();
+        let mut walkable_grid =
+            Self::generate_walkable_grid(&raw_map.name, &grid, &map_bounds, &start_positions)?;
+        let refined = if options.refine_narrow_passages {
+            Self::refine_narrow_passages(
+                &grid,
+                &mut walkable_grid,
+                &map_bounds,
+                &chunks,
+                options.player_radius,
+            )?
+        } else {
+            HashMap::new()
+        };
+        let mut jumps = if options.max_jump_gap > 0 {
+            Self::find_jumps(&grid, &mut walkable_grid, options.max_jump_gap)?
+        } else {
+            HashMap::new()
+        };
+
+        debug!(
+            "Grid of {} is {:?} with {} cells",
+            raw_map.name,
+            grid.shape(),
+            grid.len()
+        );
+
+        // The chunks borrow the objects, which are moved into the map
+        drop(chunks);
+        // Only the grid uses the exact shape of rotated objects
+        objects.extend(rotated.iter().map(|rotated| rotated.bounds));
+
+        let walkable_cells_before_clearance = Self::count_walkable(&walkable_grid);
+        Self::apply_clearance(&grid, &mut walkable_grid, options.player_radius);
+        // Jumps from or onto cells the clearance removed are gone as well
+        jumps.retain(|cell, landings| {
+            landings.retain(|landing| walkable_grid[*landing] != 0);
+            walkable_grid[*cell] != 0 && !landings.is_empty()
+        });
+        let teleports = Self::link_teleporters(&grid, &walkable_grid, &map_bounds, &teleporters);
+        let hazard_cells = Self::mark_hazards(&mut walkable_grid, &map_bounds, &hazards);
+        let coverage = MapCoverage {
+            walkable_cells_before_clearance,
+            walkable_cells: Self::count_walkable(&walkable_grid),
+            refined_cells: refined.len(),
+            jump_edges: jumps.values().map(Vec::len).sum(),
+            hazard_cells,
+            skipped_objects: degenerate.skipped,
+            normalized_objects: degenerate.normalized,
+        };
+
+        debug!(
+            "Finished loading {} ({} of {} walkable cells left after clearance)",
+            raw_map.name, coverage.walkable_cells, coverage.walkable_cells_before_clearance
+        );
+        // Broken spawns or geometry leave a map that can't be walked on instead of failing
+        let area = grid.dim().0 * grid.dim().2;
+        if (coverage.walkable_cells as f32) < area as f32 * MIN_WALKABLE_SHARE {
+            warn!(
+                "{} has only {} walkable cells for an area of {} cells",
+                raw_map.name, coverage.walkable_cells, area
+            );
+        }
+
+        // The dense grid is only needed while building
+        let mut compact_grid = WalkableGrid::new(&walkable_grid);
+        compact_grid.label_components(
+            jumps
+                .iter()
+                .flat_map(|(cell, landings)| landings.iter().map(|landing| (*cell, *landing)))
+                .chain(teleports.iter().map(|(entry, exit)| (*entry, *exit))),
+        );
+        debug!(
+            "Walkable grid of {} takes {} bytes instead of {}",
+            raw_map.name,
+            compact_grid.memory_size(),
+            walkable_grid.len()
+        );
+
+        let mut map = Self {
+            name: raw_map.name.clone(),
+            fingerprint: raw_map.fingerprint(),
+            spawns,
+            bounds: map_bounds,
+            ceiling,
+            // Ids above u8 can't come from the matchmaker
+            modes: raw_map
+                .config
+                .modes
+                .iter()
+                .filter_map(|&id| u8::try_from(id).ok().map(GameMode::from_id))
+                .collect(),
+            walkable_cells: walkable_grid
+                .iter()
+                .enumerate()
+                .filter(|(_, value)| **value != 0)
+                .map(|(i, _)| i as u32)
+                .collect(),
+            walkable_grid: compact_grid,
+            solid: SolidGrid::new(&grid),
+            object_chunks: ObjectChunks::new(
+                &map_bounds,
+                CHUNK_SIZE,
+                &[
+                    (HitKind::Object, &objects),
+                    (
+                        HitKind::Ramp,
+                        &ramps.iter().map(|ramp| ramp.bounds).collect::<Vec<_>>(),
+                    ),
+                    (HitKind::Ladder, &ladders),
+                ],
+            ),
+            objects,
+            ramps,
+            ladders,
+            refined,
+            jumps,
+            teleporters,
+            teleports,
+            coverage,
+            portals: PortalGraph::default(),
+            annotations: Annotations::default(),
+            regions: None,
+            region_options: None,
+            options: *options,
+            tuning: MapTuning::default(),
+        };
+        // The portals are found with the same steps as the search, which needs the finished map
+        if options.hierarchical_paths {
+            map.portals = map.build_portal_graph();
+            debug!(
+                "Portal graph of {} has {} portals",
+                raw_map.name,
+                map.portals.portals.len()
+            );
+        }
+
+        Ok(map)
+    }
+
+    fn build_portal_graph(&self) -> PortalGraph {
+        let (size_x, _, size_z) = self.walkable_grid.dim();
+        let mut graph = PortalGraph {
+            cluster_shape: (
+                size_x.div_ceil(CLUSTER_CELLS),
+                size_z.div_ceil(CLUSTER_CELLS),
+            ),
+            ..Default::default()
+        };
+        let cluster_count = graph.cluster_shape.0 * graph.cluster_shape.1;
+        // Built with the default costs, the tuning of the map is only set later
+        let options = PathOptions::default();
+
+        let clusters = (0..graph.cluster_shape.0)
+            .flat_map(|x| (0..graph.cluster_shape.1).map(move |z| (x, z)))
+            .collect::<Vec<_>>();
+        #[cfg(feature = "parallel")]
+        let clusters = clusters.into_par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let clusters = clusters.into_iter();
+        let cluster_steps = clusters
+            .map(|(x, z)| self.cluster_steps(x, z, &options))
+            .collect::<Vec<_>>();
+
+        // Neighbouring steps between the same clusters and heights form an entrance, its middle
+        // step connects the clusters
+        let mut entrances = HashMap::<_, Vec<ClusterCrossing>>::new();
+        for crossing in cluster_steps
+            .iter()
+            .flat_map(|steps| steps.crossings.iter())
+        {
+            let (from, to, _) = crossing;
+            entrances
+                .entry((graph.cluster_of(from), graph.cluster_of(to), from.1, to.1))
+                .or_default()
+                .push(*crossing);
+        }
+        let mut crossings = vec![];
+        for mut steps in entrances.into_values() {
+            steps.sort_unstable_by_key(|(from, _, _)| (from.0, from.2));
+            for entrance in
+                steps.chunk_by(|a, b| a.0 .0.abs_diff(b.0 .0) + a.0 .2.abs_diff(b.0 .2) == 1)
+            {
+                crossings.push(entrance[entrance.len() / 2]);
+            }
+        }
+
+        let mut portals = crossings
+            .iter()
+            .flat_map(|(from, to, _)| [*from, *to])
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        portals.sort_unstable_by_key(|cell| (graph.cluster_of(cell), cell.1, cell.0, cell.2));
+        let mut cluster_starts = vec![0_u32; cluster_count + 1];
+        for portal in portals.iter() {
+            cluster_starts[graph.cluster_of(portal) + 1] += 1;
+        }
+        for i in 0..cluster_count {
+            cluster_starts[i + 1] += cluster_starts[i];
+        }
+        graph.portals = portals;
+        graph.cluster_starts = cluster_starts;
+
+        let portal_index = graph
+            .portals
+            .iter()
+            .enumerate()
+            .map(|(i, portal)| (*portal, i as u32))
+            .collect::<HashMap<_, _>>();
+        let mut edges = vec![vec![]; graph.portals.len()];
+        for (from, to, cost) in crossings {
+            edges[portal_index[&from] as usize].push((portal_index[&to], cost));
+        }
+
+        // Portals of a cluster are connected by their cost through it
+        #[cfg(feature = "parallel")]
+        let clusters = cluster_steps.par_iter().enumerate();
+        #[cfg(not(feature = "parallel"))]
+        let clusters = cluster_steps.iter().enumerate();
+        let inner_edges = clusters
+            .map(|(cluster, steps)| {
+                let portals = graph.cluster_portals(cluster);
+                portals
+                    .clone()
+                    .map(|from| {
+                        let costs = steps.costs_from(steps.index[&graph.portals[from]]);
+                        portals
+                            .clone()
+                            .filter(|to| *to != from)
+                            .filter_map(|to| {
+                                let cost = costs[steps.index[&graph.portals[to]]];
+                                (cost != i32::MAX).then_some((to as u32, cost))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        for (portal_edges, inner) in edges.iter_mut().zip(inner_edges.into_iter().flatten()) {
+            portal_edges.extend(inner);
+        }
+
+        graph.edge_starts = Vec::with_capacity(edges.len() + 1);
+        graph.edge_starts.push(0);
+        for portal_edges in edges {
+            graph.edges.extend(portal_edges);
+            graph.edge_starts.push(graph.edges.len() as u32);
+        }
+
+        graph
+    }
+
+    fn cluster_steps(&self, x: usize, z: usize, options: &PathOptions) -> ClusterSteps {
+        let (size_x, _, size_z) = self.walkable_grid.dim();
+        let xs = x * CLUSTER_CELLS..((x + 1) * CLUSTER_CELLS).min(size_x);
+        let zs = z * CLUSTER_CELLS..((z + 1) * CLUSTER_CELLS).min(size_z);
+
+        let cells = xs
+            .flat_map(|x| zs.clone().map(move |z| (x, z)))
+            .flat_map(|(x, z)| self.walkable_grid.column(x, z).map(move |y| (x, y, z)))
+            .collect::<Vec<_>>();
+        let index = cells
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| (*cell, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut steps = vec![vec![]; cells.len()];
+        let mut crossings = vec![];
+        for (i, cell) in cells.iter().enumerate() {
+            for (next, cost) in self.step_successors(cell, options) {
+                match index.get(&next) {
+                    Some(j) => steps[i].push((*j, cost)),
+                    None => crossings.push((*cell, next, cost)),
+                }
+            }
+        }
+
+        ClusterSteps {
+            index,
+            steps,
+            crossings,
+        }
+    }
+
+    // Cost from the cell to the portals of its cluster it can reach without leaving the cluster, a
+    // cell that is a portal itself reaches it for free
+    fn cluster_costs(
+        &self,
+        start_cell: &(usize, usize, usize),
+        options: &PathOptions,
+    ) -> Vec<(u32, i32)> {
+        let graph = &self.portals;
+        let cluster = graph.cluster_of(start_cell);
+        let targets = graph
+            .cluster_portals(cluster)
+            .map(|portal| (graph.portals[portal], portal as u32))
+            .collect::<HashMap<_, _>>();
+
+        // Stops once every portal was reached, cells can come up more than once
+        let remaining = RefCell::new(targets.keys().copied().collect::<HashSet<_>>());
+        let (reached, _) = dijkstra_partial(
+            start_cell,
+            |cell| {
+                let mut successors = self.step_successors(cell, options);
+                successors.retain(|(next, _)| graph.cluster_of(next) == cluster);
+                successors
+            },
+            |cell| {
+                let mut remaining = remaining.borrow_mut();
+                remaining.remove(cell);
+                remaining.is_empty()
+            },
+        );
+
+        targets
+            .into_iter()
+            .filter_map(|(cell, portal)| {
+                if cell == *start_cell {
+                    return Some((portal, 0));
+                }
+                reached.get(&cell).map(|(_, cost)| (portal, *cost))
+            })
+            .collect()
+    }
+
+    fn filter_objects(raw: &RawMap) -> Result<FilteredObjects, Error> {
+        let mut map_bounds = AABB::zero();
+        // Border objects are usually very high walls, so the y range of the grid only uses the other objects
+        let mut geometry_y: Option<(f32, f32)> = None;
+
+        // estimate the number of objects to avoid frequent allocation
+        let mut objects = Vec::<AABB>::with_capacity(raw.objects.len() / 3);
+        let mut rotated = Vec::<RotatedBox>::new();
+        let mut ramps = Vec::<Ramp>::new();
+        let mut ladders = Vec::<AABB>::new();
+        let mut teleporters = Vec::<Teleporter>::new();
+        let mut hazards = Vec::<AABB>::new();
+        let mut degenerate = DegenerateObjects::default();
+
+        let sizes = raw.get_size_groups();
+        for object in raw.objects.iter() {
+            if object.id == Some(TELEPORTER_ID) {
+                match Self::teleporter(raw, object, &sizes) {
+                    Some(teleporter) => teleporters.push(teleporter),
+                    None => debug!("Skipped a teleporter of {} without a valid link", raw.name),
+                }
+                continue;
+            }
+
+            if object.id.is_some_and(|id| HAZARD_OBJECT_IDS.contains(&id)) {
+                match Self::hazard(object, &sizes) {
+                    Some(bounds) => hazards.push(bounds),
+                    None => degenerate.skipped += 1,
+                }
+                continue;
+            }
+
+            // filter out everything that is not collidable
+            if object.not_collidable.is_some() {
+                continue;
+            }
+
+            if let Some(id) = object.id {
+                if EXCLUDE_OBJECT_IDS.contains(&id) {
+                    continue;
+                }
+            }
+
+            if let Some(size_index) = object.size_index {
+                // The last size group can be incomplete, objects pointing at it have no size
+                let size = match sizes.get(size_index) {
+                    Some(size) => size,
+                    None => {
+                        degenerate.skipped += 1;
+                        continue;
+                    }
+                };
+
+                let finite = object.position.iter().all(|v| v.is_finite())
+                    && [size.x, size.y, size.z].iter().all(|v| v.is_finite())
+                    && object
+                        .rotation
+                        .is_none_or(|rotation| rotation.iter().all(|a| a.is_finite()));
+                if !finite || size.x == 0.0 || size.y == 0.0 || size.z == 0.0 {
+                    degenerate.skipped += 1;
+                    continue;
+                }
+
+                let mut bounds = AABB {
+                    min_x: object.position[0] - size.x / 2.0,
+                    min_y: object.position[1],
+                    min_z: object.position[2] - size.z / 2.0,
+                    max_x: object.position[0] + size.x / 2.0,
+                    max_y: object.position[1] + size.y,
+                    max_z: object.position[2] + size.z / 2.0,
+                };
+                if bounds.normalize() {
+                    degenerate.normalized += 1;
+                }
+
+                let mut rotated_box = None;
+                if let Some(rotation) = object.rotation.filter(|r| r.iter().any(|a| *a != 0.0)) {
+                    let pivot = Vec3 {
+                        x: object.position[0],
+                        y: object.position[1],
+                        z: object.position[2],
+                    };
+                    let (rotated, aligned) = RotatedBox::new(&bounds, &pivot, rotation);
+                    bounds = rotated.bounds;
+                    // Ramps, ladders and borders keep the bounds of the rotated box
+                    if !aligned && object.border.is_none() && !matches!(object.id, Some(3 | 9)) {
+                        rotated_box = Some(rotated);
+                    }
+                }
+
+                map_bounds.extend_by(&bounds);
+
+                // extend the height of the object if it is a border object
+                if object.border.is_some() {
+                    bounds.max_y = MAX_MAP_BOUNDS.max_y;
+                } else {
+                    geometry_y = Some(match geometry_y {
+                        Some((min_y, max_y)) => (min_y.min(bounds.min_y), max_y.max(bounds.max_y)),
+                        None => (bounds.min_y, bounds.max_y),
+                    });
+                }
+
+                if let Some(id) = object.id {
+                    if id == 9 {
+                        // is ramp
+                        ramps.push(Ramp {
+                            bounds,
+                            direction: object.direction.unwrap_or(0),
+                        });
+                        continue;
+                    } else if id == 3 {
+                        // is ladder
+                        ladders.push(bounds);
+                        continue;
+                    }
+                }
+
+                match rotated_box {
+                    Some(rotated_box) => rotated.push(rotated_box),
+                    None => objects.push(bounds),
+                }
+            }
+        }
+
+        map_bounds.limit_by(&MAX_MAP_BOUNDS);
+        let ceiling = map_bounds.max_y;
+
+        if let Some((min_y, max_y)) = geometry_y {
+            map_bounds.min_y = map_bounds.min_y.max(min_y.min(0.0));
+            map_bounds.max_y = map_bounds.max_y.min(max_y.max(0.0) + GRID_Y_MARGIN);
+        }
+
+        if !map_bounds.is_finite() {
+            return Err(Error::MapParse(format!(
+                "Bounds of {} are not finite: {:?}",
+                raw.name, map_bounds
+            )));
+        }
+        // Without any object the grid would have no cells
+        if map_bounds.max_x <= map_bounds.min_x
+            || map_bounds.max_y <= map_bounds.min_y
+            || map_bounds.max_z <= map_bounds.min_z
+        {
+            return Err(Error::MapParse(format!(
+                "{} has no objects with a valid size",
+                raw.name
+            )));
+        }
+
+        Ok((
+            map_bounds,
+            ceiling,
+            objects,
+            rotated,
+            ramps,
+            ladders,
+            teleporters,
+            hazards,
+            degenerate,
+        ))
+    }
+
+    // Bounds of the hazard, rotated ones cover the bounds of the rotated box
+    fn hazard(object: &RawMapObject, sizes: &[Vec3]) -> Option<AABB> {
+        let size = sizes.get(object.size_index?)?;
+        let mut bounds = AABB {
+            min_x: object.position[0] - size.x / 2.0,
+            min_y: object.position[1],
+            min_z: object.position[2] - size.z / 2.0,
+            max_x: object.position[0] + size.x / 2.0,
+            max_y: object.position[1] + size.y,
+            max_z: object.position[2] + size.z / 2.0,
+        };
+        bounds.normalize();
+        if let Some(rotation) = object.rotation.filter(|r| r.iter().all(|a| a.is_finite())) {
+            let pivot = Vec3 {
+                x: object.position[0],
+                y: object.position[1],
+                z: object.position[2],
+            };
+            bounds = RotatedBox::new(&bounds, &pivot, rotation).0.bounds;
+        }
+        bounds.is_finite().then_some(bounds)
+    }
+
+    // The box of the teleporter is the entry, the player comes out on top of the linked object
+    fn teleporter(raw: &RawMap, object: &RawMapObject, sizes: &[Vec3]) -> Option<Teleporter> {
+        let size = sizes.get(object.size_index?)?;
+        let exit_object = raw.objects.get(object.teleport_link?)?;
+        let exit_height = exit_object
+            .size_index
+            .and_then(|i| sizes.get(i))
+            .map_or(0.0, |size| size.y);
+
+        let mut entry = AABB {
+            min_x: object.position[0] - size.x / 2.0,
+            min_y: object.position[1],
+            min_z: object.position[2] - size.z / 2.0,
+            max_x: object.position[0] + size.x / 2.0,
+            max_y: object.position[1] + size.y,
+            max_z: object.position[2] + size.z / 2.0,
+        };
+        entry.normalize();
+        let exit = Vec3 {
+            x: exit_object.position[0],
+            y: exit_object.position[1] + exit_height,
+            z: exit_object.position[2],
+        };
+
+        (entry.is_finite() && exit.x.is_finite() && exit.y.is_finite() && exit.z.is_finite())
+            .then_some(Teleporter { entry, exit })
+    }
+
+    fn generate_object_chunks<'a>(
+        map_bounds: &AABB,
+        objects: &'a [AABB],
+        rotated: &'a [RotatedBox],
+        ramps: &'a [Ramp],
+        ladders: &'a [AABB],
+    ) -> Array2<Chunk<'a>> {
+        let chunk_shape = (
+            ((map_bounds.max_x - map_bounds.min_x) / CHUNK_SIZE).ceil() as usize,
+            ((map_bounds.max_z - map_bounds.min_z) / CHUNK_SIZE).ceil() as usize,
+        );
+
+        Array2::<Chunk<'a>>::from_shape_fn(chunk_shape, |(x, z)| {
+            let chunk_bounds = AABB {
+                min_x: map_bounds.min_x + x as f32 * CHUNK_SIZE,
+                min_y: MAX_MAP_BOUNDS.min_y,
+                min_z: map_bounds.min_z + z as f32 * CHUNK_SIZE,
+                max_x: map_bounds.min_x + x as f32 * CHUNK_SIZE + CHUNK_SIZE,
+                max_y: MAX_MAP_BOUNDS.max_y,
+                max_z: map_bounds.min_z + z as f32 * CHUNK_SIZE + CHUNK_SIZE,
+            };
+
+            let mut chunk_objects =
+                Vec::<&'a AABB>::with_capacity(objects.len() / (chunk_shape.0 * chunk_shape.1));
+            let mut chunk_rotated = Vec::<&'a RotatedBox>::new();
+            let mut chunk_ramps = Vec::<&'a Ramp>::new();
+            let mut chunk_ladders = Vec::<&'a AABB>::new();
+
+            for object in objects.iter() {
+                if chunk_bounds.intersects(object) {
+                    chunk_objects.push(object);
+                }
+            }
+
+            for rotated in rotated.iter() {
+                if chunk_bounds.intersects(&rotated.bounds) {
+                    chunk_rotated.push(rotated);
+                }
+            }
+
+            for ramp in ramps.iter() {
+                if chunk_bounds.intersects(&ramp.bounds) {
+                    chunk_ramps.push(ramp);
+                }
+            }
+
+            for ladder in ladders.iter() {
+                if chunk_bounds.intersects(ladder) {
+                    chunk_ladders.push(ladder);
+                }
+            }
+
+            Chunk {
+                bounds: chunk_bounds,
+                objects: chunk_objects,
+                rotated: chunk_rotated,
+                ramps: chunk_ramps,
+                ladders: chunk_ladders,
+            }
+        })
+    }
+
+    // Every chunk fills its own block of the grid. An object is only tested against the cells
+    // around it instead of every cell against every object of the chunk.
+    fn generate_grid<'a>(map_bounds: &AABB, chunks: &Array2<Chunk<'a>>) -> Array3<u8> {
+        let grid_shape = (
+            ((map_bounds.max_x - map_bounds.min_x) / CELL_SIZE).ceil() as usize,
+            ((map_bounds.max_y - map_bounds.min_y) / CELL_SIZE).ceil() as usize,
+            ((map_bounds.max_z - map_bounds.min_z) / CELL_SIZE).ceil() as usize,
+        );
+
+        // Cells past the last chunk because of rounding belong to it
+        let (chunks_x, chunks_z) = chunks.dim();
+        let chunk_cells = |chunk: usize, chunk_count: usize, cells: usize| {
+            let start = (chunk * CHUNK_CELLS).min(cells);
+            let end = if chunk + 1 == chunk_count {
+                cells
+            } else {
+                ((chunk + 1) * CHUNK_CELLS).min(cells)
+            };
+            start..end
+        };
+
+        let blocks = chunks
+            .indexed_iter()
+            .map(|((x, z), chunk)| {
+                (
+                    chunk_cells(x, chunks_x, grid_shape.0),
+                    chunk_cells(z, chunks_z, grid_shape.2),
+                    chunk,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        #[cfg(feature = "parallel")]
+        let blocks = blocks.into_par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let blocks = blocks.into_iter();
+
+        let filled = blocks
+            .map(|(xs, zs, chunk)| {
+                let block = Self::fill_chunk(map_bounds, chunk, &xs, grid_shape.1, &zs);
+                (xs, zs, block)
+            })
+            .collect::<Vec<_>>();
+
+        let mut grid = Array3::<u8>::zeros(grid_shape);
+        for (xs, zs, block) in filled {
+            grid.slice_mut(s![xs, .., zs]).assign(&block);
+        }
+        grid
+    }
+
+    // Cells of the chunk in the given ranges. Objects are written from the lowest to the highest
+    // priority: ramps, rotated objects, objects and ladders, earlier ramps win over later ones.
+    fn fill_chunk(
+        map_bounds: &AABB,
+        chunk: &Chunk,
+        xs: &Range<usize>,
+        size_y: usize,
+        zs: &Range<usize>,
+    ) -> Array3<u8> {
+        let mut block = Array3::<u8>::zeros((xs.len(), size_y, zs.len()));
+        let ys = 0..size_y;
+
+        let cell_bounds = |x: usize, y: usize, z: usize| AABB {
+            min_x: map_bounds.min_x + x as f32 * CELL_SIZE,
+            min_y: map_bounds.min_y + y as f32 * CELL_SIZE,
+            min_z: map_bounds.min_z + z as f32 * CELL_SIZE,
+            max_x: map_bounds.min_x + x as f32 * CELL_SIZE + CELL_SIZE,
+            max_y: map_bounds.min_y + y as f32 * CELL_SIZE + CELL_SIZE,
+            max_z: map_bounds.min_z + z as f32 * CELL_SIZE + CELL_SIZE,
+        };
+        // Cells that can touch the bounds, one more on each side for rounding. The exact test is
+        // done per cell.
+        let range = |min: f32, max: f32, origin: f32, cells: &Range<usize>| {
+            let first = (((min - origin) / CELL_SIZE).floor() - 1.0).max(cells.start as f32);
+            let last = (((max - origin) / CELL_SIZE).ceil() + 1.0).min(cells.end as f32);
+            first as usize..(last as usize).max(first as usize)
+        };
+        let mut fill = |bounds: &AABB, value: &mut dyn FnMut(&AABB, &mut u8)| {
+            for x in range(bounds.min_x, bounds.max_x, map_bounds.min_x, xs) {
+                for y in range(bounds.min_y, bounds.max_y, map_bounds.min_y, &ys) {
+                    for z in range(bounds.min_z, bounds.max_z, map_bounds.min_z, zs) {
+                        let cell = cell_bounds(x, y, z);
+                        if cell.intersects(bounds) {
+                            value(&cell, &mut block[(x - xs.start, y, z - zs.start)]);
+                        }
+                    }
+                }
+            }
+        };
+
+        for ramp in chunk.ramps.iter().rev() {
+            fill(&ramp.bounds, &mut |_, value| *value = 2 + ramp.direction);
+        }
+
+        // Cells the rotated box only touches block the player, but only cells whose center column
+        // goes through the box are a floor
+        for rotated in chunk.rotated.iter() {
+            fill(&rotated.bounds, &mut |cell, value| {
+                if !rotated.overlaps(cell) {
+                    return;
+                }
+                if rotated.crosses_column(
+                    cell.min_x + CELL_SIZE / 2.0,
+                    cell.min_z + CELL_SIZE / 2.0,
+                    cell.min_y,
+                    cell.max_y,
+                ) {
+                    *value = 1;
+                } else if *value != 1 {
+                    *value = PARTIAL_CELL;
+                }
+            });
+        }
+
+        for object in chunk.objects.iter() {
+            fill(object, &mut |_, value| *value = 1);
+        }
+
+        for ladder in chunk.ladders.iter() {
+            fill(ladder, &mut |_, value| *value = 6);
+        }
+
+        block
+    }
 
     fn generate_walkable_grid(
+        name: &str,
         grid: &Array3<u8>,
         map_bounds: &AABB,
         spawns: &[Vec3],
@@ -326,357 +1813,3086 @@ impl Map {
         let shape = grid.shape();
         let grid_size = (shape[0], shape[1], shape[2]);
 
-        let mut walkable_grid = Array3::<u8>::zeros(grid_size);
+        let mut walkable_grid = Array3::<u8>::zeros(grid_size);
+
+        // start with all spawn cells as we expect the player to be able to stand there
+        let mut spawn_cells = vec![];
+        let mut ungrounded = vec![];
+        for spawn in spawns {
+            match Self::standing_cell(grid, map_bounds, spawn) {
+                Some(cell) => spawn_cells.push(cell),
+                None => ungrounded.push(spawn),
+            }
+        }
+        if !ungrounded.is_empty() {
+            warn!(
+                "{} has {} spawns without a floor below them: {:?}",
+                name,
+                ungrounded.len(),
+                ungrounded
+            );
+        }
+        Self::fill_walkable(grid, &mut walkable_grid, spawn_cells)?;
+
+        Ok(walkable_grid)
+    }
+
+    // Cell of a position the player is known to stand at. Positions inside geometry are moved up by a
+    // cell and positions in the air drop down onto the floor below them, None if there is none.
+    fn standing_cell(
+        grid: &Array3<u8>,
+        map_bounds: &AABB,
+        position: &Vec3,
+    ) -> Option<(usize, usize, usize)> {
+        let mut cell = position_to_cell_clamped(map_bounds, position);
+        if grid[cell] != 0 {
+            cell.1 += 1;
+            if cell.1 >= grid.dim().1 {
+                return None;
+            }
+        }
+        loop {
+            if cell.1 == 0 {
+                return None;
+            }
+            if !matches!(grid[(cell.0, cell.1 - 1, cell.2)], 0 | PARTIAL_CELL) {
+                return Some(cell);
+            }
+            cell.1 -= 1;
+        }
+    }
+
+    // Plain walkable cells inside hazards and standing on them become hazardous, ladders and narrow or
+    // low cells keep their value. Returns the number of hazardous cells.
+    fn mark_hazards(walkable_grid: &mut Array3<u8>, map_bounds: &AABB, hazards: &[AABB]) -> usize {
+        let mut marked = 0;
+        for hazard in hazards {
+            if !hazard.intersects(map_bounds) {
+                continue;
+            }
+            let min = position_to_cell_clamped(
+                map_bounds,
+                &Vec3 {
+                    x: hazard.min_x,
+                    y: hazard.min_y,
+                    z: hazard.min_z,
+                },
+            );
+            let max = position_to_cell_clamped(
+                map_bounds,
+                &Vec3 {
+                    x: hazard.max_x,
+                    // Walkable cells are the ones above the floor
+                    y: hazard.max_y + CELL_SIZE,
+                    z: hazard.max_z,
+                },
+            );
+            for value in walkable_grid
+                .slice_mut(s![min.0..=max.0, min.1..=max.1, min.2..=max.2])
+                .iter_mut()
+                .filter(|value| **value == 1)
+            {
+                *value = HAZARD_CELL;
+                marked += 1;
+            }
+        }
+
+        debug!("Marked {} cells as hazardous", marked);
+        marked
+    }
+
+    // Every walkable cell inside the entry of a teleporter leads to the cell at its exit
+    fn link_teleporters(
+        grid: &Array3<u8>,
+        walkable_grid: &Array3<u8>,
+        map_bounds: &AABB,
+        teleporters: &[Teleporter],
+    ) -> TeleportEdges {
+        let mut teleports = HashMap::new();
+        for teleporter in teleporters {
+            let exit = match Self::standing_cell(grid, map_bounds, &teleporter.exit) {
+                Some(exit) if walkable_grid[exit] != 0 => exit,
+                _ => continue,
+            };
+
+            let min = position_to_cell_clamped(
+                map_bounds,
+                &Vec3 {
+                    x: teleporter.entry.min_x,
+                    y: teleporter.entry.min_y,
+                    z: teleporter.entry.min_z,
+                },
+            );
+            let max = position_to_cell_clamped(
+                map_bounds,
+                &Vec3 {
+                    x: teleporter.entry.max_x,
+                    y: teleporter.entry.max_y,
+                    z: teleporter.entry.max_z,
+                },
+            );
+            for x in min.0..=max.0 {
+                for y in min.1..=max.1 {
+                    for z in min.2..=max.2 {
+                        if walkable_grid[(x, y, z)] != 0 && (x, y, z) != exit {
+                            teleports.insert((x, y, z), exit);
+                        }
+                    }
+                }
+            }
+        }
+
+        debug!("Linked {} cells to teleporter exits", teleports.len());
+        teleports
+    }
+
+    // Flood fill of the walkable cells reachable from the start cells, returns the newly found cells
+    fn fill_walkable(
+        grid: &Array3<u8>,
+        walkable_grid: &mut Array3<u8>,
+        start_cells: Vec<(usize, usize, usize)>,
+    ) -> Result<Vec<(usize, usize, usize)>, Error> {
+        let shape = grid.shape();
+        let grid_size = (shape[0], shape[1], shape[2]);
+
+        let mut found = vec![];
+        let mut cells_to_see = VecDeque::from(start_cells);
+
+        // Look at the surrounding cells of the cells in the queue and check if they are walkable.
+        // If they are, add them to the queue too. If the queue is empty every walkable cell has been found.
+        while let Some(cell) = cells_to_see.pop_front() {
+            if cell.0 >= grid_size.0 || cell.1 >= grid_size.1 || cell.2 >= grid_size.2 {
+                return Err(Error::MapParse("Cell index out of bounds".to_owned()));
+            }
+
+            if walkable_grid[cell] != 0 {
+                continue;
+            }
+
+            // Differentiate between ladder, low and other cells for pathfinding
+            walkable_grid[cell] = if grid[cell] == 6 {
+                2
+            } else if Self::is_cell_crouchable(&cell, grid) {
+                CROUCH_CELL
+            } else {
+                1
+            };
+            found.push(cell);
+
+            // For air cells, only consider the 4 horizontal neighbours on the same level and y +- 1.
+            // For ramp and ladder cells, check all neighbours including edges.
+            if grid[cell] == 0 {
+                for neighbour in Self::horizontal_neighbours(&cell, &grid_size, false).iter() {
+                    if Self::is_cell_passable(neighbour, grid) {
+                        cells_to_see.push_back(*neighbour);
+                    } else if Self::is_cell_passable(
+                        &(neighbour.0, neighbour.1 + 1, neighbour.2),
+                        grid,
+                    ) {
+                        cells_to_see.push_back((neighbour.0, neighbour.1 + 1, neighbour.2));
+                    } else if neighbour.1 > 0
+                        && Self::is_cell_passable(
+                            &(neighbour.0, neighbour.1 - 1, neighbour.2),
+                            grid,
+                        )
+                    {
+                        cells_to_see.push_back((neighbour.0, neighbour.1 - 1, neighbour.2));
+                    }
+                }
+            } else {
+                for neighbour in Self::neighbours(&cell, &grid_size, true).iter() {
+                    if Self::is_cell_passable(neighbour, grid) {
+                        cells_to_see.push_back(*neighbour);
+                    }
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    // Look for short runs of cells that aren't walkable between two walkable cells on the same level and check them
+    // again at half the cell size. Runs the player fits through are marked as refined cells and the
+    // flood fill continues behind them, until no new passages are found.
+    fn refine_narrow_passages(
+        grid: &Array3<u8>,
+        walkable_grid: &mut Array3<u8>,
+        map_bounds: &AABB,
+        chunks: &Array2<Chunk>,
+        player_radius: f32,
+    ) -> Result<HashMap<(usize, usize, usize), RefinedCell>, Error> {
+        let shape = grid.shape();
+        let grid_size = (shape[0], shape[1], shape[2]);
+
+        let mut refined = HashMap::new();
+        let mut rejected = HashSet::new();
+        let mut frontier = walkable_grid
+            .indexed_iter()
+            .filter(|(_, cell)| **cell == 1)
+            .map(|(cell, _)| cell)
+            .collect::<Vec<_>>();
+
+        while !frontier.is_empty() {
+            let mut start_cells = vec![];
+
+            for cell in frontier.iter() {
+                for (dx, dz) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    let step = |n: usize| {
+                        let x = cell.0 as isize + dx * n as isize;
+                        let z = cell.2 as isize + dz * n as isize;
+                        (x >= 0 && x < grid_size.0 as isize && z >= 0 && z < grid_size.2 as isize)
+                            .then_some((x as usize, cell.1, z as usize))
+                    };
+
+                    for gap in 1..=MAX_REFINED_GAP {
+                        let (behind, gap_cells) = match (
+                            step(gap + 1),
+                            (1..=gap).map(step).collect::<Option<Vec<_>>>(),
+                        ) {
+                            (Some(behind), Some(gap_cells)) => (behind, gap_cells),
+                            _ => break,
+                        };
+                        if walkable_grid[gap_cells[gap - 1]] != 0 {
+                            break;
+                        }
+                        if walkable_grid[behind] != 1 && !Self::is_cell_walkable(&behind, grid) {
+                            continue;
+                        }
+
+                        let along_x = dx != 0;
+                        let passable = gap_cells.iter().all(|gap_cell| {
+                            if rejected.contains(gap_cell) {
+                                return false;
+                            }
+                            let refined_cell = refined.get(gap_cell).copied().or_else(|| {
+                                Self::refine_cell(gap_cell, map_bounds, chunks, player_radius)
+                            });
+                            match refined_cell {
+                                Some(refined_cell) => {
+                                    refined.insert(*gap_cell, refined_cell);
+                                    if along_x {
+                                        refined_cell.along_x
+                                    } else {
+                                        refined_cell.along_z
+                                    }
+                                }
+                                None => {
+                                    rejected.insert(*gap_cell);
+                                    false
+                                }
+                            }
+                        });
+
+                        if passable {
+                            for gap_cell in gap_cells {
+                                walkable_grid[gap_cell] = REFINED_CELL;
+                            }
+                            if walkable_grid[behind] == 0 {
+                                start_cells.push(behind);
+                            }
+                        }
+                        break;
+                    }
+                }
+            }
+
+            frontier = Self::fill_walkable(grid, walkable_grid, start_cells)?;
+        }
+
+        // Cells that were checked but aren't part of a passage stay blocked
+        refined.retain(|cell, _| walkable_grid[*cell] == REFINED_CELL);
+        debug!(
+            "Refined {} cells of narrow passages, {} were checked",
+            refined.len(),
+            refined.len() + rejected.len()
+        );
+
+        Ok(refined)
+    }
+
+    // Look for gaps next to walkable cells that can be jumped across to another walkable cell at the
+    // same or a lower height. Cells behind a gap that weren't walkable yet are filled from, until no
+    // new jumps are found.
+    fn find_jumps(
+        grid: &Array3<u8>,
+        walkable_grid: &mut Array3<u8>,
+        max_gap: usize,
+    ) -> Result<JumpEdges, Error> {
+        let mut jumps = HashMap::<_, Vec<_>>::new();
+        let mut frontier = walkable_grid
+            .indexed_iter()
+            .filter(|(_, cell)| **cell == 1)
+            .map(|(cell, _)| cell)
+            .collect::<Vec<_>>();
+
+        while !frontier.is_empty() {
+            let mut start_cells = vec![];
+
+            // Only plain cells, no jumping off ladders or while crouching
+            for cell in frontier.iter().filter(|cell| walkable_grid[**cell] == 1) {
+                for direction in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    if let Some(landing) =
+                        Self::jump_landing(cell, direction, grid, walkable_grid, max_gap)
+                    {
+                        jumps.entry(*cell).or_default().push(landing);
+                        if walkable_grid[landing] == 0 {
+                            start_cells.push(landing);
+                        }
+                    }
+                }
+            }
+
+            frontier = Self::fill_walkable(grid, walkable_grid, start_cells)?;
+        }
+
+        debug!(
+            "Found {} jumps",
+            jumps.values().map(Vec::len).sum::<usize>()
+        );
+        Ok(jumps)
+    }
+
+    // Closest cell behind a gap in the direction that a jump from the cell lands on. The cells in
+    // between have to be empty from the start up to above the head, so the jump doesn't cut through
+    // geometry. Cells next to a ledge aren't walkable, so besides the columns
+    // without a floor that make the gap, there can be one column with a floor on either side.
+    fn jump_landing(
+        cell: &(usize, usize, usize),
+        (dx, dz): (isize, isize),
+        grid: &Array3<u8>,
+        walkable_grid: &Array3<u8>,
+        max_gap: usize,
+    ) -> Option<(usize, usize, usize)> {
+        let (size_x, size_y, size_z) = grid.dim();
+        let step = |n: usize| {
+            let x = cell.0 as isize + dx * n as isize;
+            let z = cell.2 as isize + dz * n as isize;
+            (x >= 0 && x < size_x as isize && z >= 0 && z < size_z as isize)
+                .then_some((x as usize, cell.1, z as usize))
+        };
+
+        let first = step(1)?;
+        if walkable_grid[first] != 0 {
+            return None;
+        }
+
+        let top = (cell.1 + PLAYER_HEIGHT).min(size_y - 1);
+        for n in 2..=max_gap + 3 {
+            let over = step(n)?;
+            for drop in 0..=MAX_JUMP_DROP.min(cell.1 - 1) {
+                let landing = (over.0, cell.1 - drop, over.2);
+                let walkable = match walkable_grid[landing] {
+                    1 => true,
+                    0 => Self::is_cell_walkable(&landing, grid),
+                    _ => false,
+                };
+                if !walkable {
+                    continue;
+                }
+
+                let between = (1..n).filter_map(step).collect::<Vec<_>>();
+                let clear = between
+                    .iter()
+                    .all(|c| (cell.1..=top).all(|y| grid[(c.0, y, c.2)] == 0));
+                let holes = between
+                    .iter()
+                    .filter(|c| {
+                        grid[(c.0, cell.1 - 1, c.2)] == 0 && grid[(c.0, landing.1 - 1, c.2)] == 0
+                    })
+                    .count();
+                return (clear && holes > 0 && holes <= max_gap).then_some(landing);
+            }
+        }
+
+        None
+    }
+
+    // Split the cell into 2x2 columns and check which of them have room for the player and ground
+    // below. None if the player can't cross the cell along either axis.
+    fn refine_cell(
+        cell: &(usize, usize, usize),
+        map_bounds: &AABB,
+        chunks: &Array2<Chunk>,
+        player_radius: f32,
+    ) -> Option<RefinedCell> {
+        let blocked = |bounds: &AABB, floor: bool| {
+            chunks
+                .iter()
+                .filter(|chunk| chunk.bounds.intersects(bounds))
+                .any(|chunk| {
+                    chunk.objects.iter().any(|object| object.intersects(bounds))
+                        || chunk.rotated.iter().any(|rotated| rotated.overlaps(bounds))
+                        || (floor
+                            && chunk
+                                .ramps
+                                .iter()
+                                .any(|ramp| ramp.bounds.intersects(bounds)))
+                })
+        };
+
+        let min_x = map_bounds.min_x + cell.0 as f32 * CELL_SIZE;
+        let min_y = map_bounds.min_y + cell.1 as f32 * CELL_SIZE;
+        let min_z = map_bounds.min_z + cell.2 as f32 * CELL_SIZE;
+        // The player stands in the center of a column
+        let margin = (player_radius - FINE_CELL_SIZE / 2.0).max(0.0);
+
+        let mut free = [[false; 2]; 2];
+        for (i, column) in free.iter_mut().enumerate() {
+            for (j, free) in column.iter_mut().enumerate() {
+                let x = min_x + i as f32 * FINE_CELL_SIZE;
+                let z = min_z + j as f32 * FINE_CELL_SIZE;
+                let body = AABB {
+                    min_x: x - margin,
+                    min_y,
+                    min_z: z - margin,
+                    max_x: x + FINE_CELL_SIZE + margin,
+                    max_y: min_y + (PLAYER_HEIGHT - 1) as f32 * CELL_SIZE,
+                    max_z: z + FINE_CELL_SIZE + margin,
+                };
+                let ground = AABB {
+                    min_x: x,
+                    min_y: min_y - CELL_SIZE,
+                    min_z: z,
+                    max_x: x + FINE_CELL_SIZE,
+                    max_y: min_y,
+                    max_z: z + FINE_CELL_SIZE,
+                };
+                *free = !blocked(&body, false) && blocked(&ground, true);
+            }
+        }
+
+        // Crossing along x needs both columns of a row free, along z both of a column
+        let rows = (0..2).filter(|j| free[0][*j] && free[1][*j]);
+        let columns = (0..2).filter(|i| free[*i][0] && free[*i][1]);
+        let mut used = vec![];
+        used.extend(rows.clone().flat_map(|j| [(0, j), (1, j)]));
+        used.extend(columns.clone().flat_map(|i| [(i, 0), (i, 1)]));
+        if used.is_empty() {
+            return None;
+        }
+
+        let center = |i: usize, min: f32| min + i as f32 * FINE_CELL_SIZE + FINE_CELL_SIZE / 2.0;
+        Some(RefinedCell {
+            along_x: rows.count() > 0,
+            along_z: columns.count() > 0,
+            position: Vec3 {
+                x: used.iter().map(|(i, _)| center(*i, min_x)).sum::<f32>() / used.len() as f32,
+                y: cell_to_position(map_bounds, cell).y,
+                z: used.iter().map(|(_, j)| center(*j, min_z)).sum::<f32>() / used.len() as f32,
+            },
+        })
+    }
+
+    // Remove walkable cells that have a filled cell at body height within the player radius.
+    // The filled cells are dilated by the radius with a separable pass along x and then z,
+    // so the cost doesn't depend on the radius.
+    fn apply_clearance(grid: &Array3<u8>, walkable_grid: &mut Array3<u8>, player_radius: f32) {
+        // The player stands in the center of a cell so only the part of the radius that reaches
+        // past the cell counts
+        let radius = ((player_radius - CELL_SIZE / 2.0) / CELL_SIZE)
+            .ceil()
+            .max(0.0) as usize;
+        if radius == 0 {
+            return;
+        }
+
+        let shape = grid.shape();
+        let grid_size = (shape[0], shape[1], shape[2]);
+
+        let mut blocked = Array3::<u8>::from_shape_fn(grid_size, |(x, y, z)| {
+            let top = (y + PLAYER_HEIGHT - 1).min(grid_size.1);
+            (y..top).any(|y| matches!(grid[(x, y, z)], 1 | PARTIAL_CELL)) as u8
+        });
+
+        Self::dilate_axis(&mut blocked, Axis(0), radius);
+        Self::dilate_axis(&mut blocked, Axis(2), radius);
+
+        // Ladders are always against a wall and are kept as they are, low cells are next to a
+        // ceiling at body height anyway
+        walkable_grid.zip_mut_with(&blocked, |cell, blocked| {
+            if *cell == 1 && *blocked != 0 {
+                *cell = 0;
+            }
+        });
+    }
+
+    fn dilate_axis(mask: &mut Array3<u8>, axis: Axis, radius: usize) {
+        let mut prefix = Vec::<usize>::new();
+        for mut lane in mask.lanes_mut(axis) {
+            // Prefix sums of the lane give the number of set cells in any window in constant time
+            prefix.clear();
+            prefix.push(0);
+            for value in lane.iter() {
+                prefix.push(prefix[prefix.len() - 1] + (*value != 0) as usize);
+            }
+
+            let len = lane.len();
+            for (i, value) in lane.iter_mut().enumerate() {
+                let from = i.saturating_sub(radius);
+                let to = (i + radius + 1).min(len);
+                *value = (prefix[to] > prefix[from]) as u8;
+            }
+        }
+    }
+
+    fn count_walkable(walkable_grid: &Array3<u8>) -> usize {
+        walkable_grid.iter().filter(|cell| **cell != 0).count()
+    }
+
+    fn neighbours(
+        cell: &(usize, usize, usize),
+        grid_size: &(usize, usize, usize),
+        edges: bool,
+    ) -> Vec<(usize, usize, usize)> {
+        let cell = (cell.0 as isize, cell.1 as isize, cell.2 as isize);
+
+        let mut neighbours = vec![
+            (cell.0, cell.1 + 1, cell.2),
+            (cell.0 - 1, cell.1 + 1, cell.2),
+            (cell.0 + 1, cell.1 + 1, cell.2),
+            (cell.0, cell.1 + 1, cell.2 - 1),
+            (cell.0, cell.1 + 1, cell.2 + 1),
+            (cell.0 - 1, cell.1, cell.2),
+            (cell.0 + 1, cell.1, cell.2),
+            (cell.0, cell.1, cell.2 - 1),
+            (cell.0, cell.1, cell.2 + 1),
+            (cell.0, cell.1 - 1, cell.2),
+            (cell.0 - 1, cell.1 - 1, cell.2),
+            (cell.0 + 1, cell.1 - 1, cell.2),
+            (cell.0, cell.1 - 1, cell.2 - 1),
+            (cell.0, cell.1 - 1, cell.2 + 1),
+        ];
+
+        if edges {
+            neighbours.append(&mut vec![
+                (cell.0 - 1, cell.1 + 1, cell.2 - 1),
+                (cell.0 - 1, cell.1 + 1, cell.2 + 1),
+                (cell.0 + 1, cell.1 + 1, cell.2 - 1),
+                (cell.0 + 1, cell.1 + 1, cell.2 + 1),
+                (cell.0 - 1, cell.1, cell.2 - 1),
+                (cell.0 - 1, cell.1, cell.2 + 1),
+                (cell.0 + 1, cell.1, cell.2 - 1),
+                (cell.0 + 1, cell.1, cell.2 + 1),
+                (cell.0 - 1, cell.1 - 1, cell.2 - 1),
+                (cell.0 - 1, cell.1 - 1, cell.2 + 1),
+                (cell.0 + 1, cell.1 - 1, cell.2 - 1),
+                (cell.0 + 1, cell.1 - 1, cell.2 + 1),
+            ]);
+        }
+
+        // only include cells that are in bounds
+        neighbours
+            .iter()
+            .filter_map(|(x, y, z)| {
+                if (*x >= 0 && *x < grid_size.0 as isize)
+                    && (*y >= 0 && *y < grid_size.1 as isize)
+                    && (*z >= 0 && *z < grid_size.2 as isize)
+                {
+                    Some((*x as usize, *y as usize, *z as usize))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn horizontal_neighbours(
+        cell: &(usize, usize, usize),
+        grid_size: &(usize, usize, usize),
+        edges: bool,
+    ) -> Vec<(usize, usize, usize)> {
+        let cell = (cell.0 as isize, cell.1, cell.2 as isize);
+
+        let mut neighbours = vec![
+            (cell.0 - 1, cell.1, cell.2),
+            (cell.0 + 1, cell.1, cell.2),
+            (cell.0, cell.1, cell.2 - 1),
+            (cell.0, cell.1, cell.2 + 1),
+        ];
+
+        if edges {
+            neighbours.append(&mut vec![
+                (cell.0 - 1, cell.1, cell.2 - 1),
+                (cell.0 - 1, cell.1, cell.2 + 1),
+                (cell.0 + 1, cell.1, cell.2 - 1),
+                (cell.0 + 1, cell.1, cell.2 + 1),
+            ]);
+        }
+
+        // only include cells that are in bounds
+        neighbours
+            .iter()
+            .filter_map(|(x, y, z)| {
+                if *x >= 0 && *x < grid_size.0 as isize && *z >= 0 && *z < grid_size.2 as isize {
+                    Some((*x as usize, *y, *z as usize))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn is_cell_walkable(cell: &(usize, usize, usize), grid: &Array3<u8>) -> bool {
+        Self::has_room(cell, grid, PLAYER_HEIGHT)
+    }
+
+    // Only walkable while crouching
+    fn is_cell_crouchable(cell: &(usize, usize, usize), grid: &Array3<u8>) -> bool {
+        !Self::is_cell_walkable(cell, grid) && Self::has_room(cell, grid, CROUCH_HEIGHT)
+    }
+
+    fn is_cell_passable(cell: &(usize, usize, usize), grid: &Array3<u8>) -> bool {
+        Self::has_room(cell, grid, CROUCH_HEIGHT)
+    }
+
+    // Whether a player of the height in cells can stand in the cell
+    fn has_room(cell: &(usize, usize, usize), grid: &Array3<u8>, height: usize) -> bool {
+        let shape = grid.shape();
+        let grid_size = (shape[0], shape[1], shape[2]);
+
+        // check if the following checks are in bounds
+        if (cell.0 == 0 || cell.0 + 1 >= grid_size.0)
+            || (cell.1 < 2 || cell.1 + CROUCH_HEIGHT > grid_size.1)
+            || (cell.2 == 0 || cell.2 + 1 >= grid_size.2)
+        {
+            return false;
+        }
+
+        // check that cell and cells above are not filled, there is nothing above the grid
+        for i in 0..(height - 1).min(grid_size.1 - cell.1) {
+            if matches!(grid[(cell.0, cell.1 + i, cell.2)], 1 | PARTIAL_CELL) {
+                return false;
+            }
+        }
+
+        // check that cell below is a floor
+        if matches!(grid[(cell.0, cell.1 - 1, cell.2)], 0 | PARTIAL_CELL) {
+            return false;
+        }
+
+        if grid[*cell] == 0 {
+            // make sure that all filled blocks connected to the wrong site of a ramp are not walkable
+            for i in 0..2 {
+                if grid[(cell.0 - 1, cell.1 - i, cell.2)] == 3
+                    || grid[(cell.0 - 1, cell.1 - i, cell.2)] == 5
+                    || grid[(cell.0 + 1, cell.1 - i, cell.2)] == 3
+                    || grid[(cell.0 + 1, cell.1 - i, cell.2)] == 5
+                    || grid[(cell.0, cell.1 - i, cell.2 - 1)] == 2
+                    || grid[(cell.0, cell.1 - i, cell.2 - 1)] == 4
+                    || grid[(cell.0, cell.1 - i, cell.2 + 1)] == 2
+                    || grid[(cell.0, cell.1 - i, cell.2 + 1)] == 4
+                {
+                    return false;
+                }
+            }
+
+            for neighbour in Self::horizontal_neighbours(cell, &grid_size, false) {
+                // check that surrounding cells on the same height or one above or below are not air
+                if grid[(neighbour.0, neighbour.1 - 2, neighbour.2)] == 0
+                    && grid[(neighbour.0, neighbour.1 - 1, neighbour.2)] == 0
+                    && grid[neighbour] == 0
+                {
+                    return false;
+                }
+
+                // check that surrounding cells above are not filled
+                if matches!(
+                    grid[(neighbour.0, neighbour.1 + 1, neighbour.2)],
+                    1 | PARTIAL_CELL
+                ) {
+                    return false;
+                }
+            }
+        } else if grid[*cell] == 6
+            && (grid[(cell.0 - 1, cell.1, cell.2)] != 6 || grid[(cell.0 + 1, cell.1, cell.2)] != 6)
+            && (grid[(cell.0, cell.1, cell.2 - 1)] != 6 || grid[(cell.0, cell.1, cell.2 + 1)] != 6)
+        {
+            // check that ladders have other ladder cells on both sides in either the x or z direction
+            return false;
+        }
+
+        true
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn spawns(&self) -> Vec<Vec3> {
+        self.spawns.iter().map(|spawn| spawn.position).collect()
+    }
+
+    pub fn spawn_details(&self) -> &[Spawn] {
+        &self.spawns
+    }
+
+    // Spawns of the team and the ones of any team
+    pub fn spawns_for_team(&self, team: u8) -> Vec<Spawn> {
+        self.spawns
+            .iter()
+            .filter(|spawn| spawn.team.is_none_or(|spawn_team| spawn_team == team))
+            .copied()
+            .collect()
+    }
+
+    pub fn bounds(&self) -> AABB {
+        self.bounds
+    }
+
+    // Top of the map geometry including border objects, can be above the top of the grid
+    pub fn ceiling(&self) -> f32 {
+        self.ceiling
+    }
+
+    pub fn fingerprint(&self) -> String {
+        self.fingerprint.clone()
+    }
+
+    // Modes the map can be played in according to its config
+    pub fn modes(&self) -> &[GameMode] {
+        &self.modes
+    }
+
+    pub fn supports_mode(&self, mode: GameMode) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    pub fn build_options(&self) -> MapBuildOptions {
+        self.options
+    }
+
+    pub fn tuning(&self) -> &MapTuning {
+        &self.tuning
+    }
+
+    pub(crate) fn set_tuning(&mut self, tuning: MapTuning) {
+        self.tuning = tuning;
+    }
+
+    // Annotations and regions of the map this one replaces after a re-parse
+    pub(crate) fn carry_over(&mut self, previous: &Map) {
+        self.annotations = previous.annotations.clone();
+        if let Some(options) = previous.region_options {
+            self.label_regions(&options);
+        }
+    }
+
+    pub fn walkable_cell_count(&self) -> usize {
+        self.walkable_cells.len()
+    }
+
+    pub fn coverage(&self) -> MapCoverage {
+        self.coverage
+    }
+
+    // Cells along x, y and z
+    pub fn grid_size(&self) -> (usize, usize, usize) {
+        self.walkable_grid.dim()
+    }
+
+    // Center of the cell, see cell_position for the position a player walks to
+    pub fn cell_to_position(&self, cell: &(usize, usize, usize)) -> Vec3 {
+        cell_to_position(&self.bounds, cell)
+    }
+
+    // None for positions outside of the grid
+    pub fn position_to_cell(&self, position: &Vec3) -> Option<(usize, usize, usize)> {
+        if !self.bounds.contains(position) {
+            return None;
+        }
+        let cell = position_to_cell(&self.bounds, position);
+        self.walkable_grid.get(cell).map(|_| cell)
+    }
+
+    pub fn is_walkable_cell(&self, cell: &(usize, usize, usize)) -> bool {
+        self.walkable_grid
+            .get(*cell)
+            .is_some_and(|value| value != 0)
+    }
+
+    pub fn is_walkable_position(&self, position: &Vec3) -> bool {
+        self.position_to_cell(position)
+            .is_some_and(|cell| self.is_walkable_cell(&cell))
+    }
+
+    // Solid objects without ramps and ladders
+    pub fn objects(&self) -> &[AABB] {
+        &self.objects
+    }
+
+    pub fn ramps(&self) -> &[Ramp] {
+        &self.ramps
+    }
+
+    pub fn ladders(&self) -> &[AABB] {
+        &self.ladders
+    }
+
+    pub fn teleporters(&self) -> &[Teleporter] {
+        &self.teleporters
+    }
+
+    // First object, ramp or ladder the ray hits within the distance, tested against the objects
+    // themselves instead of the grid
+    pub fn raycast(&self, origin: &Vec3, direction: &Vec3, max_distance: f32) -> Option<RayHit> {
+        let length = (direction.x.powi(2) + direction.y.powi(2) + direction.z.powi(2)).sqrt();
+        if length == 0.0 || !length.is_finite() || max_distance.is_nan() || max_distance < 0.0 {
+            return None;
+        }
+        let direction = Vec3 {
+            x: direction.x / length,
+            y: direction.y / length,
+            z: direction.z / length,
+        };
+
+        self.object_chunks
+            .raycast(origin, &direction, max_distance, |kind, i| match kind {
+                HitKind::Object => self.objects[i as usize],
+                HitKind::Ramp => self.ramps[i as usize].bounds,
+                HitKind::Ladder => self.ladders[i as usize],
+            })
+    }
+
+    // Uniformly from all walkable cells
+    pub fn random_walkable_cell(&self, rng: &mut impl Rng) -> Option<(usize, usize, usize)> {
+        let index = *self.walkable_cells.choose(rng)? as usize;
+        let (_, size_y, size_z) = self.walkable_grid.dim();
+        Some((
+            index / (size_y * size_z),
+            index / size_z % size_y,
+            index % size_z,
+        ))
+    }
+
+    pub fn random_walkable_position(&self, rng: &mut impl Rng) -> Option<Vec3> {
+        self.random_walkable_cell(rng)
+            .map(|cell| self.cell_position(&cell))
+    }
+
+    // Position of a random walkable cell whose center is within the radius
+    pub fn random_walkable_near(
+        &self,
+        center: &Vec3,
+        radius: f32,
+        rng: &mut impl Rng,
+    ) -> Option<Vec3> {
+        if radius.is_nan() || radius < 0.0 {
+            return None;
+        }
+        let (size_x, size_y, size_z) = self.walkable_grid.dim();
+        let range = |value: f32, min: f32, size: usize| {
+            let low = ((value - radius - min) / CELL_SIZE).floor().max(0.0) as usize;
+            let high =
+                (((value + radius - min) / CELL_SIZE).floor().max(0.0) as usize).min(size - 1);
+            low..=high
+        };
+
+        // Only the cells in the cube around the sphere are looked at
+        let mut cells = vec![];
+        for x in range(center.x, self.bounds.min_x, size_x) {
+            for y in range(center.y, self.bounds.min_y, size_y) {
+                for z in range(center.z, self.bounds.min_z, size_z) {
+                    let cell = (x, y, z);
+                    if self.walkable_grid[cell] == 0 {
+                        continue;
+                    }
+                    let position = cell_to_position(&self.bounds, &cell);
+                    if (position.x - center.x).powi(2)
+                        + (position.y - center.y).powi(2)
+                        + (position.z - center.z).powi(2)
+                        <= radius.powi(2)
+                    {
+                        cells.push(cell);
+                    }
+                }
+            }
+        }
+
+        cells.choose(rng).map(|cell| self.cell_position(cell))
+    }
+
+    // Closest walkable cell by steps from the position where the threat can't see the head of a
+    // standing player, only cells within the radius of the position are expanded. Hazardous cells
+    // don't count as cover.
+    pub fn nearest_cover(&self, from: &Vec3, threat: &Vec3, max_radius: f32) -> Option<Vec3> {
+        if max_radius.is_nan() || max_radius < 0.0 {
+            return None;
+        }
+        let start = self.closest_walkable_cell(from)?;
+        let options = self.tuning.path.unwrap_or_default();
+        let head = (PLAYER_HEIGHT - 1) as f32 * CELL_SIZE;
+
+        let mut visited = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(cell) = queue.pop_front() {
+            let position = self.cell_position(&cell);
+            let eye = Vec3 {
+                y: position.y + head,
+                ..position
+            };
+            if self.walkable_grid[cell] != HAZARD_CELL && !self.line_of_sight(&eye, threat) {
+                return Some(position);
+            }
+
+            for (next, _) in self.step_successors(&cell, &options) {
+                let next_position = self.cell_position(&next);
+                let distance = ((next_position.x - from.x).powi(2)
+                    + (next_position.y - from.y).powi(2)
+                    + (next_position.z - from.z).powi(2))
+                .sqrt();
+                if distance <= max_radius && visited.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        None
+    }
+
+    // Whether nothing blocks the segment, the cells of the two positions don't count. Positions
+    // outside of the map can't be seen.
+    pub fn line_of_sight(&self, from: &Vec3, to: &Vec3) -> bool {
+        self.bounds.contains(from) && self.bounds.contains(to) && self.first_hit(from, to).is_none()
+    }
+
+    // Center of the first cell with geometry on the segment, None if it is clear or leaves the map
+    pub fn first_hit(&self, from: &Vec3, to: &Vec3) -> Option<Vec3> {
+        if !self.bounds.contains(from) || !self.bounds.contains(to) {
+            return None;
+        }
+
+        // Cells are walked in the order the segment enters them (3D DDA), in grid units
+        let shape = self.solid.shape;
+        let origin = [
+            (from.x - self.bounds.min_x) / CELL_SIZE,
+            (from.y - self.bounds.min_y) / CELL_SIZE,
+            (from.z - self.bounds.min_z) / CELL_SIZE,
+        ];
+        let target = [
+            (to.x - self.bounds.min_x) / CELL_SIZE,
+            (to.y - self.bounds.min_y) / CELL_SIZE,
+            (to.z - self.bounds.min_z) / CELL_SIZE,
+        ];
+        let max = [shape.0, shape.1, shape.2];
+        let to_cell = |p: [f32; 3]| {
+            [0, 1, 2].map(|axis| (p[axis].floor().max(0.0) as usize).min(max[axis] - 1))
+        };
+
+        let mut cell = to_cell(origin);
+        let end = to_cell(target);
+        let mut step = [0_isize; 3];
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            let dir = target[axis] - origin[axis];
+            if dir > 0.0 {
+                step[axis] = 1;
+                t_max[axis] = (cell[axis] as f32 + 1.0 - origin[axis]) / dir;
+                t_delta[axis] = 1.0 / dir;
+            } else if dir < 0.0 {
+                step[axis] = -1;
+                t_max[axis] = (cell[axis] as f32 - origin[axis]) / dir;
+                t_delta[axis] = -1.0 / dir;
+            }
+        }
+
+        while cell != end {
+            let axis = (0..3)
+                .min_by(|a, b| t_max[*a].total_cmp(&t_max[*b]))
+                .unwrap();
+            if t_max[axis] > 1.0 {
+                break;
+            }
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next as usize >= max[axis] {
+                break;
+            }
+            cell[axis] = next as usize;
+            t_max[axis] += t_delta[axis];
+
+            let cell = (cell[0], cell[1], cell[2]);
+            if cell != (end[0], end[1], end[2]) && self.solid.get(&cell) {
+                return Some(cell_to_position(&self.bounds, &cell));
+            }
+        }
+
+        None
+    }
+
+    // Position to walk to for a cell, cells of refined passages use the center of their free part
+    pub fn cell_position(&self, cell: &(usize, usize, usize)) -> Vec3 {
+        self.refined
+            .get(cell)
+            .map(|refined| refined.position)
+            .unwrap_or_else(|| cell_to_position(&self.bounds, cell))
+    }
+
+    // Steps into, out of or through refined cells have to stay on the level and go along an axis
+    // the refined cells can be crossed on
+    fn refined_step(&self, from: &(usize, usize, usize), to: &(usize, usize, usize)) -> bool {
+        if from.1 != to.1 || self.walkable_grid[*to] == 0 {
+            return false;
+        }
+
+        let along_x = from.0 != to.0;
+        [from, to].iter().all(|cell| match self.refined.get(*cell) {
+            Some(refined) if along_x => refined.along_x,
+            Some(refined) => refined.along_z,
+            None => true,
+        })
+    }
+
+    // Objects left out of the grid because of broken geometry
+    pub fn skipped_objects(&self) -> usize {
+        self.coverage.skipped_objects
+    }
+
+    pub fn annotations(&self) -> &Annotations {
+        &self.annotations
+    }
+
+    pub fn load_annotations(
+        &mut self,
+        source: &AnnotationSource,
+    ) -> Result<AnnotationReport, Error> {
+        self.apply_annotations(&source.parse()?)?
+            .ok_or_else(|| format!("No annotations found for map {}", self.name).into())
+    }
+
+    pub(crate) fn apply_annotations(
+        &mut self,
+        file: &AnnotationFile,
+    ) -> Result<Option<AnnotationReport>, Error> {
+        let annotations = if let Some(annotations) = file.maps.get(&self.name) {
+            annotations
+        } else {
+            return Ok(None);
+        };
+
+        let mut report = AnnotationReport::default();
+
+        if let Some(fingerprint) = &annotations.fingerprint {
+            if *fingerprint != self.fingerprint {
+                warn!(
+                    "Annotations for {} were made for fingerprint {} but the map has {}",
+                    self.name, fingerprint, self.fingerprint
+                );
+                report.stale = true;
+            }
+        }
+
+        // Every point should be somewhere the player can actually walk to
+        for (name, point) in annotations.points.iter() {
+            if self.closest_walkable_cell(point).is_none() {
+                warn!("Annotated point {} on {} is not walkable", name, self.name);
+                report.unwalkable_points.push(name.clone());
+            }
+        }
+
+        for (route, points) in annotations.routes.iter() {
+            for point in points {
+                if !annotations.points.contains_key(point) {
+                    warn!(
+                        "Route {} on {} references unknown point {}",
+                        route, self.name, point
+                    );
+                    report
+                        .unknown_route_points
+                        .push((route.clone(), point.clone()));
+                }
+            }
+        }
+
+        report.unwalkable_points.sort();
+        report.unknown_route_points.sort();
+
+        self.annotations = annotations.clone();
+        if let Some(regions) = self.regions.as_mut() {
+            regions.rename(&self.annotations.regions);
+        }
+
+        Ok(Some(report))
+    }
+
+    // Segment the walkable cells into named regions, replacing any previous labeling
+    pub fn label_regions(&mut self, options: &RegionOptions) -> &[Region] {
+        let mut regions = label_regions(&self.walkable_grid, &self.bounds, options);
+        regions.rename(&self.annotations.regions);
+        self.region_options = Some(*options);
+        debug!("Labeled {} regions on {}", regions.regions.len(), self.name);
+
+        &self.regions.insert(regions).regions
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        self.regions
+            .as_ref()
+            .map(|regions| regions.regions.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn region_at(&self, position: &Vec3) -> Option<&Region> {
+        let regions = self.regions.as_ref()?;
+        regions.region_of(&self.closest_walkable_cell(position)?)
+    }
+
+    pub fn topdown_projection(&self, max_dim: usize) -> Array2<u8> {
+        let grid_size = self.walkable_grid.dim();
+
+        // Downsample by an integer factor so that neither side exceeds max_dim
+        let max_dim = max_dim.max(1);
+        let factor = grid_size.0.max(grid_size.2).div_ceil(max_dim).max(1);
+
+        let projection_shape = (grid_size.0.div_ceil(factor), grid_size.2.div_ceil(factor));
+
+        // A column is blocked if the player can't stand anywhere in it.
+        // Max-pool the columns so that a blocked column is never lost when downsampling.
+        let mut projection = Array2::<u8>::zeros(projection_shape);
+        for x in 0..grid_size.0 {
+            for z in 0..grid_size.2 {
+                let blocked = (0..grid_size.1).all(|y| self.walkable_grid[(x, y, z)] == 0);
+                if blocked {
+                    projection[(x / factor, z / factor)] = 1;
+                }
+            }
+        }
+
+        projection
+    }
+
+    pub fn closest_walkable_cell(&self, position: &Vec3) -> Option<(usize, usize, usize)> {
+        self.closest_walkable_cell_within(position, CLOSEST_WALKABLE_RADIUS)
+            .map(|(cell, _)| cell)
+    }
+
+    // Walkable cell with the center closest to the position and the distance to it. Columns up to
+    // radius cells away horizontally are searched, ring by ring, within two player heights of the
+    // position. None for positions outside of the bounds.
+    pub fn closest_walkable_cell_within(
+        &self,
+        position: &Vec3,
+        radius: usize,
+    ) -> Option<((usize, usize, usize), f32)> {
+        // Positions above the grid (e.g. while jumping near the top) are moved down to the top of the grid
+        if !self.bounds.contains(&Vec3 {
+            y: position.y.min(self.bounds.max_y),
+            ..*position
+        }) {
+            return None;
+        }
+
+        let (size_x, size_y, size_z) = self.walkable_grid.dim();
+        let cell = position_to_cell_clamped(&self.bounds, position);
+        let ys =
+            cell.1.saturating_sub(PLAYER_HEIGHT * 2 - 1)..(cell.1 + PLAYER_HEIGHT * 2).min(size_y);
+
+        let mut closest: Option<((usize, usize, usize), f32)> = None;
+        for ring in 0..=radius {
+            // Cells of this ring are at least ring - 0.5 cells away from anywhere in the center cell
+            if closest.is_some_and(|(_, distance)| distance <= (ring as f32 - 0.5) * CELL_SIZE) {
+                break;
+            }
+
+            let ring = ring as isize;
+            for dx in -ring..=ring {
+                for dz in -ring..=ring {
+                    if dx.abs() != ring && dz.abs() != ring {
+                        continue;
+                    }
+                    let (x, z) = (cell.0 as isize + dx, cell.2 as isize + dz);
+                    if x < 0 || z < 0 || x >= size_x as isize || z >= size_z as isize {
+                        continue;
+                    }
+
+                    let (x, z) = (x as usize, z as usize);
+                    for y in self.walkable_grid.column(x, z).filter(|y| ys.contains(y)) {
+                        let center = cell_to_position(&self.bounds, &(x, y, z));
+                        let distance = ((center.x - position.x).powi(2)
+                            + (center.y - position.y).powi(2)
+                            + (center.z - position.z).powi(2))
+                        .sqrt();
+                        if closest.is_none_or(|(_, closest)| distance < closest) {
+                            closest = Some(((x, y, z), distance));
+                        }
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    // Connected component of the walkable cell, None for cells that aren't walkable
+    pub fn component_of(&self, cell: &(usize, usize, usize)) -> Option<u16> {
+        self.walkable_grid.component(*cell)
+    }
+
+    // Whether both cells are walkable and in the same component. Without it there is no path
+    // between them, with it there almost always is one, except past one way drops or teleports.
+    pub fn same_component(&self, a: &(usize, usize, usize), b: &(usize, usize, usize)) -> bool {
+        self.component_of(a).is_some() && !self.disconnected(a, b)
+    }
+
+    // Cheap check whether a path between the positions could exist, without searching for it
+    pub fn reachable(&self, start: &Vec3, end: &Vec3) -> bool {
+        match (
+            self.closest_walkable_cell(start),
+            self.closest_walkable_cell(end),
+        ) {
+            (Some(start_cell), Some(end_cell)) => self.same_component(&start_cell, &end_cell),
+            _ => false,
+        }
+    }
+
+    // Both cells are walkable but can't reach each other. The last id is shared by the components
+    // past the others and can't tell them apart.
+    fn disconnected(&self, a: &(usize, usize, usize), b: &(usize, usize, usize)) -> bool {
+        match (self.component_of(a), self.component_of(b)) {
+            (Some(a), Some(b)) => a != b && a != u16::MAX && b != u16::MAX,
+            _ => false,
+        }
+    }
+
+    pub fn find_path(
+        &self,
+        start_cell: &(usize, usize, usize),
+        end_cell: &(usize, usize, usize),
+    ) -> Option<Vec<(usize, usize, usize)>> {
+        self.find_path_with(start_cell, end_cell, &self.tuning.path.unwrap_or_default())
+            .ok()
+            .map(|search| search.cells)
+    }
+
+    // Fails with PathNotFound or, if the options limit the explored cells, PathBudgetExceeded
+    pub fn find_path_with(
+        &self,
+        start_cell: &(usize, usize, usize),
+        end_cell: &(usize, usize, usize),
+        options: &PathOptions,
+    ) -> Result<PathSearch, Error> {
+        let (path, cost, explored) = self.search(start_cell, end_cell, options, &HashMap::new())?;
+
+        Ok(PathSearch {
+            cells: self.simplify_path(&path),
+            cost: cost as f32 / PATH_COST_SCALE as f32,
+            explored,
+        })
+    }
+
+    // Simplified path to the goal that is cheapest to reach with its index in the goals. A goal
+    // that is the start is reached right away.
+    pub fn find_path_to_any(
+        &self,
+        start_cell: &(usize, usize, usize),
+        goals: &[(usize, usize, usize)],
+    ) -> Option<GoalPath> {
+        let options = self.tuning.path.unwrap_or_default();
+        let (path, _, _) = self
+            .search_any(start_cell, goals, &options, &HashMap::new(), None)
+            .ok()?;
+        let reached = path.last()?;
+        let index = goals.iter().position(|goal| goal == reached)?;
+        Some((index, self.simplify_path(&path)))
+    }
+
+    // Like find_path, but routes over the portals between clusters first and only searches the cells
+    // between consecutive portals. Much faster across large maps, the path can be slightly longer.
+    // Cells in the same cluster and maps built without hierarchical_paths are searched directly.
+    pub fn find_path_hierarchical(
+        &self,
+        start_cell: &(usize, usize, usize),
+        end_cell: &(usize, usize, usize),
+    ) -> Option<Vec<(usize, usize, usize)>> {
+        let cells = self.hierarchical_search(start_cell, end_cell)?;
+        Some(self.simplify_path(&cells))
+    }
+
+    // Cells of the path before simplifying it
+    fn hierarchical_search(
+        &self,
+        start_cell: &(usize, usize, usize),
+        end_cell: &(usize, usize, usize),
+    ) -> Option<Vec<(usize, usize, usize)>> {
+        let options = self.tuning.path.unwrap_or_default();
+        let direct = || {
+            self.search(start_cell, end_cell, &options, &HashMap::new())
+                .ok()
+                .map(|(path, _, _)| path)
+        };
+        if self.disconnected(start_cell, end_cell) {
+            return None;
+        }
+
+        let graph = &self.portals;
+        if graph.cluster_starts.is_empty()
+            || graph.cluster_of(start_cell) == graph.cluster_of(end_cell)
+        {
+            return direct();
+        }
+
+        let from_start = self.cluster_costs(start_cell, &options);
+        // Steps are close to symmetric, so the costs from the end stand in for the ones to it
+        let to_end = self
+            .cluster_costs(end_cell, &options)
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        // The start and the end come after the portals
+        let start = graph.portals.len() as u32;
+        let end = start + 1;
+        let node_cell = |node: u32| match node {
+            node if node == start => *start_cell,
+            node if node == end => *end_cell,
+            portal => graph.portals[portal as usize],
+        };
+
+        let successors = |node: &u32| {
+            let mut successors = if *node == start {
+                from_start.clone()
+            } else {
+                graph.portal_edges(*node as usize).to_vec()
+            };
+            if let Some(cost) = to_end.get(node) {
+                successors.push((end, *cost));
+            }
+            successors
+        };
+        let heuristic = |node: &u32| {
+            let cell = node_cell(*node);
+            let distance = ((cell.0 as f32 - end_cell.0 as f32).powi(2)
+                + (cell.1 as f32 - end_cell.1 as f32).powi(2)
+                + (cell.2 as f32 - end_cell.2 as f32).powi(2))
+            .sqrt();
+            (distance * PATH_COST_SCALE as f32 * options.heuristic_weight).floor() as i32
+        };
+        let (route, _) = astar(&start, successors, heuristic, |node| *node == end)?;
+
+        let mut cells = vec![*start_cell];
+        for pair in route.windows(2) {
+            let (from, to) = (node_cell(pair[0]), node_cell(pair[1]));
+            // Steps across a border are single steps, jumps or teleports
+            if graph.cluster_of(&from) != graph.cluster_of(&to) {
+                cells.push(to);
+                continue;
+            }
+            match self.search(&from, &to, &options, &HashMap::new()) {
+                Ok((segment, _, _)) => cells.extend_from_slice(&segment[1..]),
+                // The estimated costs to the end can connect a portal that can't reach it
+                Err(_) => return direct(),
+            }
+        }
+
+        Some(cells)
+    }
+
+    // Like find_path, but gives up after expanding max_expansions cells instead of exploring
+    // everything reachable when the end can't be reached
+    pub fn find_path_bounded(
+        &self,
+        start_cell: &(usize, usize, usize),
+        end_cell: &(usize, usize, usize),
+        max_expansions: usize,
+    ) -> PathResult {
+        // Only a cancellation fails the search otherwise
+        self.search_bounded(start_cell, end_cell, max_expansions, None)
+            .unwrap_or(PathResult::NotFound)
+    }
+
+    // find_path_bounded on a blocking thread. Fails with PathCancelled as soon as the token is
+    // cancelled, the search stops at its next expanded cell.
+    pub async fn find_path_async(
+        self: Arc<Self>,
+        start_cell: (usize, usize, usize),
+        end_cell: (usize, usize, usize),
+        max_expansions: usize,
+        cancel: CancellationToken,
+    ) -> Result<PathResult, Error> {
+        let token = cancel.clone();
+        let search = task::spawn_blocking(move || {
+            self.search_bounded(&start_cell, &end_cell, max_expansions, Some(&token))
+        });
+
+        tokio::select! {
+            result = search => result.map_err(|_| "Path search task panicked")?,
+            _ = cancel.cancelled() => Err(Error::PathCancelled),
+        }
+    }
+
+    fn search_bounded(
+        &self,
+        start_cell: &(usize, usize, usize),
+        end_cell: &(usize, usize, usize),
+        max_expansions: usize,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<PathResult, Error> {
+        let options = self
+            .tuning
+            .path
+            .unwrap_or_default()
+            .max_explored(max_expansions);
+        match self.search_any(start_cell, &[*end_cell], &options, &HashMap::new(), cancel) {
+            Ok((cells, cost, _)) => Ok(PathResult::Found(self.build_path(cells, cost))),
+            Err(Error::PathNotFound) => Ok(PathResult::NotFound),
+            Err(Error::PathBudgetExceeded) => Ok(PathResult::BudgetExceeded),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Path between two positions, both are moved to the closest walkable cell first
+    pub fn find_path_positions(&self, start: &Vec3, end: &Vec3) -> Result<Path, Error> {
+        let start_cell = self
+            .closest_walkable_cell(start)
+            .ok_or(Error::StartNotWalkable)?;
+        let end_cell = self
+            .closest_walkable_cell(end)
+            .ok_or(Error::DestinationNotWalkable)?;
+
+        let options = self.tuning.path.unwrap_or_default();
+        let (cells, cost, _) = self.search(&start_cell, &end_cell, &options, &HashMap::new())?;
+        Ok(self.build_path(cells, cost))
+    }
+
+    fn build_path(&self, cells: Vec<(usize, usize, usize)>, cost: i32) -> Path {
+        let simplified = self.simplify_path(&cells);
+        let waypoints = simplified
+            .iter()
+            .map(|cell| self.cell_position(cell))
+            .collect::<Vec<_>>();
+        let crouch = simplified
+            .windows(2)
+            .map(|w| {
+                self.walkable_grid[w[0]] == CROUCH_CELL || self.walkable_grid[w[1]] == CROUCH_CELL
+            })
+            .collect();
+        let jumps = simplified
+            .windows(2)
+            .map(|w| self.is_jump(&w[0], &w[1]))
+            .collect();
+        let teleports = simplified
+            .windows(2)
+            .map(|w| self.is_teleport(&w[0], &w[1]))
+            .collect();
+        // The waypoints are cells of the path in the same order
+        let mut hazards = vec![];
+        let mut hazardous = false;
+        let mut waypoint = 1;
+        for cell in cells.iter().skip(1) {
+            if waypoint >= simplified.len() {
+                break;
+            }
+            hazardous |= self.walkable_grid[*cell] == HAZARD_CELL;
+            if *cell == simplified[waypoint] {
+                hazards
+                    .push(hazardous || self.walkable_grid[simplified[waypoint - 1]] == HAZARD_CELL);
+                hazardous = false;
+                waypoint += 1;
+            }
+        }
+        let length = waypoints
+            .windows(2)
+            .map(|w| {
+                ((w[1].x - w[0].x).powi(2) + (w[1].y - w[0].y).powi(2) + (w[1].z - w[0].z).powi(2))
+                    .sqrt()
+            })
+            .sum();
+
+        Path {
+            waypoints,
+            cells,
+            cost: cost as f32 / PATH_COST_SCALE as f32,
+            length,
+            crouch,
+            jumps,
+            teleports,
+            hazards,
+        }
+    }
+
+    // Up to k different routes between the positions, best first. After every route its cells get more
+    // expensive and the search runs again, so the following routes avoid the ones already found.
+    pub fn likely_routes(&self, from: &Vec3, to: &Vec3, k: usize) -> Vec<Path> {
+        let (start_cell, end_cell) = match (
+            self.closest_walkable_cell(from),
+            self.closest_walkable_cell(to),
+        ) {
+            (Some(start_cell), Some(end_cell)) => (start_cell, end_cell),
+            _ => return vec![],
+        };
+
+        let mut penalties = HashMap::<(usize, usize, usize), i32>::new();
+        let mut seen = HashSet::<Vec<(usize, usize, usize)>>::new();
+        let mut routes = vec![];
+
+        // Penalized searches can come back with a route that was already found, give up after a few
+        for _ in 0..k * 2 {
+            if routes.len() >= k {
+                break;
+            }
+
+            let (cells, cost, _) =
+                match self.search(&start_cell, &end_cell, &PathOptions::default(), &penalties) {
+                    Ok(search) => search,
+                    Err(_) => break,
+                };
+
+            let penalty = cells
+                .iter()
+                .skip(1)
+                .map(|cell| penalties.get(cell).copied().unwrap_or(0))
+                .sum::<i32>();
+            for cell in cells.iter() {
+                *penalties.entry(*cell).or_default() += ROUTE_OVERLAP_PENALTY;
+            }
+
+            if !seen.insert(cells.clone()) {
+                continue;
+            }
+
+            routes.push(self.build_path(cells, cost - penalty));
+        }
+
+        routes
+    }
+
+    // The route of likely_routes that fits the observed positions of a player best
+    pub fn predict_route(&self, observed: &[Vec3], destination: &Vec3, k: usize) -> Option<Path> {
+        let from = observed.first()?;
+        self.likely_routes(from, destination, k)
+            .into_iter()
+            .map(|route| (route.distance_to(&self.bounds, observed), route))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, route)| route)
+    }
+
+    // A* over the walkable grid, returns the unsimplified path, its cost including the penalties and the
+    // number of explored cells
+    fn search(
+        &self,
+        start_cell: &(usize, usize, usize),
+        end_cell: &(usize, usize, usize),
+        options: &PathOptions,
+        penalties: &HashMap<(usize, usize, usize), i32>,
+    ) -> Result<SearchResult, Error> {
+        self.search_any(start_cell, &[*end_cell], options, penalties, None)
+    }
+
+    // Calculate the successors of a cell, giving them different cost based on their failure potential.
+    // Cells surrounded by other walkable cells get the flat cost, steps up or down cost more.
+    // Cells on the edge of the walkable grid cost more as it is easier for the player to walk off/against something.
+    // Ladder cells cost the most by default as the chance of the player failing to walk up is highest
+    fn step_successors(
+        &self,
+        cell: &(usize, usize, usize),
+        options: &PathOptions,
+    ) -> Vec<((usize, usize, usize), i32)> {
+        let grid_size = self.walkable_grid.dim();
+        let (flat_cost, vertical_cost, edge_cost, crouch_cost, ladder_cost, jump_cost) =
+            options.step_costs();
+
+        let mut successors = Self::neighbours(cell, &grid_size, false)
+            .iter()
+            .filter_map(|c| {
+                if self.walkable_grid[*c] == REFINED_CELL
+                    || self.walkable_grid[*cell] == REFINED_CELL
+                {
+                    // Narrow passages are as likely to fail as the edge of the walkable cells
+                    self.refined_step(cell, c).then_some((*c, edge_cost))
+                } else if self.walkable_grid[*c] == HAZARD_CELL {
+                    (!options.forbid_hazards).then(|| {
+                        let cost = if cell.1 == c.1 {
+                            flat_cost
+                        } else {
+                            vertical_cost
+                        };
+                        (
+                            *c,
+                            (cost as f32 * options.hazard_cost_multiplier).round() as i32,
+                        )
+                    })
+                } else if self.walkable_grid[*c] == 1 {
+                    for n in Self::horizontal_neighbours(c, &grid_size, true) {
+                        if !self.walkable_grid.any_walkable(
+                            n.0,
+                            n.2,
+                            n.1.saturating_sub(1)..n.1 + 2,
+                        ) {
+                            return Some((*c, edge_cost));
+                        }
+                    }
+
+                    Some((
+                        *c,
+                        if cell.1 == c.1 {
+                            flat_cost
+                        } else {
+                            vertical_cost
+                        },
+                    ))
+                } else if self.walkable_grid[*c] == 2 {
+                    (!options.forbid_ladders).then_some((*c, ladder_cost))
+                } else if self.walkable_grid[*c] == CROUCH_CELL {
+                    Some((*c, crouch_cost))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Teleporting takes no longer than a step
+        if let Some(exit) = self.teleports.get(cell) {
+            successors.push((*exit, flat_cost));
+        }
+
+        for landing in self.jumps.get(cell).into_iter().flatten() {
+            let distance = cell.0.abs_diff(landing.0) + cell.2.abs_diff(landing.2);
+            successors.push((*landing, distance as i32 * flat_cost + jump_cost));
+        }
+
+        successors
+    }
 
-        // start with all spawn cells as we expect the player to be able to stand there
-        let mut cells_to_see = VecDeque::from(
-            spawns
+    // Search that ends at whichever goal it reaches first
+    fn search_any(
+        &self,
+        start_cell: &(usize, usize, usize),
+        goals: &[(usize, usize, usize)],
+        options: &PathOptions,
+        penalties: &HashMap<(usize, usize, usize), i32>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<SearchResult, Error> {
+        // Goals in other components would only be given up on once everything reachable was explored
+        if goals.iter().all(|goal| self.disconnected(start_cell, goal)) {
+            return Err(Error::PathNotFound);
+        }
+        let goal_set = goals.iter().copied().collect::<HashSet<_>>();
+
+        let budget = options.max_explored.unwrap_or(usize::MAX);
+
+        // The successors are sorted by cost and then by cell (y, x, z) so ties in the search are always
+        // broken the same way, independent of the order the neighbour functions produce cells in.
+        let explored = Cell::new(0_usize);
+        let successors = |cell: &(usize, usize, usize)| -> Vec<((usize, usize, usize), i32)> {
+            // Without successors the search runs dry and ends
+            if explored.get() >= budget || cancel.is_some_and(CancellationToken::is_cancelled) {
+                return vec![];
+            }
+            explored.set(explored.get() + 1);
+            let mut successors = self.step_successors(cell, options);
+
+            for (c, cost) in successors.iter_mut() {
+                *cost += penalties.get(c).copied().unwrap_or(0);
+            }
+
+            successors.sort_unstable_by_key(|(c, cost)| (*cost, c.1, c.0, c.2));
+            successors
+        };
+
+        // Simple function that calculates the direct distance from the cell to the closest goal,
+        // weighted to trade optimality for fewer explored cells
+        let heuristic = |cell: &(usize, usize, usize)| {
+            let distance = goals
                 .iter()
-                .map(|spawn| {
-                    let mut cell = position_to_cell(map_bounds, spawn);
-                    if grid[cell] != 0 {
-                        cell.1 += 1;
-                    }
-                    cell
+                .map(|goal| {
+                    (cell.0 as f32 - goal.0 as f32).powi(2)
+                        + (cell.1 as f32 - goal.1 as f32).powi(2)
+                        + (cell.2 as f32 - goal.2 as f32).powi(2)
                 })
-                .collect::<Vec<_>>(),
-        );
+                .fold(f32::INFINITY, f32::min)
+                .sqrt();
+            (distance * PATH_COST_SCALE as f32 * options.heuristic_weight).floor() as i32
+        };
 
-        // Look at the surrounding cells of the cells in the queue and check if they are walkable.
-        // If they are, add them to the queue too. If the queue is empty every walkable cell has been found.
-        while let Some(cell) = cells_to_see.pop_front() {
-            if cell.0 >= grid_size.0 || cell.1 >= grid_size.1 || cell.2 >= grid_size.2 {
-                return Err("Cell index out of bounds".into());
+        let success = |cell: &(usize, usize, usize)| goal_set.contains(cell);
+
+        match astar(start_cell, successors, heuristic, success) {
+            Some((path, cost)) => Ok((path, cost, explored.get())),
+            None if cancel.is_some_and(CancellationToken::is_cancelled) => {
+                Err(Error::PathCancelled)
             }
+            None if explored.get() >= budget => Err(Error::PathBudgetExceeded),
+            None => Err(Error::PathNotFound),
+        }
+    }
 
-            if walkable_grid[cell] != 0 {
-                continue;
+    fn is_jump(&self, from: &(usize, usize, usize), to: &(usize, usize, usize)) -> bool {
+        self.jumps
+            .get(from)
+            .is_some_and(|landings| landings.contains(to))
+    }
+
+    fn is_teleport(&self, from: &(usize, usize, usize), to: &(usize, usize, usize)) -> bool {
+        self.teleports.get(from) == Some(to)
+    }
+
+    // Jumps and teleports have to start and end at a waypoint, the walks between them are simplified
+    // on their own
+    fn simplify_path(&self, path: &[(usize, usize, usize)]) -> Vec<(usize, usize, usize)> {
+        let mut simplified_path = vec![];
+        let mut start = 0;
+        for i in 1..=path.len() {
+            if i == path.len()
+                || self.is_jump(&path[i - 1], &path[i])
+                || self.is_teleport(&path[i - 1], &path[i])
+            {
+                simplified_path.extend(self.simplify_walk(&path[start..i]));
+                start = i;
             }
+        }
+        simplified_path
+    }
 
-            // Differentiate between ladder and other cells for pathfinding
-            walkable_grid[cell] = if grid[cell] == 6 { 2 } else { 1 };
+    fn simplify_walk(&self, path: &[(usize, usize, usize)]) -> Vec<(usize, usize, usize)> {
+        if path.len() <= 2 {
+            return Vec::from(path);
+        }
 
-            // For air cells, only consider the 4 horizontal neighbours on the same level and y +- 1.
-            // For ramp and ladder cells, check all neighbours including edges.
-            if grid[cell] == 0 {
-                for neighbour in Self::horizontal_neighbours(&cell, &grid_size, false).iter() {
-                    if Self::is_cell_walkable(neighbour, grid) {
-                        cells_to_see.push_back(*neighbour);
-                    } else if Self::is_cell_walkable(
-                        &(neighbour.0, neighbour.1 + 1, neighbour.2),
-                        grid,
-                    ) {
-                        cells_to_see.push_back((neighbour.0, neighbour.1 + 1, neighbour.2));
-                    } else if neighbour.1 > 0
-                        && Self::is_cell_walkable(
-                            &(neighbour.0, neighbour.1 - 1, neighbour.2),
-                            grid,
-                        )
-                    {
-                        cells_to_see.push_back((neighbour.0, neighbour.1 - 1, neighbour.2));
-                    }
-                }
-            } else {
-                for neighbour in Self::neighbours(&cell, &grid_size, true).iter() {
-                    if Self::is_cell_walkable(neighbour, grid) {
-                        cells_to_see.push_back(*neighbour);
-                    }
-                }
+        // Simplify the path by checking if the direct path between two cells is walkable.
+        // If it is, try the next cell until one is found that doesn't have a direct walkable path.
+        // In that case add the previous cell to the simplified path and repeat the process starting from that cell.
+        // This eliminates a lot of unnecessary cells and allows the player to walk diagonal.
+        let mut simplified_path = Vec::from([path[0]]);
+        let mut from_cell = path[0];
+        let mut last_cell = path[1];
+        for cell in &path[2..] {
+            if (cell.0 != last_cell.0 || cell.2 != last_cell.2)
+                && !self.is_direct_walk(&from_cell, cell)
+            {
+                simplified_path.push(last_cell);
+                from_cell = last_cell;
             }
+
+            last_cell = *cell;
         }
 
-        Ok(walkable_grid)
+        simplified_path.push(last_cell);
+        simplified_path
     }
 
-    fn neighbours(
-        cell: &(usize, usize, usize),
-        grid_size: &(usize, usize, usize),
-        edges: bool,
-    ) -> Vec<(usize, usize, usize)> {
-        let cell = (cell.0 as isize, cell.1 as isize, cell.2 as isize);
+    // Whether every column of the cells between the two cells and one cell around them has a walkable
+    // cell between their heights. Columns outside of the grid are blocked.
+    fn is_direct_walk(&self, from: &(usize, usize, usize), to: &(usize, usize, usize)) -> bool {
+        let window = |a: usize, b: usize| a.min(b).checked_sub(1).map(|low| low..=a.max(b) + 1);
+        let (xs, zs) = match (window(from.0, to.0), window(from.2, to.2)) {
+            (Some(xs), Some(zs)) => (xs, zs),
+            _ => return false,
+        };
+        let ys = from.1.min(to.1)..=from.1.max(to.1);
 
-        let mut neighbours = vec![
-            (cell.0, cell.1 + 1, cell.2),
-            (cell.0 - 1, cell.1 + 1, cell.2),
-            (cell.0 + 1, cell.1 + 1, cell.2),
-            (cell.0, cell.1 + 1, cell.2 - 1),
-            (cell.0, cell.1 + 1, cell.2 + 1),
-            (cell.0 - 1, cell.1, cell.2),
-            (cell.0 + 1, cell.1, cell.2),
-            (cell.0, cell.1, cell.2 - 1),
-            (cell.0, cell.1, cell.2 + 1),
-            (cell.0, cell.1 - 1, cell.2),
-            (cell.0 - 1, cell.1 - 1, cell.2),
-            (cell.0 + 1, cell.1 - 1, cell.2),
-            (cell.0, cell.1 - 1, cell.2 - 1),
-            (cell.0, cell.1 - 1, cell.2 + 1),
+        xs.into_iter().all(|x| {
+            zs.clone().all(|z| {
+                // Refined, low and hazardous cells can't be cut through, they are kept as waypoints
+                ys.clone()
+                    .any(|y| matches!(self.walkable_grid.get((x, y, z)), Some(1 | 2)))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    // Flat 6x6 floor at y 0 of a small map, the rest of the map is left as parsed
+    fn flat_map() -> (Map, Array3<u8>) {
+        let mut grid = Array3::<u8>::zeros((6, 3, 6));
+        grid.slice_mut(s![.., 0, ..]).fill(1);
+        let mut map = Map::new(&crate::soak::raw_map("flat")).unwrap();
+        map.walkable_grid = WalkableGrid::new(&grid);
+        (map, grid)
+    }
+
+    #[test]
+    fn paths_along_the_grid_edges_keep_their_waypoints() {
+        let (map, _) = flat_map();
+        let edges = [
+            (0..6).map(|z| (0, 0, z)).collect::<Vec<_>>(),
+            (0..6).map(|x| (x, 0, 0)).collect(),
+            (0..6).map(|z| (5, 0, z)).collect(),
+            (0..6).map(|x| (x, 0, 5)).collect(),
         ];
+        for path in edges {
+            // Columns outside of the grid are blocked, so no cell can be cut
+            assert!(!map.is_direct_walk(&path[0], &path[2]));
+            assert_eq!(map.simplify_path(&path), path);
+        }
 
-        if edges {
-            neighbours.append(&mut vec![
-                (cell.0 - 1, cell.1 + 1, cell.2 - 1),
-                (cell.0 - 1, cell.1 + 1, cell.2 + 1),
-                (cell.0 + 1, cell.1 + 1, cell.2 - 1),
-                (cell.0 + 1, cell.1 + 1, cell.2 + 1),
-                (cell.0 - 1, cell.1, cell.2 - 1),
-                (cell.0 - 1, cell.1, cell.2 + 1),
-                (cell.0 + 1, cell.1, cell.2 - 1),
-                (cell.0 + 1, cell.1, cell.2 + 1),
-                (cell.0 - 1, cell.1 - 1, cell.2 - 1),
-                (cell.0 - 1, cell.1 - 1, cell.2 + 1),
-                (cell.0 + 1, cell.1 - 1, cell.2 - 1),
-                (cell.0 + 1, cell.1 - 1, cell.2 + 1),
-            ]);
+        assert!(!map.is_direct_walk(&(0, 0, 0), &(5, 0, 5)));
+        assert!(!map.is_direct_walk(&(5, 0, 5), &(1, 0, 1)));
+    }
+
+    #[test]
+    fn closest_walkable_cells_are_searched_up_to_the_radius() {
+        let (mut map, _) = flat_map();
+        let mut grid = Array3::<u8>::zeros((6, 3, 6));
+        grid[(4, 0, 4)] = 1;
+        map.walkable_grid = WalkableGrid::new(&grid);
+        let corner = map.cell_to_position(&(0, 0, 0));
+
+        assert!(map.closest_walkable_cell_within(&corner, 3).is_none());
+        let (cell, distance) = map.closest_walkable_cell_within(&corner, 4).unwrap();
+        assert_eq!(cell, (4, 0, 4));
+        assert!((distance - 32.0_f32.sqrt() * CELL_SIZE).abs() < 1e-3);
+
+        // A radius of zero only looks at the column of the position
+        let (cell, distance) = map
+            .closest_walkable_cell_within(&map.cell_to_position(&(4, 2, 4)), 0)
+            .unwrap();
+        assert_eq!(cell, (4, 0, 4));
+        assert!((distance - 2.0 * CELL_SIZE).abs() < 1e-3);
+        assert!(map
+            .closest_walkable_cell_within(&map.cell_to_position(&(4, 0, 3)), 0)
+            .is_none());
+    }
+
+    #[test]
+    fn closest_walkable_cells_outside_of_the_bounds() {
+        let map = Map::new(&crate::soak::raw_map("bounds")).unwrap();
+        let spawn = map.closest_walkable_cell(&at(0.0, 0.0)).unwrap();
+
+        assert!(map.closest_walkable_cell(&at(500.0, 0.0)).is_none());
+        assert!(map.closest_walkable_cell(&at(0.0, -500.0)).is_none());
+        let below = Vec3 {
+            y: map.bounds.min_y - 10.0,
+            ..at(0.0, 0.0)
+        };
+        assert!(map.closest_walkable_cell(&below).is_none());
+
+        // Positions above the grid are looked up from its top
+        let above = Vec3 {
+            y: map.bounds.max_y + 10.0,
+            ..at(0.0, 0.0)
+        };
+        assert_eq!(map.closest_walkable_cell(&above), Some(spawn));
+    }
+
+    #[test]
+    fn paths_inside_the_grid_are_simplified() {
+        let (map, mut grid) = flat_map();
+        let path = (1..5).map(|z| (2, 0, z)).collect::<Vec<_>>();
+        assert_eq!(map.simplify_path(&path), vec![(2, 0, 1), (2, 0, 4)]);
+
+        // A hole next to the start only blocks the windows that reach back to it
+        grid[(3, 0, 0)] = 0;
+        let mut map = map;
+        map.walkable_grid = WalkableGrid::new(&grid);
+        assert!(!map.is_direct_walk(&(2, 0, 1), &(2, 0, 3)));
+        assert!(map.is_direct_walk(&(2, 0, 2), &(2, 0, 4)));
+        assert_eq!(
+            map.simplify_path(&path),
+            vec![(2, 0, 1), (2, 0, 2), (2, 0, 4)]
+        );
+    }
+
+    #[test]
+    fn projection_keeps_blocked_columns_when_downsampling() {
+        let (mut map, mut grid) = flat_map();
+        grid[(4, 0, 1)] = 0;
+        map.walkable_grid = WalkableGrid::new(&grid);
+
+        let projection = map.topdown_projection(6);
+        assert_eq!(projection.dim(), (6, 6));
+        assert_eq!(projection.sum(), 1);
+        assert_eq!(projection[(4, 1)], 1);
+
+        // Every side fits into max_dim, a blocked cell blocks its whole block
+        let projection = map.topdown_projection(4);
+        assert_eq!(projection.dim(), (3, 3));
+        assert_eq!(projection.sum(), 1);
+        assert_eq!(projection[(2, 0)], 1);
+
+        assert_eq!(map.topdown_projection(0).dim(), (1, 1));
+    }
+
+    #[test]
+    fn clearance_removes_cells_within_the_radius_of_walls() {
+        // Floor at y 0 with a wall at x 5 from y 1 up, walkable cells and a ladder above the floor
+        let size = (11, 10, 11);
+        let mut grid = Array3::<u8>::zeros(size);
+        grid.slice_mut(s![.., 0, ..]).fill(1);
+        grid.slice_mut(s![5, 1.., 5]).fill(1);
+        let mut walkable = Array3::<u8>::zeros(size);
+        walkable.slice_mut(s![.., 1, ..]).fill(1);
+        walkable[(5, 1, 5)] = 0;
+        walkable[(4, 1, 4)] = 2;
+
+        let mut cleared = walkable.clone();
+        Map::apply_clearance(&grid, &mut cleared, CELL_SIZE / 2.0);
+        assert_eq!(cleared, walkable);
+
+        // Reaches one cell past the own one, diagonals included
+        Map::apply_clearance(&grid, &mut cleared, CELL_SIZE * 1.5);
+        for x in 0..size.0 {
+            for z in 0..size.2 {
+                let near = x.abs_diff(5) <= 1 && z.abs_diff(5) <= 1;
+                let expected = match (x, z) {
+                    (4, 4) => 2,
+                    _ if near => 0,
+                    _ => 1,
+                };
+                assert_eq!(cleared[(x, 1, z)], expected, "{:?}", (x, z));
+            }
         }
+    }
 
-        // only include cells that are in bounds
-        neighbours
+    #[test]
+    fn dilation_matches_a_window_search() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mask = Array3::from_shape_fn((12, 3, 9), |_| rng.gen_bool(0.1) as u8);
+        for radius in 0..4 {
+            let mut dilated = mask.clone();
+            Map::dilate_axis(&mut dilated, Axis(0), radius);
+            Map::dilate_axis(&mut dilated, Axis(2), radius);
+
+            let expected = Array3::from_shape_fn(mask.dim(), |(x, y, z)| {
+                mask.indexed_iter().any(|((mx, my, mz), value)| {
+                    *value != 0 && my == y && mx.abs_diff(x) <= radius && mz.abs_diff(z) <= radius
+                }) as u8
+            });
+            assert_eq!(dilated, expected, "radius {}", radius);
+        }
+    }
+
+    #[test]
+    fn player_radius_shrinks_the_walkable_area() {
+        let raw_map = random_raw_map(2);
+        let map = Map::new(&raw_map).unwrap();
+        let options = MapBuildOptions {
+            player_radius: 3.0,
+            ..Default::default()
+        };
+        let cleared = Map::with_options(&raw_map, &options).unwrap();
+
+        let coverage = cleared.coverage();
+        assert_eq!(
+            coverage.walkable_cells_before_clearance,
+            map.walkable_cell_count()
+        );
+        assert!(coverage.walkable_cells < coverage.walkable_cells_before_clearance);
+        assert!(cleared
+            .walkable_cells
             .iter()
-            .filter_map(|(x, y, z)| {
-                if (*x >= 0 && *x < grid_size.0 as isize)
-                    && (*y >= 0 && *y < grid_size.1 as isize)
-                    && (*z >= 0 && *z < grid_size.2 as isize)
-                {
-                    Some((*x as usize, *y as usize, *z as usize))
-                } else {
-                    None
-                }
+            .all(|i| map.walkable_cells.binary_search(i).is_ok()));
+    }
+
+    #[test]
+    fn weighted_paths_stay_within_the_bound() {
+        let map = Map::new(&random_raw_map(3)).unwrap();
+        let optimal = PathOptions::default();
+        let weighted = PathOptions::default().suboptimality_bound(2.0);
+        assert_eq!(
+            PathOptions::default()
+                .heuristic_weight(0.5)
+                .heuristic_weight,
+            1.0
+        );
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let (_, size_y, size_z) = map.grid_size();
+        let cell = |i: u32| {
+            let i = i as usize;
+            (i / (size_y * size_z), i / size_z % size_y, i % size_z)
+        };
+        let (mut explored, mut explored_weighted) = (0, 0);
+        let mut searches = 0;
+        while searches < 4 {
+            let start = cell(*map.walkable_cells.choose(&mut rng).unwrap());
+            let end = cell(*map.walkable_cells.choose(&mut rng).unwrap());
+            let (Ok(search), Ok(weighted_search)) = (
+                map.find_path_with(&start, &end, &optimal),
+                map.find_path_with(&start, &end, &weighted),
+            ) else {
+                continue;
+            };
+            assert!(search.cost <= weighted_search.cost);
+            assert!(weighted_search.cost <= search.cost * 2.0);
+            explored += search.explored;
+            explored_weighted += weighted_search.explored;
+            searches += 1;
+        }
+        assert!(
+            explored_weighted < explored,
+            "{} cells explored with the weight, {} without",
+            explored_weighted,
+            explored
+        );
+    }
+
+    #[test]
+    fn grid_height_comes_from_non_border_geometry() {
+        // Floor from -6 to 0 and a border wall from -6 to 194
+        let mut raw_map = crate::soak::raw_map("borders");
+        raw_map.sizes[4] = 200.0;
+        let (bounds, ceiling, ..) = Map::filter_objects(&raw_map).unwrap();
+        assert_eq!(bounds.min_y, -6.0);
+        assert_eq!(bounds.max_y, GRID_Y_MARGIN);
+        assert_eq!(ceiling, 194.0);
+
+        // A box on the floor raises the top of the grid
+        raw_map.sizes.extend([10.0, 30.0, 10.0]);
+        raw_map.objects.push(RawMapObject {
+            position: [20.0, 0.0, 20.0],
+            size_index: Some(2),
+            ..Default::default()
+        });
+        let (bounds, ..) = Map::filter_objects(&raw_map).unwrap();
+        assert_eq!(bounds.max_y, 30.0 + GRID_Y_MARGIN);
+
+        // The grid always reaches down to 0, borders don't lower it
+        raw_map.objects[0].position[1] = 10.0;
+        raw_map.objects[2].position[1] = 10.0;
+        raw_map.objects[1].position[1] = -100.0;
+        let (bounds, ..) = Map::filter_objects(&raw_map).unwrap();
+        assert_eq!(bounds.min_y, 0.0);
+
+        let map = Map::new(&crate::soak::raw_map("borders")).unwrap();
+        let height = ((6.0 + GRID_Y_MARGIN) / CELL_SIZE).ceil() as usize;
+        assert_eq!(map.grid_size().1, height);
+    }
+
+    #[test]
+    fn positions_are_looked_up_in_the_labeled_regions() {
+        let mut map = Map::new(&crate::soak::raw_map("regions")).unwrap();
+        let spawn = map.spawns()[0];
+        assert!(map.region_at(&spawn).is_none());
+
+        let options = RegionOptions {
+            band_height: 4,
+            tile_size: 10,
+        };
+        let count = map.label_regions(&options).len();
+        assert!(count > 1);
+        assert_eq!(map.regions().len(), count);
+        let region = map.region_at(&spawn).unwrap();
+        assert!(region
+            .bounds()
+            .contains(&map.cell_to_position(&map.closest_walkable_cell(&spawn).unwrap())));
+        assert!(map
+            .region_at(&Vec3 {
+                x: 1000.0,
+                y: 0.0,
+                z: 0.0
             })
-            .collect()
+            .is_none());
+    }
+
+    fn at(x: f32, z: f32) -> Vec3 {
+        Vec3 { x, y: 0.0, z }
+    }
+
+    #[test]
+    fn likely_routes_go_around_both_sides_of_a_block() {
+        let mut raw_map = crate::soak::raw_map("block");
+        raw_map.sizes.extend([40.0, 20.0, 40.0]);
+        raw_map.objects.push(RawMapObject {
+            position: [0.0, 0.0, 0.0],
+            size_index: Some(2),
+            ..Default::default()
+        });
+        raw_map.spawns = vec![vec![Some(-60.0), Some(0.0), Some(0.0)]];
+        let map = Map::new(&raw_map).unwrap();
+        let (from, to) = (at(-60.0, 0.0), at(60.0, 0.0));
+
+        let routes = map.likely_routes(&from, &to, 2);
+        assert_eq!(routes.len(), 2);
+        assert!(routes[0].cost <= routes[1].cost);
+        // Whether the route passes the block on the side of positive z
+        let middle = map.closest_walkable_cell(&from).unwrap().2;
+        let side = |route: &Path| route.cells.iter().any(|cell| cell.2 > middle);
+        assert_ne!(side(&routes[0]), side(&routes[1]));
+
+        // Positions seen on one side pick the route on that side
+        for z in [30.0, -30.0] {
+            let observed = [from, at(-30.0, z), at(0.0, z)];
+            let predicted = map.predict_route(&observed, &to, 2).unwrap();
+            assert_eq!(side(&predicted), z > 0.0);
+        }
+
+        assert!(map.likely_routes(&from, &to, 0).is_empty());
+        assert!(map.likely_routes(&at(500.0, 0.0), &to, 2).is_empty());
+        assert!(map.predict_route(&[], &to, 2).is_none());
+    }
+
+    fn bounds(center: [f32; 3], half: [f32; 3]) -> AABB {
+        AABB {
+            min_x: center[0] - half[0],
+            min_y: center[1] - half[1],
+            min_z: center[2] - half[2],
+            max_x: center[0] + half[0],
+            max_y: center[1] + half[1],
+            max_z: center[2] + half[2],
+        }
+    }
+
+    #[test]
+    fn quarter_turns_give_the_exact_box() {
+        let slab = bounds([5.0, 0.0, 0.0], [5.0, 1.0, 2.0]);
+        let pivot = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let (rotated, aligned) =
+            RotatedBox::new(&slab, &pivot, [0.0, std::f32::consts::FRAC_PI_2, 0.0]);
+        assert!(aligned);
+        // The slab swings around the pivot from positive x to negative z
+        let expected = bounds([0.0, 0.0, -5.0], [2.0, 1.0, 5.0]);
+        for (actual, expected) in [
+            (rotated.bounds.min_x, expected.min_x),
+            (rotated.bounds.min_y, expected.min_y),
+            (rotated.bounds.min_z, expected.min_z),
+            (rotated.bounds.max_x, expected.max_x),
+            (rotated.bounds.max_y, expected.max_y),
+            (rotated.bounds.max_z, expected.max_z),
+        ] {
+            assert!((actual - expected).abs() < 1e-4, "{:?}", rotated.bounds);
+        }
+
+        let (_, aligned) = RotatedBox::new(&slab, &pivot, [std::f32::consts::PI, 0.0, 0.0]);
+        assert!(aligned);
+    }
+
+    #[test]
+    fn diagonal_boxes_only_overlap_along_the_diagonal() {
+        let wall = bounds([0.0, 0.0, 0.0], [30.0, 10.0, 2.0]);
+        let pivot = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let (rotated, aligned) =
+            RotatedBox::new(&wall, &pivot, [0.0, std::f32::consts::FRAC_PI_4, 0.0]);
+        assert!(!aligned);
+        // The bounds contain both ends of the wall
+        let extent = (30.0 + 2.0) * std::f32::consts::FRAC_1_SQRT_2;
+        assert!((rotated.bounds.max_x - extent).abs() < 1e-3);
+        assert!((rotated.bounds.min_z + extent).abs() < 1e-3);
+
+        let cell = |x, z| bounds([x, 0.0, z], [1.0, 1.0, 1.0]);
+        assert!(rotated.overlaps(&cell(0.0, 0.0)));
+        assert!(rotated.overlaps(&cell(15.0, -15.0)));
+        assert!(!rotated.overlaps(&cell(15.0, 15.0)));
+        assert!(!rotated.overlaps(&cell(-20.0, -20.0)));
+
+        // Columns above and below the wall pass through it, columns beside it don't
+        assert!(rotated.crosses_column(15.0, -15.0, -20.0, 20.0));
+        assert!(!rotated.crosses_column(15.0, -15.0, 11.0, 20.0));
+        assert!(!rotated.crosses_column(15.0, 15.0, -20.0, 20.0));
+    }
+
+    #[test]
+    fn rotated_walls_block_their_real_shape() {
+        // Angle, positions on the wall and positions beside it. The free positions of the
+        // diagonal wall are inside its bounds.
+        let walls = [
+            (
+                std::f32::consts::FRAC_PI_2,
+                [(0.0, 20.0), (0.0, -20.0)],
+                [(20.0, 0.0), (-20.0, 20.0)],
+            ),
+            (
+                std::f32::consts::FRAC_PI_4,
+                [(15.0, -15.0), (-15.0, 15.0)],
+                [(15.0, 15.0), (-15.0, -15.0)],
+            ),
+        ];
+        for (angle, blocked, free) in walls {
+            let mut raw_map = crate::soak::raw_map("rotated");
+            raw_map.sizes.extend([60.0, 20.0, 4.0]);
+            raw_map.objects.push(RawMapObject {
+                position: [0.0, 0.0, 0.0],
+                size_index: Some(2),
+                rotation: Some([0.0, angle, 0.0]),
+                ..Default::default()
+            });
+            raw_map.spawns = vec![vec![Some(-60.0), Some(0.0), Some(-60.0)]];
+            let map = Map::new(&raw_map).unwrap();
+
+            // Horizontal distance to the walkable cell closest to the floor at x, z
+            let snapped = |x, z| {
+                let cell = map.closest_walkable_cell(&at(x, z)).unwrap();
+                let position = map.cell_to_position(&cell);
+                (position.x - x).hypot(position.z - z)
+            };
+            for (x, z) in blocked {
+                assert!(snapped(x, z) > 2.0, "{} at {} {}", angle, x, z);
+            }
+            for (x, z) in free {
+                assert!(snapped(x, z) < CELL_SIZE, "{} at {} {}", angle, x, z);
+            }
+        }
+    }
+
+    fn moat_raw_map() -> RawMap {
+        serde_json::from_str(include_str!("../tests/fixtures/maps/moat.json")).unwrap()
+    }
+
+    // Two ledges 10 high with a gap of 8 between them and a wall of 30 standing in the gap
+    fn ledges_raw_map() -> RawMap {
+        serde_json::from_str(include_str!("../tests/fixtures/maps/ledges.json")).unwrap()
+    }
+
+    // A wall across the map that is only open past x 80 and a teleporter on one side of it that puts
+    // the player on a pad on the other side
+    fn teleporter_raw_map() -> RawMap {
+        serde_json::from_str(include_str!("../tests/fixtures/maps/teleporter.json")).unwrap()
+    }
+
+    // A wall across the map with a doorway of 1.3, narrower than a cell but wider than half of one
+    fn doorway_raw_map() -> RawMap {
+        serde_json::from_str(include_str!("../tests/fixtures/maps/doorway.json")).unwrap()
+    }
+
+    #[test]
+    fn refinement_opens_doorways_narrower_than_a_cell() {
+        let raw_map = doorway_raw_map();
+        let coarse = Map::new(&raw_map).unwrap();
+        let (from, to) = (coarse.spawns()[0], coarse.spawns()[1]);
+        assert!(matches!(
+            coarse.find_path_positions(&from, &to),
+            Err(Error::PathNotFound)
+        ));
+
+        let options = MapBuildOptions {
+            refine_narrow_passages: true,
+            ..Default::default()
+        };
+        let refined = Map::with_options(&raw_map, &options).unwrap();
+        let path = refined.find_path_positions(&from, &to).unwrap();
+        // Through the free half of the cell, not its center
+        assert!(path.waypoints.iter().any(|waypoint| {
+            (-1.65..-0.35).contains(&waypoint.x) && (waypoint.z - 11.1).abs() < CELL_SIZE
+        }));
+
+        // Only the doorway is kept at the finer resolution, a globally fine grid would have 8 times
+        // the cells at one byte each
+        let (x, y, z) = refined.walkable_grid.shape;
+        let fine_grid = x * y * z * 8;
+        let overlay =
+            refined.refined.len() * std::mem::size_of::<((usize, usize, usize), RefinedCell)>();
+        assert_eq!(refined.coverage().refined_cells, refined.refined.len());
+        assert!(refined.refined.len() <= 4);
+        assert!(overlay * 100 < fine_grid, "{} {}", overlay, fine_grid);
+        assert!(
+            refined.walkable_grid.memory_size() + overlay < fine_grid / 4,
+            "{} {}",
+            refined.walkable_grid.memory_size(),
+            fine_grid
+        );
+    }
+
+    // A wall of 10x10x2 standing on the floor at z 20, it covers x -5 to 5 and z 19 to 21
+    fn wall_map() -> Map {
+        Map::from_raw_json(include_str!("../tests/fixtures/maps/wall.json")).unwrap()
+    }
+
+    fn eye(x: f32, z: f32) -> Vec3 {
+        Vec3 { x, y: 2.0, z }
+    }
+
+    #[test]
+    fn walls_block_the_line_of_sight() {
+        let map = wall_map();
+        assert!(!map.line_of_sight(&eye(0.0, 0.0), &eye(0.0, 40.0)));
+        let hit = map.first_hit(&eye(0.0, 0.0), &eye(0.0, 40.0)).unwrap();
+        assert!((hit.z - 20.0).abs() < CELL_SIZE, "{:?}", hit);
+        assert!(hit.x.abs() < CELL_SIZE);
+
+        // Past the end of the wall
+        assert!(map.line_of_sight(&eye(20.0, 0.0), &eye(20.0, 40.0)));
+        assert!(map.first_hit(&eye(20.0, 0.0), &eye(20.0, 40.0)).is_none());
+        // Over it
+        let high = |z| Vec3 { x: 0.0, y: 14.0, z };
+        assert!(map.line_of_sight(&high(0.0), &high(40.0)));
+    }
+
+    #[test]
+    fn grazing_the_edge_of_a_wall_is_decided_by_the_cells() {
+        let map = wall_map();
+        // The cell from x 3.2 to 5.6 holds the edge of the wall, a segment through it is blocked
+        // even though it misses the wall itself
+        assert!(!map.line_of_sight(&eye(5.5, 0.0), &eye(5.5, 40.0)));
+        assert!(map.line_of_sight(&eye(6.0, 0.0), &eye(6.0, 40.0)));
+        // Diagonally past the corner
+        assert!(map.line_of_sight(&eye(-10.0, 40.0), &eye(40.0, 10.0)));
+    }
+
+    #[test]
+    fn the_closest_goal_is_reached_first() {
+        let map = wall_map();
+        let cell = |x, z| map.closest_walkable_cell(&Vec3 { x, y: 0.0, z }).unwrap();
+        let start = cell(0.0, 0.0);
+        let goals = [cell(0.0, 40.0), cell(-20.0, 0.0), cell(30.0, 0.0)];
+
+        let (index, path) = map.find_path_to_any(&start, &goals).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&goals[1]));
+    }
+
+    #[test]
+    fn searches_without_goals_or_already_at_one() {
+        let map = wall_map();
+        let start = map
+            .closest_walkable_cell(&Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            })
+            .unwrap();
+        assert!(map.find_path_to_any(&start, &[]).is_none());
+
+        let far = map
+            .closest_walkable_cell(&Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 40.0,
+            })
+            .unwrap();
+        let (index, path) = map.find_path_to_any(&start, &[far, start]).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(path, vec![start]);
+    }
+
+    #[test]
+    fn cover_is_found_behind_the_wall() {
+        let map = wall_map();
+        let threat = eye(0.0, 30.0);
+        let from = Vec3 {
+            x: 20.0,
+            y: 0.0,
+            z: 10.0,
+        };
+        assert!(map.line_of_sight(&eye(from.x, from.z), &threat));
+
+        // The shadow of the wall starts a few cells to the left, closer to the wall
+        let cover = map.nearest_cover(&from, &threat, 40.0).unwrap();
+        let head = Vec3 {
+            y: cover.y + (PLAYER_HEIGHT - 1) as f32 * CELL_SIZE,
+            ..cover
+        };
+        assert!(!map.line_of_sight(&head, &threat));
+        assert!(cover.x > 5.0 && cover.x < from.x, "{:?}", cover);
+        assert!(
+            cover.z > from.z - CELL_SIZE && cover.z < 19.0,
+            "{:?}",
+            cover
+        );
+
+        // Too far away, or already covered
+        assert!(map.nearest_cover(&from, &threat, 5.0).is_none());
+        let behind = eye(0.0, 10.0);
+        let cover = map.nearest_cover(&behind, &threat, 40.0).unwrap();
+        assert!((cover.x - behind.x).abs() < CELL_SIZE && (cover.z - behind.z).abs() < CELL_SIZE);
+    }
+
+    #[test]
+    fn segments_outside_of_the_map_or_without_length() {
+        let map = wall_map();
+        assert!(map.line_of_sight(&eye(0.0, 0.0), &eye(0.0, 0.0)));
+        assert!(!map.line_of_sight(&eye(0.0, 0.0), &eye(0.0, 400.0)));
+        assert!(map.first_hit(&eye(0.0, 0.0), &eye(0.0, 400.0)).is_none());
+    }
+
+    fn with_path_options(mut map: Map, options: PathOptions) -> Map {
+        map.set_tuning(MapTuning {
+            path: Some(options),
+            ..Default::default()
+        });
+        map
     }
 
-    fn horizontal_neighbours(
-        cell: &(usize, usize, usize),
-        grid_size: &(usize, usize, usize),
-        edges: bool,
-    ) -> Vec<(usize, usize, usize)> {
-        let cell = (cell.0 as isize, cell.1, cell.2 as isize);
+    #[test]
+    fn paths_take_the_bridge_over_a_moat() {
+        let map = Map::new(&moat_raw_map()).unwrap();
+        assert!(map.coverage().hazard_cells > 0);
+        let (from, to) = (map.spawns()[0], map.spawns()[1]);
 
-        let mut neighbours = vec![
-            (cell.0 - 1, cell.1, cell.2),
-            (cell.0 + 1, cell.1, cell.2),
-            (cell.0, cell.1, cell.2 - 1),
-            (cell.0, cell.1, cell.2 + 1),
-        ];
+        // The bridge is at the far end of the moat
+        let path = map.find_path_positions(&from, &to).unwrap();
+        assert!(path.waypoints.iter().any(|waypoint| waypoint.x > 60.0));
+        assert!(!path.hazards.contains(&true));
 
-        if edges {
-            neighbours.append(&mut vec![
-                (cell.0 - 1, cell.1, cell.2 - 1),
-                (cell.0 - 1, cell.1, cell.2 + 1),
-                (cell.0 + 1, cell.1, cell.2 - 1),
-                (cell.0 + 1, cell.1, cell.2 + 1),
-            ]);
-        }
+        // Hazards that cost no more than the floor are walked through
+        let map = with_path_options(map, PathOptions::default().hazard_cost_multiplier(1.0));
+        let path = map.find_path_positions(&from, &to).unwrap();
+        assert!(path.waypoints.iter().all(|waypoint| waypoint.x < 0.0));
+        assert!(path.hazards.contains(&true));
+        assert_eq!(path.hazards.len(), path.waypoints.len() - 1);
+    }
 
-        // only include cells that are in bounds
-        neighbours
-            .iter()
-            .filter_map(|(x, y, z)| {
-                if *x >= 0 && *x < grid_size.0 as isize && *z >= 0 && *z < grid_size.2 as isize {
-                    Some((*x as usize, *y, *z as usize))
-                } else {
-                    None
-                }
-            })
-            .collect()
+    #[test]
+    fn moats_without_a_bridge_are_crossed_unless_forbidden() {
+        let mut raw_map = moat_raw_map();
+        // The moat spans the whole map
+        raw_map.sizes[6] = 200.0;
+        raw_map.objects[2].position[0] = 0.0;
+        let map = Map::new(&raw_map).unwrap();
+        let (from, to) = (map.spawns()[0], map.spawns()[1]);
+
+        let path = map.find_path_positions(&from, &to).unwrap();
+        assert!(path.hazards.contains(&true));
+
+        let map = with_path_options(map, PathOptions::default().forbid_hazards(true));
+        assert!(matches!(
+            map.find_path_positions(&from, &to),
+            Err(Error::PathNotFound)
+        ));
     }
 
-    fn is_cell_walkable(cell: &(usize, usize, usize), grid: &Array3<u8>) -> bool {
-        let shape = grid.shape();
-        let grid_size = (shape[0], shape[1], shape[2]);
+    #[test]
+    fn gaps_between_ledges_are_jumped() {
+        let mut raw_map = ledges_raw_map();
+        raw_map.objects.pop();
+        let map = Map::new(&raw_map).unwrap();
+        assert!(map.coverage().jump_edges > 0);
 
-        // check if the following checks are in bounds
-        if (cell.0 == 0 || cell.0 + 1 >= grid_size.0)
-            || (cell.1 < 2 || cell.1 + PLAYER_HEIGHT > grid_size.1)
-            || (cell.2 == 0 || cell.2 + 1 >= grid_size.2)
-        {
-            return false;
-        }
+        let (from, to) = (map.spawns()[0], map.spawns()[1]);
+        let path = map.find_path_positions(&from, &to).unwrap();
+        assert_eq!(path.jumps.iter().filter(|jump| **jump).count(), 1);
+        assert!(path.waypoints.iter().all(|waypoint| waypoint.y > 9.0));
+    }
 
-        // check that cell and cells above are not filled
-        for i in 0..(PLAYER_HEIGHT - 1) {
-            if grid[(cell.0, cell.1 + i, cell.2)] == 1 {
-                return false;
-            }
-        }
+    #[test]
+    fn jumps_never_cross_solid_cells() {
+        let walled = Map::new(&ledges_raw_map()).unwrap();
+        let (from, to) = (walled.spawns()[0], walled.spawns()[1]);
+        assert!(walled.find_path_positions(&from, &to).is_err());
 
-        // check that cell below is not air
-        if grid[(cell.0, cell.1 - 1, cell.2)] == 0 {
-            return false;
+        let mut raw_map = ledges_raw_map();
+        raw_map.objects.pop();
+        let open = Map::new(&raw_map).unwrap();
+        assert!(open.coverage().jump_edges > walled.coverage().jump_edges);
+
+        for map in [walled, open] {
+            assert_jumps_are_clear(&map);
         }
+    }
 
-        if grid[*cell] == 0 {
-            // make sure that all filled blocks connected to the wrong site of a ramp are not walkable
-            for i in 0..2 {
-                if grid[(cell.0 - 1, cell.1 - i, cell.2)] == 3
-                    || grid[(cell.0 - 1, cell.1 - i, cell.2)] == 5
-                    || grid[(cell.0 + 1, cell.1 - i, cell.2)] == 3
-                    || grid[(cell.0 + 1, cell.1 - i, cell.2)] == 5
-                    || grid[(cell.0, cell.1 - i, cell.2 - 1)] == 2
-                    || grid[(cell.0, cell.1 - i, cell.2 - 1)] == 4
-                    || grid[(cell.0, cell.1 - i, cell.2 + 1)] == 2
-                    || grid[(cell.0, cell.1 - i, cell.2 + 1)] == 4
-                {
-                    return false;
+    fn assert_jumps_are_clear(map: &Map) {
+        for (cell, landings) in &map.jumps {
+            for landing in landings {
+                let (dx, dz) = (
+                    landing.0 as isize - cell.0 as isize,
+                    landing.2 as isize - cell.2 as isize,
+                );
+                let steps = dx.abs().max(dz.abs());
+                for n in 1..steps {
+                    let x = (cell.0 as isize + dx.signum() * n) as usize;
+                    let z = (cell.2 as isize + dz.signum() * n) as usize;
+                    for y in cell.1..cell.1 + PLAYER_HEIGHT {
+                        assert!(!map.solid.get(&(x, y, z)), "{:?} {:?}", cell, landing);
+                    }
                 }
             }
+        }
+    }
 
-            for neighbour in Self::horizontal_neighbours(cell, &grid_size, false) {
-                // check that surrounding cells on the same height or one above or below are not air
-                if grid[(neighbour.0, neighbour.1 - 2, neighbour.2)] == 0
-                    && grid[(neighbour.0, neighbour.1 - 1, neighbour.2)] == 0
-                    && grid[neighbour] == 0
-                {
-                    return false;
-                }
+    #[test]
+    fn teleporters_shorten_paths_around_walls() {
+        let mut raw_map = teleporter_raw_map();
+        let map = Map::new(&raw_map).unwrap();
+        assert_eq!(map.teleporters().len(), 1);
+        let (from, to) = (map.spawns()[0], map.spawns()[1]);
 
-                // check that surrounding cells above are not filled
-                if grid[(neighbour.0, neighbour.1 + 1, neighbour.2)] == 1 {
-                    return false;
-                }
-            }
-        } else if grid[*cell] == 6
-            && (grid[(cell.0 - 1, cell.1, cell.2)] != 6 || grid[(cell.0 + 1, cell.1, cell.2)] != 6)
-            && (grid[(cell.0, cell.1, cell.2 - 1)] != 6 || grid[(cell.0, cell.1, cell.2 + 1)] != 6)
-        {
-            // check that ladders have other ladder cells on both sides in either the x or z direction
-            return false;
-        }
+        let teleported = map.find_path_positions(&from, &to).unwrap();
+        let entry = teleported.teleports.iter().position(|teleport| *teleport);
+        let entry = teleported.waypoints[entry.unwrap()];
+        assert!(
+            (entry.x + 60.0).abs() < 4.0 && (entry.z + 20.0).abs() < 4.0,
+            "{:?}",
+            entry
+        );
+        assert!(teleported.waypoints.iter().all(|waypoint| waypoint.x < 0.0));
 
-        true
+        raw_map.objects.pop();
+        let map = Map::new(&raw_map).unwrap();
+        let walked = map.find_path_positions(&from, &to).unwrap();
+        assert!(!walked.teleports.contains(&true));
+        assert!(walked.waypoints.iter().any(|waypoint| waypoint.x > 80.0));
+        assert!(teleported.cost < walked.cost);
+        assert!(teleported.length < walked.length / 2.0);
     }
 
-    pub fn name(&self) -> String {
-        self.name.clone()
+    #[test]
+    fn spawns_in_the_air_drop_onto_the_floor() {
+        let spawned_at = |y| {
+            let mut raw_map = crate::soak::raw_map("floating");
+            raw_map.spawns = vec![vec![Some(0.0), Some(y), Some(0.0)]];
+            Map::new(&raw_map).unwrap()
+        };
+        let grounded = spawned_at(0.0);
+        let floating = spawned_at(8.0);
+        assert!(grounded.walkable_cell_count() > 80 * 80);
+        assert_eq!(
+            floating.walkable_cell_count(),
+            grounded.walkable_cell_count()
+        );
+
+        let start = floating
+            .closest_walkable_cell(&floating.spawns()[0])
+            .unwrap();
+        let end = floating
+            .closest_walkable_cell(&Vec3 {
+                x: 50.0,
+                y: 0.0,
+                z: 50.0,
+            })
+            .unwrap();
+        assert!(floating.find_path(&start, &end).is_some());
     }
 
-    pub fn spawns(&self) -> Vec<Vec3> {
-        self.spawns.clone()
+    #[test]
+    fn malformed_spawn_rows_are_skipped() {
+        let mut raw_map = crate::soak::raw_map("spawns");
+        raw_map.spawns = vec![
+            vec![
+                Some(-20.0),
+                Some(0.0),
+                Some(0.0),
+                Some(1.0),
+                Some(1.0),
+                Some(1.5),
+            ],
+            vec![None, Some(0.0), Some(0.0), Some(1.0)],
+            vec![Some(0.0), Some(0.0)],
+            vec![],
+            vec![Some(20.0), Some(0.0), Some(0.0), None],
+            vec![Some(0.0), Some(0.0), Some(20.0), Some(2.5)],
+        ];
+        let map = Map::new(&raw_map).unwrap();
+
+        let spawns = map.spawn_details();
+        assert_eq!(spawns.len(), 3);
+        assert_eq!(spawns[0].team, Some(1));
+        assert_eq!(spawns[0].rotation, Some(1.5));
+        assert_eq!(spawns[1].position.x, 20.0);
+        // Teams that aren't an index spawn anyone
+        assert_eq!(spawns[2].team, None);
+        assert_eq!(map.spawns_for_team(1).len(), 3);
+        assert_eq!(map.spawns_for_team(0).len(), 2);
+
+        // Maps without a single usable spawn are still built
+        raw_map.spawns = vec![vec![None, None, None]];
+        assert!(Map::new(&raw_map).unwrap().spawns().is_empty());
     }
 
-    pub fn closest_walkable_cell(&self, position: &Vec3) -> Option<(usize, usize, usize)> {
-        if !self.bounds.contains(position) {
-            return None;
+    #[test]
+    fn floors_without_a_connection_are_separate_components() {
+        let mut raw_map = crate::soak::raw_map("islands");
+        // Two floors of 80 with a gap of 40 between them, too wide to jump
+        raw_map.sizes.extend([80.0, 6.0, 200.0]);
+        raw_map.objects[0] = RawMapObject {
+            position: [-60.0, -6.0, 0.0],
+            size_index: Some(2),
+            ..Default::default()
+        };
+        raw_map.objects.push(RawMapObject {
+            position: [60.0, -6.0, 0.0],
+            size_index: Some(2),
+            ..Default::default()
+        });
+        raw_map.spawns = vec![
+            vec![Some(-60.0), Some(0.0), Some(-40.0)],
+            vec![Some(-60.0), Some(0.0), Some(40.0)],
+            vec![Some(60.0), Some(0.0), Some(0.0)],
+        ];
+        let map = Map::new(&raw_map).unwrap();
+        let cells = map
+            .spawns()
+            .iter()
+            .map(|spawn| map.closest_walkable_cell(spawn).unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(map.same_component(&cells[0], &cells[1]));
+        assert!(!map.same_component(&cells[0], &cells[2]));
+        assert_ne!(map.component_of(&cells[0]), map.component_of(&cells[2]));
+        assert!(map.find_path(&cells[0], &cells[2]).is_none());
+        assert!(map.find_path(&cells[0], &cells[1]).is_some());
+        let spawns = map.spawns();
+        assert!(map.reachable(&spawns[0], &spawns[1]));
+        assert!(!map.reachable(&spawns[0], &spawns[2]));
+
+        // The labels are part of the cached map
+        let cached = bincode::deserialize::<Map>(&bincode::serialize(&map).unwrap()).unwrap();
+        assert!(cached.same_component(&cells[0], &cells[1]));
+        assert!(!cached.same_component(&cells[0], &cells[2]));
+    }
+
+    #[test]
+    fn custom_maps_ignore_unknown_keys() {
+        let json = r##"{
+            "name": "custom",
+            "ambient": "#97a0a8",
+            "xyz": [200, 6, 200, 200, 30, 1],
+            "objects": [
+                { "p": [0, -6, 0], "si": 0, "c": "#ffffff", "t": 3 },
+                { "p": [0, -6, -100], "si": 1, "bo": 1, "v": 1 }
+            ],
+            "config": { "gravity": 0.8, "deathY": -40 },
+            "spawns": [[0, 0, 0]],
+            "camPos": [0, 0, 0]
+        }"##;
+        let map = Map::from_raw_json(json).unwrap();
+        assert_eq!(map.name(), "custom");
+        assert!(map.walkable_cell_count() > 0);
+
+        let raw_map = serde_json::from_str::<RawMap>(json).unwrap();
+        assert!(raw_map.config.modes.is_empty());
+        let without_config = json.replace(r#""config": { "gravity": 0.8, "deathY": -40 },"#, "");
+        let raw_map = serde_json::from_str::<RawMap>(&without_config).unwrap();
+        assert!(raw_map.config.modes.is_empty());
+        assert_eq!(raw_map.objects.len(), 2);
+
+        assert!(Map::from_raw_json(r#"{ "name": "broken" }"#).is_err());
+    }
+
+    #[test]
+    fn degenerate_objects_are_skipped() {
+        let mut raw_map = crate::soak::raw_map("degenerate");
+        // Zero, non-finite and negative sizes, the last group is incomplete
+        raw_map
+            .sizes
+            .extend([0.0, 5.0, 5.0, f32::NAN, 5.0, 5.0, -10.0, 10.0, 10.0, 5.0]);
+        let object = |position: [f32; 3], size_index| RawMapObject {
+            position,
+            size_index: Some(size_index),
+            ..Default::default()
+        };
+        raw_map.objects.extend([
+            object([20.0, 0.0, 20.0], 2),
+            object([20.0, 0.0, 20.0], 3),
+            object([20.0, 0.0, 20.0], 5),
+            object([f32::INFINITY, 0.0, 20.0], 4),
+            RawMapObject {
+                rotation: Some([0.0, f32::NAN, 0.0]),
+                ..object([20.0, 0.0, 20.0], 4)
+            },
+            object([-20.0, 0.0, -20.0], 4),
+        ]);
+
+        let map = Map::new(&raw_map).unwrap();
+        let coverage = map.coverage();
+        assert_eq!(coverage.skipped_objects, 5);
+        assert_eq!(coverage.normalized_objects, 1);
+        assert!(map.bounds().is_finite());
+        // The inverted box is kept with its axes swapped
+        let normalized = map.objects().iter().find(|object| object.min_x == -25.0);
+        assert_eq!(normalized.map(|object| object.max_x), Some(-15.0));
+    }
+
+    #[test]
+    fn maps_without_valid_objects_fail() {
+        let mut raw_map = crate::soak::raw_map("empty");
+        for object in raw_map.objects.iter_mut() {
+            object.position[0] = f32::NAN;
         }
+        assert!(matches!(Map::new(&raw_map), Err(Error::MapParse(_))));
+    }
 
-        let shape = self.walkable_grid.shape();
-        let grid_size = (shape[0], shape[1], shape[2]);
+    // Grid generation before it was done per chunk, every cell is tested against every object of
+    // the first chunk it touches
+    fn reference_grid(map_bounds: &AABB, chunks: &Array2<Chunk>) -> Array3<u8> {
+        let grid_shape = (
+            ((map_bounds.max_x - map_bounds.min_x) / CELL_SIZE).ceil() as usize,
+            ((map_bounds.max_y - map_bounds.min_y) / CELL_SIZE).ceil() as usize,
+            ((map_bounds.max_z - map_bounds.min_z) / CELL_SIZE).ceil() as usize,
+        );
 
-        let cell = position_to_cell(&self.bounds, position);
+        Array3::<u8>::from_shape_fn(grid_shape, |(x, y, z)| {
+            let cell_bounds = AABB {
+                min_x: map_bounds.min_x + x as f32 * CELL_SIZE,
+                min_y: map_bounds.min_y + y as f32 * CELL_SIZE,
+                min_z: map_bounds.min_z + z as f32 * CELL_SIZE,
+                max_x: map_bounds.min_x + x as f32 * CELL_SIZE + CELL_SIZE,
+                max_y: map_bounds.min_y + y as f32 * CELL_SIZE + CELL_SIZE,
+                max_z: map_bounds.min_z + z as f32 * CELL_SIZE + CELL_SIZE,
+            };
 
-        let mut cells = vec![cell];
-        cells.append(&mut Self::horizontal_neighbours(&cell, &grid_size, true));
+            for chunk in chunks.iter() {
+                if chunk.bounds.intersects(&cell_bounds) {
+                    let mut cell = 0_u8;
 
-        for cell in cells {
-            for offset in 0..(PLAYER_HEIGHT * 2.0 as usize) {
-                if cell.1 + offset < grid_size.1
-                    && self.walkable_grid[(cell.0, cell.1 + offset, cell.2)] != 0
-                {
-                    return Some((cell.0, cell.1 + offset, cell.2));
-                }
-                if cell.1 >= offset && self.walkable_grid[(cell.0, cell.1 - offset, cell.2)] != 0 {
-                    return Some((cell.0, cell.1 - offset, cell.2));
+                    for ladder in &chunk.ladders {
+                        if cell_bounds.intersects(ladder) {
+                            cell = 6;
+                            break;
+                        }
+                    }
+
+                    if cell == 0 {
+                        for object in &chunk.objects {
+                            if cell_bounds.intersects(object) {
+                                cell = 1;
+                                break;
+                            }
+                        }
+                    }
+
+                    if cell == 0 {
+                        for rotated in &chunk.rotated {
+                            if rotated.bounds.intersects(&cell_bounds)
+                                && rotated.overlaps(&cell_bounds)
+                            {
+                                if rotated.crosses_column(
+                                    cell_bounds.min_x + CELL_SIZE / 2.0,
+                                    cell_bounds.min_z + CELL_SIZE / 2.0,
+                                    cell_bounds.min_y,
+                                    cell_bounds.max_y,
+                                ) {
+                                    cell = 1;
+                                    break;
+                                }
+                                cell = PARTIAL_CELL;
+                            }
+                        }
+                    }
+
+                    if cell == 0 {
+                        for ramp in &chunk.ramps {
+                            if cell_bounds.intersects(&ramp.bounds) {
+                                cell = 2 + ramp.direction;
+                                break;
+                            }
+                        }
+                    }
+
+                    return cell;
                 }
             }
+
+            panic!("Cell not in a chunk");
+        })
+    }
+
+    // Floor over several chunks with random objects, ramps, ladders and rotated objects on it
+    fn random_raw_map(seed: u64) -> RawMap {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut raw_map = crate::soak::raw_map("random");
+        raw_map.sizes = vec![400.0, 6.0, 400.0, 200.0, 30.0, 1.0];
+        for _ in 0..6 {
+            raw_map
+                .sizes
+                .extend((0..3).map(|_| rng.gen_range(1.0..40.0_f32)));
         }
 
-        None
+        for _ in 0..150 {
+            let id = match rng.gen_range(0..6) {
+                0 => Some(9),
+                1 => Some(3),
+                _ => None,
+            };
+            let rotation = rng
+                .gen_bool(0.3)
+                .then(|| [0.0, rng.gen_range(0.0..std::f32::consts::TAU), 0.0]);
+            raw_map.objects.push(RawMapObject {
+                position: [
+                    rng.gen_range(-190.0..190.0),
+                    rng.gen_range(-6.0..30.0),
+                    rng.gen_range(-190.0..190.0),
+                ],
+                size_index: Some(rng.gen_range(2..22)),
+                id,
+                direction: id.map(|_| rng.gen_range(0..4)),
+                rotation,
+                ..Default::default()
+            });
+        }
+        raw_map
     }
 
-    pub fn find_path(
-        &self,
-        start_cell: &(usize, usize, usize),
-        end_cell: &(usize, usize, usize),
-    ) -> Option<Vec<(usize, usize, usize)>> {
-        let shape = self.walkable_grid.shape();
-        let grid_size = (shape[0], shape[1], shape[2]);
+    #[test]
+    fn grid_matches_the_per_cell_generator() {
+        for seed in 0..2 {
+            let raw_map = random_raw_map(seed);
+            let (map_bounds, _, objects, rotated, ramps, ladders, ..) =
+                Map::filter_objects(&raw_map).unwrap();
+            assert!(!rotated.is_empty() && !ramps.is_empty() && !ladders.is_empty());
 
-        // Calculate the successors of a cell, giving them different cost based on their failure potential.
-        // Cells surrounded by other walkable cells get a cost of 1.
-        // Cells on the edge of the walkable grid get a cost of 2 as it is easier for the player to walk off/against something.
-        // Ladder cells get a cost of 3 as the chance of the player failing to walk up is highest
-        let successors = |cell: &(usize, usize, usize)| -> Vec<((usize, usize, usize), i32)> {
-            Self::neighbours(cell, &grid_size, false)
-                .iter()
-                .filter_map(|c| {
-                    if self.walkable_grid[*c] == 1 {
-                        for n in Self::horizontal_neighbours(c, &grid_size, true) {
-                            if self.walkable_grid[n] == 0
-                                && self.walkable_grid[(n.0, n.1 + 1, n.2)] == 0
-                                && self.walkable_grid[(n.0, n.1 - 1, n.2)] == 0
-                            {
-                                return Some((*c, 3));
-                            }
-                        }
+            let chunks =
+                Map::generate_object_chunks(&map_bounds, &objects, &rotated, &ramps, &ladders);
+            assert!(chunks.len() > 1);
+            let grid = Map::generate_grid(&map_bounds, &chunks);
+            assert!(
+                grid == reference_grid(&map_bounds, &chunks),
+                "grids of seed {} differ",
+                seed
+            );
+        }
+    }
+
+    fn dense(grid: &WalkableGrid) -> Array3<u8> {
+        Array3::from_shape_fn(grid.dim(), |cell| grid[cell])
+    }
 
-                        Some((*c, if cell.1 == c.1 { 1 } else { 2 }))
-                    } else if self.walkable_grid[*c] == 2 {
-                        Some((*c, 3))
-                    } else {
-                        None
+    #[test]
+    fn walkable_grid_is_an_order_of_magnitude_below_the_dense_grid() {
+        // A pillar up to the grid limit makes the map as tall as large real maps
+        let mut raw_map = random_raw_map(0);
+        raw_map.sizes.extend([4.0, 190.0, 4.0]);
+        raw_map.objects.push(RawMapObject {
+            position: [150.0, -6.0, 150.0],
+            size_index: Some(raw_map.sizes.len() / 3 - 1),
+            ..Default::default()
+        });
+        let map = Map::new(&raw_map).unwrap();
+
+        let dense = dense(&map.walkable_grid);
+        assert!(dense.dim().1 > 75);
+        assert!(
+            map.walkable_grid.memory_size() * 10 <= dense.len(),
+            "{} bytes for {} cells",
+            map.walkable_grid.memory_size(),
+            dense.len()
+        );
+
+        // Same cells as the dense grid the map was built from
+        let walkable = dense
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| **value != 0)
+            .map(|(i, _)| i as u32)
+            .collect::<Vec<_>>();
+        assert_eq!(walkable, map.walkable_cells);
+    }
+
+    // A* over the dense grid as it was done before the grid was compacted, for maps without
+    // refined cells, hazards, jumps and teleporters
+    fn reference_search(
+        dense: &Array3<u8>,
+        start: &(usize, usize, usize),
+        end: &(usize, usize, usize),
+        options: &PathOptions,
+    ) -> Option<SearchResult> {
+        let grid_size = dense.dim();
+        let (flat_cost, vertical_cost, edge_cost, crouch_cost, ladder_cost, _) =
+            options.step_costs();
+        let column_walkable = |x: usize, z: usize, ys: Range<usize>| {
+            ys.filter(|y| *y < grid_size.1)
+                .any(|y| dense[(x, y, z)] != 0)
+        };
+
+        let successors = |cell: &(usize, usize, usize)| {
+            let mut successors = Map::neighbours(cell, &grid_size, false)
+                .into_iter()
+                .filter_map(|c| match dense[c] {
+                    1 => {
+                        let edge = Map::horizontal_neighbours(&c, &grid_size, true)
+                            .iter()
+                            .any(|n| !column_walkable(n.0, n.2, n.1.saturating_sub(1)..n.1 + 2));
+                        let cost = if edge {
+                            edge_cost
+                        } else if cell.1 == c.1 {
+                            flat_cost
+                        } else {
+                            vertical_cost
+                        };
+                        Some((c, cost))
                     }
+                    2 => (!options.forbid_ladders).then_some((c, ladder_cost)),
+                    CROUCH_CELL => Some((c, crouch_cost)),
+                    _ => None,
                 })
-                .collect::<Vec<_>>()
+                .collect::<Vec<_>>();
+            successors.sort_unstable_by_key(|(c, cost)| (*cost, c.1, c.0, c.2));
+            successors
         };
-
-        // Simple function that calculates the direct distance from the cell to the end cell
         let heuristic = |cell: &(usize, usize, usize)| {
-            ((cell.0 as f32 - end_cell.0 as f32).powi(2)
-                + (cell.1 as f32 - end_cell.1 as f32).powi(2)
-                + (cell.2 as f32 - end_cell.2 as f32).powi(2))
-            .sqrt()
-            .floor() as i32
+            let distance = ((cell.0 as f32 - end.0 as f32).powi(2)
+                + (cell.1 as f32 - end.1 as f32).powi(2)
+                + (cell.2 as f32 - end.2 as f32).powi(2))
+            .sqrt();
+            (distance * PATH_COST_SCALE as f32 * options.heuristic_weight).floor() as i32
         };
 
-        let success = |cell: &(usize, usize, usize)| *cell == *end_cell;
+        astar(start, successors, heuristic, |cell| cell == end).map(|(path, cost)| (path, cost, 0))
+    }
 
-        let path = astar(start_cell, successors, heuristic, success);
+    #[test]
+    fn paths_match_a_search_over_the_dense_grid() {
+        let options = MapBuildOptions {
+            max_jump_gap: 0,
+            ..Default::default()
+        };
+        let map = Map::with_options(&random_raw_map(1), &options).unwrap();
+        let dense = dense(&map.walkable_grid);
+        let path_options = PathOptions::default();
 
-        if let Some((path, _)) = path {
-            Some(self.simplify_path(&path))
-        } else {
-            None
+        let mut rng = StdRng::seed_from_u64(1);
+        let cell = |i: u32| {
+            let (_, size_y, size_z) = dense.dim();
+            let i = i as usize;
+            (i / (size_y * size_z), i / size_z % size_y, i % size_z)
+        };
+        let mut found = 0;
+        for _ in 0..6 {
+            let start = cell(*map.walkable_cells.choose(&mut rng).unwrap());
+            let end = cell(*map.walkable_cells.choose(&mut rng).unwrap());
+            // Without the components the reference explores everything reachable
+            if map.disconnected(&start, &end) {
+                continue;
+            }
+
+            let search = map
+                .search(&start, &end, &path_options, &HashMap::new())
+                .ok()
+                .map(|(path, cost, _)| (path, cost, 0));
+            let reference = reference_search(&dense, &start, &end, &path_options);
+            assert_eq!(search, reference, "{:?} to {:?}", start, end);
+            if let Some((path, ..)) = reference {
+                assert_eq!(map.find_path(&start, &end), Some(map.simplify_path(&path)));
+                found += 1;
+            }
         }
+        assert!(found > 0);
     }
 
-    fn simplify_path(&self, path: &[(usize, usize, usize)]) -> Vec<(usize, usize, usize)> {
-        if path.len() <= 2 {
-            return Vec::from(path);
+    // Floor of the size with walls across it every 40 units, their gaps alternate between the ends
+    fn serpentine_raw_map(size: f32) -> RawMap {
+        let mut raw_map = crate::soak::raw_map("serpentine");
+        raw_map.sizes = vec![size, 6.0, size, size, 30.0, 1.0, size - 40.0, 20.0, 4.0];
+        raw_map.objects[1].position[2] = -size / 2.0;
+        let walls = (size / 40.0) as usize;
+        for i in 1..walls {
+            let side = if i % 2 == 0 { 20.0 } else { -20.0 };
+            raw_map.objects.push(RawMapObject {
+                position: [side, 0.0, -size / 2.0 + i as f32 * 40.0],
+                size_index: Some(2),
+                ..Default::default()
+            });
         }
+        let corner = size / 2.0 - 10.0;
+        raw_map.spawns = vec![
+            vec![Some(-corner), Some(0.0), Some(-corner)],
+            vec![Some(corner), Some(0.0), Some(corner)],
+        ];
+        raw_map
+    }
 
-        // Simplify the path by checking if the direct path between two cells is walkable.
-        // If it is, try the next cell until one is found that doesn't have a direct walkable path.
-        // In that case add the previous cell to the simplified path and repeat the process starting from that cell.
-        // This eliminates a lot of unnecessary cells and allows the player to walk diagonal.
-        let mut simplified_path = Vec::from([path[0]]);
-        let mut from_cell = path[0];
-        let mut last_cell = path[1];
-        'outer: for cell in &path[2..] {
-            if cell.0 != last_cell.0 || cell.2 != last_cell.2 {
-                for x in cell.0.min(from_cell.0) - 1..cell.0.max(from_cell.0) + 2 {
-                    for z in cell.2.min(from_cell.2) - 1..cell.2.max(from_cell.2) + 2 {
-                        let mut found_filled = false;
-                        for y in cell.1.min(from_cell.1)..cell.1.max(from_cell.1) + 1 {
-                            if self.walkable_grid[(x, y, z)] > 0 {
-                                found_filled = true;
-                                break;
-                            }
-                        }
+    fn hierarchical(raw_map: &RawMap) -> Map {
+        let options = MapBuildOptions {
+            hierarchical_paths: true,
+            ..Default::default()
+        };
+        Map::with_options(raw_map, &options).unwrap()
+    }
 
-                        if !found_filled {
-                            simplified_path.push(last_cell);
-                            from_cell = last_cell;
-                            last_cell = *cell;
-                            continue 'outer;
-                        }
-                    }
-                }
-            }
+    fn path_length(path: &[(usize, usize, usize)]) -> f32 {
+        path.windows(2)
+            .map(|pair| {
+                let (a, b) = (pair[0], pair[1]);
+                ((a.0 as f32 - b.0 as f32).powi(2)
+                    + (a.1 as f32 - b.1 as f32).powi(2)
+                    + (a.2 as f32 - b.2 as f32).powi(2))
+                .sqrt()
+            })
+            .sum()
+    }
 
-            last_cell = *cell;
-        }
+    #[test]
+    fn hierarchical_paths_are_close_to_plain_paths() {
+        let map = hierarchical(&serpentine_raw_map(240.0));
+        assert!(map.portals.cluster_starts.len() > 2);
+        let start = map.closest_walkable_cell(&map.spawns()[0]).unwrap();
+        let end = map.closest_walkable_cell(&map.spawns()[1]).unwrap();
+        assert_ne!(map.portals.cluster_of(&start), map.portals.cluster_of(&end));
 
-        simplified_path.push(last_cell);
-        simplified_path
+        let plain = map.find_path(&start, &end).unwrap();
+        let path = map.find_path_hierarchical(&start, &end).unwrap();
+        assert_eq!((path[0], path[path.len() - 1]), (start, end));
+        assert!(path.iter().all(|cell| map.is_walkable_cell(cell)));
+        assert!(path_length(&path) <= path_length(&plain) * 1.2);
+    }
+
+    #[test]
+    fn hierarchical_paths_within_a_cluster_are_plain_paths() {
+        let map = hierarchical(&serpentine_raw_map(240.0));
+        let start = map.closest_walkable_cell(&map.spawns()[0]).unwrap();
+        let end = (start.0 + 3, start.1, start.2 + 3);
+        assert_eq!(map.portals.cluster_of(&start), map.portals.cluster_of(&end));
+        assert_eq!(
+            map.find_path_hierarchical(&start, &end),
+            map.find_path(&start, &end)
+        );
+
+        // Maps without the portal graph always search directly
+        let map = Map::new(&serpentine_raw_map(240.0)).unwrap();
+        let end = map.closest_walkable_cell(&map.spawns()[1]).unwrap();
+        assert_eq!(
+            map.find_path_hierarchical(&start, &end),
+            map.find_path(&start, &end)
+        );
+    }
+
+    #[tokio::test]
+    async fn the_portal_graph_survives_the_map_cache() {
+        let map = hierarchical(&serpentine_raw_map(240.0));
+        let dir = std::env::temp_dir().join(format!("krunker-portals-{}", std::process::id()));
+        crate::map_cache::store(&dir, "1", std::slice::from_ref(&map))
+            .await
+            .unwrap();
+        let cached = crate::map_cache::load(&dir, "1").await;
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let cached = &cached[0];
+        assert_eq!(cached.portals.portals, map.portals.portals);
+        assert_eq!(cached.portals.cluster_starts, map.portals.cluster_starts);
+        assert_eq!(cached.portals.edges, map.portals.edges);
+        let start = map.closest_walkable_cell(&map.spawns()[0]).unwrap();
+        let end = map.closest_walkable_cell(&map.spawns()[1]).unwrap();
+        assert_eq!(
+            cached.find_path_hierarchical(&start, &end),
+            map.find_path_hierarchical(&start, &end)
+        );
+    }
+
+    // The start is inside a U of walls that opens away from the end, plain A* fills the whole U
+    // before it finds the way around
+    fn trap_raw_map() -> RawMap {
+        let mut raw_map = crate::soak::raw_map("trap");
+        raw_map.sizes = vec![
+            1200.0, 6.0, 1200.0, 1200.0, 30.0, 1.0, 800.0, 20.0, 4.0, 4.0, 20.0, 600.0,
+        ];
+        raw_map.objects[1].position[2] = -600.0;
+        let wall = |position, size_index| RawMapObject {
+            position,
+            size_index: Some(size_index),
+            ..Default::default()
+        };
+        raw_map.objects.extend([
+            wall([0.0, 0.0, 200.0], 2),
+            wall([-400.0, 0.0, -100.0], 3),
+            wall([400.0, 0.0, -100.0], 3),
+        ]);
+        raw_map.spawns = vec![
+            vec![Some(0.0), Some(0.0), Some(0.0)],
+            vec![Some(0.0), Some(0.0), Some(500.0)],
+        ];
+        raw_map
+    }
+
+    // Takes a while in debug builds, run with --release --ignored
+    #[test]
+    #[ignore]
+    fn hierarchical_paths_are_an_order_of_magnitude_faster() {
+        let map = hierarchical(&trap_raw_map());
+        let start = map.closest_walkable_cell(&map.spawns()[0]).unwrap();
+        let end = map.closest_walkable_cell(&map.spawns()[1]).unwrap();
+        let options = PathOptions::default();
+
+        // Both paths are simplified the same way, only the searches are compared
+        let timed = |search: &dyn Fn() -> Option<Vec<(usize, usize, usize)>>| {
+            let started = std::time::Instant::now();
+            assert!(search().is_some());
+            started.elapsed()
+        };
+        let plain = timed(&|| {
+            map.search(&start, &end, &options, &HashMap::new())
+                .ok()
+                .map(|(path, _, _)| path)
+        });
+        let fast = timed(&|| map.hierarchical_search(&start, &end));
+        assert!(fast * 10 <= plain, "{:?} against {:?}", fast, plain);
     }
 }
@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{map::Map, utils::Error};
+
+const CACHE_FILE: &str = "maps.bin";
+// Bumped whenever the layout of Map or the meaning of its grids changes, caches of other formats
+// are parsed again
+const CACHE_FORMAT: u32 = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MapCache {
+    format: u32,
+    version: String,
+    maps: Vec<Map>,
+}
+
+// Maps cached for the version, empty if there is no usable cache. A broken cache only means the
+// maps are parsed again.
+pub(crate) async fn load(dir: &Path, version: &str) -> Vec<Map> {
+    let path = dir.join(CACHE_FILE);
+    let bytes = match tokio::fs::read(&path).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to read map cache {}: {}", path.display(), err);
+            }
+            return vec![];
+        }
+    };
+
+    match bincode::deserialize::<MapCache>(&bytes) {
+        Ok(cache) if cache.format != CACHE_FORMAT => {
+            info!("Map cache {} has an old format", path.display());
+            vec![]
+        }
+        Ok(cache) if cache.version == version => {
+            info!("Loaded {} maps from {}", cache.maps.len(), path.display());
+            cache.maps
+        }
+        Ok(cache) => {
+            info!(
+                "Map cache is for version {}, the game is at {}",
+                cache.version, version
+            );
+            vec![]
+        }
+        Err(err) => {
+            warn!("Map cache {} is corrupt: {}", path.display(), err);
+            vec![]
+        }
+    }
+}
+
+pub(crate) async fn store(dir: &Path, version: &str, maps: &[Map]) -> Result<(), Error> {
+    let bytes = bincode::serialize(&MapCache {
+        format: CACHE_FORMAT,
+        version: version.to_owned(),
+        maps: maps.to_vec(),
+    })?;
+
+    // Written next to the cache and renamed, so an interrupted write never leaves half a cache
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(CACHE_FILE);
+    let partial = path.with_extension("bin.partial");
+    tokio::fs::write(&partial, bytes).await?;
+    tokio::fs::rename(&partial, &path).await?;
+
+    info!("Stored {} maps in {}", maps.len(), path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn cache_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("krunker-cache-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn maps() -> Vec<Map> {
+        vec![Map::new(&crate::soak::raw_map("cached")).unwrap()]
+    }
+
+    #[tokio::test]
+    async fn stored_maps_are_loaded_for_the_same_version() {
+        let dir = cache_dir("stored");
+        assert!(load(&dir, "1.0").await.is_empty());
+
+        store(&dir, "1.0", &maps()).await.unwrap();
+        let loaded = load(&dir, "1.0").await;
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].fingerprint, maps()[0].fingerprint);
+        // Nothing is left behind by the write
+        assert!(!dir.join("maps.bin.partial").exists());
+
+        // Another version of the game parses the maps again
+        assert!(load(&dir, "1.1").await.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn broken_caches_are_parsed_again() {
+        let dir = cache_dir("broken");
+        store(&dir, "1.0", &maps()).await.unwrap();
+        let path = dir.join(CACHE_FILE);
+        let bytes = std::fs::read(&path).unwrap();
+
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(load(&dir, "1.0").await.is_empty());
+        std::fs::write(&path, b"not a map cache").unwrap();
+        assert!(load(&dir, "1.0").await.is_empty());
+
+        // The cache stored after parsing replaces the broken one
+        store(&dir, "1.0", &maps()).await.unwrap();
+        assert_eq!(load(&dir, "1.0").await.len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn caches_of_another_format_are_parsed_again() {
+        let dir = cache_dir("format");
+        std::fs::create_dir_all(&dir).unwrap();
+        let bytes = bincode::serialize(&MapCache {
+            format: CACHE_FORMAT - 1,
+            version: "1.0".to_owned(),
+            maps: maps(),
+        })
+        .unwrap();
+        std::fs::write(dir.join(CACHE_FILE), bytes).unwrap();
+
+        assert!(load(&dir, "1.0").await.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
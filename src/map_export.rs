@@ -0,0 +1,170 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    map::{Map, Ramp, MAX_MAP_BOUNDS},
+    utils::{Error, AABB},
+};
+
+const BLOCKED: [u8; 3] = [0, 0, 0];
+const WALKABLE: [u8; 3] = [255, 255, 255];
+const LADDER: [u8; 3] = [0, 0, 255];
+const PATH: [u8; 3] = [255, 0, 0];
+
+impl Map {
+    // One image per y layer of the walkable grid, x to the right and z downwards
+    pub fn export_walkable_slices(&self, dir: &Path) -> Result<(), Error> {
+        let (_, size_y, _) = self.walkable_grid.dim();
+        for y in 0..size_y {
+            self.write_slice(dir, y, &[])?;
+        }
+        Ok(())
+    }
+
+    // Only the layers the path goes through are written, with the path cells drawn over them
+    pub fn export_path_overlay(
+        &self,
+        dir: &Path,
+        cells: &[(usize, usize, usize)],
+    ) -> Result<(), Error> {
+        let mut layers = cells.iter().map(|cell| cell.1).collect::<Vec<_>>();
+        layers.sort_unstable();
+        layers.dedup();
+
+        for y in layers {
+            self.write_slice(dir, y, cells)?;
+        }
+        Ok(())
+    }
+
+    fn write_slice(
+        &self,
+        dir: &Path,
+        y: usize,
+        path: &[(usize, usize, usize)],
+    ) -> Result<(), Error> {
+        let (size_x, size_y, size_z) = self.walkable_grid.dim();
+        if y >= size_y {
+            return Err(format!("Layer {} is outside of the grid", y).into());
+        }
+
+        let mut data = Vec::with_capacity(size_x * size_z * 3);
+        for z in 0..size_z {
+            for x in 0..size_x {
+                let color = match self.walkable_grid[(x, y, z)] {
+                    0 => BLOCKED,
+                    2 => LADDER,
+                    _ => WALKABLE,
+                };
+                data.extend_from_slice(&color);
+            }
+        }
+        for &(x, _, z) in path.iter().filter(|cell| cell.1 == y) {
+            if x < size_x && z < size_z {
+                let i = (z * size_x + x) * 3;
+                data[i..i + 3].copy_from_slice(&PATH);
+            }
+        }
+
+        std::fs::create_dir_all(dir)?;
+        let file = File::create(dir.join(format!("{}_y{:03}.png", file_name(&self.name()), y)))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), size_x as u32, size_z as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(())
+    }
+
+    // Collision geometry as a Wavefront OBJ, one box per object and ladder and one slanted quad per
+    // ramp. Border objects reach up to the top of the map bounds like they do for collision.
+    pub fn export_obj(&self, writer: impl Write) -> Result<(), Error> {
+        let mut obj = ObjWriter {
+            writer: BufWriter::new(writer),
+            vertices: 0,
+        };
+        writeln!(obj.writer, "# {}", self.name())?;
+
+        let (border, objects): (Vec<&AABB>, Vec<&AABB>) = self
+            .objects()
+            .iter()
+            .partition(|object| object.max_y >= MAX_MAP_BOUNDS.max_y);
+        obj.group("objects")?;
+        for bounds in objects {
+            obj.cuboid(bounds)?;
+        }
+        obj.group("border")?;
+        for bounds in border {
+            obj.cuboid(bounds)?;
+        }
+        obj.group("ramps")?;
+        for ramp in self.ramps() {
+            obj.ramp(ramp)?;
+        }
+        obj.group("ladders")?;
+        for bounds in self.ladders() {
+            obj.cuboid(bounds)?;
+        }
+
+        obj.writer.flush()?;
+        Ok(())
+    }
+}
+
+struct ObjWriter<W: Write> {
+    writer: W,
+    vertices: usize,
+}
+
+impl<W: Write> ObjWriter<W> {
+    fn group(&mut self, name: &str) -> Result<(), Error> {
+        writeln!(self.writer, "g {}", name)?;
+        Ok(())
+    }
+
+    // Face indices start at 1 and count the vertices of the whole file
+    fn face(&mut self, corners: &[[f32; 3]]) -> Result<(), Error> {
+        for [x, y, z] in corners {
+            writeln!(self.writer, "v {} {} {}", x, y, z)?;
+        }
+        write!(self.writer, "f")?;
+        for i in 0..corners.len() {
+            write!(self.writer, " {}", self.vertices + i + 1)?;
+        }
+        writeln!(self.writer)?;
+        self.vertices += corners.len();
+        Ok(())
+    }
+
+    fn cuboid(&mut self, b: &AABB) -> Result<(), Error> {
+        let (x0, y0, z0, x1, y1, z1) = (b.min_x, b.min_y, b.min_z, b.max_x, b.max_y, b.max_z);
+        self.face(&[[x0, y0, z0], [x1, y0, z0], [x1, y0, z1], [x0, y0, z1]])?;
+        self.face(&[[x0, y1, z0], [x0, y1, z1], [x1, y1, z1], [x1, y1, z0]])?;
+        self.face(&[[x0, y0, z0], [x0, y1, z0], [x1, y1, z0], [x1, y0, z0]])?;
+        self.face(&[[x0, y0, z1], [x1, y0, z1], [x1, y1, z1], [x0, y1, z1]])?;
+        self.face(&[[x0, y0, z0], [x0, y0, z1], [x0, y1, z1], [x0, y1, z0]])?;
+        self.face(&[[x1, y0, z0], [x1, y1, z0], [x1, y1, z1], [x1, y0, z1]])
+    }
+
+    // The surface going from the bottom of the low side to the top of the high side. Directions 0
+    // to 3 rise towards +x, +z, -x and -z.
+    fn ramp(&mut self, ramp: &Ramp) -> Result<(), Error> {
+        let b = ramp.bounds();
+        let (x0, y0, z0, x1, y1, z1) = (b.min_x, b.min_y, b.min_z, b.max_x, b.max_y, b.max_z);
+        match ramp.direction() % 4 {
+            0 => self.face(&[[x0, y0, z0], [x0, y0, z1], [x1, y1, z1], [x1, y1, z0]]),
+            1 => self.face(&[[x0, y0, z0], [x0, y1, z1], [x1, y1, z1], [x1, y0, z0]]),
+            2 => self.face(&[[x0, y1, z0], [x0, y1, z1], [x1, y0, z1], [x1, y0, z0]]),
+            _ => self.face(&[[x0, y1, z0], [x0, y0, z1], [x1, y0, z1], [x1, y1, z0]]),
+        }
+    }
+}
+
+// Map names can contain spaces and other characters that don't belong in file names
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,298 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::{RequestBuilder, Response};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{retry::RetryPolicy, utils::Error};
+
+pub const DEFAULT_MATCHMAKER_URL: &str = "https://matchmaker.krunker.io";
+const FAILOVER_COOLDOWN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointHealth {
+    pub url: String,
+    pub healthy: bool,
+    pub preferred: bool,
+    pub consecutive_failures: usize,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: String,
+    consecutive_failures: usize,
+    unhealthy_until: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl Endpoint {
+    fn is_healthy(&self) -> bool {
+        self.unhealthy_until
+            .is_none_or(|until| Instant::now() >= until)
+    }
+}
+
+#[derive(Debug)]
+struct MatchmakerState {
+    endpoints: Vec<Endpoint>,
+    preferred: usize,
+}
+
+// Matchmaker base urls (the primary and its mirrors) with their health state, shared between
+// the Client and all Games created by it
+#[derive(Debug)]
+pub(crate) struct Matchmaker {
+    state: Mutex<MatchmakerState>,
+    retry: RetryPolicy,
+}
+
+impl Matchmaker {
+    pub(crate) fn new(urls: &[&str], retry: RetryPolicy) -> Self {
+        Self {
+            retry,
+            state: Mutex::new(MatchmakerState {
+                endpoints: urls
+                    .iter()
+                    .map(|url| Endpoint {
+                        url: url.trim_end_matches('/').to_owned(),
+                        consecutive_failures: 0,
+                        unhealthy_until: None,
+                        last_error: None,
+                    })
+                    .collect(),
+                preferred: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn add_mirror(&self, url: &str) {
+        self.state.lock().unwrap().endpoints.push(Endpoint {
+            url: url.trim_end_matches('/').to_owned(),
+            consecutive_failures: 0,
+            unhealthy_until: None,
+            last_error: None,
+        });
+    }
+
+    pub(crate) fn health(&self) -> Vec<EndpointHealth> {
+        let state = self.state.lock().unwrap();
+        state
+            .endpoints
+            .iter()
+            .enumerate()
+            .map(|(i, endpoint)| EndpointHealth {
+                url: endpoint.url.clone(),
+                healthy: endpoint.is_healthy(),
+                preferred: i == state.preferred,
+                consecutive_failures: endpoint.consecutive_failures,
+                last_error: endpoint.last_error.clone(),
+            })
+            .collect()
+    }
+
+    // The endpoint that worked last comes first, then the other healthy ones in the configured order.
+    // Endpoints in their cooldown are only tried as a last resort.
+    fn candidates(&self) -> Vec<(usize, String)> {
+        let state = self.state.lock().unwrap();
+
+        let mut order = (0..state.endpoints.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| (!state.endpoints[*i].is_healthy(), *i != state.preferred, *i));
+
+        order
+            .into_iter()
+            .map(|i| (i, state.endpoints[i].url.clone()))
+            .collect()
+    }
+
+    fn mark_healthy(&self, i: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.preferred = i;
+        let endpoint = &mut state.endpoints[i];
+        endpoint.consecutive_failures = 0;
+        endpoint.unhealthy_until = None;
+    }
+
+    fn mark_failed(&self, i: usize, err: String) {
+        let mut state = self.state.lock().unwrap();
+        let endpoint = &mut state.endpoints[i];
+        warn!("Matchmaker {} failed: {}", endpoint.url, err);
+        endpoint.consecutive_failures += 1;
+        endpoint.unhealthy_until = Some(Instant::now() + FAILOVER_COOLDOWN);
+        endpoint.last_error = Some(err);
+    }
+
+    // Every attempt goes through all endpoints, see send_once
+    pub(crate) async fn send(
+        &self,
+        path: &str,
+        build: impl Fn(String) -> RequestBuilder,
+    ) -> Result<Response, Error> {
+        self.retry
+            .send(&format!("Matchmaker request {}", path), || {
+                self.send_once(path, &build)
+            })
+            .await
+    }
+
+    // Send the request built for the path to the matchmaker endpoints until one of them responds
+    // without a connection error or server error
+    async fn send_once(
+        &self,
+        path: &str,
+        build: &impl Fn(String) -> RequestBuilder,
+    ) -> Result<Response, Error> {
+        let mut last_err: Option<Error> = None;
+
+        for (i, base) in self.candidates() {
+            match build(format!("{}{}", base, path)).send().await {
+                Ok(res) if res.status().is_server_error() => {
+                    let status = res.status().as_u16();
+                    let body = res.text().await.unwrap_or_default();
+                    self.mark_failed(i, format!("Matchmaker responded with {}", status));
+                    last_err = Some(Error::Matchmaker { status, body });
+                }
+                Ok(res) => {
+                    self.mark_healthy(i);
+                    return Ok(res);
+                }
+                Err(err) if err.is_connect() || err.is_timeout() => {
+                    self.mark_failed(i, err.to_string());
+                    last_err = Some(err.into());
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| "No matchmaker endpoint configured".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    // Local http server answering every request with the current status, counting the requests
+    async fn server(status: Arc<AtomicU16>, hits: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 4096];
+                let mut read = 0;
+                while !request[..read].windows(4).any(|end| end == b"\r\n\r\n") {
+                    match stream.read(&mut request[read..]).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => read += n,
+                    }
+                }
+                hits.fetch_add(1, Ordering::Relaxed);
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}",
+                    status.load(Ordering::Relaxed)
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    struct Server {
+        url: String,
+        status: Arc<AtomicU16>,
+        hits: Arc<AtomicUsize>,
+    }
+
+    impl Server {
+        async fn spawn(status: u16) -> Self {
+            let status = Arc::new(AtomicU16::new(status));
+            let hits = Arc::new(AtomicUsize::new(0));
+            Self {
+                url: server(status.clone(), hits.clone()).await,
+                status,
+                hits,
+            }
+        }
+
+        fn hits(&self) -> usize {
+            self.hits.load(Ordering::Relaxed)
+        }
+    }
+
+    async fn get(matchmaker: &Matchmaker) -> Result<u16, Error> {
+        let http = reqwest::Client::new();
+        let res = matchmaker.send("/game-list", |url| http.get(url)).await?;
+        Ok(res.status().as_u16())
+    }
+
+    #[tokio::test]
+    async fn server_errors_fail_over_to_the_mirror() {
+        let primary = Server::spawn(503).await;
+        let mirror = Server::spawn(200).await;
+        let matchmaker = Matchmaker::new(&[&primary.url, &mirror.url], RetryPolicy::none());
+
+        assert_eq!(get(&matchmaker).await.unwrap(), 200);
+        assert_eq!((primary.hits(), mirror.hits()), (1, 1));
+
+        let health = matchmaker.health();
+        assert!(!health[0].healthy && !health[0].preferred);
+        assert_eq!(health[0].consecutive_failures, 1);
+        assert_eq!(
+            health[0].last_error.as_deref(),
+            Some("Matchmaker responded with 503")
+        );
+        assert!(health[1].healthy && health[1].preferred);
+    }
+
+    #[tokio::test]
+    async fn the_mirror_stays_preferred_during_the_cooldown() {
+        let primary = Server::spawn(500).await;
+        let mirror = Server::spawn(200).await;
+        let matchmaker = Matchmaker::new(&[&primary.url, &mirror.url], RetryPolicy::none());
+
+        get(&matchmaker).await.unwrap();
+        // The primary recovered, but is not asked again until its cooldown is over
+        primary.status.store(200, Ordering::Relaxed);
+        for _ in 0..3 {
+            assert_eq!(get(&matchmaker).await.unwrap(), 200);
+        }
+        assert_eq!((primary.hits(), mirror.hits()), (1, 4));
+        assert!(matchmaker.health()[1].preferred);
+
+        // Endpoints in their cooldown are still the last resort
+        mirror.status.store(502, Ordering::Relaxed);
+        assert_eq!(get(&matchmaker).await.unwrap(), 200);
+        assert_eq!((primary.hits(), mirror.hits()), (2, 5));
+
+        let health = matchmaker.health();
+        assert!(health[0].healthy && health[0].preferred);
+        assert_eq!(health[0].consecutive_failures, 0);
+        assert!(!health[1].healthy);
+    }
+
+    #[tokio::test]
+    async fn the_last_server_error_is_returned_when_every_endpoint_fails() {
+        let primary = Server::spawn(500).await;
+        let mirror = Server::spawn(503).await;
+        let matchmaker = Matchmaker::new(&[&primary.url, &mirror.url], RetryPolicy::none());
+
+        match get(&matchmaker).await {
+            Err(Error::Matchmaker { status, .. }) => assert_eq!(status, 503),
+            other => panic!("expected a matchmaker error, got {:?}", other),
+        }
+        assert!(matchmaker.health().iter().all(|endpoint| !endpoint.healthy));
+    }
+}
@@ -1,12 +1,51 @@
-use std::time::Duration;
+use std::{f32::consts::PI, time::Duration};
 
 use serde_json::{json, Value};
 
 use crate::{
+    input::InputState,
     player::Account,
     utils::{Error, Vec3},
 };
 
+// The game doesn't let players look further up or down
+pub const MAX_PITCH: f32 = 80.0 * PI / 180.0;
+
+#[derive(Debug, Clone)]
+pub struct ServerMessage {
+    pub kind: String,
+    pub data: Vec<Value>,
+}
+
+// What happens to older messages of a kind when a backlog is collapsed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BacklogRule {
+    Keep,
+    // Older messages are folded into the newest one, it carries the full state
+    Newest,
+    // Older messages are dropped, only the newest one is answered
+    ReplyNewest,
+}
+
+// Kinds that aren't listed are kept, so nothing unknown is ever lost
+const BACKLOG_RULES: &[(&str, BacklogRule)] = &[
+    ("pi", BacklogRule::ReplyNewest),
+    // Deaths are always kept, see MessageParser::is_death
+    ("l", BacklogRule::Newest),
+    ("0", BacklogRule::Keep),
+    ("init", BacklogRule::Keep),
+    ("ready", BacklogRule::Keep),
+    ("end", BacklogRule::Keep),
+    ("error", BacklogRule::Keep),
+];
+
+pub fn backlog_rule(kind: &str) -> BacklogRule {
+    BACKLOG_RULES
+        .iter()
+        .find(|(rule_kind, _)| *rule_kind == kind)
+        .map_or(BacklogRule::Keep, |(_, rule)| *rule)
+}
+
 pub struct MessageBuilder;
 
 impl MessageBuilder {
@@ -60,38 +99,38 @@ impl MessageBuilder {
         ])
     }
 
-    pub fn init_tick() -> Value {
-        json!(["q", 0, 0, "3000", 2, [0, 0], { "0-4": -1, "0-5": 0, "0-6": 0, "0-7": 0, "0-8": 0, "0-9": 0, "0-10": 0, "0-11": 0, "0-12": 0, "0-13": 0, "0-14": 0 }])
+    pub fn init_tick(slot: u8) -> Value {
+        json!([
+            "q",
+            0,
+            0,
+            "3000",
+            2,
+            [0, 0],
+            InputState::new(slot).to_value()
+        ])
     }
 
     pub fn tick(
         num_tick: u32,
         tick_interval: &Duration,
-        rotation: Option<f32>,
-        state_str: Option<String>,
-    ) -> Result<Value, Error> {
-        let rotation = if let Some(rotation) = rotation {
-            json!([0, (rotation * -1000.0).round() as i32])
+        // Yaw and pitch
+        rotation: Option<(f32, f32)>,
+        inputs: Option<&InputState>,
+    ) -> Value {
+        let rotation = if let Some((yaw, pitch)) = rotation {
+            json!([
+                (pitch.clamp(-MAX_PITCH, MAX_PITCH) * 1000.0).round() as i32,
+                (yaw * -1000.0).round() as i32
+            ])
         } else {
             json!(())
         };
 
-        let state = if let Some(state_str) = state_str {
-            serde_json::from_str(&state_str)?
-        } else {
-            json!(())
-        };
+        let state = inputs.map_or(json!(()), InputState::to_value);
 
         let dt = ((tick_interval.as_micros() as f32 / 10.0).round() as i32).min(3333);
-        Ok(json!([
-            "q",
-            0,
-            num_tick,
-            dt.to_string(),
-            2,
-            rotation,
-            state
-        ]))
+        json!(["q", 0, num_tick, dt.to_string(), 2, rotation, state])
     }
 }
 
@@ -152,6 +191,52 @@ impl MessageParser {
         }
     }
 
+    // Slot of the player in a spawn message, the value between its id and its position. The input
+    // keys start with it.
+    pub fn spawn_slot(msg: &[Value], id: &str) -> Result<Option<u8>, Error> {
+        let positions = msg
+            .first()
+            .ok_or("Wrong Message Type")?
+            .as_array()
+            .ok_or("Wrong Message Type")?;
+
+        Ok(positions
+            .iter()
+            .position(|p| p.as_str() == Some(id))
+            .and_then(|id_index| positions.get(id_index + 1)?.as_u64())
+            .and_then(|slot| u8::try_from(slot).ok()))
+    }
+
+    // Ids and positions of all players in a spawn message, an id is followed by the slot and the
+    // position
+    pub fn spawn_players(msg: &[Value]) -> Result<Vec<(String, Vec3)>, Error> {
+        let positions = msg
+            .first()
+            .ok_or("Wrong Message Type")?
+            .as_array()
+            .ok_or("Wrong Message Type")?;
+
+        Ok(positions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| {
+                let id = p.as_str()?;
+                let coordinate = |j: usize| Some(positions.get(i + j)?.as_f64()? as f32);
+                let position = Vec3 {
+                    x: coordinate(2)?,
+                    y: coordinate(3)?,
+                    z: coordinate(4)?,
+                };
+                Some((id.to_owned(), position))
+            })
+            .collect())
+    }
+
+    // Player update that reports our death
+    pub fn is_death(msg: &[Value]) -> bool {
+        msg.first().and_then(Value::as_i64) == Some(0)
+    }
+
     pub fn player_state(msg: &[Value]) -> Result<PlayerState, Error> {
         let first = msg.first().ok_or("Wrong Message Type")?;
 
@@ -170,7 +255,7 @@ impl MessageParser {
                 is_dead: false,
                 tick: Some(
                     first
-                        .get(0)
+                        .first()
                         .ok_or("Wrong Message Type")?
                         .as_i64()
                         .ok_or("Tick has wrong type")? as u32,
@@ -198,6 +283,34 @@ impl MessageParser {
         }
     }
 
+    // Ids of the killer and the victim
+    pub fn kill(msg: &[Value]) -> Result<(String, String), Error> {
+        let id = |i: usize| -> Result<String, Error> {
+            Ok(msg
+                .get(i)
+                .ok_or("Wrong Message Type")?
+                .as_str()
+                .ok_or("Wrong Message Type")?
+                .to_owned())
+        };
+        Ok((id(0)?, id(1)?))
+    }
+
+    // Id of the sender and the text
+    pub fn chat(msg: &[Value]) -> Result<(String, String), Error> {
+        let sender = match msg.first().ok_or("Wrong Message Type")? {
+            Value::String(sender) => sender.clone(),
+            sender => sender.to_string(),
+        };
+        let text = msg
+            .get(1)
+            .ok_or("Wrong Message Type")?
+            .as_str()
+            .ok_or("Wrong Message Type")?
+            .to_owned();
+        Ok((sender, text))
+    }
+
     pub fn error(msg: &[Value]) -> String {
         msg.first()
             .unwrap_or(&Value::String(String::from("")))
@@ -206,3 +319,69 @@ impl MessageParser {
             .to_owned()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn backlog_rules_keep_unlisted_kinds() {
+        assert_eq!(backlog_rule("pi"), BacklogRule::ReplyNewest);
+        assert_eq!(backlog_rule("l"), BacklogRule::Newest);
+        assert_eq!(backlog_rule("end"), BacklogRule::Keep);
+        assert_eq!(backlog_rule("unknown"), BacklogRule::Keep);
+    }
+
+    #[test]
+    fn init_tick_matches_the_official_client() {
+        assert_eq!(
+            MessageBuilder::init_tick(0),
+            json!(["q", 0, 0, "3000", 2, [0, 0], {
+                "0-4": -1, "0-5": 0, "0-6": 0, "0-7": 0, "0-8": 0, "0-9": 0, "0-10": 0, "0-11": 0,
+                "0-12": 0, "0-13": 0, "0-14": 0
+            }])
+        );
+    }
+
+    #[test]
+    fn tick_without_rotation_or_inputs() {
+        assert_eq!(
+            MessageBuilder::tick(7, &Duration::from_millis(20), None, None),
+            json!(["q", 0, 7, "2000", 2, (), ()])
+        );
+        // The delta is capped
+        assert_eq!(
+            MessageBuilder::tick(7, &Duration::from_millis(66), None, None)[3],
+            json!("3333")
+        );
+    }
+
+    #[test]
+    fn spawn_slots_follow_the_id() {
+        let msg = [json!([
+            "a", 0, 1.0, 2.0, 3.0, "b", 2, 4.0, 5.0, 6.0, "c", 300, 0, 0, 0
+        ])];
+        assert_eq!(MessageParser::spawn_slot(&msg, "a").unwrap(), Some(0));
+        assert_eq!(MessageParser::spawn_slot(&msg, "b").unwrap(), Some(2));
+        // Not a slot the input keys could use
+        assert_eq!(MessageParser::spawn_slot(&msg, "c").unwrap(), None);
+        assert_eq!(MessageParser::spawn_slot(&msg, "d").unwrap(), None);
+        assert!(MessageParser::spawn_slot(&[json!(0)], "a").is_err());
+    }
+
+    #[test]
+    fn tick_with_inputs() {
+        let inputs = InputState {
+            walk: true,
+            jump: true,
+            ..InputState::new(0)
+        };
+        let tick = MessageBuilder::tick(1, &Duration::from_millis(50), None, Some(&inputs));
+        assert_eq!(tick[6]["0-4"], json!(1));
+        assert_eq!(tick[6]["0-7"], json!(1));
+        assert_eq!(tick[6]["0-5"], json!(0));
+        assert_eq!(tick[6].as_object().unwrap().len(), 11);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ObjectiveKind {
+    Kills,
+    Hardpoint,
+    Flag,
+    Parkour,
+    Survival,
+    Infection,
+    Deposit,
+    Bomb,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ModeInfo {
+    pub id: u8,
+    pub name: &'static str,
+    pub team_based: bool,
+    pub respawns: bool,
+    pub objective: ObjectiveKind,
+}
+
+const fn mode(
+    id: u8,
+    name: &'static str,
+    team_based: bool,
+    respawns: bool,
+    objective: ObjectiveKind,
+) -> ModeInfo {
+    ModeInfo {
+        id,
+        name,
+        team_based,
+        respawns,
+        objective,
+    }
+}
+
+// Indexed by the mode id the matchmaker reports in the game list. Best-effort: the names and
+// flags are written down from playing the modes, not sourced from the game config, and can be
+// wrong for modes that changed since
+#[rustfmt::skip]
+const MODES: [ModeInfo; 22] = [
+    mode(0, "Free for All", false, true, ObjectiveKind::Kills),
+    mode(1, "Team Deathmatch", true, true, ObjectiveKind::Kills),
+    mode(2, "Hardpoint", true, true, ObjectiveKind::Hardpoint),
+    mode(3, "Capture the Flag", true, true, ObjectiveKind::Flag),
+    mode(4, "Parkour", false, true, ObjectiveKind::Parkour),
+    mode(5, "Hide & Seek", true, false, ObjectiveKind::Survival),
+    mode(6, "Infected", true, true, ObjectiveKind::Infection),
+    mode(7, "Race", false, true, ObjectiveKind::Parkour),
+    mode(8, "Last Man Standing", false, false, ObjectiveKind::Survival),
+    mode(9, "Simon Says", false, false, ObjectiveKind::Survival),
+    mode(10, "Gun Game", false, true, ObjectiveKind::Kills),
+    mode(11, "Prop Hunt", true, false, ObjectiveKind::Survival),
+    mode(12, "Boss Hunt", true, true, ObjectiveKind::Other),
+    mode(13, "Classic FFA", false, true, ObjectiveKind::Kills),
+    mode(14, "Deposit", true, true, ObjectiveKind::Deposit),
+    mode(15, "Stalker", true, false, ObjectiveKind::Survival),
+    mode(16, "King of the Hill", false, true, ObjectiveKind::Hardpoint),
+    mode(17, "One in the Chamber", false, false, ObjectiveKind::Kills),
+    mode(18, "Trade", true, true, ObjectiveKind::Other),
+    mode(19, "Kill Confirmed", true, true, ObjectiveKind::Kills),
+    mode(20, "Defuse", true, false, ObjectiveKind::Bomb),
+    mode(21, "Sharp Shooter", false, true, ObjectiveKind::Kills),
+];
+
+impl ModeInfo {
+    // Unknown modes are assumed to allow respawning without teams, which keeps the default player behavior
+    pub fn for_id(id: u8) -> Self {
+        MODES.get(id as usize).copied().unwrap_or(ModeInfo {
+            id,
+            name: "Unknown",
+            team_based: false,
+            respawns: true,
+            objective: ObjectiveKind::Other,
+        })
+    }
+
+    pub fn all() -> &'static [ModeInfo] {
+        &MODES
+    }
+}
+
+// Mode of a game as reported by the matchmaker. Ids without a variant are kept in Unknown, which
+// parses and serializes back to the same id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "u8", into = "u8")]
+pub enum GameMode {
+    Ffa,
+    Tdm,
+    Hardpoint,
+    Ctf,
+    Parkour,
+    HideAndSeek,
+    Infected,
+    Race,
+    LastManStanding,
+    SimonSays,
+    GunGame,
+    PropHunt,
+    BossHunt,
+    ClassicFfa,
+    Deposit,
+    Stalker,
+    KingOfTheHill,
+    OneInTheChamber,
+    Trade,
+    KillConfirmed,
+    Defuse,
+    SharpShooter,
+    Unknown(u8),
+}
+
+// Indexed by the mode id like MODES, the keys are used by Display and FromStr
+#[rustfmt::skip]
+const GAME_MODES: [(GameMode, &str); 22] = [
+    (GameMode::Ffa, "ffa"),
+    (GameMode::Tdm, "tdm"),
+    (GameMode::Hardpoint, "hardpoint"),
+    (GameMode::Ctf, "ctf"),
+    (GameMode::Parkour, "parkour"),
+    (GameMode::HideAndSeek, "hide-and-seek"),
+    (GameMode::Infected, "infected"),
+    (GameMode::Race, "race"),
+    (GameMode::LastManStanding, "last-man-standing"),
+    (GameMode::SimonSays, "simon-says"),
+    (GameMode::GunGame, "gun-game"),
+    (GameMode::PropHunt, "prop-hunt"),
+    (GameMode::BossHunt, "boss-hunt"),
+    (GameMode::ClassicFfa, "classic-ffa"),
+    (GameMode::Deposit, "deposit"),
+    (GameMode::Stalker, "stalker"),
+    (GameMode::KingOfTheHill, "king-of-the-hill"),
+    (GameMode::OneInTheChamber, "one-in-the-chamber"),
+    (GameMode::Trade, "trade"),
+    (GameMode::KillConfirmed, "kill-confirmed"),
+    (GameMode::Defuse, "defuse"),
+    (GameMode::SharpShooter, "sharp-shooter"),
+];
+
+impl GameMode {
+    pub fn from_id(id: u8) -> Self {
+        GAME_MODES
+            .get(id as usize)
+            .map(|(mode, _)| *mode)
+            .unwrap_or(GameMode::Unknown(id))
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            GameMode::Unknown(id) => *id,
+            mode => GAME_MODES
+                .iter()
+                .position(|(known, _)| known == mode)
+                .unwrap() as u8,
+        }
+    }
+
+    pub fn info(&self) -> ModeInfo {
+        ModeInfo::for_id(self.id())
+    }
+}
+
+impl From<u8> for GameMode {
+    fn from(id: u8) -> Self {
+        Self::from_id(id)
+    }
+}
+
+impl From<GameMode> for u8 {
+    fn from(mode: GameMode) -> Self {
+        mode.id()
+    }
+}
+
+// Unknown modes are shown as their id
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match GAME_MODES.iter().find(|(known, _)| known == self) {
+            Some((_, key)) => write!(f, "{}", key),
+            None => write!(f, "{}", self.id()),
+        }
+    }
+}
+
+// Takes the keys of Display and plain mode ids
+impl FromStr for GameMode {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = value.parse::<u8>() {
+            return Ok(Self::from_id(id));
+        }
+        GAME_MODES
+            .iter()
+            .find(|(_, key)| key.eq_ignore_ascii_case(value))
+            .map(|(mode, _)| *mode)
+            .ok_or_else(|| format!("Unknown game mode {}", value).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_is_indexed_by_id() {
+        for (i, info) in ModeInfo::all().iter().enumerate() {
+            assert_eq!(info.id as usize, i);
+            assert_eq!(ModeInfo::for_id(info.id).name, info.name);
+        }
+    }
+
+    #[test]
+    fn unknown_ids_respawn_without_teams() {
+        let info = ModeInfo::for_id(200);
+        assert_eq!(info.id, 200);
+        assert!(info.respawns);
+        assert!(!info.team_based);
+        assert_eq!(info.objective, ObjectiveKind::Other);
+    }
+
+    #[test]
+    fn one_life_modes() {
+        let one_life = ModeInfo::all()
+            .iter()
+            .filter(|info| !info.respawns)
+            .map(|info| info.id)
+            .collect::<Vec<_>>();
+        assert_eq!(one_life, [5, 8, 9, 11, 15, 17, 20]);
+    }
+
+    #[test]
+    fn modes_round_trip_through_their_names_and_ids() {
+        assert_eq!(GAME_MODES.len(), ModeInfo::all().len());
+        for id in 0..=u8::MAX {
+            let mode = GameMode::from_id(id);
+            assert_eq!(mode.id(), id);
+            assert_eq!(mode.to_string().parse::<GameMode>().unwrap(), mode);
+            assert_eq!(id.to_string().parse::<GameMode>().unwrap(), mode);
+            assert_eq!(serde_json::to_string(&mode).unwrap(), id.to_string());
+        }
+        assert_eq!(GameMode::from_id(200).to_string(), "200");
+    }
+
+    #[test]
+    fn mode_names_ignore_case() {
+        assert_eq!(
+            "Hide-And-Seek".parse::<GameMode>().unwrap(),
+            GameMode::HideAndSeek
+        );
+        assert_eq!(GameMode::KingOfTheHill.to_string(), "king-of-the-hill");
+        assert!("hide and seek".parse::<GameMode>().is_err());
+        assert!("".parse::<GameMode>().is_err());
+        assert!("256".parse::<GameMode>().is_err());
+    }
+}
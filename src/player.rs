@@ -1,34 +1,532 @@
-use std::{collections::VecDeque, f32::consts::PI, sync::Arc, time::Duration};
+use std::{
+    collections::{HashSet, VecDeque},
+    f32::consts::PI,
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures_util::{stream, FutureExt, Stream};
 
-use tokio::{sync::Mutex, time};
-use tracing::{debug, error, info};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    runtime::Handle,
+    sync::{broadcast, mpsc, oneshot, watch, Mutex},
+    time,
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    map::Map,
-    messages::{MessageBuilder, MessageParser},
-    socket::{Socket, SocketMessage},
-    utils::{cell_to_position, Error, Vec3},
+    accounts::AccountSource,
+    clock::Clock,
+    error_budget::{BreakerAction, ErrorBudget, ErrorBudgetOptions, ErrorCategory},
+    input::InputState,
+    lifecycle::{InvalidTransition, LifecycleState},
+    map::{Map, Path, PathResult},
+    messages::{
+        backlog_rule, BacklogRule, MessageBuilder, MessageParser, ServerMessage, MAX_PITCH,
+    },
+    modes::GameMode,
+    profile::Profile,
+    proxy::ProxyConfig,
+    quirks::ProtocolQuirks,
+    server_clock::ServerClock,
+    socket::{Socket, SocketMessage, SocketStats},
+    tasks::TaskRegistry,
+    utils::{position_to_cell_clamped, Cell, Error, Vec3},
+    walk_queue::{QueuedWalk, WalkCompleted, WalkOutcome, WalkQueue},
+    world::{RemotePlayer, WorldState},
     Client, Game,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
     pub username: String,
     pub password: String,
 }
 
+impl Account {
+    // Checks that the account exists without joining a game. The password can only be checked
+    // by logging in on a game server.
+    pub async fn verify(&self, client: &Arc<Mutex<Client>>) -> Result<Profile, Error> {
+        client.lock().await.profile(&self.username).await
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerSnapshot {
+    pub id: Option<String>,
+    pub game_id: String,
+    pub map: Option<String>,
+    // Only set if the regions of the map are labeled
+    pub region: Option<String>,
+    pub state: LifecycleState,
+    pub connected: bool,
+    pub in_game: bool,
+    // Killed and waiting to respawn or for the next round
+    pub dead: bool,
+    // Position of the spectated player while dead
+    pub spectating: Option<Vec3>,
+    // Set in the last snapshot after the player stopped
+    pub end_reason: Option<EndReason>,
+    // Enter attempts the server ignored before the player gave up, cleared on the next spawn
+    pub enter_rejected: Option<u32>,
+    // Alone in a game the matchmaker no longer lists
+    pub lobby_stale: bool,
+    // Category that used up its error budget, see ErrorBudgetOptions
+    pub unhealthy: Option<ErrorCategory>,
+    pub walking: bool,
+    pub position: Vec3,
+    pub rotation: f32,
+    pub pitch: f32,
+}
+
+// Events of Player::events. The channel keeps the last EVENT_CAPACITY events, subscribers only get
+// events sent after they subscribed and slow ones skip the oldest events they missed.
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    LoggedIn(Result<(), String>),
+    // Game info of the round after the matchmaker was asked for it
+    GameInit {
+        map: String,
+        mode: GameMode,
+    },
+    Spawned(Vec3),
+    // Spawned again after a death
+    Respawned(Vec3),
+    Died,
+    // Died in a mode without respawns, the player spectates until the game ends
+    RoundEnded,
+    GameEnded,
+    ChatMessage {
+        sender: String,
+        text: String,
+    },
+    SocketError(String),
+    Disconnected(EndReason),
+    // The tracked player was forgotten, see Player::track_target
+    TargetLost(String),
+    StateChanged {
+        from: LifecycleState,
+        to: LifecycleState,
+    },
+    // Position of the spectated player while dead
+    Spectating(Vec3),
+    // Every walked or cleared destination of the walk queue
+    WalkCompleted(WalkCompleted),
+    EnterRejected {
+        attempts: u32,
+    },
+    // Alone in a game the matchmaker no longer lists
+    LobbyStale,
+    Backlog {
+        dropped: usize,
+        collapsed: usize,
+    },
+    Unhealthy {
+        category: ErrorCategory,
+        count: usize,
+    },
+    // Last event of the player, sent after Disconnected
+    Ended(EndState),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KickReason {
+    // Text of the error message the server sent before closing the connection
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum EndReason {
+    UserDisconnect,
+    Kicked(KickReason),
+    // The server closed the connection without an error, usually because the game was shut down
+    GameGone,
+    // Too many messages in a row could not be decoded
+    ProtocolDesync,
+    // The server ignored every enter, see EnterRejectedPolicy
+    EnterRejected { attempts: u32 },
+    // Nobody else played and the matchmaker no longer lists the game, see StaleLobbyPolicy
+    Delisted,
+    // Errors of the category kept coming, see ErrorBudgetOptions
+    Unhealthy(ErrorCategory),
+    SocketError(String),
+    Panic(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SessionSummary {
+    pub ticks_sent: u32,
+    // Integrated dead reckoned movement
+    pub distance_walked: f32,
+    pub deaths: u32,
+    // Kill feed entries with the player as the killer
+    pub kills: u32,
+    // Times the connection went into LifecycleState::Reconnecting
+    pub reconnects: u32,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndState {
+    pub reason: EndReason,
+    pub at: SystemTime,
+    pub summary: SessionSummary,
+}
+
+#[derive(Debug, Clone)]
+pub enum HookAction {
+    // Let the built-in handler process the message
+    Continue,
+    // Skip the built-in handler
+    Suppress,
+    // Skip the built-in handler and send the reply to the server
+    Custom(serde_json::Value),
+}
+
+pub type MessageHook = Arc<dyn Fn(&ServerMessage) -> HookAction + Send + Sync>;
+
+// Game info and map fetched by a task after init
+type GameUpdate = Result<(Game, Option<Arc<Map>>), Error>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PlayerDiagnostics {
+    pub state_buffer_len: usize,
+    pub queued_messages: usize,
+    // Ids seen in spawns of the current game
+    pub known_players: usize,
+    pub socket: SocketStats,
+    pub backlog: BacklogStats,
+}
+
+// Backlogs collapsed since the player connected, see BacklogRule
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct BacklogStats {
+    pub backlogs: usize,
+    // Pings that weren't answered
+    pub dropped: usize,
+    // Updates that were folded into a newer one
+    pub collapsed: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    pub arrive_distance_xz: f32,
+    pub arrive_distance_y: f32,
+    // Widen the arrive radius and predict the position by the measured latency
+    pub latency_compensation: bool,
+    // Distance ahead of the predicted position used to decide if a waypoint has been passed
+    pub lookahead_distance: Option<f32>,
+    // After the last cell walk straight to the exact destination until this close, None stops at the
+    // center of the last cell
+    pub final_arrive_distance: Option<f32>,
+    pub final_approach_budget: Duration,
+    // Cells the path search may expand before giving up, unreachable destinations would explore
+    // everything reachable otherwise
+    pub max_path_expansions: usize,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            arrive_distance_xz: WALK_TO_DISTANCE_XZ_THRESHOLD,
+            arrive_distance_y: WALK_TO_DISTANCE_Y_THRESHOLD,
+            latency_compensation: true,
+            lookahead_distance: Some(1.2),
+            final_arrive_distance: Some(0.3),
+            final_approach_budget: Duration::from_secs(1),
+            max_path_expansions: 200_000,
+        }
+    }
+}
+
+// Skip tick messages while the player stands still without changing rotation or inputs.
+// The skips are randomized and capped so the pattern doesn't stand out.
+#[derive(Debug, Clone, Copy)]
+pub struct IdleTickOptions {
+    // Unchanged ticks before skipping starts
+    pub idle_after: u32,
+    pub skip_chance: f32,
+    // Used while the pool is above its bandwidth cap
+    pub throttled_skip_chance: f32,
+    pub max_consecutive_skips: u32,
+    // The server kicks inactive players, so a tick is always sent after this long
+    pub max_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnterRejectedPolicy {
+    // Wait in the lobby, the next round enters again
+    StayInLobby,
+    // Disconnect so a pool or autoscaler can replace the player in another game
+    Disconnect,
+}
+
+// The server ignores enter while the lobby is full or the mode is in intermission
+#[derive(Debug, Clone, Copy)]
+pub struct EnterRetryOptions {
+    // Time to wait for the spawn after every enter
+    pub timeout: Duration,
+    // Including the first enter
+    pub max_attempts: u32,
+    pub on_rejected: EnterRejectedPolicy,
+}
+
+impl Default for EnterRetryOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_attempts: 3,
+            on_rejected: EnterRejectedPolicy::StayInLobby,
+        }
+    }
+}
+
+// Walking plans the path again when the distance to the waypoint didn't go down for a while
+#[derive(Debug, Clone, Copy)]
+pub struct StuckOptions {
+    pub ticks: u32,
+    // Plans in a row without reaching a waypoint before giving up with Error::Stuck
+    pub max_replans: u32,
+}
+
+impl Default for StuckOptions {
+    fn default() -> Self {
+        Self {
+            ticks: 30,
+            max_replans: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StaleLobbyPolicy {
+    // Only set lobby_stale
+    Report,
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct WalkProgress {
+    // Index of the waypoint the player walks to, 0 while the path is searched
+    pub waypoint: usize,
+    pub waypoints: usize,
+    // Along the rest of the path, the horizontal distance to the destination once the walk finished
+    pub remaining_distance: f32,
+}
+
+// Walk started with Player::start_walk_to, awaiting the handle waits for the walk to finish.
+// Dropping it doesn't stop the walk.
+#[derive(Debug)]
+pub struct WalkHandle {
+    cancel: CancellationToken,
+    progress: watch::Receiver<WalkProgress>,
+    done: oneshot::Receiver<Result<(), Error>>,
+}
+
+impl WalkHandle {
+    // The walk ends with Error::WalkCancelled on the next tick
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn progress(&self) -> WalkProgress {
+        *self.progress.borrow()
+    }
+
+    // None while the walk is running
+    fn try_result(&mut self) -> Option<Result<(), Error>> {
+        match self.done.try_recv() {
+            Ok(result) => Some(result),
+            Err(oneshot::error::TryRecvError::Empty) => None,
+            Err(oneshot::error::TryRecvError::Closed) => Some(Err(Error::Disconnected)),
+        }
+    }
+}
+
+impl Future for WalkHandle {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The player was dropped in the middle of the walk
+        Pin::new(&mut self.done)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(Error::Disconnected)))
+    }
+}
+
+type PathReceiver = oneshot::Receiver<Result<PathResult, Error>>;
+
+// Walk the tick loop advances, see Player::start_walk_to
+struct ActiveWalk {
+    destination: Vec3,
+    end_cell: (usize, usize, usize),
+    arrives_on_end_cell: bool,
+    options: WalkOptions,
+    cancel: CancellationToken,
+    _cancel_on_drop: DropGuard,
+    progress: watch::Sender<WalkProgress>,
+    done: oneshot::Sender<Result<(), Error>>,
+    phase: WalkPhase,
+    // Plans in a row without reaching a waypoint
+    replans: u32,
+    // Whether the walk crouched for a segment and has to stand up again
+    crouched: bool,
+}
+
+impl ActiveWalk {
+    fn finish(self, result: Result<(), Error>, distance: f32) {
+        self.progress
+            .send_modify(|progress| progress.remaining_distance = distance);
+        // Nobody waiting is fine
+        let _ = self.done.send(result);
+    }
+}
+
+enum WalkPhase {
+    Searching(PathReceiver),
+    Walking {
+        path: Path,
+        waypoint: usize,
+        // Closest distance to the waypoint and the ticks since it went down
+        closest: f32,
+        stalled: u32,
+    },
+    // Straight at the destination after the last cell
+    Approaching {
+        started: time::Instant,
+    },
+}
+
+enum WalkStep {
+    Continue,
+    // Continue with a shorter tick
+    Shortened(Duration),
+    Finished,
+}
+
+// Checks whether the game is still listed once no other player spawned for a while
+#[derive(Debug, Clone, Copy)]
+pub struct StaleLobbyOptions {
+    pub alone_for: Duration,
+    pub poll_interval: Duration,
+    pub policy: StaleLobbyPolicy,
+}
+
+impl Default for StaleLobbyOptions {
+    fn default() -> Self {
+        Self {
+            alone_for: Duration::from_secs(300),
+            poll_interval: Duration::from_secs(60),
+            policy: StaleLobbyPolicy::Report,
+        }
+    }
+}
+
+impl Default for IdleTickOptions {
+    fn default() -> Self {
+        Self {
+            idle_after: 15,
+            skip_chance: 0.5,
+            throttled_skip_chance: 0.9,
+            max_consecutive_skips: 10,
+            max_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IdleTicks {
+    options: IdleTickOptions,
+    unchanged: u32,
+    skipped: u32,
+    last_sent: Option<time::Instant>,
+    // Yaw and pitch of the last tick
+    last_view: (f32, f32),
+}
+
+impl IdleTicks {
+    fn reset(&mut self) {
+        self.unchanged = 0;
+    }
+
+    fn should_skip(
+        &mut self,
+        idle: bool,
+        view: (f32, f32),
+        now: time::Instant,
+        throttled: bool,
+    ) -> bool {
+        if !idle || view != self.last_view {
+            self.unchanged = 0;
+        } else {
+            self.unchanged += 1;
+        }
+        self.last_view = view;
+
+        let overdue = self.last_sent.is_none_or(|last_sent| {
+            now.saturating_duration_since(last_sent) >= self.options.max_interval
+        });
+        let chance = if throttled {
+            self.options.throttled_skip_chance
+        } else {
+            self.options.skip_chance
+        };
+
+        let skip = self.unchanged >= self.options.idle_after
+            && self.skipped < self.options.max_consecutive_skips
+            && !overdue
+            && rand::thread_rng().gen::<f32>() < chance;
+
+        if skip {
+            self.skipped += 1;
+        } else {
+            self.skipped = 0;
+            self.last_sent = Some(now);
+        }
+
+        skip
+    }
+}
+
 #[derive(Debug)]
 struct State {
     tick: u32,
+    sent_at: time::Instant,
     position: Vec3,
     rotation: f32,
-    walking: bool,
+    inputs: InputState,
 }
 
 pub struct PlayerBuilder {
     client: Arc<Mutex<Client>>,
     tick_interval: Duration,
-    account: Option<Account>,
+    handshake_timeout: Duration,
+    account: Option<AccountSource>,
+    message_hooks: Vec<MessageHook>,
+    quirks: ProtocolQuirks,
+    clock: Clock,
+    idle_ticks: Option<IdleTickOptions>,
+    tick_alignment: Option<Duration>,
+    keep_walk_queue_on_death: bool,
+    stale_player_ticks: Option<u32>,
+    max_turn_rate: f32,
+    scope_delay: Duration,
+    enter_retry: EnterRetryOptions,
+    stale_lobby: Option<StaleLobbyOptions>,
+    error_budget: ErrorBudgetOptions,
+    stuck: StuckOptions,
+    proxy: Option<ProxyConfig>,
+    runtime: Option<Handle>,
 }
 
 impl PlayerBuilder {
@@ -36,7 +534,23 @@ impl PlayerBuilder {
         Self {
             client,
             tick_interval: Duration::from_millis(66),
+            handshake_timeout: Duration::from_secs(10),
             account: None,
+            message_hooks: vec![],
+            quirks: ProtocolQuirks::default(),
+            clock: Clock::default(),
+            idle_ticks: None,
+            tick_alignment: None,
+            keep_walk_queue_on_death: false,
+            stale_player_ticks: None,
+            max_turn_rate: DEFAULT_MAX_TURN_RATE,
+            scope_delay: DEFAULT_SCOPE_DELAY,
+            enter_retry: EnterRetryOptions::default(),
+            stale_lobby: None,
+            error_budget: ErrorBudgetOptions::default(),
+            stuck: StuckOptions::default(),
+            proxy: None,
+            runtime: None,
         }
     }
 
@@ -45,283 +559,1904 @@ impl PlayerBuilder {
         self
     }
 
-    pub fn account(mut self, account: Account) -> Self {
-        self.account = Some(account);
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    // An account or a store and username, a stored account is looked up on every connect
+    pub fn account(mut self, account: impl Into<AccountSource>) -> Self {
+        self.account = Some(account.into());
+        self
+    }
+
+    // Hooks are called in the order they were added for every received message before the built-in
+    // handler. The first hook that doesn't return HookAction::Continue decides what happens.
+    pub fn message_hook(
+        mut self,
+        hook: impl Fn(&ServerMessage) -> HookAction + Send + Sync + 'static,
+    ) -> Self {
+        self.message_hooks.push(Arc::new(hook));
+        self
+    }
+
+    pub fn protocol_quirks(mut self, quirks: ProtocolQuirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    // A manual clock makes the player fully deterministic, its tick loop only runs when the clock is
+    // advanced
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn idle_tick_suppression(mut self, options: IdleTickOptions) -> Self {
+        self.idle_ticks = Some(options);
+        self
+    }
+
+    // Shift the tick loop so ticks arrive this long before the server processes its next tick,
+    // based on the estimated server clock
+    pub fn tick_alignment(mut self, lead: Duration) -> Self {
+        self.tick_alignment = Some(lead);
+        self
+    }
+
+    // The walk queue is cleared on death unless this is set
+    pub fn keep_walk_queue_on_death(mut self, keep: bool) -> Self {
+        self.keep_walk_queue_on_death = keep;
+        self
+    }
+
+    // Other players are forgotten after this many ticks without a spawn or observation, kept
+    // until the game ends by default
+    pub fn stale_player_ticks(mut self, ticks: u32) -> Self {
+        self.stale_player_ticks = Some(ticks);
+        self
+    }
+
+    // Radians the view turns at most per tick while tracking a target
+    pub fn max_turn_rate(mut self, max_turn_rate: f32) -> Self {
+        self.max_turn_rate = max_turn_rate.max(0.0);
+        self
+    }
+
+    // Time shoot_at waits after aiming down sights. The client doesn't know which weapon is held or
+    // the scope-in times of the weapons, so it is the same for every weapon.
+    pub fn scope_delay(mut self, scope_delay: Duration) -> Self {
+        self.scope_delay = scope_delay;
+        self
+    }
+
+    pub fn enter_retry(mut self, options: EnterRetryOptions) -> Self {
+        self.enter_retry = options;
+        self
+    }
+
+    pub fn stale_lobby(mut self, options: StaleLobbyOptions) -> Self {
+        self.stale_lobby = Some(options);
+        self
+    }
+
+    pub fn error_budget(mut self, options: ErrorBudgetOptions) -> Self {
+        self.error_budget = options;
+        self
+    }
+
+    pub fn stuck_detection(mut self, options: StuckOptions) -> Self {
+        self.stuck = options;
+        self
+    }
+
+    // Proxy for the connection of this player instead of the one of the client
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    // Runtime the tick and socket tasks are spawned on, defaults to the runtime connect is called on
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
         self
     }
 
     pub async fn connect(&self, game: &Game) -> Result<Arc<Mutex<Player>>, Error> {
-        let mut socket = Socket::new(&self.client).await;
+        let account = self
+            .account
+            .as_ref()
+            .map(|account| account.resolve())
+            .transpose()?;
+        let mut socket = self.socket().await;
         socket.connect(game).await?;
 
-        let player = Arc::new(Mutex::new(Player {
+        if let Err(err) = socket.wait_for_handshake(self.handshake_timeout).await {
+            socket.close().await?;
+            return Err(err);
+        }
+
+        let player = self.start(socket, game, account);
+        let tasks = player.lock().await.tasks.clone();
+        Player::run_tick(player.clone(), &tasks);
+
+        Ok(player)
+    }
+
+    // Player on a mocked connection without the tick loop, the test drives it with Player::step
+    #[cfg(test)]
+    pub(crate) async fn connect_mock(
+        &self,
+        game: &Game,
+    ) -> (Arc<Mutex<Player>>, crate::socket::MockTransport) {
+        let mut socket = self.socket().await;
+        let transport = socket.connect_mock().await;
+        let account = self
+            .account
+            .as_ref()
+            .map(|account| account.resolve().unwrap());
+        (self.start(socket, game, account), transport)
+    }
+
+    async fn socket(&self) -> Socket {
+        let mut socket = Socket::with_quirks(&self.client, self.quirks.clone()).await;
+        socket.set_clock(self.clock.clone());
+        socket.set_runtime(self.runtime_handle());
+        if let Some(proxy) = &self.proxy {
+            socket.set_proxy(Some(proxy.clone()));
+        }
+        socket
+    }
+
+    fn runtime_handle(&self) -> Handle {
+        self.runtime.clone().unwrap_or_else(Handle::current)
+    }
+
+    fn start(&self, socket: Socket, game: &Game, account: Option<Account>) -> Arc<Mutex<Player>> {
+        let tasks = TaskRegistry::new(self.runtime_handle());
+        let position = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+
+        let (snapshot, _) = watch::channel(PlayerSnapshot {
+            id: None,
+            game_id: game.id.clone(),
+            map: None,
+            region: None,
+            state: LifecycleState::Handshaking,
+            connected: true,
+            in_game: false,
+            dead: false,
+            spectating: None,
+            end_reason: None,
+            enter_rejected: None,
+            lobby_stale: false,
+            unhealthy: None,
+            walking: false,
+            position,
+            rotation: 0.0,
+            pitch: 0.0,
+        });
+
+        let (game_updates_tx, game_updates) = mpsc::unbounded_channel();
+        let (listing_tx, listing) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let walk_queue = WalkQueue::new();
+        // Destinations can be completed or cleared through other handles of the queue
+        let mut completed = walk_queue.subscribe();
+        let walk_events = events.clone();
+        tasks.spawn(async move {
+            loop {
+                match completed.recv().await {
+                    Ok(walk) => {
+                        let _ = walk_events.send(PlayerEvent::WalkCompleted(walk));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+        Arc::new(Mutex::new(Player {
             client: self.client.clone(),
             socket,
             game: game.clone(),
             map: None,
             tick: 0,
             tick_interval: self.tick_interval,
-            account: self.account.clone(),
+            account,
+            message_hooks: self.message_hooks.clone(),
             id: None,
-            disconnected: false,
-            ready: false,
-            in_game: false,
-            walking: false,
-            position: Vec3 {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            state: LifecycleState::Handshaking,
+            inputs: InputState::default(),
+            sent_inputs: InputState::default(),
+            position,
             rotation: 0.0,
+            pitch: 0.0,
             state_buffer: VecDeque::new(),
-        }));
-
-        Player::run_tick(player.clone());
-
-        Ok(player)
+            latency: None,
+            clock: self.clock.clone(),
+            pending_spawns: VecDeque::new(),
+            known_ids: HashSet::new(),
+            idle_ticks: self.idle_ticks.map(|options| IdleTicks {
+                options,
+                unchanged: 0,
+                skipped: 0,
+                last_sent: None,
+                last_view: (0.0, 0.0),
+            }),
+            inputs_changed: false,
+            jump_pending: false,
+            slide_until: None,
+            jumping: false,
+            airborne_until: None,
+            throttled: Arc::new(AtomicBool::new(false)),
+            server_clock: ServerClock::new(),
+            tick_alignment: self.tick_alignment,
+            last_alignment: None,
+            respawn_at: None,
+            spectating: None,
+            connected_at: self.clock.now(),
+            summary: SessionSummary::default(),
+            end_state: None,
+            kick: None,
+            decode_errors: 0,
+            walk_queue,
+            keep_walk_queue_on_death: self.keep_walk_queue_on_death,
+            world: WorldState::default(),
+            team: None,
+            stale_player_ticks: self.stale_player_ticks,
+            target: None,
+            max_turn_rate: self.max_turn_rate,
+            scope_delay: self.scope_delay,
+            active_walk: None,
+            enter_retry: self.enter_retry,
+            stuck: self.stuck,
+            enter_attempts: 0,
+            enter_deadline: None,
+            enter_rejected: None,
+            tasks: tasks.clone(),
+            deferred_messages: VecDeque::new(),
+            backlog: BacklogStats::default(),
+            game_updates_tx,
+            game_updates,
+            stale_lobby: self.stale_lobby,
+            last_other_spawn: self.clock.now(),
+            last_listing_check: None,
+            listing_pending: false,
+            listing_tx,
+            listing,
+            lobby_stale: false,
+            error_budget: ErrorBudget::new(self.error_budget.clone()),
+            unhealthy: None,
+            paused_messages: HashSet::new(),
+            snapshot,
+            events,
+        }))
     }
 }
 
-const MOVEMENT_SPEED: f32 = 0.0000459;
+pub(crate) const MOVEMENT_SPEED: f32 = 0.0000459;
+// Movement is slower while aiming down sights
+const ADS_SPEED_MULTIPLIER: f32 = 0.6;
+const CROUCH_SPEED_MULTIPLIER: f32 = 0.6;
+// Height of the camera above the position, the head of Map::line_of_sight
+const EYE_HEIGHT: f32 = 12.0;
+// How long a slide keeps crouching before standing up again
+const SLIDE_DURATION: Duration = Duration::from_millis(600);
+// Long enough for the scoped weapons, their actual scope-in times aren't known
+const DEFAULT_SCOPE_DELAY: Duration = Duration::from_millis(200);
+// The server acknowledges states a few ticks after they were sent, anything older is useless
+const MAX_STATE_BUFFER: usize = 256;
+const HOOK_TIME_BUDGET: Duration = Duration::from_millis(1);
+// Messages left after this long are processed in the next tick
+const MESSAGE_DRAIN_BUDGET: Duration = Duration::from_millis(20);
+// More queued messages than this are collapsed before they are processed, e.g. after the host stalled
+const BACKLOG_THRESHOLD: usize = 64;
+// Spawn messages that arrived before io-init
+const MAX_PENDING_SPAWNS: usize = 4;
+const RESPAWN_DELAY: Duration = Duration::from_secs(3);
+const MAX_CONSECUTIVE_DECODE_ERRORS: u32 = 20;
+// Time between two phase corrections of the tick loop
+const TICK_ALIGNMENT_INTERVAL: Duration = Duration::from_secs(5);
 const WALK_TO_DISTANCE_XZ_THRESHOLD: f32 = 2.2;
 const WALK_TO_DISTANCE_Y_THRESHOLD: f32 = 8.3;
+// Smaller changes of the distance to the waypoint don't count as progress
+const STUCK_PROGRESS: f32 = 0.1;
+const EVENT_CAPACITY: usize = 64;
+// About 20 degrees
+const DEFAULT_MAX_TURN_RATE: f32 = 0.35;
+// Rough time from a jump until the player lands again
+const AIRBORNE_TIME: Duration = Duration::from_millis(700);
+// Air movement isn't predicted, so corrections while airborne need a larger difference
+const AIRBORNE_MAX_DIFF_XZ: f32 = 2.0;
 
 pub struct Player {
     client: Arc<Mutex<Client>>,
     socket: Socket,
 
     game: Game,
-    map: Option<Map>,
+    // Shared with path searches running on blocking threads
+    map: Option<Arc<Map>>,
     tick: u32,
 
     tick_interval: Duration,
     account: Option<Account>,
+    message_hooks: Vec<MessageHook>,
 
     id: Option<String>,
-    disconnected: bool,
-    ready: bool,
-    in_game: bool,
-    walking: bool,
+    state: LifecycleState,
+    inputs: InputState,
+    // Inputs the server saw last
+    sent_inputs: InputState,
+    // Standing up again from a slide at this time
+    slide_until: Option<time::Instant>,
     position: Vec3,
     rotation: f32,
+    // Positive looks up, kept within MAX_PITCH
+    pitch: f32,
     state_buffer: VecDeque<State>,
+    latency: Option<Duration>,
+    clock: Clock,
+    pending_spawns: VecDeque<Vec<serde_json::Value>>,
+    // Ids of all players that spawned since connecting
+    known_ids: HashSet<String>,
+    idle_ticks: Option<IdleTicks>,
+    inputs_changed: bool,
+    // A single jump for the next tick
+    jump_pending: bool,
+    // Keeps jumping until it is unset
+    jumping: bool,
+    // Only xz of the predicted position is trusted until then
+    airborne_until: Option<time::Instant>,
+    throttled: Arc<AtomicBool>,
+    server_clock: ServerClock,
+    tick_alignment: Option<Duration>,
+    last_alignment: Option<time::Instant>,
+    respawn_at: Option<time::Instant>,
+    // Last position of the player the server lets us spectate while dead
+    spectating: Option<Vec3>,
+    connected_at: time::Instant,
+    summary: SessionSummary,
+    end_state: Option<EndState>,
+    // Error message of the server, the connection is closed right after it
+    kick: Option<KickReason>,
+    decode_errors: u32,
+    walk_queue: WalkQueue,
+    keep_walk_queue_on_death: bool,
+    // Other players, including our own id until it is known
+    world: WorldState,
+    team: Option<u8>,
+    stale_player_ticks: Option<u32>,
+    // Id of the player the view follows
+    target: Option<String>,
+    max_turn_rate: f32,
+    scope_delay: Duration,
+    active_walk: Option<ActiveWalk>,
+    enter_retry: EnterRetryOptions,
+    stuck: StuckOptions,
+    enter_attempts: u32,
+    // Resends enter when no spawn arrived by then
+    enter_deadline: Option<time::Instant>,
+    enter_rejected: Option<u32>,
+    tasks: TaskRegistry,
+    // Received messages that didn't fit into the drain budget of the last tick
+    deferred_messages: VecDeque<SocketMessage>,
+    backlog: BacklogStats,
+    game_updates_tx: mpsc::UnboundedSender<GameUpdate>,
+    game_updates: mpsc::UnboundedReceiver<GameUpdate>,
+    stale_lobby: Option<StaleLobbyOptions>,
+    // Last spawn of any other player, own deaths don't count
+    last_other_spawn: time::Instant,
+    last_listing_check: Option<time::Instant>,
+    listing_pending: bool,
+    listing_tx: mpsc::UnboundedSender<Result<bool, Error>>,
+    listing: mpsc::UnboundedReceiver<Result<bool, Error>>,
+    lobby_stale: bool,
+    error_budget: ErrorBudget,
+    unhealthy: Option<ErrorCategory>,
+    // Message kinds that aren't handled any more because their handler kept failing
+    paused_messages: HashSet<String>,
+
+    snapshot: watch::Sender<PlayerSnapshot>,
+    events: broadcast::Sender<PlayerEvent>,
 }
 
 impl Player {
     pub async fn enter(&mut self) -> Result<(), Error> {
-        if self.in_game || self.disconnected {
-            return Err("Player already in game or disconnected".into());
-        }
-
+        self.check_transition(LifecycleState::Entering)?;
         self.socket.send(&MessageBuilder::enter()).await?;
-        Ok(())
+        self.enter_attempts = 1;
+        self.enter_deadline = Some(self.clock.now() + self.enter_retry.timeout);
+        self.transition(LifecycleState::Entering)
     }
 
-    pub async fn walk_to(&mut self, position: &Vec3) -> Result<(), Error> {
-        if !self.in_game || self.disconnected {
-            return Err("Player not in game or disconnected".into());
+    async fn retry_enter(&mut self) -> Result<(), Error> {
+        if self.enter_attempts < self.enter_retry.max_attempts {
+            self.enter_attempts += 1;
+            debug!(
+                "No spawn in {}, sending enter again ({}/{})",
+                self.game.id, self.enter_attempts, self.enter_retry.max_attempts
+            );
+            self.socket.send(&MessageBuilder::enter()).await?;
+            self.enter_deadline = Some(self.clock.now() + self.enter_retry.timeout);
+            return Ok(());
+        }
+
+        warn!(
+            "Server in {} ignored {} enter attempts",
+            self.game.id, self.enter_attempts
+        );
+        self.enter_deadline = None;
+        self.enter_rejected = Some(self.enter_attempts);
+        self.transition(LifecycleState::Lobby)?;
+        self.emit(PlayerEvent::EnterRejected {
+            attempts: self.enter_attempts,
+        });
+        match self.enter_retry.on_rejected {
+            EnterRejectedPolicy::StayInLobby => Ok(()),
+            EnterRejectedPolicy::Disconnect => {
+                self.end_with(EndReason::EnterRejected {
+                    attempts: self.enter_attempts,
+                })
+                .await
+            }
         }
+    }
 
-        if let Some(map) = &self.map {
-            if let (Some(start_cell), Some(end_cell)) = (
-                map.closest_walkable_cell(&self.position),
-                map.closest_walkable_cell(position),
-            ) {
-                if let Some(path) = map.find_path(&start_cell, &end_cell) {
-                    let mut interval = time::interval(self.tick_interval);
+    pub fn lobby_stale(&self) -> bool {
+        self.lobby_stale
+    }
 
-                    let bounds = map.bounds;
+    // Asks the matchmaker in a task, the result is applied by a later tick
+    fn poll_listing(&mut self) {
+        let options = match self.stale_lobby {
+            Some(options) => options,
+            None => return,
+        };
 
-                    self.walk(true).await?;
+        // Failing connections would only add failed requests
+        let healthy = self.decode_errors == 0
+            && self.unhealthy.is_none()
+            && matches!(
+                self.state,
+                LifecycleState::Lobby
+                    | LifecycleState::Entering
+                    | LifecycleState::InGame
+                    | LifecycleState::Dead { .. }
+            );
+        if !healthy
+            || self.listing_pending
+            || self.lobby_stale
+            || self.clock.elapsed(self.last_other_spawn) < options.alone_for
+            || self
+                .last_listing_check
+                .is_some_and(|last| self.clock.elapsed(last) < options.poll_interval)
+        {
+            return;
+        }
 
-                    let mut last_cell = path[0];
-                    'outer: for cell in path.iter().skip(1) {
-                        let cell_pos = cell_to_position(&bounds, cell);
+        self.last_listing_check = Some(self.clock.now());
+        self.listing_pending = true;
+        let game = self.game.clone();
+        let listing = self.listing_tx.clone();
+        self.tasks.spawn(async move {
+            let _ = listing.send(game.is_listed().await);
+        });
+    }
 
-                        debug!("Moving to cell {:?}", cell);
+    // Attempts of the last enter the server ignored, None after a spawn
+    pub fn enter_rejected(&self) -> Option<u32> {
+        self.enter_rejected
+    }
 
-                        loop {
-                            if self.disconnected {
-                                break 'outer;
-                            }
+    pub fn state(&self) -> LifecycleState {
+        self.state
+    }
 
-                            if self.in_game {
-                                if let Err(err) = self.tick().await {
-                                    return Err(err);
-                                }
-                            } else {
-                                return Err("Game ended or Player died".into());
-                            }
+    fn check_transition(&self, to: LifecycleState) -> Result<(), Error> {
+        if self.state.can_transition(&to) {
+            Ok(())
+        } else {
+            warn!(
+                "Player in {}: invalid transition from {:?} to {:?}",
+                self.game.id, self.state, to
+            );
+            Err(InvalidTransition {
+                from: self.state,
+                to,
+            }
+            .into())
+        }
+    }
 
-                            self.look_at(&cell_pos);
+    // Every state change goes through here
+    fn transition(&mut self, to: LifecycleState) -> Result<(), Error> {
+        self.check_transition(to)?;
+        debug!("Player in {}: {:?} -> {:?}", self.game.id, self.state, to);
+        self.emit(PlayerEvent::StateChanged {
+            from: self.state,
+            to,
+        });
+        if to == LifecycleState::Reconnecting {
+            self.summary.reconnects += 1;
+        }
+        self.state = to;
+        Ok(())
+    }
 
-                            interval.tick().await;
+    pub async fn walk_to(&mut self, position: &Vec3) -> Result<(), Error> {
+        let options = self
+            .map
+            .as_ref()
+            .and_then(|map| map.tuning().walk.clone())
+            .unwrap_or_default();
+        self.walk_to_with(position, &options).await
+    }
 
-                            if self
-                                .position
-                                .max_diff_xz(&cell_pos, WALK_TO_DISTANCE_XZ_THRESHOLD)
-                                && (last_cell.1 >= cell.1
-                                    || self
-                                        .position
-                                        .max_diff_y(&cell_pos, WALK_TO_DISTANCE_Y_THRESHOLD))
-                            {
-                                debug!("Arrived at cell {:?}", cell);
-                                break;
-                            }
-                        }
+    pub async fn walk_to_with(
+        &mut self,
+        position: &Vec3,
+        options: &WalkOptions,
+    ) -> Result<(), Error> {
+        self.walk_path(position, options).await.map(|_| ())
+    }
 
-                        last_cell = *cell;
-                    }
+    // Drives the ticks until the walk finished and returns the horizontal distance to the position
+    // where the player stopped
+    async fn walk_path(&mut self, position: &Vec3, options: &WalkOptions) -> Result<f32, Error> {
+        let mut handle = self.start_walk_to_with(position, options);
+        let mut interval = self.clock.interval(self.tick_interval);
 
-                    debug!("Arrived at end cell");
-                    self.walk(false).await?;
+        loop {
+            if let Some(result) = handle.try_result() {
+                return result.map(|_| handle.progress().remaining_distance);
+            }
 
-                    Ok(())
-                } else {
-                    Err("No path found".into())
+            if self.state == LifecycleState::InGame {
+                if let Err(err) = self.tick().await {
+                    self.active_walk = None;
+                    return Err(err);
                 }
             } else {
-                Err("Position not walkable".into())
+                self.advance_walk().await;
             }
-        } else {
-            Err("Map information not available".into())
-        }
-    }
 
-    pub async fn walk(&mut self, state: bool) -> Result<(), Error> {
-        if !self.in_game || self.disconnected {
-            return Err("Player not in game or disconnected".into());
+            interval.tick().await;
         }
+    }
 
-        self.walking = state;
-        self.socket
-            .send(&MessageBuilder::tick(
-                self.tick,
-                &self.tick_interval,
-                None,
-                Some(format!("{{\"0-4\": {}}}", if state { 1 } else { -1 })),
-            )?)
-            .await?;
-        self.tick += 1;
-        Ok(())
+    pub fn start_walk_to(&mut self, position: &Vec3) -> WalkHandle {
+        let options = self
+            .map
+            .as_ref()
+            .and_then(|map| map.tuning().walk.clone())
+            .unwrap_or_default();
+        self.start_walk_to_with(position, &options)
     }
 
-    pub async fn shoot(&mut self, state: bool) -> Result<(), Error> {
-        if !self.in_game || self.disconnected {
-            return Err("Player not in game or disconnected".into());
+    // The tick loop walks the path while other methods can be called, a walk that is still running
+    // is cancelled
+    pub fn start_walk_to_with(&mut self, position: &Vec3, options: &WalkOptions) -> WalkHandle {
+        let cancel = CancellationToken::new();
+        let (progress_tx, progress) = watch::channel(WalkProgress::default());
+        let (done_tx, done) = oneshot::channel();
+        let handle = WalkHandle {
+            cancel: cancel.clone(),
+            progress,
+            done,
+        };
+
+        if let Some(previous) = self.active_walk.take() {
+            let distance = self.position.distance_xz(&previous.destination);
+            previous.finish(Err(Error::WalkCancelled), distance);
         }
 
-        self.socket
-            .send(&MessageBuilder::tick(
+        match self.plan_walk(position, options, &cancel) {
+            Ok((end_cell, arrives_on_end_cell, search)) => {
+                self.active_walk = Some(ActiveWalk {
+                    destination: *position,
+                    end_cell,
+                    arrives_on_end_cell,
+                    options: options.clone(),
+                    _cancel_on_drop: cancel.clone().drop_guard(),
+                    cancel,
+                    progress: progress_tx,
+                    done: done_tx,
+                    phase: WalkPhase::Searching(search),
+                    replans: 0,
+                    crouched: false,
+                })
+            }
+            Err(err) => {
+                let _ = done_tx.send(Err(err));
+            }
+        }
+
+        handle
+    }
+
+    pub fn is_walking_to(&self) -> Option<Vec3> {
+        self.active_walk.as_ref().map(|walk| walk.destination)
+    }
+
+    fn plan_walk(
+        &self,
+        position: &Vec3,
+        options: &WalkOptions,
+        cancel: &CancellationToken,
+    ) -> Result<(Cell, bool, PathReceiver), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        let map = self.map.clone().ok_or(Error::MapUnavailable)?;
+        let start_cell = map
+            .closest_walkable_cell(&self.position)
+            .ok_or(Error::StartNotWalkable)?;
+        let end_cell = map
+            .closest_walkable_cell(position)
+            .ok_or(Error::DestinationNotWalkable)?;
+        let arrives_on_end_cell = position_to_cell_clamped(&map.bounds, position) == end_cell;
+
+        let search = self.spawn_search(&map, start_cell, end_cell, options, cancel);
+        Ok((end_cell, arrives_on_end_cell, search))
+    }
+
+    // The walk checks for the result every tick, cancelling the walk cancels the search
+    fn spawn_search(
+        &self,
+        map: &Arc<Map>,
+        start_cell: (usize, usize, usize),
+        end_cell: (usize, usize, usize),
+        options: &WalkOptions,
+        cancel: &CancellationToken,
+    ) -> PathReceiver {
+        let (result_tx, result_rx) = oneshot::channel();
+        let search = map.clone().find_path_async(
+            start_cell,
+            end_cell,
+            options.max_path_expansions,
+            cancel.child_token(),
+        );
+        self.tasks.spawn(async move {
+            let _ = result_tx.send(search.await);
+        });
+        result_rx
+    }
+
+    // Called by every tick before the rotation is sent. Returns a shorter interval for the last tick
+    // of the final approach so it doesn't overshoot.
+    async fn advance_walk(&mut self) -> Option<Duration> {
+        let mut walk = self.active_walk.take()?;
+        let result = match self.step_walk(&mut walk).await {
+            Ok(WalkStep::Continue) => {
+                self.active_walk = Some(walk);
+                return None;
+            }
+            Ok(WalkStep::Shortened(interval)) => {
+                self.active_walk = Some(walk);
+                return Some(interval);
+            }
+            Ok(WalkStep::Finished) => Ok(()),
+            Err(err) => Err(err),
+        };
+
+        if self.inputs.walk && self.state.is_in_game() {
+            if let Err(err) = self.walk(false).await {
+                warn!("Failed to stop walking: {}", err);
+            }
+        }
+        if walk.crouched && self.state.is_in_game() {
+            if let Err(err) = self.crouch(false).await {
+                warn!("Failed to stop crouching: {}", err);
+            }
+        }
+        let distance = self.position.distance_xz(&walk.destination);
+        walk.finish(result, distance);
+        None
+    }
+
+    async fn step_walk(&mut self, walk: &mut ActiveWalk) -> Result<WalkStep, Error> {
+        if walk.cancel.is_cancelled() {
+            return Err(Error::WalkCancelled);
+        }
+        match self.state {
+            LifecycleState::InGame => (),
+            state if !state.is_connected() => return Ok(WalkStep::Finished),
+            _ => return Err(Error::NotInGame),
+        }
+        let map = self.map.clone().ok_or(Error::MapUnavailable)?;
+
+        // A phase can end within the tick, the next one starts right away
+        loop {
+            // Walking goes where the view points, so a walk waits while a target is tracked
+            if self.target.is_some() && !matches!(walk.phase, WalkPhase::Searching(_)) {
+                if self.inputs.walk {
+                    self.walk(false).await?;
+                }
+                if let WalkPhase::Approaching { started } = &mut walk.phase {
+                    *started = self.clock.now();
+                }
+                return Ok(WalkStep::Continue);
+            }
+
+            match &mut walk.phase {
+                WalkPhase::Searching(search) => {
+                    let path = match search.try_recv() {
+                        Err(oneshot::error::TryRecvError::Empty) => {
+                            // Walking on blindly while the path is searched could go anywhere
+                            if self.inputs.walk {
+                                self.walk(false).await?;
+                            }
+                            return Ok(WalkStep::Continue);
+                        }
+                        Err(oneshot::error::TryRecvError::Closed) => {
+                            return Err("Path search task was aborted".into())
+                        }
+                        Ok(result) => match result? {
+                            PathResult::Found(path) => path,
+                            PathResult::NotFound => return Err(Error::PathNotFound),
+                            PathResult::BudgetExceeded => return Err(Error::PathBudgetExceeded),
+                        },
+                    };
+
+                    self.walk(true).await?;
+                    walk.phase = WalkPhase::Walking {
+                        path,
+                        waypoint: 1,
+                        closest: f32::INFINITY,
+                        stalled: 0,
+                    };
+                }
+                WalkPhase::Walking {
+                    path,
+                    waypoint,
+                    closest,
+                    stalled,
+                } => {
+                    if *waypoint >= path.waypoints.len() {
+                        debug!("Arrived at end cell");
+                        // The cell center can be off by half a cell, destinations off the walkable
+                        // ground keep it
+                        if walk.options.final_arrive_distance.is_none() || !walk.arrives_on_end_cell
+                        {
+                            return Ok(WalkStep::Finished);
+                        }
+                        walk.phase = WalkPhase::Approaching {
+                            started: self.clock.now(),
+                        };
+                        continue;
+                    }
+
+                    let i = *waypoint;
+                    let cell_pos = path.waypoints[i];
+                    let last_pos = path.waypoints[i - 1];
+                    let is_last = i + 1 == path.waypoints.len();
+                    // The server moves the player to the exit, keep walking into the entry until then
+                    let target = if path.teleports[i - 1] {
+                        last_pos
+                    } else {
+                        cell_pos
+                    };
+
+                    // Going down only needs the horizontal distance, the player falls onto the cell
+                    if self.arrived(&last_pos, &cell_pos, is_last, &walk.options)
+                        && (last_pos.y >= cell_pos.y
+                            || self
+                                .position
+                                .max_diff_y(&cell_pos, walk.options.arrive_distance_y))
+                    {
+                        debug!("Arrived at {:?}", cell_pos);
+                        *waypoint += 1;
+                        *closest = f32::INFINITY;
+                        *stalled = 0;
+                        walk.replans = 0;
+                        continue;
+                    }
+
+                    let distance = self.position.distance(&target);
+                    if distance < *closest - STUCK_PROGRESS {
+                        *closest = distance;
+                        *stalled = 0;
+                    } else {
+                        *stalled += 1;
+                    }
+
+                    if *stalled >= self.stuck.ticks {
+                        warn!(
+                            "Stuck at {:?} (cell {:?}) walking to {:?} (cell {:?}) on {}",
+                            self.position,
+                            position_to_cell_clamped(&map.bounds, &self.position),
+                            target,
+                            position_to_cell_clamped(&map.bounds, &target),
+                            map.name()
+                        );
+                        if walk.replans >= self.stuck.max_replans {
+                            return Err(Error::Stuck {
+                                position: self.position,
+                                waypoint: target,
+                            });
+                        }
+                        walk.replans += 1;
+
+                        // The rest of the way to the destination is planned from where the player is
+                        let start_cell = map
+                            .closest_walkable_cell(&self.position)
+                            .ok_or(Error::StartNotWalkable)?;
+                        let search = self.spawn_search(
+                            &map,
+                            start_cell,
+                            walk.end_cell,
+                            &walk.options,
+                            &walk.cancel,
+                        );
+                        walk.phase = WalkPhase::Searching(search);
+                        return Ok(WalkStep::Continue);
+                    }
+
+                    let remaining = self.position.distance(&cell_pos)
+                        + path.waypoints[i..]
+                            .windows(2)
+                            .map(|w| w[0].distance(&w[1]))
+                            .sum::<f32>();
+                    walk.progress.send_replace(WalkProgress {
+                        waypoint: i,
+                        waypoints: path.waypoints.len(),
+                        remaining_distance: remaining,
+                    });
+
+                    // Only stands up again if the walk crouched, not if the player already was
+                    if path.crouch[i - 1] && !self.inputs.crouch {
+                        self.crouch(true).await?;
+                        walk.crouched = true;
+                    } else if !path.crouch[i - 1] && walk.crouched {
+                        self.crouch(false).await?;
+                        walk.crouched = false;
+                    }
+
+                    self.look_at(&target);
+                    if !self.inputs.walk {
+                        self.walk(true).await?;
+                    }
+                    return Ok(WalkStep::Continue);
+                }
+                // Steers straight at the destination, bypassing the grid
+                WalkPhase::Approaching { started } => {
+                    let distance = self.position.distance_xz(&walk.destination);
+                    if walk
+                        .options
+                        .final_arrive_distance
+                        .is_none_or(|arrive_distance| distance <= arrive_distance)
+                        || self.clock.elapsed(*started) >= walk.options.final_approach_budget
+                    {
+                        return Ok(WalkStep::Finished);
+                    }
+
+                    self.look_at(&walk.destination);
+                    if !self.inputs.walk {
+                        self.walk(true).await?;
+                    }
+                    let step = self.tick_distance(&self.inputs);
+                    return Ok(if distance < step {
+                        WalkStep::Shortened(self.tick_interval.mul_f32(distance / step))
+                    } else {
+                        WalkStep::Continue
+                    });
+                }
+            }
+        }
+    }
+
+    fn arrived(&self, from: &Vec3, to: &Vec3, is_last: bool, options: &WalkOptions) -> bool {
+        // Predict where the server will see us once our latest inputs arrive
+        let lead = if options.latency_compensation {
+            self.latency.unwrap_or_default().as_micros() as f32 * MOVEMENT_SPEED
+        } else {
+            0.0
+        };
+        let predicted = Vec3 {
+            x: self.position.x + lead * self.rotation.sin(),
+            y: self.position.y,
+            z: self.position.z + lead * -self.rotation.cos(),
+        };
+
+        // Widen the arrive radius by the distance walked during the latency
+        let radius = options.arrive_distance_xz + lead;
+        if predicted.max_diff_xz(to, radius) {
+            return true;
+        }
+
+        // Waypoint lookahead: consider intermediate waypoints reached as soon as the predicted position
+        // passes the plane perpendicular to the segment at the waypoint so we steer to the next one
+        // instead of turning around to hit the cell center exactly
+        if let (false, Some(lookahead)) = (is_last, options.lookahead_distance) {
+            let (dx, dz) = (to.x - from.x, to.z - from.z);
+            let len = (dx * dx + dz * dz).sqrt();
+            if len > f32::EPSILON {
+                let (dx, dz) = (dx / len, dz / len);
+                let probe_x = predicted.x + dx * lookahead - to.x;
+                let probe_z = predicted.z + dz * lookahead - to.z;
+                let along = probe_x * dx + probe_z * dz;
+                let across = (probe_x * dz - probe_z * dx).abs();
+                return along >= 0.0 && across <= radius + lookahead;
+            }
+        }
+
+        false
+    }
+
+    pub async fn walk_to_named(&mut self, name: &str) -> Result<(), Error> {
+        let position = self
+            .map
+            .as_ref()
+            .ok_or(Error::MapUnavailable)?
+            .annotations()
+            .point(name)
+            .ok_or_else(|| format!("Unknown location {}", name))?;
+
+        self.walk_to(&position).await
+    }
+
+    pub async fn walk_route(&mut self, name: &str) -> Result<(), Error> {
+        let route = self
+            .map
+            .as_ref()
+            .ok_or(Error::MapUnavailable)?
+            .annotations()
+            .route(name)
+            .ok_or_else(|| format!("Unknown or incomplete route {}", name))?;
+
+        for position in route.iter() {
+            self.walk_to(position).await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn queue_walk(&self, position: &Vec3, options: WalkOptions) {
+        self.walk_queue.push(*position, options);
+    }
+
+    // Jumps the queue, walked after the current destination
+    pub fn queue_walk_priority(&self, position: &Vec3, options: WalkOptions) {
+        self.walk_queue.push_front(*position, options);
+    }
+
+    pub fn clear_walk_queue(&self) {
+        self.walk_queue.clear();
+    }
+
+    pub fn walk_queue(&self) -> Vec<QueuedWalk> {
+        self.walk_queue.entries()
+    }
+
+    // Shared handle to add destinations while the player is locked by walk_queued
+    pub fn walk_queue_handle(&self) -> WalkQueue {
+        self.walk_queue.clone()
+    }
+
+    // Walk to the queued destinations until the queue is empty, including destinations that are
+    // added in the meantime. A failed destination doesn't stop the queue, the outcome of every entry
+    // is sent to the subscribers of the queue.
+    pub async fn walk_queued(&mut self) -> Result<(), Error> {
+        while let Some(entry) = self.walk_queue.pop() {
+            if !self.state.is_connected() {
+                self.walk_queue
+                    .complete(entry.destination, WalkOutcome::Cleared);
+                self.walk_queue.clear();
+                return Err(Error::Disconnected);
+            }
+
+            let outcome = match self.walk_path(&entry.destination, &entry.options).await {
+                Ok(distance) => WalkOutcome::Arrived { distance },
+                Err(err) => WalkOutcome::Failed(err.to_string()),
+            };
+            self.walk_queue.complete(entry.destination, outcome);
+        }
+
+        Ok(())
+    }
+
+    pub async fn walk(&mut self, state: bool) -> Result<(), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        self.inputs.walk = state;
+        self.send_inputs().await
+    }
+
+    pub async fn crouch(&mut self, state: bool) -> Result<(), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        self.inputs.crouch = state;
+        self.send_inputs().await
+    }
+
+    pub fn is_crouching(&self) -> bool {
+        self.inputs.crouch
+    }
+
+    // Crouching while moving slides, the tick loop stands up again after the slide
+    pub async fn slide(&mut self) -> Result<(), Error> {
+        self.crouch(true).await?;
+        self.slide_until = Some(self.clock.now() + SLIDE_DURATION);
+        Ok(())
+    }
+
+    // Presses jump for one tick, the tick loop releases it again so it works while walking
+    pub fn jump(&mut self) -> Result<(), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        self.jump_pending = true;
+        Ok(())
+    }
+
+    // Holds jump to bunny hop until it is unset
+    pub fn set_jumping(&mut self, state: bool) -> Result<(), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        self.jumping = state;
+        Ok(())
+    }
+
+    fn is_airborne(&self) -> bool {
+        self.airborne_until
+            .is_some_and(|until| self.clock.now() < until)
+    }
+
+    // Fire and aim down sights at the same time
+    pub async fn shoot(&mut self, state: bool) -> Result<(), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        self.inputs.aim = state;
+        self.inputs.fire = state;
+        self.send_inputs().await
+    }
+
+    pub async fn fire(&mut self, state: bool) -> Result<(), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        self.inputs.fire = state;
+        self.send_inputs().await
+    }
+
+    // Aiming down sights slows the player down
+    pub async fn aim(&mut self, state: bool) -> Result<(), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        self.inputs.aim = state;
+        self.send_inputs().await
+    }
+
+    // Slot of the last spawn, the input keys start with it
+    pub fn slot(&self) -> u8 {
+        self.inputs.slot
+    }
+
+    pub fn inputs(&self) -> InputState {
+        self.inputs
+    }
+
+    pub fn is_firing(&self) -> bool {
+        self.inputs.fire
+    }
+
+    pub fn is_aiming(&self) -> bool {
+        self.inputs.aim
+    }
+
+    // Look at the position and fire a single shot. With ads the player aims down sights first and waits
+    // for the scope delay of the builder, which should not be used for weapons without a scope like shotguns.
+    pub async fn shoot_at(&mut self, position: &Vec3, ads: bool) -> Result<(), Error> {
+        self.look_at(position);
+
+        if ads {
+            self.aim(true).await?;
+            self.clock.sleep(self.scope_delay).await;
+        }
+
+        self.fire(true).await?;
+        self.clock.sleep(self.tick_interval).await;
+        self.fire(false).await?;
+
+        if ads {
+            self.aim(false).await?;
+        }
+
+        Ok(())
+    }
+
+    // Sends the inputs right away instead of waiting for the next tick
+    async fn send_inputs(&mut self) -> Result<(), Error> {
+        if !self.state.is_in_game() {
+            return Err(Error::NotInGame);
+        }
+
+        self.inputs_changed = true;
+        self.socket
+            .send(&MessageBuilder::tick(
                 self.tick,
                 &self.tick_interval,
                 None,
-                Some(format!(
-                    "{{\"0-5\": {s}, \"0-6\": {s}}}",
-                    s = if state { 1 } else { 0 }
-                )),
-            )?)
+                Some(&self.inputs),
+            ))
             .await?;
+        self.sent_inputs = self.inputs;
         self.tick += 1;
+        self.summary.ticks_sent += 1;
+        Ok(())
+    }
+
+    fn tick_distance(&self, inputs: &InputState) -> f32 {
+        let mut dist = self.tick_interval.as_micros() as f32 * MOVEMENT_SPEED;
+        if inputs.aim {
+            dist *= ADS_SPEED_MULTIPLIER;
+        }
+        if inputs.crouch {
+            dist *= CROUCH_SPEED_MULTIPLIER;
+        }
+        dist
+    }
+
+    pub fn rotation(&mut self, rotation: f32) {
+        self.rotation = rotation;
+        if self.rotation > 2.0 * PI {
+            self.rotation -= 2.0 * PI;
+        } else if self.rotation < 0.0 {
+            self.rotation += 2.0 * PI;
+        }
+    }
+
+    pub fn rotate(&mut self, rotation: f32) {
+        self.rotation(self.rotation + rotation);
+    }
+
+    pub fn pitch(&mut self, pitch: f32) {
+        self.pitch = pitch.clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    pub fn set_view(&mut self, yaw: f32, pitch: f32) {
+        self.rotation(yaw);
+        self.pitch(pitch);
+    }
+
+    pub fn look_at(&mut self, position: &Vec3) {
+        self.rotation(self.yaw_to(position));
+    }
+
+    // Also looks up or down from the eyes to the position
+    pub fn look_at_3d(&mut self, position: &Vec3) {
+        self.look_at(position);
+        self.pitch(self.pitch_to(position));
+    }
+
+    fn yaw_to(&self, position: &Vec3) -> f32 {
+        (position.z - self.position.z).atan2(position.x - self.position.x) + PI / 2.0
+    }
+
+    fn pitch_to(&self, position: &Vec3) -> f32 {
+        let dy = position.y - (self.position.y + EYE_HEIGHT);
+        dy.atan2(self.position.distance_xz(position))
+    }
+
+    // The tick loop turns the view towards the last known position of the player, see
+    // Player::players, at most by the max turn rate of the builder. Walks wait until the
+    // target is cleared or lost.
+    pub fn track_target(&mut self, player_id: &str) -> Result<(), Error> {
+        if self.world.get(player_id).is_none() || Some(player_id) == self.id.as_deref() {
+            return Err(format!("Unknown player {}", player_id).into());
+        }
+
+        self.target = Some(player_id.to_owned());
+        Ok(())
+    }
+
+    pub fn clear_target(&mut self) {
+        self.target = None;
+    }
+
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    fn turn_to_target(&mut self) {
+        let target = match self.target.as_ref() {
+            Some(target) => target,
+            None => return,
+        };
+        let position = match self.world.get(target) {
+            Some(player) => player.position,
+            None => {
+                info!("Lost target {}", target);
+                self.emit(PlayerEvent::TargetLost(target.clone()));
+                self.target = None;
+                return;
+            }
+        };
+
+        let max_turn = self.max_turn_rate;
+        let yaw = (self.yaw_to(&position) - self.rotation + PI).rem_euclid(2.0 * PI) - PI;
+        self.rotate(yaw.clamp(-max_turn, max_turn));
+        let pitch = self.pitch_to(&position) - self.pitch;
+        self.pitch(self.pitch + pitch.clamp(-max_turn, max_turn));
+    }
+
+    pub async fn disconnect(&mut self) -> Result<(), Error> {
+        self.end(EndReason::UserDisconnect);
+
+        if self.state != LifecycleState::Disconnected {
+            self.transition(LifecycleState::Disconnected)?;
+            if let Some(end_state) = self.end_state.as_ref() {
+                self.emit(PlayerEvent::Disconnected(end_state.reason.clone()));
+                self.emit(PlayerEvent::Ended(end_state.clone()));
+            }
+            self.socket.close().await?;
+        }
+
+        self.snapshot.send_replace(self.snapshot());
+
+        Ok(())
+    }
+
+    // Why and when the player stopped, None while it is running
+    pub fn end_state(&self) -> Option<&EndState> {
+        self.end_state.as_ref()
+    }
+
+    pub fn session_summary(&self) -> SessionSummary {
+        self.end_state
+            .as_ref()
+            .map(|end| end.summary)
+            .unwrap_or_else(|| SessionSummary {
+                duration: self.clock.elapsed(self.connected_at),
+                ..self.summary
+            })
+    }
+
+    // Only the first reason is kept
+    fn end(&mut self, reason: EndReason) {
+        if self.end_state.is_none() {
+            info!("Player in {} stopped: {:?}", self.game.id, reason);
+            self.end_state = Some(EndState {
+                reason,
+                at: SystemTime::now(),
+                summary: self.session_summary(),
+            });
+            let _ = self.transition(LifecycleState::Ended);
+        }
+    }
+
+    async fn end_with(&mut self, reason: EndReason) -> Result<(), Error> {
+        self.end(reason);
+        self.disconnect().await
+    }
+
+    pub fn in_game(&self) -> bool {
+        self.state.is_in_game()
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.state.is_dead()
+    }
+
+    // Positions of other players at their last spawn or observation, not live, see WorldState
+    pub fn players(&self) -> Vec<RemotePlayer> {
+        self.world
+            .players()
+            .filter(|player| Some(&player.id) != self.id.as_ref())
+            .cloned()
+            .collect()
+    }
+
+    // Nearest last known position of another player. Teammates are only excluded once the teams
+    // are set, see Player::set_team.
+    pub fn nearest_enemy(&self) -> Option<RemotePlayer> {
+        self.world
+            .nearest_enemy(&self.position, self.team, self.id.as_deref())
+            .cloned()
+    }
+
+    // For teams parsed by the caller, the own id sets the team of the player itself
+    pub fn set_team(&mut self, id: &str, team: u8) -> Result<(), Error> {
+        if Some(id) == self.id.as_deref() {
+            self.team = Some(team);
+        } else if !self.world.set_team(id, team) {
+            return Err(format!("Unknown player {}", id).into());
+        }
+        Ok(())
+    }
+
+    pub fn team(&self) -> Option<u8> {
+        self.team
+    }
+
+    // For positions of other players parsed by the caller, e.g. in a message hook
+    pub fn observe_player(&mut self, id: &str, position: Vec3) {
+        if Some(id) != self.id.as_deref() {
+            self.world.update(id, position, self.summary.ticks_sent);
+        }
+    }
+
+    // For players that left the game, a spawn of the id afterwards counts as a new player
+    pub fn forget_player(&mut self, id: &str) {
+        self.world.remove(id);
+        self.known_ids.remove(id);
+    }
+
+    pub fn spectating(&self) -> Option<Vec3> {
+        self.spectating
+    }
+
+    pub async fn diagnostics(&self) -> PlayerDiagnostics {
+        PlayerDiagnostics {
+            state_buffer_len: self.state_buffer.len(),
+            known_players: self.known_ids.len(),
+            queued_messages: self.socket.queued_messages().await + self.deferred_messages.len(),
+            socket: self.socket.stats().await,
+            backlog: self.backlog,
+        }
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    pub fn map(&self) -> Option<&Map> {
+        self.map.as_deref()
+    }
+
+    pub fn snapshot(&self) -> PlayerSnapshot {
+        PlayerSnapshot {
+            id: self.id.clone(),
+            game_id: self.game.id.clone(),
+            map: self.map.as_ref().map(|map| map.name()),
+            region: self.region_name(),
+            state: self.state,
+            connected: self.state.is_connected(),
+            in_game: self.state.is_in_game(),
+            dead: self.is_dead(),
+            spectating: self.spectating,
+            end_reason: self.end_state.as_ref().map(|end| end.reason.clone()),
+            enter_rejected: self.enter_rejected,
+            lobby_stale: self.lobby_stale,
+            unhealthy: self.unhealthy,
+            walking: self.inputs.walk,
+            position: self.position,
+            rotation: self.rotation,
+            pitch: self.pitch,
+        }
+    }
+
+    // Name of the labeled region the player is in
+    pub fn region_name(&self) -> Option<String> {
+        let region = self.map.as_ref()?.region_at(&self.position)?;
+        Some(region.name().to_owned())
+    }
+
+    // Receives a new snapshot after every tick without having to lock the player,
+    // which is blocked for the whole duration of walk_to
+    pub fn watch_snapshot(&self) -> watch::Receiver<PlayerSnapshot> {
+        self.snapshot.subscribe()
+    }
+
+    // Like the snapshots the events don't need the player to be locked, see PlayerEvent for what
+    // subscribers miss
+    pub fn events(&self) -> impl Stream<Item = PlayerEvent> {
+        stream::unfold(self.events.subscribe(), |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((event, events)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    fn emit(&self, event: PlayerEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    // Background tasks of the player and its socket that are still running, the tick task stops
+    // on the first tick after disconnecting
+    pub fn task_count(&self) -> usize {
+        self.tasks.count() + self.socket.task_count()
+    }
+
+    fn run_tick(this: Arc<Mutex<Self>>, tasks: &TaskRegistry) {
+        tasks.spawn(async move {
+            let (mut interval, clock) = {
+                let this = this.lock().await;
+                (this.clock.interval(this.tick_interval), this.clock.clone())
+            };
+            loop {
+                interval.tick().await;
+
+                let mut this_lock = this.lock().await;
+                if !this_lock.step().await {
+                    break;
+                }
+
+                let shift = this_lock.alignment_shift();
+                drop(this_lock);
+                if let Some(shift) = shift {
+                    clock.sleep(shift).await;
+                    interval.reset();
+                }
+            }
+        });
+    }
+
+    // One tick of the tick loop, false once the loop stops
+    pub(crate) async fn step(&mut self) -> bool {
+        if !self.state.is_connected() {
+            return false;
+        }
+
+        match AssertUnwindSafe(self.tick()).catch_unwind().await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => {
+                let category = ErrorCategory::from(&err);
+                self.record_error(category, None, "Failed to execute player tick", &err)
+                    .await;
+            }
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                error!("Player tick panicked: {}", message);
+                let _ = self.end_with(EndReason::Panic(message)).await;
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn server_clock(&self) -> &ServerClock {
+        &self.server_clock
+    }
+
+    // Fractional tick the server is at, None until enough updates were received
+    pub fn server_tick(&self) -> Option<f64> {
+        self.server_clock.tick_at(self.clock.now())
+    }
+
+    // Time on the server tick clock
+    pub fn server_time(&self) -> Option<Duration> {
+        let tick = self.server_tick()?;
+        let rate = self.server_clock.rate()?;
+        Some(Duration::from_secs_f64((tick / rate).max(0.0)))
+    }
+
+    // Negative if the server already passed the tick
+    pub fn ticks_until(&self, server_tick: u32) -> Option<f64> {
+        Some(server_tick as f64 - self.server_tick()?)
+    }
+
+    // Delay that moves the next tick to lead before the estimated processing time of the server.
+    // A server tick is processed about one way latency before its update arrives and a sent tick
+    // needs one way latency to get there.
+    fn alignment_shift(&mut self) -> Option<Duration> {
+        let lead = self.tick_alignment?;
+        let now = self.clock.now();
+        if self
+            .last_alignment
+            .is_some_and(|last| self.clock.elapsed(last) < TICK_ALIGNMENT_INTERVAL)
+        {
+            return None;
+        }
+
+        let tick = self.server_clock.tick_at(now)?;
+        let next_update = self.server_clock.time_of(tick.floor() + 1.0)?;
+        self.last_alignment = Some(now);
+
+        let interval = self.tick_interval.as_secs_f64();
+        let round_trip = self.latency.unwrap_or_default().as_secs_f64();
+        let until_send = next_update.saturating_duration_since(now).as_secs_f64()
+            - round_trip
+            - lead.as_secs_f64();
+        let shift = until_send.rem_euclid(interval);
+
+        // Small phase errors aren't worth delaying a tick
+        if shift < interval * 0.1 || shift > interval * 0.9 {
+            None
+        } else {
+            debug!("Shifting the tick loop by {:.1}ms", shift * 1000.0);
+            Some(Duration::from_secs_f64(shift))
+        }
+    }
+
+    // Set by the pool while it is above its bandwidth cap to skip more idle ticks
+    pub(crate) fn set_throttle(&mut self, throttled: Arc<AtomicBool>) {
+        self.throttled = throttled;
+    }
+
+    pub(crate) fn socket_stats(&self) -> Arc<Mutex<SocketStats>> {
+        self.socket.stats_handle()
+    }
+
+    fn skip_tick(&mut self) -> bool {
+        let jump = self.jump_pending || self.jumping;
+        let idle = !self.inputs.walk && !self.inputs_changed && jump == self.sent_inputs.jump;
+        self.inputs_changed = false;
+
+        let now = self.clock.now();
+        let throttled = self.throttled.load(Ordering::Relaxed);
+        match self.idle_ticks.as_mut() {
+            Some(idle_ticks) => {
+                idle_ticks.should_skip(idle, (self.rotation, self.pitch), now, throttled)
+            }
+            None => false,
+        }
+    }
+
+    async fn send_tick(&mut self) -> Result<(), Error> {
+        // A single jump is released again on the next tick
+        self.inputs.jump = std::mem::take(&mut self.jump_pending) || self.jumping;
+        // The inputs are only sent when they changed or buttons are held
+        let inputs =
+            (self.inputs != self.sent_inputs || self.inputs.is_holding()).then_some(&self.inputs);
+        self.socket
+            .send(&MessageBuilder::tick(
+                self.tick,
+                &self.tick_interval,
+                Some((self.rotation, self.pitch)),
+                inputs,
+            ))
+            .await?;
+        self.sent_inputs = self.inputs;
+        if self.inputs.jump {
+            self.airborne_until = Some(self.clock.now() + AIRBORNE_TIME);
+        }
+        self.tick += 1;
+        self.summary.ticks_sent += 1;
+
+        if self.inputs.walk {
+            let dist = self.tick_distance(&self.inputs);
+            self.summary.distance_walked += dist;
+            self.position.x += dist * self.rotation.sin();
+            self.position.z += dist * -self.rotation.cos();
+        }
+
+        if self.state_buffer.len() >= MAX_STATE_BUFFER {
+            self.state_buffer.pop_front();
+        }
+        self.state_buffer.push_back(State {
+            tick: self.tick,
+            sent_at: self.clock.now(),
+            position: self.position,
+            rotation: self.rotation,
+            inputs: self.inputs,
+        });
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> Result<(), Error> {
+        if self
+            .respawn_at
+            .is_some_and(|respawn_at| self.clock.now() >= respawn_at)
+        {
+            self.respawn_at = None;
+            self.enter().await?;
+        }
+
+        if self.state == LifecycleState::Entering
+            && self
+                .enter_deadline
+                .is_some_and(|deadline| self.clock.now() >= deadline)
+        {
+            self.retry_enter().await?;
+        }
+
+        if self
+            .slide_until
+            .is_some_and(|slide_until| self.clock.now() >= slide_until)
+        {
+            self.slide_until = None;
+            if self.state.is_in_game() {
+                self.crouch(false).await?;
+            }
+        }
+
+        if let Some(stale_ticks) = self.stale_player_ticks {
+            self.world.prune(self.summary.ticks_sent, stale_ticks);
+        }
+        self.turn_to_target();
+
+        // A walk steers before the rotation is sent
+        let shortened = self.advance_walk().await;
+        if self.state.is_in_game() && !self.skip_tick() {
+            let tick_interval = self.tick_interval;
+            self.tick_interval = shortened.unwrap_or(tick_interval);
+            let result = self.send_tick().await;
+            self.tick_interval = tick_interval;
+            result?;
+        }
+
+        while let Ok(update) = self.game_updates.try_recv() {
+            match update {
+                Ok((game, map)) => {
+                    self.emit(PlayerEvent::GameInit {
+                        map: game.map.clone(),
+                        mode: game.mode,
+                    });
+                    self.game = game;
+                    self.map = map;
+                }
+                Err(err) => error!("Failed to update game info of {}: {}", self.game.id, err),
+            }
+        }
+
+        while let Ok(listed) = self.listing.try_recv() {
+            self.listing_pending = false;
+            match listed {
+                Ok(true) => (),
+                Ok(false) => {
+                    warn!("Alone in {}, which is no longer listed", self.game.id);
+                    self.lobby_stale = true;
+                    self.emit(PlayerEvent::LobbyStale);
+                    if self.stale_lobby.map(|options| options.policy)
+                        == Some(StaleLobbyPolicy::Disconnect)
+                    {
+                        return self.end_with(EndReason::Delisted).await;
+                    }
+                }
+                Err(err) => warn!("Failed to check if {} is listed: {}", self.game.id, err),
+            }
+        }
+        self.poll_listing();
+
+        // Handlers never wait on the network, the budget only guards against message floods
+        let mut messages = std::mem::take(&mut self.deferred_messages);
+        messages.extend(self.socket.get_messages().await);
+        if messages.len() > BACKLOG_THRESHOLD {
+            self.collapse_backlog(&mut messages);
+        }
+        let start = self.clock.now();
+        while let Some(msg) = messages.pop_front() {
+            if self.clock.elapsed(start) > MESSAGE_DRAIN_BUDGET {
+                debug!("Deferring {} messages to the next tick", messages.len() + 1);
+                messages.push_front(msg);
+                break;
+            }
+
+            let msg = match msg {
+                SocketMessage::Message(msg) => msg,
+                SocketMessage::Close => {
+                    warn!("Server closed the connection to {}", self.game.id);
+                    let reason = match self.kick.take() {
+                        Some(kick) => EndReason::Kicked(kick),
+                        None => EndReason::GameGone,
+                    };
+                    return self.end_with(reason).await;
+                }
+                SocketMessage::Error(err) => {
+                    if matches!(err, Error::WebSocket(_)) {
+                        warn!("Connection to {} failed: {}", self.game.id, err);
+                        self.emit(PlayerEvent::SocketError(err.to_string()));
+                        return self.end_with(EndReason::SocketError(err.to_string())).await;
+                    }
+
+                    self.decode_errors += 1;
+                    if self.decode_errors >= MAX_CONSECUTIVE_DECODE_ERRORS {
+                        return self.end_with(EndReason::ProtocolDesync).await;
+                    }
+                    continue;
+                }
+            };
+
+            self.decode_errors = 0;
+            match self.run_hooks(&msg) {
+                HookAction::Continue => (),
+                HookAction::Suppress => continue,
+                HookAction::Custom(reply) => {
+                    if let Err(err) = self.socket.send(&reply).await {
+                        error!("Failed to send hook reply to '{}': {}", msg.kind, err);
+                    }
+                    continue;
+                }
+            }
+
+            if self.paused_messages.contains(&msg.kind) {
+                continue;
+            }
+            if let Err(err) = self.process_message(&msg.kind, msg.data).await {
+                // Handlers mostly fail on message shapes they don't know
+                let category = match ErrorCategory::from(&err) {
+                    ErrorCategory::Other => ErrorCategory::Protocol,
+                    category => category,
+                };
+                let context = format!("Failed to process server message '{}'", msg.kind);
+                self.record_error(category, Some(&msg.kind), &context, &err)
+                    .await;
+                if !self.state.is_connected() {
+                    return Ok(());
+                }
+            }
+        }
+        self.deferred_messages = messages;
+
+        self.snapshot.send_replace(self.snapshot());
+
         Ok(())
     }
 
-    pub fn rotation(&mut self, rotation: f32) {
-        self.rotation = rotation;
-        if self.rotation > 2.0 * PI {
-            self.rotation -= 2.0 * PI;
-        } else if self.rotation < 0.0 {
-            self.rotation += 2.0 * PI;
-        }
-    }
+    // Answering every ping and reconciling with every update of a backlog only desyncs the player
+    // further. Older messages are removed by the rule of their kind, hooks don't see them either.
+    fn collapse_backlog(&mut self, messages: &mut VecDeque<SocketMessage>) {
+        let queued = messages.len();
+        let mut newest = HashSet::new();
+        let mut dropped = 0;
+        let mut collapsed = 0;
 
-    pub fn rotate(&mut self, rotation: f32) {
-        self.rotation(self.rotation + rotation);
-    }
+        // Walk from the newest message back so the first message of a kind is the one kept
+        let mut kept = messages
+            .drain(..)
+            .rev()
+            .filter(|msg| {
+                let msg = match msg {
+                    SocketMessage::Message(msg) => msg,
+                    _ => return true,
+                };
+                let rule = backlog_rule(&msg.kind);
+                if rule == BacklogRule::Keep
+                    || (msg.kind == "l" && MessageParser::is_death(&msg.data))
+                    || newest.insert(msg.kind.clone())
+                {
+                    return true;
+                }
 
-    pub fn look_at(&mut self, position: &Vec3) {
-        self.rotation(
-            (position.z - self.position.z).atan2(position.x - self.position.x) + PI / 2.0,
+                match rule {
+                    BacklogRule::ReplyNewest => dropped += 1,
+                    _ => collapsed += 1,
+                }
+                false
+            })
+            .collect::<VecDeque<_>>();
+        kept.make_contiguous().reverse();
+        *messages = kept;
+
+        self.backlog.backlogs += 1;
+        self.backlog.dropped += dropped;
+        self.backlog.collapsed += collapsed;
+        self.emit(PlayerEvent::Backlog { dropped, collapsed });
+        warn!(
+            "Backlog of {} messages in {}, dropped {} and collapsed {}",
+            queued, self.game.id, dropped, collapsed
         );
     }
 
-    pub async fn disconnect(&mut self) -> Result<(), Error> {
-        self.ready = false;
-        self.in_game = false;
+    // Logs the error and applies the action of its category once the category used up its budget
+    async fn record_error(
+        &mut self,
+        category: ErrorCategory,
+        message_kind: Option<&str>,
+        context: &str,
+        err: &Error,
+    ) {
+        let trip = match self
+            .error_budget
+            .record(self.clock.now(), category, context, err)
+        {
+            Some(trip) => trip,
+            None => return,
+        };
 
-        if !self.disconnected {
-            self.disconnected = true;
-            self.socket.close().await?;
+        warn!(
+            "Player in {} is unhealthy, {} {:?} errors",
+            self.game.id, trip.count, trip.category
+        );
+        self.unhealthy = Some(trip.category);
+        self.emit(PlayerEvent::Unhealthy {
+            category: trip.category,
+            count: trip.count,
+        });
+        match (trip.action, message_kind) {
+            (BreakerAction::PauseMessage, Some(kind)) => {
+                warn!("No longer handling '{}' messages", kind);
+                self.paused_messages.insert(kind.to_owned());
+            }
+            (BreakerAction::Disconnect, _) => {
+                let _ = self.end_with(EndReason::Unhealthy(trip.category)).await;
+            }
+            _ => (),
         }
-
-        Ok(())
     }
 
-    pub fn in_game(&self) -> bool {
-        self.in_game
+    // Message kinds that are ignored because their handler kept failing
+    pub fn paused_messages(&self) -> Vec<String> {
+        self.paused_messages.iter().cloned().collect()
     }
 
-    pub fn map(&self) -> Option<&Map> {
-        self.map.as_ref()
+    pub fn unhealthy(&self) -> Option<ErrorCategory> {
+        self.unhealthy
     }
 
-    fn run_tick(this: Arc<Mutex<Self>>) {
-        tokio::spawn(async move {
-            let mut interval = time::interval(this.lock().await.tick_interval);
-            loop {
-                interval.tick().await;
-
-                let mut this_lock = this.lock().await;
+    async fn spawn(&mut self, msg: &[serde_json::Value]) -> Result<(), Error> {
+        let id = self.id.as_ref().ok_or("Id not set")?;
+        if let Some(spawn_position) = MessageParser::spawn_position(msg, id)? {
+            let slot = MessageParser::spawn_slot(msg, id)?.unwrap_or_else(|| {
+                warn!("Spawn without a valid slot, using slot 0");
+                0
+            });
+            let respawned = self.state.is_dead();
+            if !self.state.is_in_game() {
+                self.transition(LifecycleState::InGame)?;
+            }
+            self.emit(if respawned {
+                PlayerEvent::Respawned(spawn_position)
+            } else {
+                PlayerEvent::Spawned(spawn_position)
+            });
+            self.slide_until = None;
+            self.jump_pending = false;
+            self.jumping = false;
+            self.airborne_until = None;
+            self.enter_deadline = None;
+            self.enter_rejected = None;
+            self.respawn_at = None;
+            self.spectating = None;
+            self.position = spawn_position;
+            // Ticks start at 1 again, states of the previous life would never be acknowledged
+            self.state_buffer.clear();
 
-                if this_lock.disconnected {
-                    break;
-                }
+            // The initial tick releases every input
+            self.inputs = InputState::new(slot);
+            self.sent_inputs = self.inputs;
+            self.socket.send(&MessageBuilder::init_tick(slot)).await?;
+            self.tick = 1;
+        }
 
-                if let Err(err) = this_lock.tick().await {
-                    error!("Failed to execute player tick: {}", err);
-                }
-            }
-        });
+        Ok(())
     }
 
-    async fn tick(&mut self) -> Result<(), Error> {
-        if self.in_game {
-            self.socket
-                .send(&MessageBuilder::tick(
-                    self.tick,
-                    &self.tick_interval,
-                    Some(self.rotation),
-                    None,
-                )?)
-                .await?;
-            self.tick += 1;
-
-            if self.walking {
-                let dist = self.tick_interval.as_micros() as f32 * MOVEMENT_SPEED;
-                self.position.x += dist * self.rotation.sin();
-                self.position.z += dist * -self.rotation.cos();
-            }
-
-            self.state_buffer.push_back(State {
-                tick: self.tick,
-                position: self.position,
-                rotation: self.rotation,
-                walking: self.walking,
-            });
+    async fn replay_pending_spawns(&mut self) -> Result<(), Error> {
+        while let Some(msg) = self.pending_spawns.pop_front() {
+            self.spawn(&msg).await?;
         }
 
-        for msg in self.socket.get_messages().await {
-            match msg {
-                SocketMessage::Message(msg_type, msg) => {
-                    if let Err(err) = self.process_message(&msg_type, msg).await {
-                        error!("Failed to process server message '{}': {}", msg_type, err);
-                    }
-                }
-                _ => (),
+        Ok(())
+    }
+
+    // Hooks run synchronously inside the message drain, so they have to be cheap
+    fn run_hooks(&self, msg: &ServerMessage) -> HookAction {
+        for hook in self.message_hooks.iter() {
+            // Measures the real time the hook takes, independent of the clock
+            let start = Instant::now();
+            let action = hook(msg);
+            if start.elapsed() > HOOK_TIME_BUDGET {
+                warn!(
+                    "Message hook took {:?} for '{}', hooks should not block the tick",
+                    start.elapsed(),
+                    msg.kind
+                );
+            }
+
+            if !matches!(action, HookAction::Continue) {
+                return action;
             }
         }
 
-        Ok(())
+        HookAction::Continue
     }
 
     async fn process_message(
@@ -341,67 +2476,169 @@ impl Player {
             // includes player id
             "io-init" => {
                 self.id = Some(MessageParser::io_init(&msg)?);
+                self.replay_pending_spawns().await?;
             }
             // sent after connect and at the start of every game
             "init" => {
-                self.game.update_info().await?;
-                self.map = self
-                    .client
-                    .lock()
-                    .await
-                    .maps
-                    .iter()
-                    .find(|map| map.name == self.game.map)
-                    .cloned();
-                if self.ready {
+                // The game info is requested from the matchmaker, applied by a later tick
+                let mut game = self.game.clone();
+                let client = self.client.clone();
+                let game_updates = self.game_updates_tx.clone();
+                self.tasks.spawn(async move {
+                    let update = match game.update_info().await {
+                        Ok(()) => {
+                            // Maps that weren't parsed up front are parsed now
+                            let map = match Client::load_map_shared(&client, &game.map).await {
+                                Ok(map) => Some(Arc::new(map)),
+                                Err(err) => {
+                                    warn!("No map data for {}: {}", game.map, err);
+                                    None
+                                }
+                            };
+                            Ok((game, map))
+                        }
+                        Err(err) => Err(err),
+                    };
+                    let _ = game_updates.send(update);
+                });
+
+                if matches!(
+                    self.state,
+                    LifecycleState::Lobby | LifecycleState::Dead { .. }
+                ) {
                     self.enter().await?;
                 }
             }
             // sent after the server has sent all the necessary information after connect
-            "ready" => {
-                if let Some(account) = self.account.as_mut() {
+            "ready" => match (self.state, self.account.as_ref()) {
+                (LifecycleState::Handshaking, Some(account)) => {
                     self.socket.send(&MessageBuilder::login(account)).await?;
-                } else {
-                    self.ready = true;
+                    self.transition(LifecycleState::LoggingIn)?;
+                }
+                // Without an account, or sent again after the login was accepted
+                (LifecycleState::Handshaking | LifecycleState::LoggingIn, _) => {
+                    if self.state == LifecycleState::LoggingIn {
+                        self.emit(PlayerEvent::LoggedIn(Ok(())));
+                    }
+                    self.transition(LifecycleState::Lobby)?;
                     self.enter().await?;
                 }
-            }
+                _ => (),
+            },
             // spawn in game
             "0" => {
-                if let Some(spawn_position) =
-                    MessageParser::spawn_position(&msg, self.id.as_ref().ok_or("Id not set")?)?
-                {
-                    self.in_game = true;
-                    self.walking = false;
-                    self.position = spawn_position;
+                let players = MessageParser::spawn_players(&msg)?;
+                for (id, position) in &players {
+                    self.world.update(id, *position, self.summary.ticks_sent);
+                }
+                let ids = players.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+                if self.id.is_none() {
+                    // Some servers send the spawn before io-init, keep it until the id is known
+                    if self.pending_spawns.len() >= MAX_PENDING_SPAWNS {
+                        self.pending_spawns.pop_front();
+                    }
+                    self.pending_spawns.push_back(msg);
 
-                    self.socket.send(&MessageBuilder::init_tick()).await?;
-                    self.tick = 1;
+                    // If only a single player spawned that wasn't seen before it has to be us
+                    let new_ids = ids
+                        .iter()
+                        .filter(|id| !self.known_ids.contains(*id))
+                        .collect::<Vec<_>>();
+                    if let [id] = new_ids[..] {
+                        warn!("Received spawn before io-init, assuming id {}", id);
+                        self.id = Some(id.clone());
+                        self.replay_pending_spawns().await?;
+                    }
+                } else {
+                    self.spawn(&msg).await?;
                 }
+                if ids.iter().any(|id| Some(id) != self.id.as_ref()) {
+                    self.last_other_spawn = self.clock.now();
+                    self.lobby_stale = false;
+                }
+                self.known_ids.extend(ids);
             }
             // player update
             "l" => {
                 let state = MessageParser::player_state(&msg)?;
                 if state.is_dead {
-                    self.in_game = false;
-                    tokio::time::sleep(Duration::from_secs(3)).await;
-                    self.enter().await?;
+                    if self.state.is_in_game() {
+                        self.summary.deaths += 1;
+                        if !self.keep_walk_queue_on_death {
+                            self.walk_queue.clear();
+                        }
+                        if let Some(region) = self.region_name() {
+                            info!("Died at {}", region);
+                        }
+                        self.inputs.walk = false;
+                        self.inputs.fire = false;
+                        self.inputs.aim = false;
+                        self.transition(LifecycleState::Dead {
+                            since: self.clock.now(),
+                        })?;
+                        self.emit(PlayerEvent::Died);
+                        // The tick keeps processing messages while waiting to respawn
+                        if self.game.mode_info().respawns {
+                            self.respawn_at = Some(self.clock.now() + RESPAWN_DELAY);
+                        } else {
+                            // Entering again is only possible in the next round
+                            info!("Died in a mode without respawns, waiting for the round to end");
+                            self.emit(PlayerEvent::RoundEnded);
+                        }
+                    }
+                } else if matches!(
+                    self.state,
+                    LifecycleState::Dead { .. } | LifecycleState::Entering
+                ) {
+                    // Updates between death and respawn follow the spectated player
+                    if let Some(position) = state.position {
+                        self.spectating = Some(position);
+                        self.emit(PlayerEvent::Spectating(position));
+                    }
                 } else if let (Some(tick), Some(position)) = (state.tick, state.position) {
+                    self.server_clock.observe(self.clock.now(), tick);
+
+                    // Measure the round trip time of the acknowledged tick
+                    if let Some(acked) = self.state_buffer.iter().find(|s| s.tick == tick) {
+                        let sample = self.clock.elapsed(acked.sent_at);
+                        self.latency = Some(match self.latency {
+                            Some(latency) => latency.mul_f32(0.8) + sample.mul_f32(0.2),
+                            None => sample,
+                        });
+                    }
+
                     self.state_buffer.retain(|s| s.tick >= tick);
 
+                    // The height isn't predicted, the server's y is taken as is while airborne
+                    let airborne = self.is_airborne();
+                    if airborne {
+                        self.position.y = position.y;
+                    }
+                    // Neither is the speed of a slide
+                    let max_diff = if airborne || self.slide_until.is_some() {
+                        AIRBORNE_MAX_DIFF_XZ
+                    } else {
+                        0.5
+                    };
+
                     if let Some(past_state) = self.state_buffer.front() {
                         // Reconciliate the position if there is too much difference between the states
-                        if !position.max_diff_xz(&past_state.position, 0.5) {
+                        if !position.max_diff_xz(&past_state.position, max_diff) {
+                            // Send every tick again after a correction
+                            if let Some(idle_ticks) = self.idle_ticks.as_mut() {
+                                idle_ticks.reset();
+                            }
+
                             self.position = position;
-                            for state in self.state_buffer.iter_mut() {
-                                if state.walking {
-                                    let dist =
-                                        self.tick_interval.as_micros() as f32 * MOVEMENT_SPEED;
+                            for i in 0..self.state_buffer.len() {
+                                let state = &self.state_buffer[i];
+                                if state.inputs.walk {
+                                    let dist = self.tick_distance(&state.inputs);
                                     self.position.x += dist * state.rotation.sin();
                                     self.position.z += dist * -state.rotation.cos();
                                 }
 
-                                state.position = self.position;
+                                self.state_buffer[i].position = self.position;
                             }
                         }
                     }
@@ -411,10 +2648,44 @@ impl Player {
             }
             // game has ended
             "end" => {
-                self.in_game = false;
+                if matches!(
+                    self.state,
+                    LifecycleState::Entering | LifecycleState::InGame | LifecycleState::Dead { .. }
+                ) {
+                    self.transition(LifecycleState::Lobby)?;
+                }
+                // The next init enters again, an enter the round end swallowed isn't a failure
+                self.enter_deadline = None;
+                self.respawn_at = None;
+                self.spectating = None;
+                self.world.clear();
+                self.known_ids.clear();
+                self.team = None;
+                self.emit(PlayerEvent::GameEnded);
             }
             // server error
-            "error" => return Err(format!("Sever error: {}", MessageParser::error(&msg)).into()),
+            "error" => {
+                let message = MessageParser::error(&msg);
+                if self.state == LifecycleState::LoggingIn {
+                    self.emit(PlayerEvent::LoggedIn(Err(message.clone())));
+                }
+                self.kick = Some(KickReason {
+                    message: message.clone(),
+                });
+                return Err(format!("Sever error: {}", message).into());
+            }
+            // kill feed
+            "k" => {
+                let (killer, _) = MessageParser::kill(&msg)?;
+                if Some(&killer) == self.id.as_ref() {
+                    self.summary.kills += 1;
+                }
+            }
+            // chat message
+            "ch" => {
+                let (sender, text) = MessageParser::chat(&msg)?;
+                self.emit(PlayerEvent::ChatMessage { sender, text });
+            }
             "cap" => info!("Wants captcha"),
             _ => (),
         }
@@ -422,3 +2693,844 @@ impl Player {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        map::{RawMap, RawMapObject},
+        soak::Soak,
+    };
+
+    fn idle_ticks(skip_chance: f32, throttled_skip_chance: f32) -> IdleTicks {
+        IdleTicks {
+            options: IdleTickOptions {
+                idle_after: 2,
+                skip_chance,
+                throttled_skip_chance,
+                max_consecutive_skips: 3,
+                max_interval: Duration::from_secs(1),
+            },
+            unchanged: 0,
+            skipped: 0,
+            last_sent: None,
+            last_view: (0.0, 0.0),
+        }
+    }
+
+    // Whether each of the ticks 66ms apart was skipped
+    fn skips(idle_ticks: &mut IdleTicks, ticks: &[(bool, f32)], throttled: bool) -> Vec<bool> {
+        let start = time::Instant::now();
+        ticks
+            .iter()
+            .enumerate()
+            .map(|(i, (idle, yaw))| {
+                let now = start + Duration::from_millis(66 * i as u64);
+                idle_ticks.should_skip(*idle, (*yaw, 0.0), now, throttled)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn idle_ticks_are_skipped_after_a_while_and_capped() {
+        let mut idle_ticks = idle_ticks(1.0, 1.0);
+        let skipped = skips(&mut idle_ticks, &[(true, 0.0); 10], false);
+        assert_eq!(
+            skipped,
+            [false, true, true, true, false, true, true, true, false, true]
+        );
+
+        // Moving or turning sends the tick and starts over
+        let mut idle_ticks = self::idle_ticks(1.0, 1.0);
+        let ticks = [
+            (true, 0.0),
+            (true, 0.0),
+            (true, 0.0),
+            (false, 0.0),
+            (true, 0.0),
+        ];
+        assert_eq!(
+            skips(&mut idle_ticks, &ticks, false),
+            [false, true, true, false, false]
+        );
+        let ticks = [
+            (true, 0.0),
+            (true, 0.0),
+            (true, 0.0),
+            (true, 1.0),
+            (true, 1.0),
+        ];
+        let mut idle_ticks = self::idle_ticks(1.0, 1.0);
+        assert_eq!(
+            skips(&mut idle_ticks, &ticks, false),
+            [false, true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn idle_ticks_are_sent_at_least_every_max_interval() {
+        let mut idle_ticks = idle_ticks(1.0, 1.0);
+        idle_ticks.options.max_consecutive_skips = u32::MAX;
+        let skipped = skips(&mut idle_ticks, &[(true, 0.0); 40], false);
+        // The first tick and then one every second
+        let sent = skipped
+            .iter()
+            .enumerate()
+            .filter(|(_, skipped)| !**skipped)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(sent, [0, 16, 32]);
+    }
+
+    #[test]
+    fn throttled_players_use_the_throttled_chance() {
+        let mut idle_ticks = idle_ticks(0.0, 1.0);
+        assert!(!skips(&mut idle_ticks, &[(true, 0.0); 6], false).contains(&true));
+        let mut idle_ticks = self::idle_ticks(0.0, 1.0);
+        assert!(skips(&mut idle_ticks, &[(true, 0.0); 6], true).contains(&true));
+    }
+
+    #[tokio::test]
+    async fn the_first_hook_that_decides_wins() {
+        let pings = Arc::new(AtomicUsize::new(0));
+        let counted = pings.clone();
+        let mut soak = Soak::with_builder(&["a"], move |builder| {
+            let counted = counted.clone();
+            builder
+                .message_hook(move |msg| {
+                    if msg.kind == "pi" {
+                        counted.fetch_add(1, Ordering::Relaxed);
+                    }
+                    HookAction::Continue
+                })
+                .message_hook(|msg| match msg.kind.as_str() {
+                    "pi" => HookAction::Custom(json!(["custom"])),
+                    "l" => HookAction::Suppress,
+                    _ => HookAction::Continue,
+                })
+                // Never reached for the messages above
+                .message_hook(|msg| match msg.kind.as_str() {
+                    "pi" | "l" => HookAction::Custom(json!(["late"])),
+                    _ => HookAction::Continue,
+                })
+        })
+        .await;
+        let before = pings.load(Ordering::Relaxed);
+
+        soak.transport.push("pi", vec![]).await;
+        soak.step().await;
+        assert_eq!(pings.load(Ordering::Relaxed), before + 1);
+        let kinds = soak
+            .sent
+            .iter()
+            .map(|(kind, _)| kind.as_str())
+            .collect::<Vec<_>>();
+        assert!(kinds.contains(&"custom"), "{:?}", kinds);
+        assert!(!kinds.contains(&"po") && !kinds.contains(&"late"));
+
+        // Suppressed deaths never reach the built-in handler
+        soak.transport.push("l", vec![json!(0)]).await;
+        soak.step().await;
+        assert!(!soak.player.lock().await.is_dead());
+        assert!(!soak.sent.iter().any(|(kind, _)| kind == "late"));
+    }
+
+    #[tokio::test]
+    async fn suppressed_spawns_never_reach_the_player() {
+        let suppress = Arc::new(AtomicBool::new(false));
+        let suppressing = suppress.clone();
+        let mut soak = Soak::with_builder(&["a"], move |builder| {
+            let suppressing = suppressing.clone();
+            builder.message_hook(move |msg| {
+                if msg.kind == "0" && suppressing.load(Ordering::Relaxed) {
+                    HookAction::Suppress
+                } else {
+                    HookAction::Continue
+                }
+            })
+        })
+        .await;
+
+        suppress.store(true, Ordering::Relaxed);
+        soak.die_without_respawn().await;
+        // The server answered every enter with a spawn until the player gave up
+        soak.until(|player| player.snapshot().enter_rejected.is_some())
+            .await;
+        assert!(!soak.player.lock().await.in_game());
+
+        // The next round spawns the player again
+        suppress.store(false, Ordering::Relaxed);
+        soak.transport.push("init", vec![]).await;
+        soak.until(|player| player.in_game()).await;
+    }
+
+    #[tokio::test]
+    async fn hooks_answer_kinds_the_player_does_not_know() {
+        let mut soak = Soak::with_builder(&["a"], |builder| {
+            builder.message_hook(|msg| match msg.kind.as_str() {
+                "riddle" => {
+                    HookAction::Custom(json!(["answer", msg.data[0].as_i64().unwrap() + 1]))
+                }
+                _ => HookAction::Continue,
+            })
+        })
+        .await;
+
+        soak.transport.push("riddle", vec![json!(41)]).await;
+        soak.step().await;
+        assert!(soak
+            .sent
+            .iter()
+            .any(|(kind, data)| kind == "answer" && data == &[json!(42)]));
+        assert!(soak.player.lock().await.end_state().is_none());
+    }
+
+    #[tokio::test]
+    async fn backlogs_keep_the_newest_message_of_each_kind() {
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = seen.clone();
+        let mut soak = Soak::with_builder(&["a"], move |builder| {
+            let recorded = recorded.clone();
+            builder.message_hook(move |msg| {
+                recorded.lock().unwrap().push(msg.kind.clone());
+                HookAction::Continue
+            })
+        })
+        .await;
+        soak.step().await;
+        seen.lock().unwrap().clear();
+
+        for _ in 0..40 {
+            soak.transport.push("pi", vec![]).await;
+        }
+        for tick in 0..20 {
+            soak.transport
+                .push("l", vec![json!([tick, 0, 0.0, 0.0, 0.0])])
+                .await;
+        }
+        soak.transport.push("unknown", vec![json!(1)]).await;
+        soak.transport.push("l", vec![json!(0)]).await;
+        soak.transport.push("unknown", vec![json!(2)]).await;
+        for tick in 20..30 {
+            soak.transport
+                .push("l", vec![json!([tick, 0, 0.0, 0.0, 0.0])])
+                .await;
+        }
+        soak.step().await;
+
+        // One pong, the death, the newest update and every message of an unlisted kind
+        let pongs = soak.sent.iter().filter(|(kind, _)| kind == "po").count();
+        assert_eq!(pongs, 1);
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(seen, ["pi", "unknown", "l", "unknown", "l"]);
+
+        let player = soak.player.lock().await;
+        assert_eq!(player.summary.deaths, 1);
+        assert_eq!(player.backlog.backlogs, 1);
+        // The ack of the previous step is the oldest update
+        assert_eq!(player.backlog.dropped, 39);
+        assert_eq!(player.backlog.collapsed, 30);
+    }
+
+    fn failing_budget(action: BreakerAction) -> ErrorBudgetOptions {
+        ErrorBudgetOptions {
+            max_errors: 3,
+            actions: HashMap::from([(ErrorCategory::Protocol, action)]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_messages_are_paused_once_the_breaker_trips() {
+        let mut soak = Soak::with_builder(&["a"], |builder| {
+            builder.error_budget(failing_budget(BreakerAction::PauseMessage))
+        })
+        .await;
+        soak.step().await;
+
+        for _ in 0..2 {
+            soak.transport.push("l", vec![json!(1)]).await;
+        }
+        soak.step().await;
+        assert!(soak.player.lock().await.unhealthy().is_none());
+
+        soak.transport.push("l", vec![json!(1)]).await;
+        soak.step().await;
+        let player = soak.player.lock().await;
+        assert_eq!(player.unhealthy(), Some(ErrorCategory::Protocol));
+        assert_eq!(player.paused_messages(), ["l"]);
+        drop(player);
+
+        // Deaths aren't handled either but the player keeps running
+        soak.transport.push("l", vec![json!(0)]).await;
+        soak.step().await;
+        assert!(!soak.player.lock().await.is_dead());
+    }
+
+    #[tokio::test]
+    async fn the_disconnect_action_ends_the_player() {
+        let soak = Soak::with_builder(&["a"], |builder| {
+            builder.error_budget(failing_budget(BreakerAction::Disconnect))
+        })
+        .await;
+        for _ in 0..3 {
+            soak.transport.push("l", vec![json!(1)]).await;
+        }
+
+        let mut player = soak.player.lock().await;
+        player.step().await;
+        assert_eq!(
+            player.end_state().map(|end| end.reason.clone()),
+            Some(EndReason::Unhealthy(ErrorCategory::Protocol))
+        );
+        assert!(!player.step().await);
+    }
+
+    fn stale_lobby(policy: StaleLobbyPolicy) -> StaleLobbyOptions {
+        StaleLobbyOptions {
+            alone_for: Duration::from_secs(2),
+            poll_interval: Duration::from_secs(1),
+            policy,
+        }
+    }
+
+    #[tokio::test]
+    async fn listed_lobbies_are_never_stale() {
+        let mut soak = Soak::with_builder(&["a"], |builder| {
+            builder.stale_lobby(stale_lobby(StaleLobbyPolicy::Report))
+        })
+        .await;
+        soak.until(|player| player.in_game()).await;
+        // Long past alone_for, the matchmaker is asked every poll_interval
+        for _ in 0..100 {
+            soak.step().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let player = soak.player.lock().await;
+        assert!(player.last_listing_check.is_some() && !player.lobby_stale());
+        drop(player);
+
+        soak.delist();
+        soak.until(|player| player.lobby_stale()).await;
+        assert!(soak.player.lock().await.end_state().is_none());
+    }
+
+    #[tokio::test]
+    async fn delisted_lobbies_end_the_player() {
+        let mut soak = Soak::with_builder(&["a"], |builder| {
+            builder.stale_lobby(stale_lobby(StaleLobbyPolicy::Disconnect))
+        })
+        .await;
+        soak.until(|player| player.in_game()).await;
+        soak.delist();
+        soak.until(|player| player.lobby_stale()).await;
+
+        let player = soak.player.lock().await;
+        assert_eq!(
+            player.end_state().map(|end| end.reason.clone()),
+            Some(EndReason::Delisted)
+        );
+    }
+
+    #[tokio::test]
+    async fn fire_and_aim_only_latch_in_game() {
+        let mut soak = Soak::new(&["a"]).await;
+        soak.until(|player| player.in_game()).await;
+        soak.die_without_respawn().await;
+
+        let mut player = soak.player.lock().await;
+        assert!(matches!(player.fire(true).await, Err(Error::NotInGame)));
+        assert!(matches!(player.shoot(true).await, Err(Error::NotInGame)));
+        assert!(matches!(player.aim(true).await, Err(Error::NotInGame)));
+        assert!(!player.is_firing() && !player.is_aiming());
+        drop(player);
+
+        // Death releases both
+        soak.until(|player| player.in_game()).await;
+        soak.player.lock().await.shoot(true).await.unwrap();
+        assert!(soak.player.lock().await.is_firing());
+        soak.die_without_respawn().await;
+        let player = soak.player.lock().await;
+        assert!(!player.is_firing() && !player.is_aiming());
+    }
+
+    #[tokio::test]
+    async fn short_queues_are_not_collapsed() {
+        let mut soak = Soak::new(&["a"]).await;
+        for _ in 0..BACKLOG_THRESHOLD - 1 {
+            soak.transport.push("pi", vec![]).await;
+        }
+        soak.step().await;
+
+        let pongs = soak.sent.iter().filter(|(kind, _)| kind == "po").count();
+        assert_eq!(pongs, BACKLOG_THRESHOLD - 1);
+        assert_eq!(soak.player.lock().await.backlog.backlogs, 0);
+    }
+
+    #[tokio::test]
+    async fn other_players_come_from_spawns_and_leave_again() {
+        let mut soak = Soak::new(&["a"]).await;
+        soak.until(|player| player.in_game()).await;
+
+        let mut player = soak.player.lock().await;
+        let ids = |player: &Player| {
+            let mut ids = player
+                .players()
+                .into_iter()
+                .map(|player| player.id)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(&player), ["other-1"]);
+        player.observe_player(
+            "other-2",
+            Vec3 {
+                x: 3.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        );
+        assert_eq!(player.nearest_enemy().unwrap().id, "other-2");
+
+        // Teammates are skipped once the teams are known
+        assert!(player.set_team("unknown", 1).is_err());
+        player.set_team("soak", 1).unwrap();
+        player.set_team("other-2", 1).unwrap();
+        player.set_team("other-1", 2).unwrap();
+        assert_eq!(player.team(), Some(1));
+        assert_eq!(player.nearest_enemy().unwrap().id, "other-1");
+
+        player.forget_player("other-1");
+        assert_eq!(ids(&player), ["other-2"]);
+        assert!(player.nearest_enemy().is_none());
+        assert_eq!(player.diagnostics().await.known_players, 1);
+    }
+
+    // Two walls force a path that weaves left and right around them
+    fn zigzag_raw_map() -> RawMap {
+        let mut map = crate::soak::raw_map("zigzag");
+        map.sizes.extend([70.0, 10.0, 2.0]);
+        for (x, z) in [(-25.0, 20.0), (25.0, 40.0)] {
+            map.objects.push(RawMapObject {
+                position: [x, 0.0, z],
+                size_index: Some(2),
+                ..Default::default()
+            });
+        }
+        map
+    }
+
+    // Ticks on which the heading turned around compared to the tick before
+    async fn heading_reversals(options: WalkOptions) -> usize {
+        let mut soak = Soak::with_raw_maps(vec![zigzag_raw_map()], |builder| builder).await;
+        soak.until(|player| player.in_game() && player.map().is_some())
+            .await;
+        // About 260ms
+        soak.simulate_latency(4).await;
+
+        let destination = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 60.0,
+        };
+        let mut handle = soak
+            .player
+            .lock()
+            .await
+            .start_walk_to_with(&destination, &options);
+        let mut headings = vec![];
+        for _ in 0..1000 {
+            soak.step().await;
+            if let Some(result) = handle.try_result() {
+                result.unwrap();
+                break;
+            }
+            let player = soak.player.lock().await;
+            if player.inputs.walk {
+                headings.push(player.rotation);
+            }
+            drop(player);
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        headings
+            .windows(2)
+            .filter(|pair| {
+                let turn = (pair[1] - pair[0] + PI).rem_euclid(2.0 * PI) - PI;
+                turn.abs() > 2.0 * PI / 3.0
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn the_lookahead_reduces_oscillation_on_laggy_connections() {
+        let naive = heading_reversals(WalkOptions {
+            latency_compensation: false,
+            lookahead_distance: None,
+            ..Default::default()
+        })
+        .await;
+        let compensated = heading_reversals(WalkOptions::default()).await;
+        assert!(
+            naive > 0 && compensated < naive,
+            "{} {}",
+            compensated,
+            naive
+        );
+    }
+
+    // Walks to z 40 while the server keeps the player in place for the first ticks, returns the result
+    // and the ticks it took
+    async fn walk_while_frozen(
+        stuck: StuckOptions,
+        frozen_ticks: usize,
+    ) -> (Result<(), Error>, usize) {
+        let mut soak =
+            Soak::with_builder(&["a"], move |builder| builder.stuck_detection(stuck)).await;
+        soak.until(|player| player.in_game() && player.map().is_some())
+            .await;
+        soak.freeze(true).await;
+
+        let destination = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 40.0,
+        };
+        let mut handle = soak.player.lock().await.start_walk_to(&destination);
+        for tick in 0..1000 {
+            if tick == frozen_ticks {
+                soak.freeze(false).await;
+            }
+            soak.step().await;
+            if let Some(result) = handle.try_result() {
+                return (result, tick);
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        panic!("the walk didn't end");
+    }
+
+    #[tokio::test]
+    async fn walks_that_stop_advancing_are_planned_again() {
+        let stuck = StuckOptions {
+            ticks: 10,
+            max_replans: 2,
+        };
+        // Every plan stalls, the third one gives up close to the spawn
+        let (result, ticks) = walk_while_frozen(stuck, usize::MAX).await;
+        match result {
+            Err(Error::Stuck { position, waypoint }) => {
+                assert!(position.z < 20.0, "{:?}", position);
+                assert!(waypoint.z > position.z);
+            }
+            result => panic!("{:?}", result),
+        }
+        assert!(ticks >= 3 * 10, "{}", ticks);
+
+        // Released after the first plan stalled, the second one gets there
+        let stuck = StuckOptions {
+            ticks: 10,
+            max_replans: 1,
+        };
+        let (result, ticks) = walk_while_frozen(stuck, 15).await;
+        result.unwrap();
+        assert!(ticks > 15);
+
+        // Without replans the same walk gives up
+        let stuck = StuckOptions {
+            ticks: 10,
+            max_replans: 0,
+        };
+        let (result, _) = walk_while_frozen(stuck, 15).await;
+        assert!(matches!(result, Err(Error::Stuck { .. })));
+    }
+
+    #[tokio::test]
+    async fn walk_handles_cancel_and_complete() {
+        let mut soak = Soak::new(&["a"]).await;
+        soak.until(|player| player.in_game() && player.map().is_some())
+            .await;
+        let destination = |z| Vec3 { x: 0.0, y: 0.0, z };
+
+        // Cancelled in the middle of the walk
+        let mut handle = soak.player.lock().await.start_walk_to(&destination(90.0));
+        // The path is searched in the background
+        for _ in 0..100 {
+            if handle.progress().waypoint > 0 {
+                break;
+            }
+            soak.step().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        soak.step().await;
+        let progress = handle.progress();
+        assert!(progress.waypoint > 0 && progress.waypoints > progress.waypoint);
+        assert!(progress.remaining_distance > 0.0 && progress.remaining_distance < 90.0);
+        assert!(handle.try_result().is_none());
+        handle.cancel();
+        soak.step().await;
+        assert!(matches!(
+            handle.try_result(),
+            Some(Err(Error::WalkCancelled))
+        ));
+        let player = soak.player.lock().await;
+        assert!(player.is_walking_to().is_none());
+        assert!(!player.inputs.walk);
+        drop(player);
+
+        // A new walk cancels the one before it
+        let mut first = soak.player.lock().await.start_walk_to(&destination(40.0));
+        let second = soak.player.lock().await.start_walk_to(&destination(-20.0));
+        assert!(matches!(
+            first.try_result(),
+            Some(Err(Error::WalkCancelled))
+        ));
+
+        // Awaiting the handle waits until the player got there
+        let walk = tokio::spawn(second);
+        for _ in 0..1000 {
+            if walk.is_finished() {
+                break;
+            }
+            soak.step().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        walk.await.unwrap().unwrap();
+        let player = soak.player.lock().await;
+        assert!(player.position.distance_xz(&destination(-20.0)) < crate::map::CELL_SIZE);
+        assert!(player.is_walking_to().is_none());
+    }
+
+    fn queued(player: &Player) -> Vec<f32> {
+        player
+            .walk_queue()
+            .iter()
+            .map(|entry| entry.destination.z)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn priority_walks_jump_the_queue() {
+        let soak = Soak::new(&["a"]).await;
+        let player = soak.player.lock().await;
+        let at = |z| Vec3 { x: 0.0, y: 0.0, z };
+        player.queue_walk(&at(10.0), WalkOptions::default());
+        player.queue_walk(&at(20.0), WalkOptions::default());
+        player.queue_walk_priority(&at(5.0), WalkOptions::default());
+        // Added through the handle while the player is busy
+        player
+            .walk_queue_handle()
+            .push(at(30.0), WalkOptions::default());
+        player.queue_walk_priority(&at(1.0), WalkOptions::default());
+        assert_eq!(queued(&player), vec![1.0, 5.0, 10.0, 20.0, 30.0]);
+
+        player.clear_walk_queue();
+        assert!(queued(&player).is_empty());
+    }
+
+    #[tokio::test]
+    async fn deaths_clear_the_walk_queue_unless_it_is_kept() {
+        let at = |z| Vec3 { x: 0.0, y: 0.0, z };
+        let mut soak = Soak::new(&["a"]).await;
+        soak.until(|player| player.in_game()).await;
+        let mut completed = soak.player.lock().await.walk_queue_handle().subscribe();
+        for z in [10.0, 20.0] {
+            soak.player
+                .lock()
+                .await
+                .queue_walk(&at(z), WalkOptions::default());
+        }
+
+        soak.die().await;
+        assert!(queued(&*soak.player.lock().await).is_empty());
+        for z in [10.0, 20.0] {
+            let event = completed.try_recv().unwrap();
+            assert_eq!(event.destination.z, z);
+            assert_eq!(event.outcome, WalkOutcome::Cleared);
+        }
+
+        let mut soak =
+            Soak::with_builder(&["a"], |builder| builder.keep_walk_queue_on_death(true)).await;
+        soak.until(|player| player.in_game()).await;
+        soak.player
+            .lock()
+            .await
+            .queue_walk(&at(10.0), WalkOptions::default());
+        soak.die().await;
+        assert_eq!(queued(&*soak.player.lock().await), vec![10.0]);
+    }
+
+    #[tokio::test]
+    async fn inputs_use_the_slot_of_the_spawn() {
+        let mut soak = Soak::new(&["a"]).await;
+        soak.until(|player| player.in_game()).await;
+        assert_eq!(soak.player.lock().await.slot(), 0);
+
+        soak.transport
+            .push("0", vec![json!(["soak", 2, 0.0, 0.0, 0.0])])
+            .await;
+        soak.step().await;
+        assert_eq!(soak.player.lock().await.slot(), 2);
+        let init = soak
+            .sent
+            .iter()
+            .find(|(kind, data)| kind == "q" && data[1] == 0)
+            .unwrap();
+        assert_eq!(init.1[5]["2-4"], json!(-1));
+
+        soak.player.lock().await.walk(true).await.unwrap();
+        soak.step().await;
+        let inputs = soak
+            .sent
+            .iter()
+            .filter(|(kind, data)| kind == "q" && data[5].is_object())
+            .map(|(_, data)| &data[5])
+            .collect::<Vec<_>>();
+        assert!(!inputs.is_empty());
+        for inputs in inputs {
+            assert_eq!(inputs["2-4"], json!(1));
+            assert!(inputs.get("0-4").is_none());
+        }
+    }
+
+    // Steps the player once more, directly since Soak::step expects it to keep running
+    async fn end_reason(soak: &Soak) -> Option<EndReason> {
+        let mut player = soak.player.lock().await;
+        player.step().await;
+        assert!(!player.step().await);
+        player.end_state().map(|end| end.reason.clone())
+    }
+
+    #[tokio::test]
+    async fn disconnecting_is_a_user_disconnect() {
+        let soak = Soak::new(&["a"]).await;
+        soak.player.lock().await.disconnect().await.unwrap();
+        assert_eq!(end_reason(&soak).await, Some(EndReason::UserDisconnect));
+    }
+
+    #[tokio::test]
+    async fn a_close_after_a_server_error_is_a_kick() {
+        let soak = Soak::new(&["a"]).await;
+        soak.transport
+            .push("error", vec![json!("Kicked for inactivity")])
+            .await;
+        soak.transport.close().await;
+        assert_eq!(
+            end_reason(&soak).await,
+            Some(EndReason::Kicked(KickReason {
+                message: "Kicked for inactivity".to_owned()
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_plain_close_means_the_game_is_gone() {
+        let soak = Soak::new(&["a"]).await;
+        soak.transport.close().await;
+        assert_eq!(end_reason(&soak).await, Some(EndReason::GameGone));
+    }
+
+    #[tokio::test]
+    async fn undecodable_messages_end_in_a_desync() {
+        let soak = Soak::new(&["a"]).await;
+        for _ in 0..MAX_CONSECUTIVE_DECODE_ERRORS {
+            soak.transport.push_frame(vec![0xc1, 0, 0]).await;
+        }
+        assert_eq!(end_reason(&soak).await, Some(EndReason::ProtocolDesync));
+    }
+
+    #[tokio::test]
+    async fn websocket_errors_end_the_player() {
+        let soak = Soak::new(&["a"]).await;
+        soak.transport.fail().await;
+        assert!(matches!(
+            end_reason(&soak).await,
+            Some(EndReason::SocketError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn panics_in_the_tick_are_caught() {
+        let soak = Soak::with_builder(&["a"], |builder| {
+            builder.message_hook(|msg| {
+                if msg.kind == "boom" {
+                    panic!("hook exploded");
+                }
+                HookAction::Continue
+            })
+        })
+        .await;
+        soak.transport.push("boom", vec![]).await;
+        assert_eq!(
+            end_reason(&soak).await,
+            Some(EndReason::Panic("hook exploded".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn the_summary_counts_kills_deaths_and_reconnects() {
+        let mut soak = Soak::new(&["a"]).await;
+        soak.until(|player| player.in_game()).await;
+        soak.transport
+            .push("k", vec![json!("soak"), json!("other-1")])
+            .await;
+        soak.transport
+            .push("k", vec![json!("other-1"), json!("soak")])
+            .await;
+        soak.die().await;
+        soak.player
+            .lock()
+            .await
+            .transition(LifecycleState::Reconnecting)
+            .unwrap();
+        soak.player.lock().await.disconnect().await.unwrap();
+
+        let player = soak.player.lock().await;
+        let summary = player.end_state().unwrap().summary;
+        assert_eq!((summary.kills, summary.deaths), (1, 1));
+        assert_eq!(summary.reconnects, 1);
+        assert!(summary.ticks_sent > 0);
+    }
+
+    #[tokio::test]
+    async fn a_slow_matchmaker_does_not_delay_pongs() {
+        let mut soak = Soak::new(&["a", "b"]).await;
+        // The game info of the first init is applied after the spawn
+        soak.until(|player| player.in_game() && player.map().is_some())
+            .await;
+
+        // The game info of the next round takes 5 seconds
+        soak.hold_matchmaker(true);
+        soak.set_map("b");
+        soak.transport.push("init", vec![]).await;
+        let ticks = (Duration::from_secs(5).as_millis() / 66) as usize;
+        for tick in 0..ticks {
+            if tick.is_multiple_of(10) {
+                soak.transport.push("pi", vec![]).await;
+                soak.step().await;
+                assert!(soak.sent.iter().any(|(kind, _)| kind == "po"));
+            } else {
+                soak.step().await;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(soak
+            .player
+            .lock()
+            .await
+            .map()
+            .is_some_and(|map| map.name == "a"));
+
+        soak.hold_matchmaker(false);
+        soak.until(|player| player.map().is_some_and(|map| map.name == "b"))
+            .await;
+    }
+}
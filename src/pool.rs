@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use tokio::sync::{watch, Mutex};
+
+use crate::{
+    player::{Player, PlayerBuilder, PlayerSnapshot},
+    socket::SocketStats,
+    utils::Error,
+    Game,
+};
+
+struct PoolEntry {
+    game_id: String,
+    player: Arc<Mutex<Player>>,
+    snapshot: watch::Receiver<PlayerSnapshot>,
+    // Readable while the player is locked by walk_to
+    socket_stats: Arc<Mutex<SocketStats>>,
+}
+
+#[derive(Default)]
+pub struct PlayerPool {
+    entries: Vec<PoolEntry>,
+    // Upstream bytes per second above which idle ticks are skipped more aggressively
+    bandwidth_cap: Option<f32>,
+    throttled: Arc<AtomicBool>,
+    last_bandwidth_sample: Option<(Instant, usize)>,
+}
+
+impl PlayerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn connect(
+        &mut self,
+        builder: &PlayerBuilder,
+        game: &Game,
+    ) -> Result<Arc<Mutex<Player>>, Error> {
+        let player = builder.connect(game).await?;
+        let (snapshot, socket_stats) = {
+            let mut player_lock = player.lock().await;
+            player_lock.set_throttle(self.throttled.clone());
+            (player_lock.watch_snapshot(), player_lock.socket_stats())
+        };
+
+        self.entries.push(PoolEntry {
+            game_id: game.id.clone(),
+            player: player.clone(),
+            snapshot,
+            socket_stats,
+        });
+
+        Ok(player)
+    }
+
+    pub fn players(&self) -> Vec<Arc<Mutex<Player>>> {
+        self.entries
+            .iter()
+            .map(|entry| entry.player.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Reads the latest published snapshots without locking the players
+    pub fn snapshots(&self) -> Vec<PlayerSnapshot> {
+        self.entries
+            .iter()
+            .map(|entry| entry.snapshot.borrow().clone())
+            .collect()
+    }
+
+    pub fn set_bandwidth_cap(&mut self, bytes_per_sec: Option<f32>) {
+        self.bandwidth_cap = bytes_per_sec;
+        if bytes_per_sec.is_none() {
+            self.throttled.store(false, Ordering::Relaxed);
+        }
+    }
+
+    // Estimate the upstream bytes per second of all players since the last call and throttle
+    // the players if the cap is exceeded. Has to be called regularly for the cap to work.
+    pub async fn update_bandwidth(&mut self) -> f32 {
+        let mut bytes_sent = 0;
+        for entry in self.entries.iter() {
+            bytes_sent += entry.socket_stats.lock().await.bytes_sent;
+        }
+
+        let now = Instant::now();
+        let rate = match self.last_bandwidth_sample {
+            Some((sampled_at, last_bytes_sent)) => {
+                let elapsed = now.duration_since(sampled_at).as_secs_f32();
+                if elapsed > 0.0 {
+                    bytes_sent.saturating_sub(last_bytes_sent) as f32 / elapsed
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        self.last_bandwidth_sample = Some((now, bytes_sent));
+
+        if let Some(cap) = self.bandwidth_cap {
+            self.throttled.store(rate > cap, Ordering::Relaxed);
+        }
+
+        rate
+    }
+
+    // Number of players in every game the pool has players in
+    pub fn game_counts(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for entry in self.entries.iter() {
+            *counts.entry(entry.game_id.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    // Disconnect up to n players of the game, returns how many were disconnected
+    pub async fn disconnect_from(&mut self, game_id: &str, n: usize) -> Result<usize, Error> {
+        let mut disconnected = 0;
+        let mut i = self.entries.len();
+        while i > 0 && disconnected < n {
+            i -= 1;
+            if self.entries[i].game_id == game_id {
+                let entry = self.entries.remove(i);
+                entry.player.lock().await.disconnect().await?;
+                disconnected += 1;
+            }
+        }
+
+        Ok(disconnected)
+    }
+
+    // Remove the players whose connection was closed, returns the games they were in
+    pub fn remove_disconnected(&mut self) -> Vec<String> {
+        let mut removed = vec![];
+        self.entries.retain(|entry| {
+            let connected = entry.snapshot.borrow().connected;
+            if !connected {
+                removed.push(entry.game_id.clone());
+            }
+            connected
+        });
+        removed
+    }
+
+    pub async fn disconnect_all(&mut self) -> Result<(), Error> {
+        for entry in self.entries.drain(..) {
+            entry.player.lock().await.disconnect().await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Commonly used types, `use krunker_client::prelude::*;` covers most programs.
+//!
+//! Connecting a player to the first free game of a region:
+//!
+//! ```no_run
+//! use krunker_client::prelude::*;
+//!
+//! # async fn run() -> Result<(), Error> {
+//! let client = Client::new().await?;
+//! let filter = GameFilter::new()
+//!     .region(ServerRegion::Frankfurt)
+//!     .mode(GameMode::Ffa)
+//!     .exclude_custom();
+//! let games = client.lock().await.find_games(&filter).await?;
+//! let game = games.first().ok_or("No game found")?;
+//!
+//! let player = PlayerBuilder::new(client.clone()).connect(game).await?;
+//! let mut player = player.lock().await;
+//! if let Some(spawn) = player.map().and_then(|map| map.spawns().first().copied()) {
+//!     player.walk_to(&spawn).await?;
+//! }
+//! player.disconnect().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Maps can be built without a client, e.g. from hand made JSON in the format of the game source:
+//!
+//! ```
+//! use krunker_client::prelude::*;
+//!
+//! let map = Map::from_raw_json(
+//!     r#"{
+//!         "name": "hall",
+//!         "xyz": [60, 6, 20, 60, 30, 1],
+//!         "objects": [{ "p": [0, -6, 0], "si": 0 }, { "p": [0, -6, -10], "si": 1, "bo": 1 }],
+//!         "spawns": [[-20, 0, 0], [20, 0, 0]]
+//!     }"#,
+//! )?;
+//! let path = map.find_path_positions(&map.spawns()[0], &map.spawns()[1])?;
+//! assert!(!path.cells.is_empty());
+//! # Ok::<(), Error>(())
+//! ```
+//!
+//! The error type and the geometry types are in the prelude as well:
+//!
+//! ```
+//! use krunker_client::prelude::*;
+//!
+//! let mode: GameMode = "tdm".parse()?;
+//! assert_eq!(mode, GameMode::Tdm);
+//!
+//! let from = Vec3 { x: 0.0, y: 0.0, z: 0.0 };
+//! let to = Vec3 { x: 3.0 * CELL_SIZE, y: 0.0, z: 4.0 * CELL_SIZE };
+//! assert_eq!(from.distance(&to), 5.0 * CELL_SIZE);
+//!
+//! let err: Error = "Unknown game mode".into();
+//! assert!(matches!(ErrorCategory::from(&err), ErrorCategory::Other));
+//! # Ok::<(), Error>(())
+//! ```
+pub use crate::{
+    accounts::{AccountSource, AccountStore, EncryptedFileStore, MemoryStore, StoredAccount},
+    annotations::AnnotationSource,
+    autoscaler::{AutoscaleEvent, AutoscalePolicy, Autoscaler},
+    clock::{Clock, ManualClock},
+    config::FleetConfig,
+    coordination::{Bus, ChatBus, Coordination, Envelope, LocalBus},
+    diagnostics::{MapDiagnostics, MapWarning},
+    error::KrunkerError,
+    error_budget::{BreakerAction, ErrorBudgetOptions, ErrorCategory},
+    game_filter::GameFilter,
+    game_watch::{GameListEvent, GameListWatcher},
+    input::{Control, InputKey, InputState},
+    lifecycle::{InvalidTransition, LifecycleState},
+    map::{Map, MapBuildOptions, Path, PathOptions, PathResult, PathSearch, Spawn, CELL_SIZE},
+    modes::{GameMode, ModeInfo},
+    player::{
+        Account, EndReason, EndState, EnterRejectedPolicy, EnterRetryOptions, HookAction,
+        IdleTickOptions, KickReason, Player, PlayerBuilder, PlayerEvent, PlayerSnapshot,
+        SessionSummary, StaleLobbyOptions, StaleLobbyPolicy, StuckOptions, WalkHandle, WalkOptions,
+        WalkProgress,
+    },
+    pool::PlayerPool,
+    profile::Profile,
+    proxy::ProxyConfig,
+    quirks::{Padding, ProtocolQuirks},
+    raycast::{HitKind, RayHit},
+    regions::{Region, RegionOptions},
+    retry::RetryPolicy,
+    selfcheck::{SelfCheckReport, SmokeReport, StageResult},
+    server_clock::ServerClock,
+    server_region::ServerRegion,
+    tuning::MapTuning,
+    utils::{Cell, Error, Vec3, AABB},
+    walk_queue::{QueuedWalk, WalkCompleted, WalkOutcome, WalkQueue},
+    world::{RemotePlayer, WorldState},
+    Client, ClientBuilder, Game, ParseBudget, PreparseProgress,
+};
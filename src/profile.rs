@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::time;
+use tokio_tungstenite::tungstenite::{
+    handshake::client::{generate_key, Request},
+    Message,
+};
+
+use crate::{
+    proxy::{connect_ws, ProxyConfig},
+    quirks::ProtocolQuirks,
+    socket::Socket,
+    utils::Error,
+};
+
+const SOCIAL_HOST: &str = "social.krunker.io";
+const PROFILE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RawProfile {
+    player_name: Option<String>,
+    player_score: Option<u64>,
+    player_kills: Option<u64>,
+    player_deaths: Option<u64>,
+    player_wins: Option<u64>,
+    player_games_played: Option<u64>,
+    // Milliseconds
+    player_timeplayed: Option<u64>,
+    player_clan: Option<String>,
+    player_funds: Option<u64>,
+}
+
+// Most fields are missing for private profiles
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Profile {
+    pub username: String,
+    pub level: Option<u32>,
+    pub score: Option<u64>,
+    pub kills: Option<u64>,
+    pub deaths: Option<u64>,
+    pub wins: Option<u64>,
+    pub games_played: Option<u64>,
+    pub time_played: Option<Duration>,
+    pub clan: Option<String>,
+    pub kr: Option<u64>,
+    pub private: bool,
+}
+
+impl Profile {
+    pub fn kdr(&self) -> Option<f32> {
+        match (self.kills, self.deaths) {
+            (Some(kills), Some(deaths)) => Some(kills as f32 / deaths.max(1) as f32),
+            _ => None,
+        }
+    }
+
+    fn from_raw(username: &str, raw: RawProfile) -> Self {
+        Self {
+            username: raw.player_name.unwrap_or_else(|| username.to_owned()),
+            // Same formula the website uses to show the level
+            level: raw
+                .player_score
+                .map(|score| ((0.03 * (score as f64).sqrt()).floor() as u32).max(1)),
+            score: raw.player_score,
+            kills: raw.player_kills,
+            deaths: raw.player_deaths,
+            wins: raw.player_wins,
+            games_played: raw.player_games_played,
+            time_played: raw.player_timeplayed.map(Duration::from_millis),
+            clan: raw.player_clan.filter(|clan| !clan.is_empty()),
+            kr: raw.player_funds,
+            private: raw.player_score.is_none() && raw.player_kills.is_none(),
+        }
+    }
+
+    // The profile data is the first object in the response, a missing object means the player doesn't exist
+    pub(crate) fn parse(username: &str, data: &[Value]) -> Result<Self, Error> {
+        let raw = data
+            .iter()
+            .find(|value| value.is_object())
+            .ok_or_else(|| Error::ProfileNotFound(username.to_owned()))?;
+
+        Ok(Self::from_raw(
+            username,
+            serde_json::from_value(raw.clone())?,
+        ))
+    }
+}
+
+// Request the profile from the social server the website uses for profile pages
+pub(crate) async fn fetch_profile(
+    username: &str,
+    proxy: Option<&ProxyConfig>,
+) -> Result<Profile, Error> {
+    let req = Request::builder()
+        .header("Host", SOCIAL_HOST)
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", generate_key())
+        .header("Origin", "https://krunker.io")
+        .uri(format!("wss://{}/ws", SOCIAL_HOST))
+        .body(())?;
+
+    let mut ws_stream = connect_ws(req, proxy).await?;
+
+    let mut msg = rmp_serde::encode::to_vec(&json!(["r", "profile", username, (), (), (), 0, ()]))?;
+    // The social server doesn't check the padding bytes
+    msg.extend([0, 0]);
+    ws_stream.send(Message::Binary(msg)).await?;
+
+    let quirks = ProtocolQuirks::default();
+    let profile = time::timeout(PROFILE_TIMEOUT, async {
+        while let Some(msg) = ws_stream.next().await {
+            match msg? {
+                Message::Binary(msg) => {
+                    let padded = Socket::detect_padding(&msg).unwrap_or(true);
+                    let (kind, data) = Socket::decode_message_with(&msg, &quirks, padded)?;
+                    if kind == "0" && data.first().and_then(|v| v.as_str()) == Some("profile") {
+                        return Profile::parse(username, &data[1..]);
+                    }
+                }
+                Message::Close(_) => break,
+                _ => (),
+            }
+        }
+
+        Err::<Profile, Error>("Social server closed the connection".into())
+    })
+    .await
+    .map_err(|_| format!("Timed out requesting the profile of {}", username))?;
+
+    let _ = ws_stream.close(None).await;
+
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Data of a profile message as the social server sends it, the kind is the first value
+    fn parse(fixture: &str, username: &str) -> Result<Profile, Error> {
+        let data = serde_json::from_str::<Vec<Value>>(fixture).unwrap();
+        assert_eq!(data[0], "profile");
+        Profile::parse(username, &data[1..])
+    }
+
+    #[test]
+    fn public_profiles_have_their_stats() {
+        let profile = parse(
+            include_str!("../tests/fixtures/profiles/public.json"),
+            "krunkster",
+        )
+        .unwrap();
+        assert!(!profile.private);
+        assert_eq!(profile.username, "Krunkster");
+        assert_eq!(profile.level, Some(36));
+        assert_eq!(profile.kdr(), Some(2.0));
+        assert_eq!(profile.time_played, Some(Duration::from_secs(7200)));
+        assert_eq!(profile.clan.as_deref(), Some("RUST"));
+        assert_eq!(profile.kr, Some(4200));
+    }
+
+    #[test]
+    fn private_profiles_only_have_a_name() {
+        let profile = parse(
+            include_str!("../tests/fixtures/profiles/private.json"),
+            "hidden",
+        )
+        .unwrap();
+        assert!(profile.private);
+        assert_eq!(profile.username, "Hidden");
+        assert_eq!((profile.level, profile.kdr()), (None, None));
+        assert!(profile.clan.is_none());
+    }
+
+    #[test]
+    fn missing_profiles_are_not_found() {
+        let result = parse(
+            include_str!("../tests/fixtures/profiles/nonexistent.json"),
+            "ghost",
+        );
+        assert!(matches!(result, Err(Error::ProfileNotFound(username)) if username == "ghost"));
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    Enabled,
+    Disabled,
+    // Decided by the first inbound frame, outgoing messages are padded until then
+    Detect,
+}
+
+// Deviations from the official protocol used by some community servers
+#[derive(Debug, Clone)]
+pub struct ProtocolQuirks {
+    pub padding: Padding,
+    // Message kinds sent by the server mapped to the kinds of the official protocol
+    pub kind_aliases: HashMap<String, String>,
+    // Fail to decode frames with more trailing bytes than the padding instead of ignoring them
+    pub strict_decode: bool,
+}
+
+impl Default for ProtocolQuirks {
+    fn default() -> Self {
+        Self {
+            padding: Padding::Enabled,
+            kind_aliases: HashMap::new(),
+            strict_decode: false,
+        }
+    }
+}
+
+impl ProtocolQuirks {
+    // Modded servers in the style of KrankedForce drop the padding bytes on some builds, so the padding
+    // is detected
+    pub fn modded() -> Self {
+        Self {
+            padding: Padding::Detect,
+            ..Default::default()
+        }
+    }
+
+    pub fn padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn kind_alias(mut self, alias: &str, kind: &str) -> Self {
+        self.kind_aliases.insert(alias.to_owned(), kind.to_owned());
+        self
+    }
+
+    pub fn strict_decode(mut self, strict_decode: bool) -> Self {
+        self.strict_decode = strict_decode;
+        self
+    }
+
+    pub(crate) fn resolve_kind(&self, kind: String) -> String {
+        match self.kind_aliases.get(&kind) {
+            Some(alias) => alias.clone(),
+            None => kind,
+        }
+    }
+}
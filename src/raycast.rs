@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{Vec3, AABB};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HitKind {
+    Object,
+    Ramp,
+    Ladder,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RayHit {
+    pub point: Vec3,
+    pub distance: f32,
+    pub kind: HitKind,
+    // Bounds of the object that was hit
+    pub bounds: AABB,
+}
+
+// Objects of the map by the chunk columns they touch, used to only test the objects near a ray
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ObjectChunks {
+    min_x: f32,
+    min_z: f32,
+    chunk_size: f32,
+    shape: (usize, usize),
+    // Index into the object list of the kind, row-major by x
+    entries: Vec<Vec<(HitKind, u32)>>,
+}
+
+impl ObjectChunks {
+    pub(crate) fn new(map_bounds: &AABB, chunk_size: f32, objects: &[(HitKind, &[AABB])]) -> Self {
+        let shape = (
+            ((map_bounds.max_x - map_bounds.min_x) / chunk_size)
+                .ceil()
+                .max(1.0) as usize,
+            ((map_bounds.max_z - map_bounds.min_z) / chunk_size)
+                .ceil()
+                .max(1.0) as usize,
+        );
+        let mut chunks = Self {
+            min_x: map_bounds.min_x,
+            min_z: map_bounds.min_z,
+            chunk_size,
+            shape,
+            entries: vec![vec![]; shape.0 * shape.1],
+        };
+
+        for (kind, list) in objects {
+            for (i, bounds) in list.iter().enumerate() {
+                let (min_x, min_z) = chunks.chunk_of(bounds.min_x, bounds.min_z);
+                let (max_x, max_z) = chunks.chunk_of(bounds.max_x, bounds.max_z);
+                for x in min_x..=max_x {
+                    for z in min_z..=max_z {
+                        chunks.entries[x * shape.1 + z].push((*kind, i as u32));
+                    }
+                }
+            }
+        }
+
+        chunks
+    }
+
+    fn chunk_of(&self, x: f32, z: f32) -> (usize, usize) {
+        let index = |value: f32, min: f32, len: usize| {
+            (((value - min) / self.chunk_size).floor().max(0.0) as usize).min(len - 1)
+        };
+        (
+            index(x, self.min_x, self.shape.0),
+            index(z, self.min_z, self.shape.1),
+        )
+    }
+
+    // First hit along the normalized direction, the chunks are visited in the order the ray
+    // crosses them and the search stops once a hit lies inside the current chunk
+    pub(crate) fn raycast(
+        &self,
+        origin: &Vec3,
+        direction: &Vec3,
+        max_distance: f32,
+        lookup: impl Fn(HitKind, u32) -> AABB,
+    ) -> Option<RayHit> {
+        let columns = AABB {
+            min_x: self.min_x,
+            min_y: f32::NEG_INFINITY,
+            min_z: self.min_z,
+            max_x: self.min_x + self.shape.0 as f32 * self.chunk_size,
+            max_y: f32::INFINITY,
+            max_z: self.min_z + self.shape.1 as f32 * self.chunk_size,
+        };
+        let (t_enter, t_exit) = columns.ray_intersection(origin, direction)?;
+        let t_start = t_enter.max(0.0);
+        let t_end = t_exit.min(max_distance);
+        if t_start > t_end {
+            return None;
+        }
+
+        let start = Vec3 {
+            x: origin.x + direction.x * t_start,
+            y: origin.y + direction.y * t_start,
+            z: origin.z + direction.z * t_start,
+        };
+        let (mut x, mut z) = self.chunk_of(start.x, start.z);
+
+        // Distance along the ray to the next chunk border on the axis and between two borders
+        let axis = |origin: f32, direction: f32, min: f32, chunk: usize| {
+            if direction > 0.0 {
+                let border = min + (chunk + 1) as f32 * self.chunk_size;
+                (
+                    1_isize,
+                    (border - origin) / direction,
+                    self.chunk_size / direction,
+                )
+            } else if direction < 0.0 {
+                let border = min + chunk as f32 * self.chunk_size;
+                (
+                    -1,
+                    (border - origin) / direction,
+                    -self.chunk_size / direction,
+                )
+            } else {
+                (0, f32::INFINITY, f32::INFINITY)
+            }
+        };
+        let (step_x, mut next_x, delta_x) = axis(origin.x, direction.x, self.min_x, x);
+        let (step_z, mut next_z, delta_z) = axis(origin.z, direction.z, self.min_z, z);
+
+        let mut best: Option<RayHit> = None;
+        loop {
+            for (kind, i) in &self.entries[x * self.shape.1 + z] {
+                let bounds = lookup(*kind, *i);
+                let distance = match bounds.ray_intersection(origin, direction) {
+                    Some((t_enter, _)) => t_enter.max(0.0),
+                    None => continue,
+                };
+                if distance <= max_distance && best.is_none_or(|best| distance < best.distance) {
+                    best = Some(RayHit {
+                        point: Vec3 {
+                            x: origin.x + direction.x * distance,
+                            y: origin.y + direction.y * distance,
+                            z: origin.z + direction.z * distance,
+                        },
+                        distance,
+                        kind: *kind,
+                        bounds,
+                    });
+                }
+            }
+
+            let chunk_exit = next_x.min(next_z);
+            if best.is_some_and(|best| best.distance <= chunk_exit) || chunk_exit > t_end {
+                return best;
+            }
+
+            if next_x < next_z {
+                let next = x as isize + step_x;
+                if next < 0 || next as usize >= self.shape.0 {
+                    return best;
+                }
+                x = next as usize;
+                next_x += delta_x;
+            } else {
+                let next = z as isize + step_z;
+                if next < 0 || next as usize >= self.shape.1 {
+                    return best;
+                }
+                z = next as usize;
+                next_z += delta_z;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::Map;
+
+    // A wall of 10x10x2 standing on the floor at z 20, it covers x -5 to 5 and z 19 to 21
+    fn wall_map() -> Map {
+        Map::from_raw_json(include_str!("../tests/fixtures/maps/wall.json")).unwrap()
+    }
+
+    fn eye(x: f32, z: f32) -> Vec3 {
+        Vec3 { x, y: 2.0, z }
+    }
+
+    const FORWARD: Vec3 = Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    };
+
+    #[test]
+    fn rays_hit_the_first_object() {
+        let map = wall_map();
+        let hit = map.raycast(&eye(0.0, 0.0), &FORWARD, 100.0).unwrap();
+        assert_eq!(hit.kind, HitKind::Object);
+        assert!((hit.distance - 19.0).abs() < 1e-4);
+        assert!((hit.point.z - 19.0).abs() < 1e-4 && hit.point.y == 2.0);
+        assert_eq!((hit.bounds.min_x, hit.bounds.max_x), (-5.0, 5.0));
+
+        // The direction doesn't have to be normalized
+        let down = Vec3 {
+            x: 0.0,
+            y: -3.0,
+            z: 0.0,
+        };
+        let floor = map.raycast(&eye(0.0, 0.0), &down, 100.0).unwrap();
+        assert!((floor.distance - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rays_miss_past_the_wall_or_beyond_their_distance() {
+        let map = wall_map();
+        assert!(map.raycast(&eye(20.0, 0.0), &FORWARD, 100.0).is_none());
+        assert!(map.raycast(&eye(0.0, 0.0), &FORWARD, 18.0).is_none());
+        let zero = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        assert!(map.raycast(&eye(0.0, 0.0), &zero, 100.0).is_none());
+    }
+
+    #[test]
+    fn rays_along_a_face_graze_it() {
+        let map = wall_map();
+        let hit = map.raycast(&eye(5.0, 0.0), &FORWARD, 100.0).unwrap();
+        assert!((hit.distance - 19.0).abs() < 1e-4);
+        assert!(map.raycast(&eye(5.01, 0.0), &FORWARD, 100.0).is_none());
+    }
+
+    #[test]
+    fn the_nearest_hit_wins_across_chunks() {
+        let bounds = |min_x, min_z, max_x, max_z| AABB {
+            min_x,
+            min_y: 0.0,
+            min_z,
+            max_x,
+            max_y: 10.0,
+            max_z,
+        };
+        let map_bounds = bounds(0.0, 0.0, 100.0, 100.0);
+        // The long wall is in every chunk the rays cross, the post only in one
+        let objects = [
+            bounds(0.0, 50.0, 100.0, 52.0),
+            bounds(15.0, 20.0, 17.0, 22.0),
+        ];
+        let chunks = ObjectChunks::new(&map_bounds, 10.0, &[(HitKind::Object, &objects)]);
+        let cast = |x: f32| {
+            chunks
+                .raycast(&Vec3 { x, y: 1.0, z: 0.0 }, &FORWARD, 100.0, |_, i| {
+                    objects[i as usize]
+                })
+                .map(|hit| hit.distance)
+        };
+
+        assert_eq!(cast(16.0), Some(20.0));
+        assert_eq!(cast(40.0), Some(50.0));
+        // Backwards along the long wall from inside of it
+        let hit = chunks.raycast(
+            &Vec3 {
+                x: 99.0,
+                y: 1.0,
+                z: 51.0,
+            },
+            &Vec3 {
+                x: -1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            100.0,
+            |_, i| objects[i as usize],
+        );
+        assert_eq!(hit.map(|hit| hit.distance), Some(0.0));
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::{HashMap, VecDeque};
+
+use ndarray::Array3;
+use serde::Serialize;
+
+use crate::{
+    map::WalkableGrid,
+    utils::{cell_to_position, Vec3, AABB},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RegionOptions {
+    // Height of the horizontal bands in cells, a region never spans more than one band
+    pub band_height: usize,
+    // Width of the square tiles in cells that large open areas are split into
+    pub tile_size: usize,
+}
+
+impl Default for RegionOptions {
+    fn default() -> Self {
+        Self {
+            band_height: 4,
+            tile_size: 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Region {
+    pub(crate) id: usize,
+    pub(crate) name: String,
+    pub(crate) cells: usize,
+    pub(crate) center: Vec3,
+    pub(crate) bounds: AABB,
+}
+
+impl Region {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    // Automatic name like region-07 unless it was renamed in the annotations
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cells(&self) -> usize {
+        self.cells
+    }
+
+    pub fn center(&self) -> Vec3 {
+        self.center
+    }
+
+    pub fn bounds(&self) -> AABB {
+        self.bounds
+    }
+
+    pub fn default_name(id: usize) -> String {
+        format!("region-{:02}", id)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RegionMap {
+    pub(crate) regions: Vec<Region>,
+    // Region id + 1 of every walkable cell, 0 for cells without a region
+    pub(crate) labels: Array3<u16>,
+}
+
+impl RegionMap {
+    pub(crate) fn region_of(&self, cell: &(usize, usize, usize)) -> Option<&Region> {
+        let label = *self.labels.get(*cell)?;
+        if label == 0 {
+            None
+        } else {
+            self.regions.get(label as usize - 1)
+        }
+    }
+
+    pub(crate) fn rename(&mut self, names: &HashMap<String, String>) {
+        for region in self.regions.iter_mut() {
+            if let Some(name) = names.get(&Region::default_name(region.id)) {
+                region.name = name.clone();
+            }
+        }
+    }
+}
+
+// Split the walkable cells into connected areas that stay inside one band and tile.
+// Cells are visited in a fixed order, so the same grid (same map fingerprint) always gets the same ids.
+pub(crate) fn label_regions(
+    walkable_grid: &WalkableGrid,
+    map_bounds: &AABB,
+    options: &RegionOptions,
+) -> RegionMap {
+    let grid_size = walkable_grid.dim();
+    let band_height = options.band_height.max(1);
+    let tile_size = options.tile_size.max(1);
+
+    let area = |cell: &(usize, usize, usize)| {
+        (cell.1 / band_height, cell.0 / tile_size, cell.2 / tile_size)
+    };
+
+    let mut labels = Array3::<u16>::zeros(grid_size);
+    let mut regions = Vec::<Region>::new();
+    let mut queue = VecDeque::new();
+
+    for y in 0..grid_size.1 {
+        for x in 0..grid_size.0 {
+            for z in 0..grid_size.2 {
+                let start = (x, y, z);
+                if walkable_grid[start] == 0 || labels[start] != 0 {
+                    continue;
+                }
+
+                // Every cell is labeled with id + 1, stop when the ids run out
+                if regions.len() >= u16::MAX as usize {
+                    return RegionMap { regions, labels };
+                }
+
+                let id = regions.len();
+                let label = id as u16 + 1;
+                let start_area = area(&start);
+
+                let mut cells = 0;
+                let mut sum = Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                };
+                let mut bounds: Option<AABB> = None;
+
+                labels[start] = label;
+                queue.push_back(start);
+                while let Some(cell) = queue.pop_front() {
+                    let position = cell_to_position(map_bounds, &cell);
+                    cells += 1;
+                    sum.x += position.x;
+                    sum.y += position.y;
+                    sum.z += position.z;
+                    let cell_bounds = AABB {
+                        min_x: position.x,
+                        min_y: position.y,
+                        min_z: position.z,
+                        max_x: position.x,
+                        max_y: position.y,
+                        max_z: position.z,
+                    };
+                    match bounds.as_mut() {
+                        Some(bounds) => bounds.extend_by(&cell_bounds),
+                        None => bounds = Some(cell_bounds),
+                    }
+
+                    for neighbour in neighbours(&cell, &grid_size) {
+                        if walkable_grid[neighbour] != 0
+                            && labels[neighbour] == 0
+                            && area(&neighbour) == start_area
+                        {
+                            labels[neighbour] = label;
+                            queue.push_back(neighbour);
+                        }
+                    }
+                }
+
+                regions.push(Region {
+                    id,
+                    name: Region::default_name(id),
+                    cells,
+                    center: Vec3 {
+                        x: sum.x / cells as f32,
+                        y: sum.y / cells as f32,
+                        z: sum.z / cells as f32,
+                    },
+                    bounds: bounds.unwrap_or_else(AABB::zero),
+                });
+            }
+        }
+    }
+
+    RegionMap { regions, labels }
+}
+
+// All 26 surrounding cells, walkable cells are connected over steps and ramps as well
+fn neighbours(
+    cell: &(usize, usize, usize),
+    grid_size: &(usize, usize, usize),
+) -> Vec<(usize, usize, usize)> {
+    let mut neighbours = Vec::with_capacity(26);
+    for dy in -1..=1_isize {
+        for dx in -1..=1_isize {
+            for dz in -1..=1_isize {
+                if dx == 0 && dy == 0 && dz == 0 {
+                    continue;
+                }
+
+                let x = cell.0 as isize + dx;
+                let y = cell.1 as isize + dy;
+                let z = cell.2 as isize + dz;
+                if x >= 0
+                    && y >= 0
+                    && z >= 0
+                    && (x as usize) < grid_size.0
+                    && (y as usize) < grid_size.1
+                    && (z as usize) < grid_size.2
+                {
+                    neighbours.push((x as usize, y as usize, z as usize));
+                }
+            }
+        }
+    }
+    neighbours
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::s;
+
+    use super::*;
+
+    fn bounds() -> AABB {
+        AABB {
+            min_x: 0.0,
+            min_y: 0.0,
+            min_z: 0.0,
+            max_x: 100.0,
+            max_y: 100.0,
+            max_z: 100.0,
+        }
+    }
+
+    fn label(grid: &Array3<u8>, band_height: usize, tile_size: usize) -> RegionMap {
+        let options = RegionOptions {
+            band_height,
+            tile_size,
+        };
+        label_regions(&WalkableGrid::new(grid), &bounds(), &options)
+    }
+
+    #[test]
+    fn large_floors_are_split_into_tiles() {
+        let mut grid = Array3::<u8>::zeros((10, 4, 10));
+        grid.slice_mut(s![.., 1, ..]).fill(1);
+
+        let regions = label(&grid, 4, 24);
+        assert_eq!(regions.regions.len(), 1);
+        assert_eq!(regions.regions[0].cells(), 100);
+
+        let regions = label(&grid, 4, 5);
+        assert_eq!(regions.regions.len(), 4);
+        assert!(regions.regions.iter().all(|region| region.cells() == 25));
+        // Ids follow the cell order, so they are the same on every run
+        assert_eq!(regions.region_of(&(0, 1, 0)).unwrap().id(), 0);
+        assert_eq!(regions.region_of(&(0, 1, 9)).unwrap().id(), 1);
+        assert_eq!(regions.region_of(&(9, 1, 0)).unwrap().id(), 2);
+        assert_eq!(regions.region_of(&(9, 1, 9)).unwrap().name(), "region-03");
+    }
+
+    #[test]
+    fn regions_stay_connected_and_inside_their_band() {
+        // Two floors that don't touch and a step up across the band border on the first one
+        let mut grid = Array3::<u8>::zeros((10, 4, 10));
+        grid.slice_mut(s![..4, 1, ..]).fill(1);
+        grid.slice_mut(s![4..6, 2, ..]).fill(1);
+        grid.slice_mut(s![7.., 1, ..]).fill(1);
+
+        let regions = label(&grid, 4, 24);
+        assert_eq!(regions.regions.len(), 2);
+        assert_eq!(regions.regions[0].cells(), 60);
+        assert_eq!(regions.regions[1].cells(), 30);
+
+        let regions = label(&grid, 2, 24);
+        assert_eq!(regions.regions.len(), 3);
+        let step = regions.region_of(&(4, 2, 0)).unwrap();
+        assert_eq!(step.cells(), 20);
+        assert_ne!(step.id(), regions.region_of(&(0, 1, 0)).unwrap().id());
+    }
+
+    #[test]
+    fn cells_without_a_region() {
+        let mut grid = Array3::<u8>::zeros((4, 4, 4));
+        grid[(1, 1, 1)] = 1;
+        let regions = label(&grid, 4, 24);
+
+        let region = regions.region_of(&(1, 1, 1)).unwrap();
+        let center = cell_to_position(&bounds(), &(1, 1, 1));
+        assert_eq!(region.center().distance(&center), 0.0);
+        assert!(regions.region_of(&(2, 1, 1)).is_none());
+        assert!(regions.region_of(&(9, 1, 1)).is_none());
+    }
+
+    #[test]
+    fn renamed_regions_keep_their_id() {
+        let mut grid = Array3::<u8>::zeros((4, 4, 4));
+        grid[(1, 1, 1)] = 1;
+        let mut regions = label(&grid, 4, 24);
+        let names = HashMap::from([
+            ("region-00".to_owned(), "spawn".to_owned()),
+            ("region-05".to_owned(), "unused".to_owned()),
+        ]);
+        regions.rename(&names);
+        assert_eq!(regions.regions[0].name(), "spawn");
+        assert_eq!(regions.regions[0].id(), 0);
+    }
+}
@@ -0,0 +1,290 @@
+use std::{future::Future, time::Duration};
+
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+use tracing::warn;
+
+use crate::utils::Error;
+
+// Retries of the HTTP requests to the matchmaker and the source server. Only connection errors,
+// timeouts, server errors and 429 are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Including the first attempt
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f32,
+    // Fraction the backoff is randomly moved by in both directions
+    pub jitter: f32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.25,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    // Wait after the failed attempt, counted from 1
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .mul_f32(self.multiplier.powi(attempt as i32 - 1))
+            .min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        backoff.mul_f32(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+    }
+
+    // Sends until a response that isn't worth retrying, what names the request in logs and errors
+    pub(crate) async fn send<F, Fut>(&self, what: &str, send: F) -> Result<Response, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Response, Error>>,
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let (err, retry_after) = match send().await {
+                Ok(res) if is_retryable_status(res.status()) => {
+                    let retry_after = res
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.trim().parse::<u64>().ok())
+                        .map(Duration::from_secs);
+                    (
+                        Error::Http(res.error_for_status().unwrap_err()),
+                        retry_after,
+                    )
+                }
+                Ok(res) => return Ok(res),
+                Err(err) if is_retryable_error(&err) => (err, None),
+                Err(err) => return Err(err),
+            };
+
+            if attempt >= max_attempts {
+                return Err(if attempt > 1 {
+                    Error::RetriesExhausted {
+                        attempts: attempt,
+                        last: Box::new(err),
+                    }
+                } else {
+                    err
+                });
+            }
+
+            let wait = retry_after.unwrap_or_else(|| self.backoff(attempt));
+            warn!(
+                "{} failed ({}), retrying in {:.1}s",
+                what,
+                err,
+                wait.as_secs_f32()
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_retryable_error(err: &Error) -> bool {
+    match err {
+        Error::Http(err) => err.is_connect() || err.is_timeout(),
+        Error::Matchmaker { status, .. } => *status >= 500,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            multiplier: 2.0,
+            jitter: 0.0,
+        }
+    }
+
+    fn server_error() -> Error {
+        Error::Matchmaker {
+            status: 503,
+            body: String::new(),
+        }
+    }
+
+    // Sends the error on every attempt and counts the attempts
+    async fn send_failing(
+        policy: &RetryPolicy,
+        err: fn() -> Error,
+    ) -> (Result<Response, Error>, u32) {
+        let attempts = AtomicU32::new(0);
+        let result = policy
+            .send("test", || {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async move { Err(err()) }
+            })
+            .await;
+        (result, attempts.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let backoffs = (1..=5)
+            .map(|attempt| policy.backoff(attempt).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, vec![100, 200, 400, 500, 500]);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1000),
+            jitter: 0.25,
+            ..Default::default()
+        };
+        let backoffs = (0..200)
+            .map(|_| policy.backoff(1).as_millis())
+            .collect::<Vec<_>>();
+        assert!(backoffs
+            .iter()
+            .all(|backoff| (750..=1250).contains(backoff)));
+        // Retries of many clients don't line up
+        assert!(backoffs.iter().any(|backoff| *backoff != backoffs[0]));
+
+        // Jitter above 1 would wait for a negative time
+        let policy = RetryPolicy {
+            jitter: 5.0,
+            ..policy
+        };
+        assert!((0..200).all(|_| policy.backoff(1) <= Duration::from_millis(2000)));
+    }
+
+    #[tokio::test]
+    async fn retryable_errors_give_up_after_the_last_attempt() {
+        let (result, attempts) = send_failing(&policy(3), server_error).await;
+        assert_eq!(attempts, 3);
+        match result {
+            Err(Error::RetriesExhausted { attempts, last }) => {
+                assert_eq!(attempts, 3);
+                assert!(matches!(*last, Error::Matchmaker { status: 503, .. }));
+            }
+            result => panic!("{:?}", result),
+        }
+
+        // A single attempt returns the error as it is
+        let (result, attempts) = send_failing(&RetryPolicy::none(), server_error).await;
+        assert_eq!(attempts, 1);
+        assert!(matches!(result, Err(Error::Matchmaker { status: 503, .. })));
+        // So do attempts of 0
+        let (_, attempts) = send_failing(&policy(0), server_error).await;
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let not_found = || Error::Matchmaker {
+            status: 404,
+            body: String::new(),
+        };
+        let (result, attempts) = send_failing(&policy(3), not_found).await;
+        assert_eq!(attempts, 1);
+        assert!(matches!(result, Err(Error::Matchmaker { status: 404, .. })));
+
+        let (_, attempts) = send_failing(&policy(3), || "broken".into()).await;
+        assert_eq!(attempts, 1);
+    }
+
+    // Answers with the statuses in order and 200 after them, with a Retry-After of 0 on errors
+    async fn server(statuses: Vec<u16>) -> (String, Arc<AtomicU32>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicU32::new(0));
+        let counted = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let hit = counted.fetch_add(1, Ordering::Relaxed) as usize;
+                let status = statuses.get(hit).copied().unwrap_or(200);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn server_errors_wait_as_long_as_the_server_asks() {
+        let (url, hits) = server(vec![503, 429]).await;
+        let client = reqwest::Client::new();
+        // Without the Retry-After of the server this would wait for a minute
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(60),
+            ..policy(3)
+        };
+        let started = Instant::now();
+        let response = policy
+            .send("test", || async { Ok(client.get(&url).send().await?) })
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(hits.load(Ordering::Relaxed), 3);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // Client errors are returned as responses
+        let (url, hits) = server(vec![404]).await;
+        let response = policy
+            .send("test", || async { Ok(client.get(&url).send().await?) })
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    game_filter::GameFilter,
+    map::CELL_SIZE,
+    modes::GameMode,
+    player::{HookAction, Player, PlayerBuilder, PlayerSnapshot},
+    utils::Error,
+    Client, Game,
+};
+
+const STAGE_TIMEOUT: Duration = Duration::from_secs(15);
+const STAGE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+const WALK_DISTANCE: f32 = 2.0 * CELL_SIZE;
+// Regions checked at the same time by the smoke test
+const SMOKE_PARALLELISM: usize = 4;
+
+// Handshake messages in the order the server sends them, the spawn is checked on the player instead
+const MESSAGE_STAGES: [&str; 4] = ["load", "io-init", "init", "ready"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageResult {
+    pub stage: String,
+    pub passed: bool,
+    // Time since the previous stage finished
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfCheckReport {
+    pub region: String,
+    pub game_id: Option<String>,
+    pub version: Option<String>,
+    pub passed: bool,
+    pub total_ms: u64,
+    // Stops at the first failed stage
+    pub stages: Vec<StageResult>,
+}
+
+impl SelfCheckReport {
+    pub fn failed_stage(&self) -> Option<&StageResult> {
+        self.stages.iter().find(|stage| !stage.passed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SmokeReport {
+    pub passed: bool,
+    pub total_ms: u64,
+    // In the order the regions were given
+    pub regions: Vec<SelfCheckReport>,
+}
+
+impl SmokeReport {
+    pub fn failed(&self) -> impl Iterator<Item = &SelfCheckReport> {
+        self.regions.iter().filter(|region| !region.passed)
+    }
+}
+
+struct Stages {
+    start: Instant,
+    last: Instant,
+    results: Vec<StageResult>,
+}
+
+impl Stages {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            results: vec![],
+        }
+    }
+
+    fn record<T>(&mut self, stage: &str, result: Result<T, Error>) -> Option<T> {
+        self.record_at(stage, result, Instant::now())
+    }
+
+    fn record_at<T>(&mut self, stage: &str, result: Result<T, Error>, at: Instant) -> Option<T> {
+        let duration = at.saturating_duration_since(self.last);
+        self.last = at.max(self.last);
+
+        let (value, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        self.results.push(StageResult {
+            stage: stage.to_owned(),
+            passed: error.is_none(),
+            duration_ms: duration.as_millis() as u64,
+            error,
+        });
+
+        value
+    }
+}
+
+// Connects a throwaway player to an empty lobby in the region and times every step from opening the
+// websocket to walking a few cells. Meant to be run on startup or after a game update to find out
+// which part of the protocol broke.
+// The player is disconnected even if the check runs into the timeout.
+pub(crate) async fn self_check(
+    client: &Arc<Mutex<Client>>,
+    region: &str,
+    timeout: Option<Duration>,
+) -> SelfCheckReport {
+    let mut stages = Stages::new();
+    let mut game_id = None;
+    let mut version = None;
+    let mut player = None;
+
+    let run = run_check(
+        client,
+        region,
+        &mut stages,
+        &mut game_id,
+        &mut version,
+        &mut player,
+    );
+    let timed_out = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, run).await.is_err(),
+        None => {
+            run.await;
+            false
+        }
+    };
+    if let (true, Some(timeout)) = (timed_out, timeout) {
+        stages.record::<()>(
+            "timeout",
+            Err(format!("Timed out after {:?}", timeout).into()),
+        );
+    }
+    if let Some(player) = player {
+        let _ = player.lock().await.disconnect().await;
+    }
+
+    let passed = stages.results.iter().all(|stage| stage.passed);
+    SelfCheckReport {
+        region: region.to_owned(),
+        game_id,
+        version,
+        passed,
+        total_ms: stages.start.elapsed().as_millis() as u64,
+        stages: stages.results,
+    }
+}
+
+// Self-checks of the regions with a few running at the same time. Every check runs in its own task,
+// so a panic only fails its region.
+pub(crate) async fn smoke_test(
+    client: &Arc<Mutex<Client>>,
+    regions: &[&str],
+    per_region_timeout: Duration,
+) -> SmokeReport {
+    let start = Instant::now();
+
+    let reports = stream::iter(regions.iter().map(|region| region.to_string()))
+        .map(|region| {
+            let client = client.clone();
+            let task_region = region.clone();
+            let handle = tokio::spawn(async move {
+                self_check(&client, &task_region, Some(per_region_timeout)).await
+            });
+            async move {
+                handle.await.unwrap_or_else(|err| {
+                    let mut stages = Stages::new();
+                    stages.record::<()>("panic", Err(err.to_string().into()));
+                    SelfCheckReport {
+                        region,
+                        game_id: None,
+                        version: None,
+                        passed: false,
+                        total_ms: 0,
+                        stages: stages.results,
+                    }
+                })
+            }
+        })
+        .buffered(SMOKE_PARALLELISM)
+        .collect::<Vec<_>>()
+        .await;
+
+    SmokeReport {
+        passed: reports.iter().all(|report| report.passed),
+        total_ms: start.elapsed().as_millis() as u64,
+        regions: reports,
+    }
+}
+
+async fn run_check(
+    client: &Arc<Mutex<Client>>,
+    region: &str,
+    stages: &mut Stages,
+    game_id: &mut Option<String>,
+    version: &mut Option<String>,
+    connected: &mut Option<Arc<Mutex<Player>>>,
+) {
+    if let Some(game) = stages.record("matchmaker", find_lobby(client, region).await) {
+        *game_id = Some(game.id.clone());
+        *version = Some(game.version.clone());
+
+        let arrivals = Arc::new(std::sync::Mutex::new(HashMap::<String, Instant>::new()));
+        let hook_arrivals = arrivals.clone();
+        let builder = PlayerBuilder::new(client.clone()).message_hook(move |msg| {
+            hook_arrivals
+                .lock()
+                .unwrap()
+                .entry(msg.kind.clone())
+                .or_insert_with(Instant::now);
+            HookAction::Continue
+        });
+
+        if let Some(player) = stages.record("websocket", builder.connect(&game).await) {
+            *connected = Some(player.clone());
+            run_stages(stages, &player, &arrivals).await;
+        }
+    }
+}
+
+async fn find_lobby(client: &Arc<Mutex<Client>>, region: &str) -> Result<Game, Error> {
+    let filter = GameFilter::new()
+        .region(region.into())
+        .mode(GameMode::Ffa)
+        .exclude_custom()
+        .min_free_slots(1);
+    client
+        .lock()
+        .await
+        .find_game(&filter)
+        .await?
+        .ok_or_else(|| format!("No joinable lobby in {}", region).into())
+}
+
+async fn run_stages(
+    stages: &mut Stages,
+    player: &Arc<Mutex<Player>>,
+    arrivals: &Arc<std::sync::Mutex<HashMap<String, Instant>>>,
+) {
+    for kind in MESSAGE_STAGES {
+        let arrival = wait_for(player, |_| arrivals.lock().unwrap().get(kind).copied()).await;
+        let at = arrival.as_ref().copied().unwrap_or_else(|_| Instant::now());
+        if stages.record_at(kind, arrival, at).is_none() {
+            return;
+        }
+    }
+
+    // Sent by the player itself after ready
+    let spawned = wait_for(player, |snapshot| snapshot.in_game.then_some(())).await;
+    if stages.record("spawn", spawned).is_none() {
+        return;
+    }
+
+    let update = wait_for(player, |_| arrivals.lock().unwrap().get("l").copied()).await;
+    let at = update.as_ref().copied().unwrap_or_else(|_| Instant::now());
+    if stages.record_at("update", update, at).is_none() {
+        return;
+    }
+
+    let walked = walk(player).await;
+    if stages.record("walk", walked).is_none() {
+        return;
+    }
+
+    let left = player.lock().await.disconnect().await;
+    stages.record("leave", left);
+}
+
+async fn wait_for<T>(
+    player: &Arc<Mutex<Player>>,
+    check: impl Fn(&PlayerSnapshot) -> Option<T>,
+) -> Result<T, Error> {
+    let mut snapshots = player.lock().await.watch_snapshot();
+    let start = Instant::now();
+    while start.elapsed() < STAGE_TIMEOUT {
+        let snapshot = snapshots.borrow_and_update().clone();
+        if let Some(value) = check(&snapshot) {
+            return Ok(value);
+        }
+        if !snapshot.connected {
+            return Err("Server closed the connection".into());
+        }
+
+        tokio::time::sleep(STAGE_POLL_INTERVAL).await;
+    }
+
+    Err(format!("Timed out after {:?}", STAGE_TIMEOUT).into())
+}
+
+async fn walk(player: &Arc<Mutex<Player>>) -> Result<(), Error> {
+    let (mut snapshots, start) = {
+        let mut player_lock = player.lock().await;
+        player_lock.walk(true).await?;
+        (
+            player_lock.watch_snapshot(),
+            player_lock.snapshot().position,
+        )
+    };
+
+    let result = tokio::time::timeout(STAGE_TIMEOUT, async {
+        loop {
+            snapshots.changed().await?;
+            let snapshot = snapshots.borrow_and_update().clone();
+            if !snapshot.connected {
+                return Err::<(), Error>("Server closed the connection while walking".into());
+            }
+            if !snapshot.in_game {
+                return Err("Player died while walking".into());
+            }
+
+            let (dx, dz) = (snapshot.position.x - start.x, snapshot.position.z - start.z);
+            if (dx * dx + dz * dz).sqrt() >= WALK_DISTANCE {
+                return Ok(());
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err(format!("Timed out after {:?}", STAGE_TIMEOUT).into()));
+
+    player.lock().await.walk(false).await?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soak::{self, MockMatchmaker};
+
+    fn client(matchmaker: &MockMatchmaker) -> Arc<Mutex<Client>> {
+        Arc::new(Mutex::new(soak::client(vec![], &matchmaker.url)))
+    }
+
+    fn stage_names(report: &SelfCheckReport) -> Vec<&str> {
+        report
+            .stages
+            .iter()
+            .map(|stage| stage.stage.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn stages_are_timed_from_the_previous_one() {
+        let mut stages = Stages::new();
+        let start = stages.start;
+        assert_eq!(
+            stages.record_at("first", Ok(1), start + Duration::from_millis(30)),
+            Some(1)
+        );
+        // Arrivals before the last stage count as no time at all
+        stages.record_at::<()>("second", Ok(()), start + Duration::from_millis(10));
+        stages.record_at::<()>(
+            "third",
+            Err("broken".into()),
+            start + Duration::from_millis(50),
+        );
+
+        let durations = stages
+            .results
+            .iter()
+            .map(|stage| stage.duration_ms)
+            .collect::<Vec<_>>();
+        assert_eq!(durations, vec![30, 0, 20]);
+
+        let report = SelfCheckReport {
+            region: "de-fra".to_owned(),
+            game_id: None,
+            version: None,
+            passed: false,
+            total_ms: 50,
+            stages: stages.results,
+        };
+        let failed = report.failed_stage().unwrap();
+        assert_eq!(failed.stage, "third");
+        assert_eq!(failed.error.as_deref(), Some("broken"));
+    }
+
+    #[tokio::test]
+    async fn regions_without_a_lobby_fail_at_the_matchmaker() {
+        let matchmaker = MockMatchmaker::spawn("a").await;
+        let report = self_check(&client(&matchmaker), "xx-none", None).await;
+        assert!(!report.passed);
+        assert_eq!(report.region, "xx-none");
+        assert_eq!(report.game_id, None);
+        assert_eq!(stage_names(&report), vec!["matchmaker"]);
+        assert_eq!(
+            report.stages[0].error.as_deref(),
+            Some("No joinable lobby in xx-none")
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_stop_at_the_first_failed_stage() {
+        // The lobby is found, but the test client has no endpoints to open a websocket with
+        let matchmaker = MockMatchmaker::spawn("a").await;
+        let report = self_check(&client(&matchmaker), "de-fra", None).await;
+        assert!(!report.passed);
+        assert_eq!(report.game_id.as_deref(), Some("SOAK:game"));
+        assert_eq!(stage_names(&report), vec!["matchmaker", "websocket"]);
+        assert!(report.stages[0].passed);
+        assert_eq!(report.failed_stage().unwrap().stage, "websocket");
+    }
+
+    #[tokio::test]
+    async fn checks_that_run_too_long_time_out() {
+        let matchmaker = MockMatchmaker::spawn("a").await;
+        matchmaker.hold(true);
+        let timeout = Duration::from_millis(50);
+        let report = self_check(&client(&matchmaker), "de-fra", Some(timeout)).await;
+        assert!(!report.passed);
+        assert_eq!(stage_names(&report), vec!["timeout"]);
+        assert!(report.total_ms >= 50);
+        matchmaker.hold(false);
+    }
+
+    #[tokio::test]
+    async fn smoke_tests_report_every_region_in_order() {
+        let matchmaker = MockMatchmaker::spawn("a").await;
+        let regions = ["xx-none", "de-fra", "yy-none"];
+        let report = smoke_test(&client(&matchmaker), &regions, Duration::from_secs(5)).await;
+
+        assert!(!report.passed);
+        let names = report
+            .regions
+            .iter()
+            .map(|region| region.region.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, regions);
+        assert_eq!(report.failed().count(), 3);
+        assert_eq!(stage_names(&report.regions[0]), vec!["matchmaker"]);
+        assert_eq!(
+            stage_names(&report.regions[1]),
+            vec!["matchmaker", "websocket"]
+        );
+    }
+
+    #[tokio::test]
+    async fn smoke_tests_check_regions_at_the_same_time() {
+        let matchmaker = MockMatchmaker::spawn("a").await;
+        matchmaker.hold(true);
+        let regions = ["a", "b", "c", "d"];
+        let timeout = Duration::from_millis(200);
+        let report = smoke_test(&client(&matchmaker), &regions, timeout).await;
+
+        assert_eq!(report.regions.len(), SMOKE_PARALLELISM);
+        for region in &report.regions {
+            assert_eq!(stage_names(region), vec!["timeout"]);
+        }
+        // One after another they would take four timeouts
+        assert!(report.total_ms < 2 * 200, "{}", report.total_ms);
+        matchmaker.hold(false);
+    }
+
+    #[tokio::test]
+    async fn smoke_tests_without_regions_pass() {
+        let matchmaker = MockMatchmaker::spawn("a").await;
+        let report = smoke_test(&client(&matchmaker), &[], Duration::from_secs(1)).await;
+        assert!(report.passed);
+        assert!(report.regions.is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+const WINDOW: usize = 64;
+const MIN_SAMPLES: usize = 8;
+
+// Estimates the tick clock of the game server from the tick numbers echoed in player updates.
+// A least squares fit of tick over receive time on a sliding window gives the rate the server
+// processes ticks at and the tick it is at right now, jitter of single updates averages out.
+#[derive(Debug, Clone, Default)]
+pub struct ServerClock {
+    base: Option<Instant>,
+    last_tick: Option<u32>,
+    // Seconds since base and echoed tick
+    samples: VecDeque<(f64, f64)>,
+    // Tick at base and ticks per second
+    fit: Option<(f64, f64)>,
+}
+
+impl ServerClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, received_at: Instant, tick: u32) {
+        // Tick numbers start again after every spawn
+        if self.last_tick.is_some_and(|last_tick| tick < last_tick) {
+            self.reset();
+        }
+        self.last_tick = Some(tick);
+
+        let base = *self.base.get_or_insert(received_at);
+        if self.samples.len() >= WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((
+            received_at.saturating_duration_since(base).as_secs_f64(),
+            tick as f64,
+        ));
+
+        self.fit = self.regression();
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    // Server ticks per second
+    pub fn rate(&self) -> Option<f64> {
+        self.fit.map(|(_, rate)| rate)
+    }
+
+    // Fractional tick the server is at
+    pub fn tick_at(&self, at: Instant) -> Option<f64> {
+        let (offset, rate) = self.fit?;
+        Some(offset + rate * self.seconds(at)?)
+    }
+
+    // When the server reaches the tick, can be in the past
+    pub fn time_of(&self, tick: f64) -> Option<Instant> {
+        let (offset, rate) = self.fit?;
+        let seconds = (tick - offset) / rate;
+        let base = self.base?;
+        Some(if seconds >= 0.0 {
+            base + Duration::from_secs_f64(seconds)
+        } else {
+            base.checked_sub(Duration::from_secs_f64(-seconds))
+                .unwrap_or(base)
+        })
+    }
+
+    fn seconds(&self, at: Instant) -> Option<f64> {
+        let base = self.base?;
+        Some(if at >= base {
+            at.duration_since(base).as_secs_f64()
+        } else {
+            -base.duration_since(at).as_secs_f64()
+        })
+    }
+
+    fn regression(&self) -> Option<(f64, f64)> {
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+
+        let n = self.samples.len() as f64;
+        let mean_t = self.samples.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_tick = self.samples.iter().map(|(_, tick)| tick).sum::<f64>() / n;
+
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for (t, tick) in self.samples.iter() {
+            covariance += (t - mean_t) * (tick - mean_tick);
+            variance += (t - mean_t).powi(2);
+        }
+
+        // All updates arrived at the same time, or the ticks didn't advance
+        if variance <= f64::EPSILON || covariance <= 0.0 {
+            return None;
+        }
+
+        let rate = covariance / variance;
+        Some((mean_tick - rate * mean_t, rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ticks at 15 per second starting at tick 100, every update is late by up to 20ms
+    fn observed(clock: &mut ServerClock, start: Instant, updates: u32) {
+        for i in 0..updates {
+            let jitter = Duration::from_millis((i as u64 * 7) % 20);
+            let sent = Duration::from_secs_f64(i as f64 / 15.0);
+            clock.observe(start + sent + jitter, 100 + i);
+        }
+    }
+
+    #[test]
+    fn the_fit_needs_a_few_samples() {
+        let start = Instant::now();
+        let mut clock = ServerClock::new();
+        observed(&mut clock, start, MIN_SAMPLES as u32 - 1);
+        assert!(clock.rate().is_none() && clock.tick_at(start).is_none());
+
+        observed(&mut clock, start, MIN_SAMPLES as u32);
+        assert!(clock.rate().is_some());
+    }
+
+    #[test]
+    fn rate_and_tick_average_out_the_jitter() {
+        let start = Instant::now();
+        let mut clock = ServerClock::new();
+        observed(&mut clock, start, 200);
+
+        let rate = clock.rate().unwrap();
+        assert!((rate - 15.0).abs() < 0.2, "{}", rate);
+        let tick = clock.tick_at(start + Duration::from_secs(20)).unwrap();
+        assert!((tick - 400.0).abs() < 1.0, "{}", tick);
+
+        // time_of is the inverse of tick_at
+        let at = clock.time_of(tick).unwrap();
+        let error =
+            at.max(start + Duration::from_secs(20)) - at.min(start + Duration::from_secs(20));
+        assert!(error < Duration::from_millis(1));
+        assert!(clock.time_of(-1000.0).is_some());
+    }
+
+    #[test]
+    fn lower_ticks_start_a_new_fit() {
+        let start = Instant::now();
+        let mut clock = ServerClock::new();
+        observed(&mut clock, start, 20);
+        assert!(clock.rate().is_some());
+
+        clock.observe(start + Duration::from_secs(5), 3);
+        assert!(clock.rate().is_none());
+        assert_eq!(clock.samples.len(), 1);
+    }
+
+    #[test]
+    fn updates_without_progress_have_no_rate() {
+        let start = Instant::now();
+        let mut clock = ServerClock::new();
+        for i in 0..10 {
+            clock.observe(start, 100 + i);
+        }
+        assert!(clock.rate().is_none());
+
+        clock.reset();
+        for i in 0..10 {
+            clock.observe(start + Duration::from_millis(66 * i as u64), 100);
+        }
+        assert!(clock.rate().is_none());
+    }
+}
@@ -0,0 +1,90 @@
+use std::{convert::Infallible, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+// Matchmaker region of a game. Codes without a variant are kept as they are, so new regions still
+// parse and are sent back to the matchmaker unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum ServerRegion {
+    Frankfurt,
+    NewJersey,
+    California,
+    Singapore,
+    Sydney,
+    Tokyo,
+    SaoPaulo,
+    Other(String),
+}
+
+const REGIONS: [(ServerRegion, &str, &str); 7] = [
+    (ServerRegion::Frankfurt, "de-fra", "Frankfurt"),
+    (ServerRegion::NewJersey, "us-nj", "New Jersey"),
+    (ServerRegion::California, "us-ca", "California"),
+    (ServerRegion::Singapore, "sgp", "Singapore"),
+    (ServerRegion::Sydney, "au-syd", "Sydney"),
+    (ServerRegion::Tokyo, "jb-hnd", "Tokyo"),
+    (ServerRegion::SaoPaulo, "brz", "Sao Paulo"),
+];
+
+impl ServerRegion {
+    // Code the matchmaker uses, e.g. de-fra
+    pub fn as_str(&self) -> &str {
+        match self {
+            ServerRegion::Other(code) => code,
+            region => REGIONS
+                .iter()
+                .find(|(known, _, _)| known == region)
+                .map(|(_, code, _)| *code)
+                .unwrap(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        REGIONS
+            .iter()
+            .find(|(known, _, _)| known == self)
+            .map(|(_, _, name)| *name)
+            .unwrap_or_else(|| self.as_str())
+    }
+
+    pub fn all() -> impl Iterator<Item = ServerRegion> {
+        REGIONS.into_iter().map(|(region, _, _)| region)
+    }
+}
+
+impl From<&str> for ServerRegion {
+    fn from(code: &str) -> Self {
+        REGIONS
+            .iter()
+            .find(|(_, known, _)| *known == code)
+            .map(|(region, _, _)| region.clone())
+            .unwrap_or_else(|| ServerRegion::Other(code.to_owned()))
+    }
+}
+
+impl From<String> for ServerRegion {
+    fn from(code: String) -> Self {
+        code.as_str().into()
+    }
+}
+
+impl From<ServerRegion> for String {
+    fn from(region: ServerRegion) -> Self {
+        region.as_str().to_owned()
+    }
+}
+
+impl FromStr for ServerRegion {
+    type Err = Infallible;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        Ok(code.into())
+    }
+}
+
+impl fmt::Display for ServerRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
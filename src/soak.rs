@@ -0,0 +1,561 @@
+// Long runs of a player against a MockTransport. The player runs on a manual clock and is stepped
+// by the test, so hours of play take seconds. Each run checks that nothing the player keeps per
+// message, per game or per connection grows without bound.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    runtime::Handle,
+    sync::{watch, Mutex},
+};
+
+use crate::{
+    annotations::AnnotationFile,
+    clock::{Clock, ManualClock},
+    map::{MapBuildOptions, RawMap, RawMapObject},
+    matchmaker::{Matchmaker, DEFAULT_MATCHMAKER_URL},
+    modes::GameMode,
+    player::{Player, PlayerBuilder, MOVEMENT_SPEED},
+    retry::RetryPolicy,
+    server_region::ServerRegion,
+    socket::MockTransport,
+    tasks::TaskRegistry,
+    utils::Vec3,
+    Client, Endpoints, Game, PreparseProgress, RawGame, RawGameInfo,
+};
+
+const TICK_INTERVAL: Duration = Duration::from_millis(66);
+const PLAYER_ID: &str = "soak";
+
+// Flat square map with a single spawn, parses in a few milliseconds
+pub(crate) fn raw_map(name: &str) -> RawMap {
+    RawMap {
+        name: name.to_owned(),
+        sizes: vec![200.0, 6.0, 200.0, 200.0, 30.0, 1.0],
+        objects: vec![
+            RawMapObject {
+                position: [0.0, -6.0, 0.0],
+                size_index: Some(0),
+                ..Default::default()
+            },
+            // Borders make room above the floor
+            RawMapObject {
+                position: [0.0, -6.0, -100.0],
+                size_index: Some(1),
+                border: Some(1),
+                ..Default::default()
+            },
+        ],
+        spawns: vec![vec![Some(0.0), Some(0.0), Some(0.0)]],
+        ..Default::default()
+    }
+}
+
+// Client with the raw maps and nothing parsed, without downloading the source
+pub(crate) fn client(raw_maps: Vec<RawMap>, matchmaker_url: &str) -> Client {
+    let retry = RetryPolicy::none();
+    Client {
+        prime: 0,
+        client_key: String::new(),
+        matchmaker: Arc::new(Matchmaker::new(&[matchmaker_url], retry)),
+        profiles: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        maps: vec![None; raw_maps.len()],
+        raw_maps,
+        custom_maps: vec![],
+        map_options: MapBuildOptions::default(),
+        map_tuning: HashMap::new(),
+        annotations: AnnotationFile::default(),
+        region_options: None,
+        retain_raw: false,
+        parsing: HashMap::new(),
+        foreground: Arc::new(watch::channel(0).0),
+        preparse: Arc::new(watch::channel(PreparseProgress::default()).0),
+        tasks: TaskRegistry::new(Handle::current()),
+        endpoints: Arc::new(
+            Endpoints::new(String::new(), String::new(), None, retry, None).unwrap(),
+        ),
+    }
+}
+
+// Listed game that is never connected to
+pub(crate) fn game(id: &str, region: ServerRegion, map: &str, mode: GameMode) -> Game {
+    let retry = RetryPolicy::none();
+    Game {
+        client_key: String::new(),
+        id: id.to_owned(),
+        region,
+        version: String::new(),
+        players: 0,
+        max_players: 8,
+        custom: false,
+        map: map.to_owned(),
+        mode,
+        matchmaker: Arc::new(Matchmaker::new(&[DEFAULT_MATCHMAKER_URL], retry)),
+        endpoints: Arc::new(
+            Endpoints::new(String::new(), String::new(), None, retry, None).unwrap(),
+        ),
+        raw: None,
+    }
+}
+
+// Answers the game list with the one game and every other request with its game info, or 404
+// once the game is delisted. The game has a field the crate doesn't know. Requests aren't answered
+// while it is held.
+pub(crate) struct MockMatchmaker {
+    pub url: String,
+    map: Arc<std::sync::Mutex<String>>,
+    listed: Arc<AtomicBool>,
+    held: Arc<AtomicBool>,
+    // Stops the server when dropped
+    _tasks: TaskRegistry,
+}
+
+impl MockMatchmaker {
+    pub(crate) async fn spawn(map: &str) -> Self {
+        let tasks = TaskRegistry::current();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let current = Arc::new(std::sync::Mutex::new(map.to_owned()));
+        let listed = Arc::new(AtomicBool::new(true));
+        let held = Arc::new(AtomicBool::new(false));
+
+        let map = current.clone();
+        let is_listed = listed.clone();
+        let is_held = held.clone();
+        tasks.spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 4096];
+                let mut read = 0;
+                while !request[..read].windows(4).any(|end| end == b"\r\n\r\n") {
+                    match stream.read(&mut request[read..]).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => read += n,
+                    }
+                }
+
+                let game = RawGame(
+                    "SOAK:game".to_owned(),
+                    "de-fra".to_owned(),
+                    2,
+                    8,
+                    RawGameInfo {
+                        custom: 0,
+                        version: String::new(),
+                        map: map.lock().unwrap().clone(),
+                        mode: 0,
+                    },
+                );
+                let mut game = serde_json::to_value(&game).unwrap();
+                game[4]["new"] = json!({ "nested": [1, 2] });
+                let body = if request.starts_with(b"GET /game-list") {
+                    json!({ "games": [game] }).to_string()
+                } else {
+                    game.to_string()
+                };
+                while is_held.load(Ordering::Relaxed) {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                if !is_listed.load(Ordering::Relaxed) {
+                    let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    continue;
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        Self {
+            url,
+            map: current,
+            listed,
+            held,
+            _tasks: tasks,
+        }
+    }
+
+    pub(crate) fn set_map(&self, map: &str) {
+        *self.map.lock().unwrap() = map.to_owned();
+    }
+
+    pub(crate) fn delist(&self) {
+        self.listed.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn hold(&self, held: bool) {
+        self.held.store(held, Ordering::Relaxed);
+    }
+}
+
+// Largest values seen during the run
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SoakStats {
+    pub ticks: usize,
+    pub deaths: usize,
+    pub games: usize,
+    pub reconnects: usize,
+    pub max_state_buffer: usize,
+    pub max_queued_messages: usize,
+    pub max_player_tasks: usize,
+    pub max_client_tasks: usize,
+    pub max_known_players: usize,
+}
+
+type Configure = Arc<dyn Fn(PlayerBuilder) -> PlayerBuilder + Send + Sync>;
+
+// Server side movement of the player on a laggy connection. The server moves the player faster
+// than the client predicts, so the reconciled position lags behind, and its positions are
+// acknowledged latency ticks late.
+pub(crate) struct LaggyServer {
+    latency: usize,
+    position: Vec3,
+    acks: VecDeque<(u64, Vec3)>,
+    // Doesn't move the player at all, as if it was caught on geometry
+    frozen: bool,
+}
+
+impl LaggyServer {
+    const SPEED: f32 = 1.6;
+
+    // Moves by the inputs of the tick, the step is the distance the client walks per tick
+    fn advance(&mut self, walking: bool, rotation: f32, step: f32) {
+        if walking && !self.frozen {
+            self.position.x += step * Self::SPEED * rotation.sin();
+            self.position.z += step * Self::SPEED * -rotation.cos();
+        }
+    }
+}
+
+pub(crate) struct Soak {
+    client: Arc<Mutex<Client>>,
+    clock: ManualClock,
+    matchmaker: MockMatchmaker,
+    game: Game,
+    // Applied to the builder of every connection
+    configure: Configure,
+    pub player: Arc<Mutex<Player>>,
+    pub transport: MockTransport,
+    // Messages the player sent during the last step
+    pub sent: Vec<(String, Vec<Value>)>,
+    // Players of earlier connections, none of them may be kept alive by a task
+    previous: Vec<Weak<Mutex<Player>>>,
+    // Other players get a new id on every spawn
+    spawns: usize,
+    // Acknowledges the player's own positions right away if not set
+    server: Option<LaggyServer>,
+    pub stats: SoakStats,
+}
+
+impl Soak {
+    pub(crate) async fn new(maps: &[&str]) -> Self {
+        Self::with_builder(maps, |builder| builder).await
+    }
+
+    pub(crate) async fn with_builder(
+        maps: &[&str],
+        configure: impl Fn(PlayerBuilder) -> PlayerBuilder + Send + Sync + 'static,
+    ) -> Self {
+        let raw_maps = maps.iter().map(|name| raw_map(name)).collect();
+        Self::with_raw_maps(raw_maps, configure).await
+    }
+
+    // The first map is played
+    pub(crate) async fn with_raw_maps(
+        raw_maps: Vec<RawMap>,
+        configure: impl Fn(PlayerBuilder) -> PlayerBuilder + Send + Sync + 'static,
+    ) -> Self {
+        let configure: Configure = Arc::new(configure);
+        let map_name = raw_maps[0].name.clone();
+        let matchmaker = MockMatchmaker::spawn(&map_name).await;
+        let client = client(raw_maps, &matchmaker.url);
+        let mut game = client.game_by_id("SOAK:game").await.unwrap();
+        game.map = map_name;
+        let client = Arc::new(Mutex::new(client));
+
+        let clock = ManualClock::new();
+        let (player, transport) = Self::player(&client, &clock, &game, &configure).await;
+        let mut soak = Self {
+            client,
+            clock,
+            matchmaker,
+            game,
+            configure,
+            player,
+            transport,
+            sent: vec![],
+            previous: vec![],
+            spawns: 0,
+            server: None,
+            stats: SoakStats::default(),
+        };
+        soak.join().await;
+        soak
+    }
+
+    async fn player(
+        client: &Arc<Mutex<Client>>,
+        clock: &ManualClock,
+        game: &Game,
+        configure: &Configure,
+    ) -> (Arc<Mutex<Player>>, MockTransport) {
+        let builder = PlayerBuilder::new(client.clone())
+            .tick_interval(TICK_INTERVAL)
+            .clock(Clock::Manual(clock.clone()));
+        configure(builder).connect_mock(game).await
+    }
+
+    // Handshake of the server up to the first spawn
+    async fn join(&mut self) {
+        self.transport.push("io-init", vec![json!(PLAYER_ID)]).await;
+        self.transport.push("ready", vec![]).await;
+        self.transport.push("init", vec![]).await;
+        self.until(|player| player.in_game()).await;
+    }
+
+    // One tick of the player and the answers of the server to what it sent
+    pub(crate) async fn step(&mut self) {
+        self.clock.advance(TICK_INTERVAL);
+        let mut player = self.player.lock().await;
+        assert!(
+            player.step().await,
+            "player stopped: {:?}",
+            player.end_state()
+        );
+
+        self.stats.ticks += 1;
+        let diagnostics = player.diagnostics().await;
+        let stats = &mut self.stats;
+        stats.max_state_buffer = stats.max_state_buffer.max(diagnostics.state_buffer_len);
+        stats.max_queued_messages = stats.max_queued_messages.max(diagnostics.queued_messages);
+        stats.max_player_tasks = stats.max_player_tasks.max(player.task_count());
+        stats.max_known_players = stats.max_known_players.max(diagnostics.known_players);
+        stats.max_client_tasks = stats
+            .max_client_tasks
+            .max(self.client.lock().await.task_count());
+
+        let snapshot = player.snapshot();
+        drop(player);
+
+        let mut acked = None;
+        self.sent = self.transport.take_sent();
+        for (kind, data) in self.sent.clone() {
+            match kind.as_str() {
+                "q" => acked = data.get(1).and_then(Value::as_u64),
+                "en" => self.spawn().await,
+                _ => (),
+            }
+        }
+        // The server acknowledges the newest tick it got
+        let acks = match self.server.as_mut() {
+            Some(server) => {
+                let step = TICK_INTERVAL.as_micros() as f32 * MOVEMENT_SPEED;
+                server.advance(snapshot.walking, snapshot.rotation, step);
+                if let Some(tick) = acked {
+                    server.acks.push_back((tick, server.position));
+                }
+                let late = server.acks.len().saturating_sub(server.latency);
+                server.acks.drain(..late).collect()
+            }
+            None => acked
+                .map(|tick| (tick, snapshot.position))
+                .into_iter()
+                .collect::<Vec<_>>(),
+        };
+        for (tick, position) in acks {
+            self.transport
+                .push(
+                    "l",
+                    vec![json!([tick, 0, position.x, position.y, position.z])],
+                )
+                .await;
+        }
+        if self.stats.ticks.is_multiple_of(100) {
+            self.transport.push("pi", vec![]).await;
+        }
+    }
+
+    async fn spawn(&mut self) {
+        self.spawns += 1;
+        let other = format!("other-{}", self.spawns);
+        self.transport
+            .push(
+                "0",
+                vec![json!([
+                    PLAYER_ID, 0, 0.0, 0.0, 0.0, other, 0, 10.0, 0.0, 10.0
+                ])],
+            )
+            .await;
+    }
+
+    // Steps until the player got there, tasks that wait for io get a moment between the steps
+    pub(crate) async fn until(&mut self, done: impl Fn(&Player) -> bool) {
+        for _ in 0..1000 {
+            if done(&*self.player.lock().await) {
+                return;
+            }
+            self.step().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let snapshot = self.player.lock().await.snapshot();
+        panic!(
+            "player didn't get there within 1000 ticks: {:?} {:?}",
+            snapshot, self.stats
+        );
+    }
+
+    pub(crate) async fn die(&mut self) {
+        self.die_without_respawn().await;
+        // The spawn answers the enter after the respawn delay
+        self.until(|player| player.in_game()).await;
+        self.stats.deaths += 1;
+    }
+
+    // From now on the server moves the player itself and acknowledges ticks late, see LaggyServer
+    pub(crate) async fn simulate_latency(&mut self, latency: usize) {
+        self.server = Some(LaggyServer {
+            latency,
+            position: self.player.lock().await.snapshot().position,
+            acks: VecDeque::new(),
+            frozen: false,
+        });
+    }
+
+    // The server keeps the player where it is until it is released, without latency if there was none
+    pub(crate) async fn freeze(&mut self, frozen: bool) {
+        if self.server.is_none() {
+            self.simulate_latency(0).await;
+        }
+        if let Some(server) = self.server.as_mut() {
+            server.frozen = frozen;
+        }
+    }
+
+    pub(crate) fn delist(&self) {
+        self.matchmaker.delist();
+    }
+
+    // The matchmaker doesn't answer until it is released
+    pub(crate) fn hold_matchmaker(&self, held: bool) {
+        self.matchmaker.hold(held);
+    }
+
+    pub(crate) async fn die_without_respawn(&mut self) {
+        self.transport.push("l", vec![json!(0)]).await;
+        self.step().await;
+        assert!(self.player.lock().await.is_dead());
+    }
+
+    // The game info of the next init has this map
+    pub(crate) fn set_map(&self, map: &str) {
+        self.matchmaker.set_map(map);
+    }
+
+    pub(crate) async fn change_map(&mut self, map: &str) {
+        self.set_map(map);
+        self.transport.push("end", vec![]).await;
+        self.transport.push("init", vec![]).await;
+        let name = map.to_owned();
+        self.until(|player| player.in_game() && player.map().is_some_and(|map| map.name == name))
+            .await;
+        self.stats.games += 1;
+    }
+
+    pub(crate) async fn reconnect(&mut self) {
+        self.player.lock().await.disconnect().await.unwrap();
+        self.previous.push(Arc::downgrade(&self.player));
+
+        let (player, transport) =
+            Self::player(&self.client, &self.clock, &self.game, &self.configure).await;
+        self.player = player;
+        self.transport = transport;
+        self.join().await;
+        self.stats.reconnects += 1;
+    }
+
+    // Players of earlier connections that are still alive
+    pub(crate) fn leaked_players(&self) -> usize {
+        self.previous
+            .iter()
+            .filter(|player| player.strong_count() > 0)
+            .count()
+    }
+}
+
+mod tests {
+    use super::*;
+
+    async fn run(ticks: usize) {
+        let maps = ["a", "b", "c"];
+        let mut soak = Soak::new(&maps).await;
+        let mut round = 0usize;
+        while soak.stats.ticks < ticks {
+            round += 1;
+            for _ in 0..500 {
+                soak.step().await;
+            }
+            soak.die().await;
+            if round.is_multiple_of(4) {
+                let map = maps[round / 4 % maps.len()];
+                soak.change_map(map).await;
+            }
+            if round.is_multiple_of(10) {
+                soak.reconnect().await;
+            }
+            // Tasks of ended connections get a moment to finish
+            tokio::task::yield_now().await;
+        }
+
+        let stats = soak.stats;
+        assert!(stats.deaths > 0 && stats.games > 0 && stats.reconnects > 0);
+        // Every tick is acknowledged with the next one
+        assert!(stats.max_state_buffer <= 4, "{:?}", stats);
+        assert!(stats.max_queued_messages <= 8, "{:?}", stats);
+        assert!(stats.max_player_tasks <= 3, "{:?}", stats);
+        assert!(stats.max_client_tasks <= 2, "{:?}", stats);
+        // One other player per spawn, forgotten at the end of every game
+        assert!(stats.max_known_players <= 10, "{:?}", stats);
+        assert_eq!(soak.leaked_players(), 0);
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_published_every_tick() {
+        let mut soak = Soak::new(&["a"]).await;
+        soak.until(|player| player.map().is_some()).await;
+        let mut snapshots = soak.player.lock().await.watch_snapshot();
+        snapshots.mark_unchanged();
+
+        soak.step().await;
+        assert!(snapshots.has_changed().unwrap());
+        let snapshot = snapshots.borrow_and_update().clone();
+        assert!(snapshot.in_game);
+        assert_eq!(snapshot.id.as_deref(), Some(PLAYER_ID));
+        assert_eq!(snapshot.map.as_deref(), Some("a"));
+        assert_eq!(snapshot.game_id, "SOAK:game");
+    }
+
+    #[tokio::test]
+    async fn soak() {
+        run(50_000).await;
+    }
+
+    // About a week of ticks, `cargo test soak_long -- --ignored` before a release
+    #[tokio::test]
+    #[ignore]
+    async fn soak_long() {
+        run(10_000_000).await;
+    }
+}
@@ -1,47 +1,115 @@
-use std::sync::Arc;
+use std::{collections::VecDeque, io::Cursor, sync::Arc, time::Duration};
 
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
-use serde::Serialize;
-use tokio::{net::TcpStream, sync::Mutex};
+use serde::{Deserialize, Serialize};
+#[cfg(test)]
+use tokio::sync::mpsc;
+use tokio::{net::TcpStream, runtime::Handle, sync::Mutex};
 use tokio_tungstenite::{
-    connect_async,
     tungstenite::{
+        self,
         handshake::client::{generate_key, Request},
         Message,
     },
     MaybeTlsStream, WebSocketStream,
 };
+use tracing::warn;
 
-use crate::{utils::Error, Client, Game};
+use crate::{
+    clock::Clock,
+    messages::ServerMessage,
+    proxy::{connect_ws, ProxyConfig},
+    quirks::{Padding, ProtocolQuirks},
+    tasks::TaskRegistry,
+    utils::Error,
+    Client, Game,
+};
 
 type WSSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
 
+enum Sink {
+    WebSocket(WSSink),
+    // Frames sent to a MockTransport
+    #[cfg(test)]
+    Mock(mpsc::UnboundedSender<Vec<u8>>),
+}
+
+const MAX_QUEUED_MESSAGES: usize = 4096;
+const PADDING_LEN: usize = 2;
+
 #[derive(Debug)]
 pub enum SocketMessage {
-    Message(String, Vec<serde_json::Value>),
+    Message(ServerMessage),
     Error(Error),
     Close,
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketStats {
+    pub frames_sent: usize,
+    pub bytes_sent: usize,
+    pub frames_received: usize,
+    pub bytes_received: usize,
+    pub messages_decoded: usize,
+    pub decode_errors: usize,
+    pub messages_dropped: usize,
+}
+
 pub struct Socket {
-    ws_write: Option<WSSink>,
-    messages: Arc<Mutex<Vec<SocketMessage>>>,
+    ws_write: Option<Sink>,
+    messages: Arc<Mutex<VecDeque<SocketMessage>>>,
+    stats: Arc<Mutex<SocketStats>>,
+    // Only holds the read task of the current connection
+    tasks: TaskRegistry,
+    quirks: Arc<ProtocolQuirks>,
+    detected_padding: Arc<std::sync::Mutex<Option<bool>>>,
+    clock: Clock,
+    proxy: Option<ProxyConfig>,
     prime: u16,
     num: u16,
 }
 
 impl Socket {
     pub async fn new(client: &Arc<Mutex<Client>>) -> Self {
+        Self::with_quirks(client, ProtocolQuirks::default()).await
+    }
+
+    pub async fn with_quirks(client: &Arc<Mutex<Client>>, quirks: ProtocolQuirks) -> Self {
+        let client = client.lock().await;
         Self {
             ws_write: None,
-            messages: Arc::new(Mutex::new(vec![])),
-            prime: client.lock().await.prime,
+            messages: Arc::new(Mutex::new(VecDeque::new())),
+            stats: Arc::new(Mutex::new(SocketStats::default())),
+            tasks: TaskRegistry::current(),
+            quirks: Arc::new(quirks),
+            detected_padding: Arc::new(std::sync::Mutex::new(None)),
+            clock: Clock::default(),
+            proxy: client.proxy().cloned(),
+            prime: client.prime,
             num: 0,
         }
     }
 
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    // Runtime the read task is spawned on, has to be set before connecting
+    pub fn set_runtime(&mut self, runtime: Handle) {
+        self.tasks = TaskRegistry::new(runtime);
+    }
+
+    pub(crate) fn task_count(&self) -> usize {
+        self.tasks.count()
+    }
+
+    // Defaults to the proxy of the client
+    pub fn set_proxy(&mut self, proxy: Option<ProxyConfig>) {
+        self.proxy = proxy;
+    }
+
     pub async fn connect(&mut self, game: &Game) -> Result<(), Error> {
-        let game_info = game.connect_info().await?;
+        let game_info = game.with_proxy(self.proxy.as_ref())?.connect_info().await?;
 
         let req = Request::builder()
             .header("Host", game_info.host.clone())
@@ -49,64 +117,78 @@ impl Socket {
             .header("Upgrade", "websocket")
             .header("Sec-WebSocket-Version", "13")
             .header("Sec-WebSocket-Key", generate_key())
-            .header("Origin", "https://krunker.io")
+            .header("Origin", game.origin())
             .uri(format!(
                 "wss://{}/ws?gameId={}&clientKey={}",
                 game_info.host, game_info.game_id, game_info.client_id
             ))
             .body(())?;
 
-        let (ws_stream, _) = connect_async(req).await?;
+        let ws_stream = connect_ws(req, self.proxy.as_ref()).await?;
         let (ws_write, ws_read) = ws_stream.split();
 
-        self.ws_write = Some(ws_write);
-        self.num = 0;
-
-        let messages = self.messages.clone();
-        messages.lock().await.clear();
-        tokio::spawn(async move {
+        let receiver = self.reset(Sink::WebSocket(ws_write)).await;
+        self.tasks.spawn(async move {
             ws_read
-                .for_each(|msg| async {
-                    match msg {
-                        Ok(msg) => match msg {
-                            Message::Binary(msg) => match Self::decode_message(&msg) {
-                                Ok(decoded) => messages
-                                    .lock()
-                                    .await
-                                    .push(SocketMessage::Message(decoded.0, decoded.1)),
-                                Err(err) => messages.lock().await.push(SocketMessage::Error(err)),
-                            },
-                            Message::Close(_) => messages.lock().await.push(SocketMessage::Close),
-                            _ => messages.lock().await.push(SocketMessage::Error(
-                                "Received unexpected non binary or close message.".into(),
-                            )),
-                        },
-                        Err(err) => messages.lock().await.push(SocketMessage::Error(err.into())),
-                    }
-                })
+                .for_each(|msg| async { receiver.receive(msg).await })
                 .await;
         });
 
         Ok(())
     }
 
+    // Connects to a MockTransport instead of a server
+    #[cfg(test)]
+    pub(crate) async fn connect_mock(&mut self) -> MockTransport {
+        let (sent_tx, sent) = mpsc::unbounded_channel();
+        let receiver = self.reset(Sink::Mock(sent_tx)).await;
+        MockTransport { receiver, sent }
+    }
+
+    // State of the previous connection is dropped, its read task included
+    async fn reset(&mut self, ws_write: Sink) -> Receiver {
+        self.ws_write = Some(ws_write);
+        self.num = 0;
+
+        self.messages.lock().await.clear();
+        *self.stats.lock().await = SocketStats::default();
+        *self.detected_padding.lock().unwrap() = None;
+        self.tasks.shutdown().await;
+
+        Receiver {
+            messages: self.messages.clone(),
+            stats: self.stats.clone(),
+            quirks: self.quirks.clone(),
+            detected_padding: self.detected_padding.clone(),
+        }
+    }
+
     pub async fn send<S: Serialize>(&mut self, msg: &S) -> Result<(), Error> {
         let msg = self.encode_message(msg)?;
-        self.ws_write
-            .as_mut()
-            .ok_or("Socket not open")?
-            .send(Message::Binary(msg))
-            .await?;
+        let len = msg.len();
+        match self.ws_write.as_mut().ok_or("Socket not open")? {
+            Sink::WebSocket(ws_write) => ws_write.send(Message::Binary(msg)).await?,
+            #[cfg(test)]
+            Sink::Mock(sent) => sent.send(msg).map_err(|_| "Mock transport was dropped")?,
+        }
+
+        let mut stats = self.stats.lock().await;
+        stats.frames_sent += 1;
+        stats.bytes_sent += len;
 
         Ok(())
     }
 
     pub async fn close(&mut self) -> Result<(), Error> {
-        if let Some(ws_write) = self.ws_write.as_mut() {
-            ws_write.close().await?;
-            self.ws_write = None;
-        }
-        Ok(())
+        let result = match self.ws_write.take() {
+            Some(Sink::WebSocket(mut ws_write)) => ws_write.close().await,
+            #[cfg(test)]
+            Some(Sink::Mock(_)) => Ok(()),
+            None => Ok(()),
+        };
+        // The read task is stopped even if the close frame could not be sent
+        self.tasks.shutdown().await;
+        Ok(result?)
     }
 
     pub fn is_connected(&self) -> bool {
@@ -117,10 +199,91 @@ impl Socket {
         self.messages.lock().await.drain(..).collect()
     }
 
+    pub async fn queued_messages(&self) -> usize {
+        self.messages.lock().await.len()
+    }
+
+    pub async fn stats(&self) -> SocketStats {
+        *self.stats.lock().await
+    }
+
+    pub(crate) fn stats_handle(&self) -> Arc<Mutex<SocketStats>> {
+        self.stats.clone()
+    }
+
+    // Wait until the first inbound message could be decoded. If frames arrive but none of them
+    // can be decoded the protocol most likely changed and the extracted prime is outdated.
+    pub async fn wait_for_handshake(&self, timeout: Duration) -> Result<(), Error> {
+        let start = self.clock.now();
+        while self.clock.elapsed(start) < timeout {
+            if self.stats().await.messages_decoded > 0 {
+                return Ok(());
+            }
+            self.clock.sleep(Duration::from_millis(50)).await;
+        }
+
+        let stats = self.stats().await;
+        if stats.messages_decoded == 0 && stats.frames_received > 0 {
+            Err(Error::ProtocolDesync {
+                frames_received: stats.frames_received,
+                decode_errors: stats.decode_errors,
+            })
+        } else {
+            if stats.frames_received == 0 {
+                warn!("Received no messages within {:?} after connecting", timeout);
+            }
+            Ok(())
+        }
+    }
+
+    // Whether the frames of this connection carry the padding bytes
+    pub fn padded(&self) -> bool {
+        match self.quirks.padding {
+            Padding::Enabled => true,
+            Padding::Disabled => false,
+            Padding::Detect => self.detected_padding.lock().unwrap().unwrap_or(true),
+        }
+    }
+
+    fn resolve_padding(
+        quirks: &ProtocolQuirks,
+        detected_padding: &std::sync::Mutex<Option<bool>>,
+        msg: &[u8],
+    ) -> bool {
+        match quirks.padding {
+            Padding::Enabled => true,
+            Padding::Disabled => false,
+            Padding::Detect => {
+                let mut detected_padding = detected_padding.lock().unwrap();
+                if detected_padding.is_none() {
+                    *detected_padding = Self::detect_padding(msg);
+                    if *detected_padding == Some(false) {
+                        warn!("Server frames have no padding bytes, disabling padding");
+                    }
+                }
+                detected_padding.unwrap_or(true)
+            }
+        }
+    }
+
+    // A padded frame has exactly the two padding bytes left after the msgpack value, an unpadded
+    // one nothing. Anything else can't be decided from this frame.
+    pub fn detect_padding(msg: &[u8]) -> Option<bool> {
+        match Self::split_frame(msg).ok()?.1 {
+            PADDING_LEN => Some(true),
+            0 => Some(false),
+            _ => None,
+        }
+    }
+
     pub fn encode_message<S: Serialize>(&mut self, msg: &S) -> Result<Vec<u8>, Error> {
         // Encode the actual data with msgpack
         let mut encoded = rmp_serde::encode::to_vec(msg)?;
 
+        if !self.padded() {
+            return Ok(encoded);
+        }
+
         // Rotate num by the prime every message
         self.num = (self.num + self.prime) & 0xFF;
         // Append the 2 padding bytes to the message
@@ -131,21 +294,269 @@ impl Socket {
     }
 
     pub fn decode_message(msg: &[u8]) -> Result<(String, Vec<serde_json::Value>), Error> {
-        // Decode the message without the last two padding bytes wich are unused in the game
-        let mut decoded =
-            rmp_serde::decode::from_slice::<serde_json::Value>(&msg[..msg.len() - 2])?;
+        Self::decode_message_with(msg, &ProtocolQuirks::default(), true)
+    }
+
+    pub fn decode_message_with(
+        msg: &[u8],
+        quirks: &ProtocolQuirks,
+        padded: bool,
+    ) -> Result<(String, Vec<serde_json::Value>), Error> {
+        if padded && msg.len() < PADDING_LEN {
+            return Err("Message is shorter than the padding bytes".into());
+        }
+
+        // Decode the msgpack value, the padding bytes after it are unused in the game
+        let (mut decoded, trailing) = Self::split_frame(msg)?;
+        let expected = if padded { PADDING_LEN } else { 0 };
+        if trailing < expected || (trailing > expected && quirks.strict_decode) {
+            return Err(format!(
+                "Message has {} trailing bytes, expected {}",
+                trailing, expected
+            )
+            .into());
+        }
+
         let decoded = decoded
             .as_array_mut()
             .ok_or("Decoded message is not an array")?;
 
         Ok((
-            decoded
-                .first()
-                .ok_or("Decoded message length is zero")?
-                .as_str()
-                .ok_or("Decoded message type is not a string")?
-                .to_owned(),
+            quirks.resolve_kind(
+                decoded
+                    .first()
+                    .ok_or("Decoded message length is zero")?
+                    .as_str()
+                    .ok_or("Decoded message type is not a string")?
+                    .to_owned(),
+            ),
             decoded[1..].to_vec(),
         ))
     }
+
+    // Decode the first msgpack value of the frame and count the bytes left after it
+    fn split_frame(msg: &[u8]) -> Result<(serde_json::Value, usize), Error> {
+        let mut cursor = Cursor::new(msg);
+        let decoded =
+            serde_json::Value::deserialize(&mut rmp_serde::Deserializer::new(&mut cursor))?;
+        Ok((decoded, msg.len() - cursor.position() as usize))
+    }
+}
+
+// Read side of a connection, queues what the server sent for Socket::get_messages
+struct Receiver {
+    messages: Arc<Mutex<VecDeque<SocketMessage>>>,
+    stats: Arc<Mutex<SocketStats>>,
+    quirks: Arc<ProtocolQuirks>,
+    detected_padding: Arc<std::sync::Mutex<Option<bool>>>,
+}
+
+impl Receiver {
+    async fn receive(&self, msg: Result<Message, tungstenite::Error>) {
+        let stats = &self.stats;
+        let msg = match msg {
+            Ok(Message::Binary(msg)) => {
+                {
+                    let mut stats = stats.lock().await;
+                    stats.frames_received += 1;
+                    stats.bytes_received += msg.len();
+                }
+                let padded = Socket::resolve_padding(&self.quirks, &self.detected_padding, &msg);
+                match Socket::decode_message_with(&msg, &self.quirks, padded) {
+                    Ok(decoded) => {
+                        stats.lock().await.messages_decoded += 1;
+                        SocketMessage::Message(ServerMessage {
+                            kind: decoded.0,
+                            data: decoded.1,
+                        })
+                    }
+                    Err(err) => {
+                        stats.lock().await.decode_errors += 1;
+                        SocketMessage::Error(err)
+                    }
+                }
+            }
+            Ok(Message::Close(_)) => SocketMessage::Close,
+            // Answered by tungstenite
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => return,
+            Ok(_) => {
+                SocketMessage::Error("Received unexpected non binary or close message.".into())
+            }
+            Err(err) => SocketMessage::Error(err.into()),
+        };
+
+        // Drop the oldest messages if nobody drains the queue so it can't grow forever
+        let mut messages = self.messages.lock().await;
+        if messages.len() >= MAX_QUEUED_MESSAGES {
+            messages.pop_front();
+            stats.lock().await.messages_dropped += 1;
+        }
+        messages.push_back(msg);
+    }
+}
+
+// Server side of a mocked connection, see Socket::connect_mock. Frames go through the same decoding
+// and queue as frames of a server.
+#[cfg(test)]
+pub(crate) struct MockTransport {
+    receiver: Receiver,
+    sent: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub(crate) async fn push(&self, kind: &str, data: Vec<serde_json::Value>) {
+        let mut msg = vec![serde_json::Value::from(kind)];
+        msg.extend(data);
+        let mut frame = rmp_serde::encode::to_vec(&msg).unwrap();
+        frame.extend([0; PADDING_LEN]);
+        self.push_frame(frame).await;
+    }
+
+    pub(crate) async fn push_frame(&self, frame: Vec<u8>) {
+        self.receiver.receive(Ok(Message::Binary(frame))).await;
+    }
+
+    pub(crate) async fn close(&self) {
+        self.receiver.receive(Ok(Message::Close(None))).await;
+    }
+
+    pub(crate) async fn fail(&self) {
+        self.receiver
+            .receive(Err(tungstenite::Error::ConnectionClosed))
+            .await;
+    }
+
+    // Messages the client sent since the last call
+    pub(crate) fn take_sent(&mut self) -> Vec<(String, Vec<serde_json::Value>)> {
+        let mut sent = vec![];
+        while let Ok(frame) = self.sent.try_recv() {
+            sent.push(Socket::decode_message(&frame).unwrap());
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn frame(trailing: &[u8]) -> Vec<u8> {
+        let mut frame = rmp_serde::encode::to_vec(&json!(["pi", 1])).unwrap();
+        frame.extend(trailing);
+        frame
+    }
+
+    #[test]
+    fn detects_padding_from_trailing_bytes() {
+        assert_eq!(Socket::detect_padding(&frame(&[3, 7])), Some(true));
+        assert_eq!(Socket::detect_padding(&frame(&[])), Some(false));
+        assert_eq!(Socket::detect_padding(&frame(&[1])), None);
+        assert_eq!(Socket::detect_padding(&frame(&[1, 2, 3])), None);
+        assert_eq!(Socket::detect_padding(&[0xc1]), None);
+    }
+
+    #[test]
+    fn decodes_padded_frames() {
+        let (kind, data) = Socket::decode_message(&frame(&[3, 7])).unwrap();
+        assert_eq!(kind, "pi");
+        assert_eq!(data, vec![json!(1)]);
+    }
+
+    #[test]
+    fn ignores_extra_trailing_bytes_by_default() {
+        let quirks = ProtocolQuirks::default();
+        let (kind, data) =
+            Socket::decode_message_with(&frame(&[3, 7, 9, 9]), &quirks, true).unwrap();
+        assert_eq!(kind, "pi");
+        assert_eq!(data, vec![json!(1)]);
+    }
+
+    #[test]
+    fn strict_decoding_rejects_extra_trailing_bytes() {
+        let quirks = ProtocolQuirks::default().strict_decode(true);
+        assert!(Socket::decode_message_with(&frame(&[3, 7]), &quirks, true).is_ok());
+        assert!(Socket::decode_message_with(&frame(&[3, 7, 9]), &quirks, true).is_err());
+    }
+
+    #[test]
+    fn missing_padding_fails_only_when_padded() {
+        let quirks = ProtocolQuirks::default();
+        assert!(Socket::decode_message_with(&frame(&[]), &quirks, true).is_err());
+        assert!(Socket::decode_message_with(&frame(&[]), &quirks, false).is_ok());
+    }
+
+    #[test]
+    fn same_message_under_default_and_modded_quirks() {
+        let default = ProtocolQuirks::default();
+        let modded = ProtocolQuirks::modded().kind_alias("ping", "pi");
+
+        let official = Socket::decode_message_with(&frame(&[3, 7]), &default, true).unwrap();
+
+        let mut unpadded = rmp_serde::encode::to_vec(&json!(["ping", 1])).unwrap();
+        let padded = Socket::detect_padding(&unpadded).unwrap();
+        let modded_message = Socket::decode_message_with(&unpadded, &modded, padded).unwrap();
+        assert_eq!(official, modded_message);
+
+        // Modded servers that append their own data still decode
+        unpadded.extend([1, 2, 3, 4, 5]);
+        let appended = Socket::decode_message_with(&unpadded, &modded, true).unwrap();
+        assert_eq!(official, appended);
+    }
+
+    #[test]
+    fn garbled_frames_fail_to_decode() {
+        assert!(Socket::decode_message(&[0xc1, 0, 0]).is_err());
+        assert!(Socket::decode_message(&[0x92]).is_err());
+        // Not an array
+        let mut frame = rmp_serde::encode::to_vec(&json!("pi")).unwrap();
+        frame.extend([0, 0]);
+        assert!(Socket::decode_message(&frame).is_err());
+    }
+
+    async fn mock_socket() -> (Socket, MockTransport) {
+        let client = Arc::new(Mutex::new(crate::soak::client(
+            vec![],
+            "http://127.0.0.1:1",
+        )));
+        let mut socket = Socket::new(&client).await;
+        let transport = socket.connect_mock().await;
+        (socket, transport)
+    }
+
+    #[tokio::test]
+    async fn undecodable_frames_fail_the_handshake_as_a_desync() {
+        let (socket, transport) = mock_socket().await;
+        for _ in 0..3 {
+            transport.push_frame(vec![0xc1, 0, 0]).await;
+        }
+
+        match socket.wait_for_handshake(Duration::from_millis(100)).await {
+            Err(Error::ProtocolDesync {
+                frames_received,
+                decode_errors,
+            }) => assert_eq!((frames_received, decode_errors), (3, 3)),
+            other => panic!("expected a protocol desync, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn one_decoded_frame_completes_the_handshake() {
+        let (socket, transport) = mock_socket().await;
+        transport.push_frame(vec![0xc1, 0, 0]).await;
+        transport.push("io-init", vec![json!("id")]).await;
+        socket
+            .wait_for_handshake(Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        // A silent server is only logged, the player notices it by itself
+        let (socket, _transport) = mock_socket().await;
+        socket
+            .wait_for_handshake(Duration::from_millis(100))
+            .await
+            .unwrap();
+    }
 }
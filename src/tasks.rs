@@ -0,0 +1,156 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{runtime::Handle, task::JoinHandle};
+
+// Background tasks of a client, player or socket. Everything is spawned on the runtime the owner was
+// configured with instead of whatever runtime happens to be current, and the tasks are aborted together
+// with their owner.
+#[derive(Debug, Clone)]
+pub(crate) struct TaskRegistry {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    runtime: Handle,
+    handles: std::sync::Mutex<Vec<JoinHandle<()>>>,
+    running: Arc<AtomicUsize>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for handle in self.handles.get_mut().unwrap().drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+// Lives inside the spawned future, so the count also goes down for tasks that were aborted or dropped
+// by a runtime shutdown before they finished
+struct Running(Arc<AtomicUsize>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl TaskRegistry {
+    pub(crate) fn new(runtime: Handle) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                runtime,
+                handles: std::sync::Mutex::new(vec![]),
+                running: Arc::new(AtomicUsize::new(0)),
+            }),
+        }
+    }
+
+    // Panics outside of a runtime like tokio::spawn
+    pub(crate) fn current() -> Self {
+        Self::new(Handle::current())
+    }
+
+    pub(crate) fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.running.fetch_add(1, Ordering::SeqCst);
+        let running = Running(self.inner.running.clone());
+        let handle = self.inner.runtime.spawn(async move {
+            let _running = running;
+            future.await;
+        });
+        let mut handles = self.inner.handles.lock().unwrap();
+        // Finished tasks don't need to be aborted, this keeps the list as long as the running tasks
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle);
+    }
+
+    pub(crate) fn runtime(&self) -> Handle {
+        self.inner.runtime.clone()
+    }
+
+    // Tasks that have not finished yet
+    pub(crate) fn count(&self) -> usize {
+        self.inner.running.load(Ordering::SeqCst)
+    }
+
+    // Abort all tasks and wait until they are gone. Must not be called from one of the tasks.
+    pub(crate) async fn shutdown(&self) {
+        let handles = std::mem::take(&mut *self.inner.handles.lock().unwrap());
+        for handle in handles {
+            handle.abort();
+            let _ = handle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn finished_handles_are_dropped() {
+        let tasks = TaskRegistry::current();
+        for _ in 0..100 {
+            tasks.spawn(async {});
+            tokio::task::yield_now().await;
+        }
+        while tasks.count() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        tasks.spawn(async {});
+        assert!(tasks.inner.handles.lock().unwrap().len() <= 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_aborts_running_tasks() {
+        let tasks = TaskRegistry::current();
+        let (_tx, rx) = oneshot::channel::<()>();
+        tasks.spawn(async move {
+            let _ = rx.await;
+        });
+        tasks.spawn(async { tokio::time::sleep(Duration::from_secs(3600)).await });
+        assert_eq!(tasks.count(), 2);
+
+        tasks.shutdown().await;
+        assert_eq!(tasks.count(), 0);
+    }
+
+    #[test]
+    fn players_on_a_dedicated_runtime_leak_no_tasks() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let registries = (0..100)
+            .map(|_| {
+                let tasks = TaskRegistry::new(runtime.handle().clone());
+                tasks.spawn(async { tokio::time::sleep(Duration::from_secs(3600)).await });
+                tasks
+            })
+            .collect::<Vec<_>>();
+        assert!(registries.iter().all(|tasks| tasks.count() == 1));
+
+        runtime.block_on(async {
+            for tasks in &registries {
+                tasks.shutdown().await;
+            }
+        });
+        assert!(registries.iter().all(|tasks| tasks.count() == 0));
+    }
+}
@@ -0,0 +1,170 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    map::{MapBuildOptions, PathOptions},
+    player::WalkOptions,
+    utils::Error,
+};
+
+// Overrides for a single map, keyed by map name or fingerprint. Options passed to a call win over
+// the map tuning, which wins over the client wide map options and the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct MapTuning {
+    pub build: Option<MapBuildOptions>,
+    // Used by walk_to, walk_to_named and walk_route
+    pub walk: Option<WalkOptions>,
+    // Used by find_path
+    pub path: Option<PathOptions>,
+}
+
+// One [maps.<name or fingerprint>] table, unset values keep the defaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TuningEntry {
+    player_radius: Option<f32>,
+    refine_narrow_passages: Option<bool>,
+    max_jump_gap: Option<usize>,
+    hierarchical_paths: Option<bool>,
+    arrive_distance_xz: Option<f32>,
+    arrive_distance_y: Option<f32>,
+    latency_compensation: Option<bool>,
+    lookahead_distance: Option<f32>,
+    final_arrive_distance: Option<f32>,
+    heuristic_weight: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TuningFile {
+    #[serde(default)]
+    maps: HashMap<String, TuningEntry>,
+}
+
+impl MapTuning {
+    pub fn from_toml(path: impl AsRef<Path>) -> Result<HashMap<String, Self>, Error> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Self::from_toml_str(&content)
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<HashMap<String, Self>, Error> {
+        let file: TuningFile = toml::from_str(content)?;
+        Ok(file
+            .maps
+            .into_iter()
+            .map(|(key, entry)| (key, entry.into()))
+            .collect())
+    }
+}
+
+impl From<TuningEntry> for MapTuning {
+    fn from(entry: TuningEntry) -> Self {
+        let build_set = entry.player_radius.is_some()
+            || entry.refine_narrow_passages.is_some()
+            || entry.max_jump_gap.is_some()
+            || entry.hierarchical_paths.is_some();
+        let build = build_set.then(|| {
+            let defaults = MapBuildOptions::default();
+            MapBuildOptions {
+                player_radius: entry.player_radius.unwrap_or(defaults.player_radius),
+                refine_narrow_passages: entry
+                    .refine_narrow_passages
+                    .unwrap_or(defaults.refine_narrow_passages),
+                max_jump_gap: entry.max_jump_gap.unwrap_or(defaults.max_jump_gap),
+                hierarchical_paths: entry
+                    .hierarchical_paths
+                    .unwrap_or(defaults.hierarchical_paths),
+            }
+        });
+
+        let walk_set = entry.arrive_distance_xz.is_some()
+            || entry.arrive_distance_y.is_some()
+            || entry.latency_compensation.is_some()
+            || entry.lookahead_distance.is_some()
+            || entry.final_arrive_distance.is_some();
+        let walk = walk_set.then(|| {
+            let defaults = WalkOptions::default();
+            WalkOptions {
+                arrive_distance_xz: entry
+                    .arrive_distance_xz
+                    .unwrap_or(defaults.arrive_distance_xz),
+                arrive_distance_y: entry
+                    .arrive_distance_y
+                    .unwrap_or(defaults.arrive_distance_y),
+                latency_compensation: entry
+                    .latency_compensation
+                    .unwrap_or(defaults.latency_compensation),
+                lookahead_distance: entry.lookahead_distance.or(defaults.lookahead_distance),
+                final_arrive_distance: entry
+                    .final_arrive_distance
+                    .or(defaults.final_arrive_distance),
+                ..defaults
+            }
+        });
+
+        let path = entry
+            .heuristic_weight
+            .map(|weight| PathOptions::default().heuristic_weight(weight));
+
+        Self { build, walk, path }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_only_override_what_they_set() {
+        let tuning = MapTuning::from_toml_str(
+            r#"
+            [maps.Littletown]
+            player_radius = 2.0
+
+            [maps.Sandstorm]
+            lookahead_distance = 3.0
+
+            [maps.Burg]
+            heuristic_weight = 1.5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(tuning.len(), 3);
+
+        let build = tuning["Littletown"].build.unwrap();
+        assert_eq!(
+            build,
+            MapBuildOptions {
+                player_radius: 2.0,
+                ..Default::default()
+            }
+        );
+        assert!(tuning["Littletown"].walk.is_none() && tuning["Littletown"].path.is_none());
+
+        let walk = tuning["Sandstorm"].walk.clone().unwrap();
+        let defaults = WalkOptions::default();
+        assert_eq!(walk.lookahead_distance, Some(3.0));
+        assert_eq!(walk.arrive_distance_xz, defaults.arrive_distance_xz);
+        assert_eq!(walk.final_arrive_distance, defaults.final_arrive_distance);
+        assert!(tuning["Sandstorm"].build.is_none());
+
+        assert_eq!(
+            format!("{:?}", tuning["Burg"].path.unwrap()),
+            format!("{:?}", PathOptions::default().heuristic_weight(1.5))
+        );
+        assert!(tuning["Burg"].build.is_none() && tuning["Burg"].walk.is_none());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(MapTuning::from_toml_str("").unwrap().is_empty());
+        assert!(MapTuning::from_toml_str("[maps.Burg]\nradius = 2.0").is_err());
+        assert!(MapTuning::from_toml_str("[maps.Burg]\nplayer_radius = \"2\"").is_err());
+        assert!(MapTuning::from_toml("does/not/exist.toml")
+            .unwrap_err()
+            .to_string()
+            .contains("Failed to read"));
+    }
+}
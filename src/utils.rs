@@ -1,8 +1,13 @@
+use serde::{Deserialize, Serialize};
+
 use crate::map::CELL_SIZE;
 
-pub type Error = Box<dyn std::error::Error + Sync + Send>;
+pub type Error = crate::error::KrunkerError;
+
+// Index of a cell in the walkable grid as (x, y, z)
+pub type Cell = (usize, usize, usize);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct AABB {
     pub min_x: f32,
     pub min_y: f32,
@@ -66,6 +71,30 @@ impl AABB {
         }
     }
 
+    pub fn is_finite(&self) -> bool {
+        [
+            self.min_x, self.min_y, self.min_z, self.max_x, self.max_y, self.max_z,
+        ]
+        .iter()
+        .all(|v| v.is_finite())
+    }
+
+    // Swap min and max of inverted axes, returns whether anything was swapped
+    pub fn normalize(&mut self) -> bool {
+        let mut inverted = false;
+        for (min, max) in [
+            (&mut self.min_x, &mut self.max_x),
+            (&mut self.min_y, &mut self.max_y),
+            (&mut self.min_z, &mut self.max_z),
+        ] {
+            if *min > *max {
+                std::mem::swap(min, max);
+                inverted = true;
+            }
+        }
+        inverted
+    }
+
     pub fn intersects(&self, other: &Self) -> bool {
         (self.min_x < other.max_x && self.max_x > other.min_x)
             && (self.min_y < other.max_y && self.max_y > other.min_y)
@@ -77,9 +106,33 @@ impl AABB {
             && (self.min_y <= position.y && self.max_y >= position.y)
             && (self.min_z <= position.z && self.max_z >= position.z)
     }
+
+    // Distances along the ray where it enters and leaves the box (slab method), the entry is
+    // negative if the origin is inside
+    pub fn ray_intersection(&self, origin: &Vec3, direction: &Vec3) -> Option<(f32, f32)> {
+        let mut t_enter = f32::NEG_INFINITY;
+        let mut t_exit = f32::INFINITY;
+        for (origin, direction, min, max) in [
+            (origin.x, direction.x, self.min_x, self.max_x),
+            (origin.y, direction.y, self.min_y, self.max_y),
+            (origin.z, direction.z, self.min_z, self.max_z),
+        ] {
+            if direction == 0.0 {
+                if origin < min || origin > max {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (min - origin) / direction;
+            let t2 = (max - origin) / direction;
+            t_enter = t_enter.max(t1.min(t2));
+            t_exit = t_exit.min(t1.max(t2));
+        }
+        (t_enter <= t_exit && t_exit >= 0.0).then_some((t_enter, t_exit))
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Vec3 {
     pub x: f32,
     pub y: f32,
@@ -94,6 +147,15 @@ impl Vec3 {
     pub fn max_diff_y(&self, other: &Self, max_diff: f32) -> bool {
         (self.y - other.y).abs() <= max_diff
     }
+
+    pub fn distance_xz(&self, other: &Self) -> f32 {
+        ((self.x - other.x).powi(2) + (self.z - other.z).powi(2)).sqrt()
+    }
+
+    pub fn distance(&self, other: &Self) -> f32 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2) + (self.z - other.z).powi(2))
+            .sqrt()
+    }
 }
 
 pub fn position_to_cell(map_bounds: &AABB, position: &Vec3) -> (usize, usize, usize) {
@@ -104,6 +166,20 @@ pub fn position_to_cell(map_bounds: &AABB, position: &Vec3) -> (usize, usize, us
     )
 }
 
+// Like position_to_cell but positions outside of the bounds are moved to the closest cell on the edge of the grid
+pub fn position_to_cell_clamped(map_bounds: &AABB, position: &Vec3) -> (usize, usize, usize) {
+    let clamp = |value: f32, min: f32, max: f32| {
+        let cells = ((max - min) / CELL_SIZE).ceil().max(1.0);
+        ((value - min) / CELL_SIZE).floor().clamp(0.0, cells - 1.0) as usize
+    };
+
+    (
+        clamp(position.x, map_bounds.min_x, map_bounds.max_x),
+        clamp(position.y, map_bounds.min_y, map_bounds.max_y),
+        clamp(position.z, map_bounds.min_z, map_bounds.max_z),
+    )
+}
+
 pub fn cell_to_position(map_bounds: &AABB, cell: &(usize, usize, usize)) -> Vec3 {
     Vec3 {
         x: map_bounds.min_x + cell.0 as f32 * CELL_SIZE + CELL_SIZE / 2.0,
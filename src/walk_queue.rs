@@ -0,0 +1,162 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{player::WalkOptions, utils::Vec3};
+
+#[derive(Debug, Clone)]
+pub struct QueuedWalk {
+    pub destination: Vec3,
+    pub options: WalkOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum WalkOutcome {
+    // Horizontal distance to the destination where the player stopped
+    Arrived { distance: f32 },
+    Failed(String),
+    // Removed from the queue before it was walked, by clear or a death
+    Cleared,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WalkCompleted {
+    pub destination: Vec3,
+    pub outcome: WalkOutcome,
+}
+
+// Destinations a player walks to one after another. Clones share the queue, so other tasks can
+// add destinations while the player is locked by walk_queued.
+#[derive(Debug, Clone)]
+pub struct WalkQueue {
+    entries: Arc<std::sync::Mutex<VecDeque<QueuedWalk>>>,
+    events: broadcast::Sender<WalkCompleted>,
+}
+
+impl Default for WalkQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WalkQueue {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(64);
+        Self {
+            entries: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+            events,
+        }
+    }
+
+    pub fn push(&self, destination: Vec3, options: WalkOptions) {
+        self.entries.lock().unwrap().push_back(QueuedWalk {
+            destination,
+            options,
+        });
+    }
+
+    // Walked right after the current destination
+    pub fn push_front(&self, destination: Vec3, options: WalkOptions) {
+        self.entries.lock().unwrap().push_front(QueuedWalk {
+            destination,
+            options,
+        });
+    }
+
+    pub fn clear(&self) {
+        let cleared = self.entries.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for entry in cleared {
+            self.complete(entry.destination, WalkOutcome::Cleared);
+        }
+    }
+
+    pub fn entries(&self) -> Vec<QueuedWalk> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // One event per entry that was walked or cleared
+    pub fn subscribe(&self) -> broadcast::Receiver<WalkCompleted> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn pop(&self) -> Option<QueuedWalk> {
+        self.entries.lock().unwrap().pop_front()
+    }
+
+    pub(crate) fn complete(&self, destination: Vec3, outcome: WalkOutcome) {
+        // Nobody listening is fine
+        let _ = self.events.send(WalkCompleted {
+            destination,
+            outcome,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Vec3 {
+        Vec3 { x, y: 0.0, z: 0.0 }
+    }
+
+    fn destinations(queue: &WalkQueue) -> Vec<f32> {
+        queue
+            .entries()
+            .iter()
+            .map(|entry| entry.destination.x)
+            .collect()
+    }
+
+    #[test]
+    fn clones_share_the_queue() {
+        let queue = WalkQueue::new();
+        let other = queue.clone();
+        queue.push(at(1.0), WalkOptions::default());
+        other.push(at(2.0), WalkOptions::default());
+        other.push_front(at(0.0), WalkOptions::default());
+
+        assert_eq!(destinations(&queue), [0.0, 1.0, 2.0]);
+        assert_eq!(queue.pop().unwrap().destination.x, 0.0);
+        assert_eq!(other.len(), 2);
+    }
+
+    #[test]
+    fn clearing_completes_every_entry_in_order() {
+        let queue = WalkQueue::default();
+        let mut events = queue.subscribe();
+        for x in [1.0, 2.0] {
+            queue.push(at(x), WalkOptions::default());
+        }
+        queue.complete(at(0.0), WalkOutcome::Arrived { distance: 0.5 });
+        queue.clear();
+        assert!(queue.is_empty() && queue.pop().is_none());
+
+        let mut completed = vec![];
+        while let Ok(event) = events.try_recv() {
+            completed.push((event.destination.x, event.outcome));
+        }
+        assert_eq!(
+            completed,
+            [
+                (0.0, WalkOutcome::Arrived { distance: 0.5 }),
+                (1.0, WalkOutcome::Cleared),
+                (2.0, WalkOutcome::Cleared),
+            ]
+        );
+
+        // Without subscribers nothing fails
+        drop(events);
+        queue.push(at(3.0), WalkOptions::default());
+        queue.clear();
+    }
+}
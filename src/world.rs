@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::utils::Vec3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RemotePlayer {
+    pub id: String,
+    // Teams aren't in the messages the client parses, only known once set through Player::set_team
+    pub team: Option<u8>,
+    pub position: Vec3,
+    // Ticks the local player had sent at the last update, see SessionSummary::ticks_sent
+    pub last_update_tick: u32,
+}
+
+// Last known positions of the other players. The client only parses them out of spawns, the
+// "l" updates of other players aren't decoded, so positions go stale until the next spawn or
+// until a caller feeds its own observations in. Leaves aren't decoded either, see
+// Player::forget_player and Player::stale_player_ticks.
+#[derive(Debug, Clone, Default)]
+pub struct WorldState {
+    players: HashMap<String, RemotePlayer>,
+}
+
+impl WorldState {
+    pub fn update(&mut self, id: &str, position: Vec3, tick: u32) {
+        let player = self
+            .players
+            .entry(id.to_owned())
+            .or_insert_with(|| RemotePlayer {
+                id: id.to_owned(),
+                team: None,
+                position,
+                last_update_tick: tick,
+            });
+        player.position = position;
+        player.last_update_tick = tick;
+    }
+
+    // False if the player isn't known
+    pub fn set_team(&mut self, id: &str, team: u8) -> bool {
+        match self.players.get_mut(id) {
+            Some(player) => {
+                player.team = Some(team);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<RemotePlayer> {
+        self.players.remove(id)
+    }
+
+    pub fn clear(&mut self) {
+        self.players.clear();
+    }
+
+    // Forgets players without an update in the last stale_ticks ticks
+    pub fn prune(&mut self, tick: u32, stale_ticks: u32) {
+        self.players
+            .retain(|_, player| tick.saturating_sub(player.last_update_tick) <= stale_ticks);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&RemotePlayer> {
+        self.players.get(id)
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = &RemotePlayer> {
+        self.players.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    // Players of an unknown team count as enemies, as does everyone without an own team
+    pub fn nearest_enemy(
+        &self,
+        position: &Vec3,
+        team: Option<u8>,
+        exclude: Option<&str>,
+    ) -> Option<&RemotePlayer> {
+        self.players
+            .values()
+            .filter(|player| Some(player.id.as_str()) != exclude)
+            .filter(|player| team.is_none() || player.team != team)
+            .min_by(|a, b| {
+                a.position
+                    .distance(position)
+                    .total_cmp(&b.position.distance(position))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Vec3 {
+        Vec3 { x, y: 0.0, z: 0.0 }
+    }
+
+    #[test]
+    fn update_keeps_one_entry_per_player() {
+        let mut world = WorldState::default();
+        world.update("a", at(1.0), 0);
+        world.update("a", at(2.0), 5);
+
+        assert_eq!(world.len(), 1);
+        let player = world.get("a").unwrap();
+        assert_eq!(player.position.x, 2.0);
+        assert_eq!(player.last_update_tick, 5);
+
+        // The team survives position updates
+        world.set_team("a", 3);
+        world.update("a", at(3.0), 6);
+        assert_eq!(world.get("a").unwrap().team, Some(3));
+    }
+
+    #[test]
+    fn prune_forgets_players_past_the_stale_ticks() {
+        let mut world = WorldState::default();
+        world.update("old", at(0.0), 0);
+        world.update("edge", at(0.0), 50);
+        world.update("new", at(0.0), 190);
+
+        world.prune(200, 150);
+        assert!(world.get("old").is_none());
+        assert!(world.get("edge").is_some());
+        assert!(world.get("new").is_some());
+
+        // Ticks before the last update don't underflow
+        world.prune(0, 0);
+        assert_eq!(world.len(), 2);
+    }
+
+    #[test]
+    fn nearest_enemy_skips_the_excluded_player_and_teammates() {
+        let mut world = WorldState::default();
+        world.update("self", at(0.0), 0);
+        world.update("mate", at(1.0), 0);
+        world.update("enemy", at(5.0), 0);
+        world.update("far", at(-9.0), 0);
+        assert!(world.set_team("mate", 1) && world.set_team("enemy", 2));
+        assert!(!world.set_team("unknown", 1));
+
+        let nearest = |world: &WorldState, team| {
+            world
+                .nearest_enemy(&at(0.0), team, Some("self"))
+                .map(|player| player.id.clone())
+        };
+        assert_eq!(nearest(&world, None), Some("mate".to_owned()));
+        assert_eq!(nearest(&world, Some(1)), Some("enemy".to_owned()));
+
+        world.remove("enemy");
+        assert_eq!(nearest(&world, Some(1)), Some("far".to_owned()));
+    }
+}
@@ -0,0 +1,158 @@
+// Paths between every pair of spawns of the fixture maps, compared against the golden files in
+// tests/fixtures/golden. After an intended change of the routes, rewrite the golden files with
+// `BLESS_GOLDEN_PATHS=1 cargo test --test golden_paths` and review their diff.
+
+use std::{collections::HashSet, fs, path::PathBuf};
+
+use krunker_client::prelude::*;
+
+const FIXTURES: [&str; 4] = ["corridor", "ramp_tower", "ladder_shaft", "arena"];
+
+type Cells = Vec<(usize, usize, usize)>;
+
+fn fixture(dir: &str, name: &str, extension: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", dir]
+        .iter()
+        .collect::<PathBuf>()
+        .join(format!("{}.{}", name, extension))
+}
+
+fn load(name: &str) -> Map {
+    let json = fs::read_to_string(fixture("maps", name, "json")).unwrap();
+    Map::from_raw_json(&json).unwrap()
+}
+
+// One line per pair of spawns: start, end and the cells of the path or "none"
+fn golden_lines(map: &Map) -> Vec<String> {
+    let cells = map
+        .spawns()
+        .iter()
+        .map(|spawn| {
+            map.closest_walkable_cell(spawn)
+                .expect("spawn without a cell")
+        })
+        .collect::<Vec<_>>();
+
+    let mut lines = vec![];
+    for (i, start) in cells.iter().enumerate() {
+        for end in cells[i + 1..].iter() {
+            let path = match map.find_path(start, end) {
+                Some(path) => format_cells(&path),
+                None => "none".to_owned(),
+            };
+            lines.push(format!("{:?} -> {:?}: {}", start, end, path));
+        }
+    }
+    lines
+}
+
+fn format_cells(cells: &[(usize, usize, usize)]) -> String {
+    cells
+        .iter()
+        .map(|(x, y, z)| format!("{},{},{}", x, y, z))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_cells(line: &str) -> Cells {
+    let path = line.split_once(": ").map_or("none", |(_, path)| path);
+    if path == "none" {
+        return vec![];
+    }
+    path.split(' ')
+        .map(|cell| {
+            let mut coords = cell.split(',').map(|coord| coord.parse::<usize>().unwrap());
+            let mut next = || coords.next().unwrap();
+            (next(), next(), next())
+        })
+        .collect()
+}
+
+// Top-down slices of every height the paths pass through. '#' can't be stood on, 'e' is only on the
+// expected path, 'a' only on the actual one and '*' on both.
+fn ascii_slices(map: &Map, expected: &Cells, actual: &Cells) -> String {
+    let (size_x, _, size_z) = map.grid_size();
+    let expected_cells = expected.iter().collect::<HashSet<_>>();
+    let actual_cells = actual.iter().collect::<HashSet<_>>();
+    let mut heights = expected
+        .iter()
+        .chain(actual.iter())
+        .map(|cell| cell.1)
+        .collect::<Vec<_>>();
+    heights.sort_unstable();
+    heights.dedup();
+
+    let mut out = String::new();
+    for y in heights {
+        out += &format!("y = {}\n", y);
+        for z in 0..size_z {
+            for x in 0..size_x {
+                let cell = (x, y, z);
+                out.push(
+                    match (expected_cells.contains(&cell), actual_cells.contains(&cell)) {
+                        (true, true) => '*',
+                        (true, false) => 'e',
+                        (false, true) => 'a',
+                        _ if map.is_walkable_cell(&cell) => '.',
+                        _ => '#',
+                    },
+                );
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[test]
+fn golden_paths() {
+    let bless = std::env::var_os("BLESS_GOLDEN_PATHS").is_some();
+    let mut failures = vec![];
+
+    for name in FIXTURES {
+        let map = load(name);
+        let lines = golden_lines(&map);
+        assert!(
+            lines.iter().all(|line| !line.ends_with("none")),
+            "{} has spawns without a path between them:\n{}",
+            name,
+            lines.join("\n")
+        );
+
+        let golden = fixture("golden", name, "txt");
+        if bless {
+            fs::write(&golden, lines.join("\n") + "\n").unwrap();
+            continue;
+        }
+
+        let expected = fs::read_to_string(&golden).unwrap_or_default();
+        let expected = expected.lines().collect::<Vec<_>>();
+        if expected.len() != lines.len() {
+            failures.push(format!(
+                "{}: {} golden paths, found {}",
+                name,
+                expected.len(),
+                lines.len()
+            ));
+            continue;
+        }
+        for (expected, actual) in expected.iter().zip(lines.iter()) {
+            if expected != actual {
+                failures.push(format!(
+                    "{}:\n  expected {}\n  found    {}\n{}",
+                    name,
+                    expected,
+                    actual,
+                    ascii_slices(&map, &parse_cells(expected), &parse_cells(actual))
+                ));
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} paths changed, run with BLESS_GOLDEN_PATHS=1 if that is intended\n\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}